@@ -1,15 +1,27 @@
 use std::{future::Future, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
+use dashmap::mapref::entry::Entry;
 use lunatic_common_api::{get_memory, IntoTrap};
 use lunatic_distributed::{
-    distributed::message::{ClientError, Spawn, Val},
-    DistributedCtx,
+    capabilities::Capabilities,
+    control::status::ControlStatus,
+    distributed::{
+        links::RemoteLink,
+        message::{ClientError, ReplyCapability, Spawn, Val, VersionedModule},
+        monitors::MonitorWatcher,
+        pending_spawns::SpawnPoll,
+        self_test::{SelfTestCheck, SelfTestConfig},
+        spawn_config::SpawnConfig,
+    },
+    DistributedCtx, EnvironmentId, ModuleId, NodeId, ProcessId,
 };
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
+    config::ProcessConfig,
     env::Environment,
-    message::{DataMessage, Message},
+    message::{DataMessage, Message, MessageSender, Priority},
+    DeathReason, Signal, WasmProcess,
 };
 use lunatic_process_api::ProcessCtx;
 use tokio::time::timeout;
@@ -22,17 +34,62 @@ where
     E: Environment + 'static,
     for<'a> &'a T: Send,
 {
+    linker.func_wrap("lunatic::distributed", "is_distributed", is_distributed)?;
     linker.func_wrap("lunatic::distributed", "nodes_count", nodes_count)?;
     linker.func_wrap("lunatic::distributed", "get_nodes", get_nodes)?;
+    linker.func_wrap7_async(
+        "lunatic::distributed",
+        "get_nodes_by_attr",
+        get_nodes_by_attr,
+    )?;
     linker.func_wrap("lunatic::distributed", "node_id", node_id)?;
+    linker.func_wrap("lunatic::distributed", "capabilities", capabilities)?;
+    linker.func_wrap2_async("lunatic::distributed", "node_info", node_info)?;
     linker.func_wrap("lunatic::distributed", "module_id", module_id)?;
     linker.func_wrap8_async("lunatic::distributed", "spawn", spawn)?;
+    linker.func_wrap8_async(
+        "lunatic::distributed",
+        "spawn_with_fallback",
+        spawn_with_fallback,
+    )?;
+    linker.func_wrap("lunatic::distributed", "spawn_async", spawn_async)?;
+    linker.func_wrap9_async("lunatic::distributed", "spawn_replicated", spawn_replicated)?;
+    linker.func_wrap("lunatic::distributed", "spawn_poll", spawn_poll)?;
+    linker.func_wrap10_async("lunatic::distributed", "spawn_named", spawn_named)?;
+    linker.func_wrap10_async("lunatic::distributed", "spawn_version", spawn_version)?;
+    linker.func_wrap8_async("lunatic::distributed", "migrate", migrate)?;
     linker.func_wrap2_async("lunatic::distributed", "send", send)?;
+    linker.func_wrap("lunatic::distributed", "try_send", try_send)?;
+    linker.func_wrap2_async("lunatic::distributed", "send_reliable", send_reliable)?;
+    linker.func_wrap2_async("lunatic::distributed", "kill", kill)?;
+    linker.func_wrap3_async("lunatic::distributed", "notify_on_exit", notify_on_exit)?;
+    linker.func_wrap3_async("lunatic::distributed", "link", link)?;
+    linker.func_wrap4_async("lunatic::distributed", "monitor", monitor)?;
+    linker.func_wrap1_async("lunatic::distributed", "demonitor", demonitor)?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "create_cancel_token",
+        create_cancel_token,
+    )?;
+    linker.func_wrap1_async("lunatic::distributed", "cancel", cancel)?;
+    linker.func_wrap2_async("lunatic::distributed", "send_two_phase", send_two_phase)?;
+    linker.func_wrap2_async(
+        "lunatic::distributed",
+        "send_with_reply_cap",
+        send_with_reply_cap,
+    )?;
+    linker.func_wrap("lunatic::distributed", "take_reply_cap", take_reply_cap)?;
+    linker.func_wrap("lunatic::distributed", "sender_info", sender_info)?;
+    linker.func_wrap("lunatic::distributed", "message_id", message_id)?;
+    linker.func_wrap1_async("lunatic::distributed", "ack_message", ack_message)?;
+    linker.func_wrap1_async("lunatic::distributed", "ack_range", ack_range)?;
+    linker.func_wrap1_async("lunatic::distributed", "reply_cap", reply_cap)?;
     linker.func_wrap3_async(
         "lunatic::distributed",
         "send_receive_skip_search",
         send_receive_skip_search,
     )?;
+    linker.func_wrap8_async("lunatic::distributed", "call", call)?;
     linker.func_wrap5_async(
         "lunatic::distributed",
         "exec_lookup_nodes",
@@ -43,10 +100,62 @@ where
         "copy_lookup_nodes_results",
         copy_lookup_nodes_results,
     )?;
+    linker.func_wrap10_async("lunatic::distributed", "compare_and_swap", compare_and_swap)?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "in_flight_requests",
+        in_flight_requests,
+    )?;
+    linker.func_wrap("lunatic::distributed", "cancel_request", cancel_request)?;
+    linker.func_wrap4_async("lunatic::distributed", "publish", publish)?;
+    linker.func_wrap("lunatic::distributed", "counter_add", counter_add)?;
+    linker.func_wrap4_async("lunatic::distributed", "counter_get", counter_get)?;
+    linker.func_wrap6_async("lunatic::distributed", "claim_singleton", claim_singleton)?;
+    linker.func_wrap3_async(
+        "lunatic::distributed",
+        "release_singleton",
+        release_singleton,
+    )?;
+    linker.func_wrap3_async("lunatic::distributed", "group_join", group_join)?;
+    linker.func_wrap3_async("lunatic::distributed", "group_leave", group_leave)?;
+    linker.func_wrap3_async("lunatic::distributed", "group_send", group_send)?;
+    linker.func_wrap3_async("lunatic::distributed", "group_kill", group_kill)?;
+    linker.func_wrap4_async("lunatic::distributed", "group_monitor", group_monitor)?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "subscribe_node_events",
+        subscribe_node_events,
+    )?;
+    linker.func_wrap("lunatic::distributed", "control_status", control_status)?;
+    linker.func_wrap("lunatic::distributed", "drain_node", drain_node)?;
+    linker.func_wrap("lunatic::distributed", "is_draining", is_draining)?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "node_sequence_next",
+        node_sequence_next,
+    )?;
+    linker.func_wrap5_async(
+        "lunatic::distributed",
+        "topology_snapshot",
+        topology_snapshot,
+    )?;
+    linker.func_wrap6_async("lunatic::distributed", "self_test", self_test)?;
     Ok(())
 }
 
-// Returns the number of registered nodes
+// Returns 1 if the current process is running on a node of a distributed cluster, otherwise 0.
+//
+// Guests should check this before using the other functions in this namespace. Outside of a
+// cluster they don't trap, but return the reserved value 0 (e.g. for `node_id`) or fail.
+fn is_distributed<T, E>(caller: Caller<T>) -> u32
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    caller.data().distributed().is_ok() as u32
+}
+
+// Returns the number of registered nodes, or 0 if the process is not running in a cluster.
 fn nodes_count<T, E>(caller: Caller<T>) -> u32
 where
     T: DistributedCtx<E>,
@@ -85,13 +194,86 @@ where
     Ok(copy_nodes_len as u32)
 }
 
+// Copies the ids of the nodes registered with the attribute `key=value` into guest memory, see CLI
+// flag `tag`. The attributes are looked up on the control server.
+//
+// Returns:
+// * The number of nodes copied, at most `nodes_len`
+// * -1 if the control server can't be reached, the error is written to `error_ptr`
+//
+// Traps:
+// * If the key or value is not a valid UTF-8 string, or longer than the node allows
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn get_nodes_by_attr<T, E>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+    nodes_ptr: u32,
+    nodes_len: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<i32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, key_len, "lunatic::distributed::get_nodes_by_attr")?;
+        check_string_arg(
+            &caller,
+            value_len,
+            "lunatic::distributed::get_nodes_by_attr",
+        )?;
+        let key = memory
+            .data(&caller)
+            .get(key_ptr as usize..(key_ptr + key_len) as usize)
+            .or_trap("lunatic::distributed::get_nodes_by_attr::key_ptr")?;
+        let key = std::str::from_utf8(key)
+            .or_trap("lunatic::distributed::get_nodes_by_attr::key_utf8")?
+            .to_string();
+        let value = memory
+            .data(&caller)
+            .get(value_ptr as usize..(value_ptr + value_len) as usize)
+            .or_trap("lunatic::distributed::get_nodes_by_attr::value_ptr")?;
+        let value = std::str::from_utf8(value)
+            .or_trap("lunatic::distributed::get_nodes_by_attr::value_utf8")?
+            .to_string();
+        let distributed = caller.data().distributed()?;
+        match distributed.control.nodes_with_attribute(&key, &value).await {
+            Ok(node_ids) => {
+                let copy_nodes_len = node_ids.len().min(nodes_len as usize);
+                memory
+                    .data_mut(&mut caller)
+                    .get_mut(
+                        nodes_ptr as usize
+                            ..(nodes_ptr as usize + std::mem::size_of::<u64>() * copy_nodes_len),
+                    )
+                    .or_trap("lunatic::distributed::get_nodes_by_attr::memory")?
+                    .copy_from_slice(unsafe { node_ids[..copy_nodes_len].align_to::<u8>().1 });
+                Ok(copy_nodes_len as i32)
+            }
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::get_nodes_by_attr::error_ptr")?;
+                Ok(-1)
+            }
+        }
+    })
+}
+
 // Submits a lookup node query to the control server and waits for the results.
 //
 // Filtering is done based on tags which are `key=value` user defined node
 // metadata, see CLI flag `tag`.
 //
 // Traps:
-// * If the query is not a valid UTF-8 string
+// * If the query is not a valid UTF-8 string, or longer than the node allows
 // * if any memory outside the guest heap space is referenced
 fn exec_lookup_nodes<T, E>(
     mut caller: Caller<T>,
@@ -108,6 +290,7 @@ where
 {
     Box::new(async move {
         let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, query_len, "lunatic::distributed::lookup_nodes")?;
         let query_str = memory
             .data(&caller)
             .get(query_ptr as usize..(query_ptr + query_len) as usize)
@@ -183,238 +366,2979 @@ where
     }
 }
 
-// Similar to a local spawn, it spawns a new process using the passed in function inside a module
-// as the entry point. The process is spawned on a node with id `node_id`.
-//
-// If `config_id` is 0, the same config is used as in the process calling this function.
+// Copies the requests from other nodes that this node is currently handling for the environment
+// of the calling process into guest memory. Requests for other environments are not listed.
+// Each request is written as 4 little endian u64 values:
+// [connection ID, message ID, kind, running time in ms]
 //
-// The function arguments are passed as an array with the following structure:
-// [0 byte = type ID; 1..17 bytes = value as u128, ...]
-// The type ID follows the WebAssembly binary convention:
-//  - 0x7F => i32
-//  - 0x7E => i64
-//  - 0x7B => v128
-// If any other value is used as type ID, this function will trap. If your type
-// would ordinarily occupy fewer than 16 bytes (e.g. in an i32 or i64), you MUST
-// first convert it to an i128.
+// The kinds are:
+// * 0   Spawn
+// * 1   Message
+// * 2   Reply
+// * 3   Publish
+// * 4   Kill
+// * 5   Stage
+// * 6   Commit
+// * 7   Rollback
+// * 8   Replicated spawn
+// * 9   Exit notification
+// * 10  Cancellation
+// * 11  Acknowledgement
+// * 12  Acknowledgement of a range
+// * 13  Ping
+// * 14  Link
+// * 15  Link died
+// * 16  Monitor
+// * 17  Demonitor
+// * 18  Process down
+// * 19  Gossip
+// * 20  Node info
 //
-// Returns:
-// * 0      on success - The ID of the newly created process is written to `id_ptr`
-// * 1      If node does not exist
-// * 2      If module does not exist
-// * 9027   If node connection error occurred
+// Returns the number of requests copied, at most `requests_len`.
 //
 // Traps:
-// * If the function string is not a valid utf8 string.
-// * If the params array is in a wrong format.
 // * If any memory outside the guest heap space is referenced.
-#[allow(clippy::too_many_arguments)]
-fn spawn<T, E>(
+fn in_flight_requests<T, E>(
     mut caller: Caller<T>,
-    node_id: u64,
-    config_id: i64,
-    module_id: u64,
-    func_str_ptr: u32,
-    func_str_len: u32,
-    params_ptr: u32,
-    params_len: u32,
-    id_ptr: u32,
-) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+    requests_ptr: u32,
+    requests_len: u32,
+) -> Result<u32>
 where
-    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    T: DistributedCtx<E>,
     E: Environment,
-    for<'a> &'a T: Send,
 {
-    Box::new(async move {
-        if !caller.data().can_spawn() {
-            return Err(anyhow!(
-                "Process doesn't have permissions to spawn sub-processes"
-            ));
-        }
-        let memory = get_memory(&mut caller)?;
-        let func_str = memory
-            .data(&caller)
-            .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
-            .or_trap("lunatic::distributed::spawn::func_str")?;
-
-        let function =
-            std::str::from_utf8(func_str).or_trap("lunatic::distributed::spawn::func_str_utf8")?;
-
-        let params = memory
-            .data(&caller)
-            .get(params_ptr as usize..(params_ptr + params_len) as usize)
-            .or_trap("lunatic::distributed::spawn::params")?;
-        let params = params
-            .chunks_exact(17)
-            .map(|chunk| {
-                let value = u128::from_le_bytes(chunk[1..].try_into()?);
-                let result = match chunk[0] {
-                    0x7F => Val::I32(value as i32),
-                    0x7E => Val::I64(value as i64),
-                    0x7B => Val::V128(value),
-                    _ => return Err(anyhow!("Unsupported type ID")),
-                };
-                Ok(result)
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        let state = caller.data();
-
-        let config = match config_id {
-            -1 => state.config().clone(),
-            config_id => Arc::new(
-                caller
-                    .data()
-                    .config_resources()
-                    .get(config_id as u64)
-                    .or_trap("lunatic::process::spawn: Config ID doesn't exist")?
-                    .clone(),
-            ),
-        };
-        let config: Vec<u8> =
-            bincode::serialize(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
-
-        log::debug!("Spawn on node {node_id}, mod {module_id}, fn {function}, params {params:?}");
-
-        let (process_or_error_id, ret) = match state
-            .distributed()?
-            .node_client
-            .spawn(
-                node_id,
-                Spawn {
-                    environment_id: state.environment_id(),
-                    function: function.to_string(),
-                    module_id,
-                    params,
-                    config,
-                },
-            )
-            .await
-        {
-            Ok(process_id) => (process_id, 0),
-            Err(error) => {
-                let (code, message): (u32, String) = match error {
-                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
-                    ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
-                    ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
-                    ClientError::Connection(cause) => Ok((9027, cause)),
-                    _ => Err(anyhow!("unreachable")),
-                }?;
-                (
-                    caller
-                        .data_mut()
-                        .error_resources_mut()
-                        .add(anyhow!(message)),
-                    code,
-                )
-            }
-        };
+    let memory = get_memory(&mut caller)?;
+    let environment_id = caller.data().environment_id();
+    let requests = caller
+        .data()
+        .distributed()?
+        .in_flight
+        .list_in(environment_id);
+    let copy_len = requests.len().min(requests_len as usize);
+    let mut data = Vec::with_capacity(copy_len * 4 * std::mem::size_of::<u64>());
+    for request in &requests[..copy_len] {
+        data.extend_from_slice(&request.connection_id.to_le_bytes());
+        data.extend_from_slice(&request.msg_id.to_le_bytes());
+        data.extend_from_slice(&request.kind_code.to_le_bytes());
+        data.extend_from_slice(&(request.elapsed().as_millis() as u64).to_le_bytes());
+    }
+    memory
+        .write(&mut caller, requests_ptr as usize, &data)
+        .or_trap("lunatic::distributed::in_flight_requests")?;
+    Ok(copy_len as u32)
+}
 
-        memory
-            .write(
-                &mut caller,
-                id_ptr as usize,
-                &process_or_error_id.to_le_bytes(),
-            )
-            .or_trap("lunatic::distributed::spawn::write_id")?;
+// Aborts the handler of a request from another node. The node that sent the request receives a
+// cancelled response. Only requests for the environment of the calling process can be cancelled.
+//
+// Returns:
+// * 0 If the request was cancelled.
+// * 1 If the request is not in flight (anymore), or is for another environment.
+fn cancel_request<T, E>(caller: Caller<T>, connection_id: u64, msg_id: u64) -> Result<u32>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let environment_id = caller.data().environment_id();
+    let in_flight = &caller.data().distributed()?.in_flight;
+    if in_flight.cancel_in(environment_id, connection_id, msg_id) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
 
-        Ok(ret)
-    })
+// Subscribes the current process to nodes joining and leaving the cluster. Each change is
+// delivered as a message tagged with `tag`, containing the node ID as a little endian u64,
+// followed by one byte that is 1 if the node connected and 0 if it disconnected.
+//
+// Changes are collected for a short time before they are delivered, a node that disconnects and
+// reconnects in the meantime doesn't produce any messages. Subscriptions end with the process.
+//
+// Traps:
+// * If the process is not running in a cluster.
+fn subscribe_node_events<T, E>(caller: Caller<T>, tag: i64) -> Result<()>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let process = caller.data().signal_mailbox().0.clone();
+    caller
+        .data()
+        .distributed()?
+        .control
+        .node_events()
+        .subscribe(tag, process);
+    Ok(())
 }
 
-// Sends the message in scratch area to a process running on a node with id `node_id`.
+// Adds `delta` to the cluster wide counter with the name `name_ptr, name_len`.
 //
-// There are no guarantees that the message will be received.
+// Counters are aggregated by the control server from the contributions of all nodes. Deltas are
+// sent to it periodically, so other nodes observe them with a delay.
+//
+// Traps:
+// * If the name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn counter_add<T, E>(mut caller: Caller<T>, name_ptr: u32, name_len: u32, delta: i64) -> Result<()>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let memory = get_memory(&mut caller)?;
+    check_string_arg(&caller, name_len, "lunatic::distributed::counter_add")?;
+    let name = memory
+        .data(&caller)
+        .get(name_ptr as usize..(name_ptr + name_len) as usize)
+        .or_trap("lunatic::distributed::counter_add::name_ptr")?;
+    let name = std::str::from_utf8(name).or_trap("lunatic::distributed::counter_add::name_utf8")?;
+    caller
+        .data()
+        .distributed()?
+        .control
+        .counter_add(name, delta);
+    Ok(())
+}
+
+// Writes the value of the cluster wide counter with the name `name_ptr, name_len` to `value_ptr`.
+// Counters that were never added to are 0.
 //
 // Returns:
-// * 0      If message sent
-// * 1      If process_id does not exist
-// * 2      If node_id does not exist
-// * 9027   If node connection error occurred
+// * 0 If the value was written.
+// * 1 If the control server returned an error, the error ID is written to `error_ptr`.
 //
 // Traps:
-// * If it's called before creating the next message.
-// * If the message contains resources
-fn send<T, E>(
+// * If the name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn counter_get<T, E>(
     mut caller: Caller<T>,
-    node_id: u64,
-    process_id: u64,
+    name_ptr: u32,
+    name_len: u32,
+    value_ptr: u32,
+    error_ptr: u32,
 ) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
 where
-    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
-    E: Environment,
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
     for<'a> &'a T: Send,
 {
     Box::new(async move {
-        let message = caller
-            .data_mut()
-            .message_scratch_area()
-            .take()
-            .or_trap("lunatic::message::send::no_message")?;
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, name_len, "lunatic::distributed::counter_get")?;
+        let name = memory
+            .data(&caller)
+            .get(name_ptr as usize..(name_ptr + name_len) as usize)
+            .or_trap("lunatic::distributed::counter_get::name_ptr")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::distributed::counter_get::name_utf8")?
+            .to_string();
 
-        if let Message::Data(DataMessage {
-            tag,
-            buffer,
-            resources,
-            ..
-        }) = message
-        {
-            if !resources.is_empty() {
-                return Err(anyhow!("Cannot send resources to remote nodes."));
+        let control = caller.data().distributed()?.control.clone();
+        match control.counter_get(&name).await {
+            Ok(value) => {
+                memory
+                    .write(&mut caller, value_ptr as usize, &value.to_le_bytes())
+                    .or_trap("lunatic::distributed::counter_get::value_ptr")?;
+                Ok(0)
             }
-
-            let state = caller.data();
-            match state
-                .distributed()?
-                .node_client
-                .message_process(node_id, state.environment_id(), process_id, tag, buffer)
-                .await
-            {
-                Ok(_) => Ok(0),
-                Err(error) => match error {
-                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
-                    ClientError::ProcessNotFound => Ok(1),
-                    ClientError::NodeNotFound => Ok(2),
-                    ClientError::Connection(_) => Ok(9027),
-                    _ => Err(anyhow!("unreachable")),
-                },
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::counter_get::error_ptr")?;
+                Ok(1)
             }
-        } else {
-            Err(anyhow!("Only Message::Data can be sent across nodes."))
         }
     })
 }
 
-// Sends the message to a process on a node with id `node_id` and waits for a reply,
-// but doesn't look through existing messages in the mailbox queue while waiting.
-// This is an optimization that only makes sense with tagged messages.
-// In a request/reply scenario we can tag the request message with an
-// unique tag and just wait on it specifically.
+// Claims the cluster wide singleton role with the name `role_ptr, role_len` for the current
+// process. At most one process in the cluster holds a role at a time, e.g. to elect a leader.
 //
-// This operation needs to be an atomic host function, if we jumped back into the guest we could
-// miss out on the incoming message before `receive` is called.
+// The claim is released when the holder finishes or calls `release_singleton`, and when its node
+// is drained or leaves the cluster. A node losing the connection to the control server keeps the
+// claims of its processes for the grace period of the control server.
 //
-// If timeout is specified (value different from u64::MAX), the function will return on timeout
-// expiration with value 9027.
+// The node ID and process ID of the holder after the claim are written to `holder_ptr` as two
+// u64 values. If `notify` is not 0 and the role is held by another process, the current process
+// receives a message tagged with `tag` and containing the role name once the role becomes
+// available. The role is not claimed automatically at that point.
 //
 // Returns:
-// * 0    If message arrived.
-// * 1    If process_id does not exist
-// * 2    If node_id does not exist
-// * 9027 If call timed out.
+// * 0 If the role was claimed by the current process, or it already held it.
+// * 1 If the role is held by another process.
+// * 2 If the control server returned an error, the error ID is written to `error_ptr`.
 //
 // Traps:
-// * If it's called with wrong data in the scratch area.
-// * If the message contains resources
-fn send_receive_skip_search<T, E>(
+// * If the role is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn claim_singleton<T, E>(
     mut caller: Caller<T>,
-    node_id: u64,
-    process_id: u64,
-    timeout_duration: u64,
+    role_ptr: u32,
+    role_len: u32,
+    notify: u32,
+    tag: i64,
+    holder_ptr: u32,
+    error_ptr: u32,
 ) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
 where
-    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
-    E: Environment,
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, role_len, "lunatic::distributed::claim_singleton")?;
+        let role = memory
+            .data(&caller)
+            .get(role_ptr as usize..(role_ptr + role_len) as usize)
+            .or_trap("lunatic::distributed::claim_singleton::role_ptr")?;
+        let role = std::str::from_utf8(role)
+            .or_trap("lunatic::distributed::claim_singleton::role_utf8")?
+            .to_string();
+
+        let distributed = caller.data().distributed()?;
+        let control = distributed.control.clone();
+        let node_id = distributed.node_id();
+        let process_id = caller.data().id();
+        let process = caller.data().signal_mailbox().0.clone();
+        match control
+            .claim_singleton(&role, node_id, process_id, process.clone())
+            .await
+        {
+            Ok((holder_node_id, holder_process_id)) => {
+                let mut holder = [0; 16];
+                holder[..8].copy_from_slice(&holder_node_id.to_le_bytes());
+                holder[8..].copy_from_slice(&holder_process_id.to_le_bytes());
+                memory
+                    .write(&mut caller, holder_ptr as usize, &holder)
+                    .or_trap("lunatic::distributed::claim_singleton::holder_ptr")?;
+                if (holder_node_id, holder_process_id) == (node_id, process_id) {
+                    Ok(0)
+                } else {
+                    if notify != 0 {
+                        control.notify_when_available(&role, tag, process);
+                    }
+                    Ok(1)
+                }
+            }
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::claim_singleton::error_ptr")?;
+                Ok(2)
+            }
+        }
+    })
+}
+
+// Releases the cluster wide singleton role with the name `role_ptr, role_len` if it's held by the
+// current process. Releasing a role held by another process does nothing.
+//
+// Returns:
+// * 0 If the role is not held by the current process anymore.
+// * 1 If the control server returned an error, the error ID is written to `error_ptr`.
+//
+// Traps:
+// * If the role is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn release_singleton<T, E>(
+    mut caller: Caller<T>,
+    role_ptr: u32,
+    role_len: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, role_len, "lunatic::distributed::release_singleton")?;
+        let role = memory
+            .data(&caller)
+            .get(role_ptr as usize..(role_ptr + role_len) as usize)
+            .or_trap("lunatic::distributed::release_singleton::role_ptr")?;
+        let role = std::str::from_utf8(role)
+            .or_trap("lunatic::distributed::release_singleton::role_utf8")?
+            .to_string();
+
+        let distributed = caller.data().distributed()?;
+        let control = distributed.control.clone();
+        let node_id = distributed.node_id();
+        let process_id = caller.data().id();
+        match control.release_singleton(&role, node_id, process_id).await {
+            Ok(()) => Ok(0),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::release_singleton::error_ptr")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Adds the current process to the process group with the name `group_ptr, group_len`. Groups
+// are cluster wide, processes on all nodes can join the same group. Operations like `group_send`
+// target all members of a group.
+//
+// The process leaves the group when it finishes or calls `group_leave`, and when its node leaves
+// the cluster. Joining a group again does nothing.
+//
+// Returns:
+// * 0 If the process joined the group.
+// * 1 If the control server returned an error, the error ID is written to `error_ptr`.
+//
+// Traps:
+// * If the group name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn group_join<T, E>(
+    mut caller: Caller<T>,
+    group_ptr: u32,
+    group_len: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, group_len, "lunatic::distributed::group_join")?;
+        let group = memory
+            .data(&caller)
+            .get(group_ptr as usize..(group_ptr + group_len) as usize)
+            .or_trap("lunatic::distributed::group_join::group_ptr")?;
+        let group = std::str::from_utf8(group)
+            .or_trap("lunatic::distributed::group_join::group_utf8")?
+            .to_string();
+
+        let distributed = caller.data().distributed()?;
+        let control = distributed.control.clone();
+        let node_id = distributed.node_id();
+        let process_id = caller.data().id();
+        let process = caller.data().signal_mailbox().0.clone();
+        match control
+            .join_group(&group, node_id, process_id, process)
+            .await
+        {
+            Ok(()) => Ok(0),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::group_join::error_ptr")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Removes the current process from the process group with the name `group_ptr, group_len`.
+// Leaving a group the process is not a member of does nothing.
+//
+// Returns:
+// * 0 If the process is not a member of the group anymore.
+// * 1 If the control server returned an error, the error ID is written to `error_ptr`.
+//
+// Traps:
+// * If the group name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn group_leave<T, E>(
+    mut caller: Caller<T>,
+    group_ptr: u32,
+    group_len: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, group_len, "lunatic::distributed::group_leave")?;
+        let group = memory
+            .data(&caller)
+            .get(group_ptr as usize..(group_ptr + group_len) as usize)
+            .or_trap("lunatic::distributed::group_leave::group_ptr")?;
+        let group = std::str::from_utf8(group)
+            .or_trap("lunatic::distributed::group_leave::group_utf8")?
+            .to_string();
+
+        let distributed = caller.data().distributed()?;
+        let control = distributed.control.clone();
+        let node_id = distributed.node_id();
+        let process_id = caller.data().id();
+        match control.leave_group(&group, node_id, process_id).await {
+            Ok(()) => Ok(0),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::group_leave::error_ptr")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Sends the message in scratch area to all members of the process group `group_ptr, group_len`,
+// on all nodes and in the same environment as the calling process. If the calling process is a
+// member, it receives the message too. The number of members the message was delivered to is
+// written to `delivered_ptr`, members that can't be reached are skipped.
+//
+// Returns:
+// * 0      If the message was sent to the members
+// * 1      If the members could not be fetched from the control server
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources.
+// * If the group name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn group_send<T, E>(
+    mut caller: Caller<T>,
+    group_ptr: u32,
+    group_len: u32,
+    delivered_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, group_len, "lunatic::distributed::group_send")?;
+        let group = memory
+            .data(&caller)
+            .get(group_ptr as usize..(group_ptr + group_len) as usize)
+            .or_trap("lunatic::distributed::group_send::group_ptr")?;
+        let group = std::str::from_utf8(group)
+            .or_trap("lunatic::distributed::group_send::group_utf8")?
+            .to_string();
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::group_send::no_message")?;
+        let message = caller.data().priority_boost().apply(message);
+        let message = match message {
+            Message::Data(message) => message,
+            Message::LinkDied(_) => {
+                return Err(anyhow!("Only Message::Data can be sent across nodes."))
+            }
+        };
+        if !message.resources.is_empty() {
+            return Err(anyhow!("Cannot send resources to remote nodes."));
+        }
+
+        let state = caller.data();
+        let distributed = state.distributed()?;
+        let result = distributed
+            .node_client
+            .send_to_group(
+                EnvironmentId(state.environment_id()),
+                &group,
+                message.tag,
+                message.priority,
+                message.buffer,
+                Some(MessageSender {
+                    node_id: distributed.node_id(),
+                    process_id: state.id(),
+                }),
+            )
+            .await;
+        match result {
+            Ok(delivered) => {
+                memory
+                    .write(
+                        &mut caller,
+                        delivered_ptr as usize,
+                        &delivered.to_le_bytes(),
+                    )
+                    .or_trap("lunatic::distributed::group_send::delivered_ptr")?;
+                Ok(0)
+            }
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::ControlUnavailable => Ok(1),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Kills all members of the process group `group_ptr, group_len`, on all nodes and in the same
+// environment as the calling process. If the calling process is a member, it's killed too. The
+// number of killed members is written to `killed_ptr`.
+//
+// Returns:
+// * 0      If the members were killed
+// * 1      If the members could not be fetched from the control server
+//
+// Traps:
+// * If the group name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn group_kill<T, E>(
+    mut caller: Caller<T>,
+    group_ptr: u32,
+    group_len: u32,
+    killed_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, group_len, "lunatic::distributed::group_kill")?;
+        let group = memory
+            .data(&caller)
+            .get(group_ptr as usize..(group_ptr + group_len) as usize)
+            .or_trap("lunatic::distributed::group_kill::group_ptr")?;
+        let group = std::str::from_utf8(group)
+            .or_trap("lunatic::distributed::group_kill::group_utf8")?
+            .to_string();
+
+        let state = caller.data();
+        let result = state
+            .distributed()?
+            .node_client
+            .kill_group(EnvironmentId(state.environment_id()), &group)
+            .await;
+        match result {
+            Ok(killed) => {
+                memory
+                    .write(&mut caller, killed_ptr as usize, &killed.to_le_bytes())
+                    .or_trap("lunatic::distributed::group_kill::killed_ptr")?;
+                Ok(0)
+            }
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::ControlUnavailable => Ok(1),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Registers the calling process to receive a message tagged with `tag` when a member of the
+// process group `group_ptr, group_len` exits, for all current members on all nodes. The messages
+// have the same format as the ones of `notify_on_exit`. Processes that join the group later are
+// not watched. The number of watched members is written to `watched_ptr`.
+//
+// Returns:
+// * 0      If the members are watched
+// * 1      If the members could not be fetched from the control server
+//
+// Traps:
+// * If the group name is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn group_monitor<T, E>(
+    mut caller: Caller<T>,
+    group_ptr: u32,
+    group_len: u32,
+    tag: i64,
+    watched_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, group_len, "lunatic::distributed::group_monitor")?;
+        let group = memory
+            .data(&caller)
+            .get(group_ptr as usize..(group_ptr + group_len) as usize)
+            .or_trap("lunatic::distributed::group_monitor::group_ptr")?;
+        let group = std::str::from_utf8(group)
+            .or_trap("lunatic::distributed::group_monitor::group_utf8")?
+            .to_string();
+
+        let state = caller.data();
+        let result = state
+            .distributed()?
+            .node_client
+            .notify_on_exit_of_group(
+                EnvironmentId(state.environment_id()),
+                &group,
+                tag,
+                ProcessId(state.id()),
+            )
+            .await;
+        match result {
+            Ok(watched) => {
+                memory
+                    .write(&mut caller, watched_ptr as usize, &watched.to_le_bytes())
+                    .or_trap("lunatic::distributed::group_monitor::watched_ptr")?;
+                Ok(0)
+            }
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::ControlUnavailable => Ok(1),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Atomically replaces the value of a cluster wide register `key` with `new`, if the current value
+// is equal to `expected`. Registers that were never set, or were set to an empty value, are empty.
+// Registers are stored on the control server and values are limited to 4 KiB.
+//
+// The value observed before the swap is copied into the buffer at `observed_ptr`, truncated to
+// `observed_len`, and the full length of it is written to `observed_len_ptr`.
+//
+// Returns:
+// * 0 If the value was swapped.
+// * 1 If the current value didn't match `expected`.
+// * 2 If the control server returned an error, the error ID is written to `error_ptr`.
+//
+// Traps:
+// * If the key is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn compare_and_swap<T, E>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    expected_ptr: u32,
+    expected_len: u32,
+    new_ptr: u32,
+    new_len: u32,
+    observed_ptr: u32,
+    observed_len: u32,
+    observed_len_ptr: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, key_len, "lunatic::distributed::compare_and_swap")?;
+        let key = memory
+            .data(&caller)
+            .get(key_ptr as usize..(key_ptr + key_len) as usize)
+            .or_trap("lunatic::distributed::compare_and_swap::key_ptr")?;
+        let key = std::str::from_utf8(key)
+            .or_trap("lunatic::distributed::compare_and_swap::key_utf8")?
+            .to_string();
+        let expected = memory
+            .data(&caller)
+            .get(expected_ptr as usize..(expected_ptr + expected_len) as usize)
+            .or_trap("lunatic::distributed::compare_and_swap::expected_ptr")?
+            .to_vec();
+        let new = memory
+            .data(&caller)
+            .get(new_ptr as usize..(new_ptr + new_len) as usize)
+            .or_trap("lunatic::distributed::compare_and_swap::new_ptr")?
+            .to_vec();
+
+        let control = caller.data().distributed()?.control.clone();
+        match control.compare_and_swap(key, expected.clone(), new).await {
+            Ok(observed) => {
+                let copy_len = observed.len().min(observed_len as usize);
+                memory
+                    .write(&mut caller, observed_ptr as usize, &observed[..copy_len])
+                    .or_trap("lunatic::distributed::compare_and_swap::observed_ptr")?;
+                memory
+                    .write(
+                        &mut caller,
+                        observed_len_ptr as usize,
+                        &(observed.len() as u32).to_le_bytes(),
+                    )
+                    .or_trap("lunatic::distributed::compare_and_swap::observed_len_ptr")?;
+                if observed == expected {
+                    Ok(0)
+                } else {
+                    Ok(1)
+                }
+            }
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::compare_and_swap::error_ptr")?;
+                Ok(2)
+            }
+        }
+    })
+}
+
+// Similar to a local spawn, it spawns a new process using the passed in function inside a module
+// as the entry point. The process is spawned on a node with id `node_id`.
+//
+// If `config_id` is 0, the same config is used as in the process calling this function.
+//
+// The function arguments are passed as an array with the following structure:
+// [0 byte = type ID; 1..17 bytes = value as u128, ...]
+// The type ID follows the WebAssembly binary convention:
+//  - 0x7F => i32
+//  - 0x7E => i64
+//  - 0x7B => v128
+// If any other value is used as type ID, this function will trap. If your type
+// would ordinarily occupy fewer than 16 bytes (e.g. in an i32 or i64), you MUST
+// first convert it to an i128.
+//
+// Returns:
+// * 0      on success - The ID of the newly created process is written to `id_ptr`
+// * 1      If node does not exist
+// * 2      If module does not exist
+// * 5      If module is not allowed to be spawned on the node
+// * 6      If the params array is bigger than the node allows
+// * 7      If module failed to compile on the node, details are in the error
+// * 8      If the spawn request was cancelled on the node
+// * 9      If the module is not signed by the publisher the node trusts
+// * 12     If too many spawns are already waiting on the node
+// * 13     If the node lost its connection to the control server and refuses spawns
+// * 14     If the node remembers too many idempotency keys to accept the spawn
+// * 15     If the node is drained for maintenance and refuses spawns
+// * 16     If the function name is longer than the node allows
+// * 17     If the node rejected the join token of this node, or this node has none
+// * 18     If the module doesn't export the function
+// * 19     If the node failed to create the process, details are in the error
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let (process_or_error_id, ret) = spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        )
+        .await?;
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_ptr as usize,
+                &process_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::spawn::write_id")?;
+
+        Ok(ret)
+    })
+}
+
+// Same as `spawn`, but if the node `node_id` leaves the cluster before the process is spawned,
+// the spawn is retried on another node. Only use it for processes that don't depend on the node
+// they are running on, e.g. stateless workers.
+//
+// The spawn is only moved to another node once the control server stopped listing `node_id`, so
+// a node that is slow to respond doesn't end up running a duplicate of the process.
+//
+// Returns:
+// * 0      on success - The ID of the newly created process is written to `id_ptr`, followed by
+//          the ID of the node it was spawned on, both as little endian u64 values
+// * 1      If no node was left to spawn the process on
+// * Same error codes as `spawn`, the error ID is written to `id_ptr`
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_with_fallback<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let spawn = match prepare_spawn(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        )? {
+            Ok(spawn) => spawn,
+            Err((error_id, code)) => {
+                let memory = get_memory(&mut caller)?;
+                memory
+                    .write(&mut caller, id_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::spawn_with_fallback::write_id")?;
+                return Ok(code);
+            }
+        };
+        let result = caller
+            .data()
+            .distributed()?
+            .node_client
+            .spawn_with_fallback(NodeId(node_id), spawn)
+            .await;
+        let mut data = Vec::with_capacity(16);
+        let code = match result {
+            Ok((node_id, process_id)) => {
+                data.extend_from_slice(&u64::from(process_id).to_le_bytes());
+                data.extend_from_slice(&u64::from(node_id).to_le_bytes());
+                0
+            }
+            Err(error) => {
+                let (error_id, code) = spawn_result(&mut caller, Err(error))?;
+                data.extend_from_slice(&error_id.to_le_bytes());
+                code
+            }
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, id_ptr as usize, &data)
+            .or_trap("lunatic::distributed::spawn_with_fallback::write_id")?;
+        Ok(code)
+    })
+}
+
+// Same as `spawn`, but spawns `count` processes on the node with a single request. Each process
+// gets its index (0..count) appended to the params as an i32, so that a pool of workers can be
+// created without sending a spawn per worker.
+//
+// If one of the processes can't be spawned, the ones already spawned are killed.
+//
+// Returns:
+// * 0      on success - The IDs of the new processes are written to `ids_ptr` as `count` little
+//          endian u64 values, ordered by index
+// * Same error codes as `spawn`, the error ID is written to `ids_ptr`
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_replicated<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    count: u32,
+    ids_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let (data, ret) = match prepare_spawn(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        )? {
+            Ok(spawn) => {
+                let result = caller
+                    .data()
+                    .distributed()?
+                    .node_client
+                    .spawn_replicated(NodeId(node_id), spawn, count)
+                    .await;
+                match result {
+                    Ok(ids) => (
+                        ids.iter()
+                            .flat_map(|&id| u64::from(id).to_le_bytes())
+                            .collect(),
+                        0,
+                    ),
+                    Err(error) => {
+                        let (error_id, ret) = spawn_result(&mut caller, Err(error))?;
+                        (error_id.to_le_bytes().to_vec(), ret)
+                    }
+                }
+            }
+            Err((error_id, ret)) => (error_id.to_le_bytes().to_vec(), ret),
+        };
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, ids_ptr as usize, &data)
+            .or_trap("lunatic::distributed::spawn_replicated::write_ids")?;
+
+        Ok(ret)
+    })
+}
+
+// Same as `spawn`, but doesn't wait for the process to be spawned. A token is written to
+// `token_ptr` that can be passed to `spawn_poll` to check if the spawn finished.
+//
+// Results that are not polled within a timeout after the spawn finished are dropped.
+//
+// Returns:
+// * 0      on success - The token is written to `token_ptr`
+// * 6      If the params array is bigger than the node allows, the error ID is written to
+//          `token_ptr`
+// * 16     If the function name is longer than the node allows, the error ID is written to
+//          `token_ptr`
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_async<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    token_ptr: u32,
+) -> Result<u32>
+where
+    T: DistributedCtx<E> + ErrorCtx,
+    E: Environment,
+{
+    let (token_or_error_id, ret) = match prepare_spawn(
+        &mut caller,
+        node_id,
+        config_id,
+        module_id,
+        func_str_ptr,
+        func_str_len,
+        params_ptr,
+        params_len,
+    )? {
+        Ok(spawn) => {
+            let node_client = &caller.data().distributed()?.node_client;
+            (node_client.spawn_async(NodeId(node_id), spawn), 0)
+        }
+        Err(error) => error,
+    };
+
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(
+            &mut caller,
+            token_ptr as usize,
+            &token_or_error_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::distributed::spawn_async::write_token")?;
+
+    Ok(ret)
+}
+
+// Checks if the spawn started with `spawn_async` finished, without blocking.
+//
+// Once the spawn finished and its result was returned, the token becomes invalid.
+//
+// Returns:
+// * 0      If the process was spawned - The ID of the new process is written to `id_ptr`
+// * Same error codes as `spawn` if the spawn failed, the error ID is written to `id_ptr`
+// * 10     If the spawn is still in progress
+// * 11     If the token is unknown or the result expired
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn spawn_poll<T, E>(mut caller: Caller<T>, token: u64, id_ptr: u32) -> Result<u32>
+where
+    T: DistributedCtx<E> + ErrorCtx,
+    E: Environment,
+{
+    let poll = caller.data().distributed()?.node_client.spawn_poll(token);
+    let (process_or_error_id, ret) = match poll {
+        Some(SpawnPoll::Ready(result)) => spawn_result(&mut caller, result)?,
+        Some(SpawnPoll::Pending) => return Ok(10),
+        None => return Ok(11),
+    };
+
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(
+            &mut caller,
+            id_ptr as usize,
+            &process_or_error_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::distributed::spawn_poll::write_id")?;
+
+    Ok(ret)
+}
+
+// Same as `spawn`, but also registers the new process under a name derived from the name of the
+// calling process. If the caller is registered as `pool`, the suffix `worker.3` will register
+// the child as `pool.worker.3`.
+//
+// The derived name must not be registered already, on this node or by any other node of the
+// cluster, existing entries are never overwritten. The name is reserved before the spawn, so
+// concurrent spawns under the same name can't both succeed, and released again if it fails.
+//
+// Returns:
+// * 0      on success - The ID of the newly created process is written to `id_ptr`
+// * 1      If node does not exist
+// * 2      If module does not exist
+// * 3      If the calling process is not registered under any name
+// * 4      If the derived name is already registered
+// * 5      If module is not allowed to be spawned on the node
+// * 6      If the params array is bigger than the node allows
+// * 7      If module failed to compile on the node, details are in the error
+// * 8      If the spawn request was cancelled on the node
+// * 9      If the module is not signed by the publisher the node trusts
+// * 12     If too many spawns are already waiting on the node
+// * 13     If the node lost its connection to the control server and refuses spawns
+// * 14     If the node remembers too many idempotency keys to accept the spawn
+// * 15     If the node is drained for maintenance and refuses spawns
+// * 16     If the function name is longer than the node allows
+// * 17     If the node rejected the join token of this node, or this node has none
+// * 18     If the module doesn't export the function
+// * 19     If the node failed to create the process, details are in the error
+// * 20     If the name couldn't be reserved with the control server, details are in the error
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If the function or suffix string is not a valid utf8 string.
+// * If the suffix is longer than the node allows.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_named<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    suffix_str_ptr: u32,
+    suffix_str_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, suffix_str_len, "lunatic::distributed::spawn_named")?;
+        let suffix_str = memory
+            .data(&caller)
+            .get(suffix_str_ptr as usize..(suffix_str_ptr + suffix_str_len) as usize)
+            .or_trap("lunatic::distributed::spawn_named::suffix_str")?;
+        let suffix = std::str::from_utf8(suffix_str)
+            .or_trap("lunatic::distributed::spawn_named::suffix_str_utf8")?
+            .to_string();
+
+        let state = caller.data();
+        let parent = (state.distributed()?.node_id(), state.id());
+        let parent_name = state
+            .registry()
+            .iter()
+            .find(|entry| *entry.value() == parent)
+            .map(|entry| entry.key().clone());
+        let (process_or_error_id, ret) = match parent_name {
+            Some(parent_name) => {
+                let name = derive_name(&parent_name, &suffix);
+                // Process IDs start at 1, so the name is held by this node until the spawn is done
+                let reservation = (parent.0, 0);
+                let reserved = match caller.data().registry().entry(name.clone()) {
+                    Entry::Occupied(_) => false,
+                    Entry::Vacant(entry) => {
+                        entry.insert(reservation);
+                        true
+                    }
+                };
+                if reserved {
+                    spawn_reserved(
+                        &mut caller,
+                        &name,
+                        reservation,
+                        node_id,
+                        config_id,
+                        module_id,
+                        func_str_ptr,
+                        func_str_len,
+                        params_ptr,
+                        params_len,
+                    )
+                    .await?
+                } else {
+                    let error = anyhow!("Name `{name}` is already registered.");
+                    (caller.data_mut().error_resources_mut().add(error), 4)
+                }
+            }
+            None => {
+                let error = anyhow!("Parent process is not registered.");
+                (caller.data_mut().error_resources_mut().add(error), 3)
+            }
+        };
+
+        memory
+            .write(
+                &mut caller,
+                id_ptr as usize,
+                &process_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::spawn_named::write_id")?;
+
+        Ok(ret)
+    })
+}
+
+// Reserves the locally reserved `name` with the control server and spawns the process under it.
+// The name is released locally and with the control server if the spawn doesn't succeed.
+#[allow(clippy::too_many_arguments)]
+async fn spawn_reserved<T, E>(
+    caller: &mut Caller<'_, T>,
+    name: &str,
+    reservation: (u64, u64),
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+) -> Result<(u64, u32)>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    let control = caller.data().distributed()?.control.clone();
+    let error = match control.swap_name(name, None, Some(reservation)).await {
+        Ok(None) => None,
+        Ok(Some(_)) => Some((anyhow!("Name `{name}` is already registered."), 4)),
+        Err(error) => Some((
+            error.context(format!("Failed to reserve name `{name}`")),
+            20,
+        )),
+    };
+    if let Some((error, ret)) = error {
+        caller.data().registry().remove(name);
+        return Ok((caller.data_mut().error_resources_mut().add(error), ret));
+    }
+
+    let (process_or_error_id, ret) = spawn_on_node(
+        caller,
+        node_id,
+        config_id,
+        module_id,
+        func_str_ptr,
+        func_str_len,
+        params_ptr,
+        params_len,
+    )
+    .await?;
+    let process = (ret == 0).then_some((node_id, process_or_error_id));
+    if let Some(process) = process {
+        caller.data().registry().insert(name.to_string(), process);
+    } else {
+        caller.data().registry().remove(name);
+    }
+    // Without the update the cluster keeps the name reserved for this node, which still keeps
+    // other nodes from taking it.
+    if let Err(error) = control.swap_name(name, Some(reservation), process).await {
+        log::warn!("Failed to update the reservation of name `{name}`: {error:?}");
+    }
+    Ok((process_or_error_id, ret))
+}
+
+// Same as `spawn`, but spawns from a version of the module registered under the name
+// `name_str_ptr, name_str_len` instead of a module id. New versions of a module are registered
+// with the `--module-name` flag and processes spawned from older versions keep running.
+//
+// If `version` is 0, the latest version at the time of the spawn is used. Otherwise the spawn is
+// pinned to the given version.
+//
+// Returns:
+// * 0      on success - The ID of the newly created process is written to `id_ptr`
+// * 2      If the module or the version does not exist
+// * Same error codes as `spawn`, the error ID is written to `id_ptr`
+//
+// Traps:
+// * If the function or name string is not a valid utf8 string.
+// * If the name is longer than the node allows.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_version<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    version: u32,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, name_str_len, "lunatic::distributed::spawn_version")?;
+        let name_str = memory
+            .data(&caller)
+            .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+            .or_trap("lunatic::distributed::spawn_version::name_str")?;
+        let name = std::str::from_utf8(name_str)
+            .or_trap("lunatic::distributed::spawn_version::name_str_utf8")?
+            .to_string();
+
+        let spawn = prepare_spawn(
+            &mut caller,
+            node_id,
+            config_id,
+            0,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        )?;
+        let (process_or_error_id, ret) = match spawn {
+            Ok(spawn) => {
+                let spawn = Spawn {
+                    module_version: Some(VersionedModule {
+                        name,
+                        version: (version != 0).then_some(version),
+                    }),
+                    ..spawn
+                };
+                let result = caller
+                    .data()
+                    .distributed()?
+                    .node_client
+                    .spawn(NodeId(node_id), spawn)
+                    .await;
+                spawn_result(&mut caller, result)?
+            }
+            Err(error) => error,
+        };
+
+        memory
+            .write(
+                &mut caller,
+                id_ptr as usize,
+                &process_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::spawn_version::write_id")?;
+
+        Ok(ret)
+    })
+}
+
+// Moves the calling process to the node `node_id`. A new process is spawned there with the same
+// arguments as `spawn`, and all messages waiting in the mailbox of the calling process are sent to
+// it in the order they were received. Registry names pointing to the calling process are changed
+// to point to the new process.
+//
+// Guests can migrate their state by putting a snapshot of it into the scratch area before calling
+// this function. The snapshot is delivered to the new process before any of the mailbox messages,
+// and the new process is responsible for restoring the state from it. If the scratch area is empty
+// only the mailbox is migrated. Messages containing resources and `LinkDied` messages can't be
+// migrated and stay in the mailbox.
+//
+// Messages are forwarded in their order until the node doesn't accept one. That message and all
+// messages after it stay in the mailbox in their order, and if the snapshot wasn't accepted it
+// stays in the scratch area. The new process keeps running and names point to it either way.
+//
+// The calling process should finish right after a successful migration, messages arriving after
+// this call are not forwarded.
+//
+// Returns:
+// * 0      on success - The ID of the new process is written to `id_ptr`
+// * 21     If the process was migrated, but some messages stayed in the mailbox or the snapshot
+//          stayed in the scratch area - The ID of the new process is written to `id_ptr`
+// * Same error codes as `spawn`, in which case nothing was migrated and the snapshot stays in
+//   the scratch area.
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If the snapshot contains resources.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn migrate<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let snapshot = match caller.data_mut().message_scratch_area().take() {
+            Some(Message::Data(snapshot)) => {
+                if !snapshot.resources.is_empty() {
+                    return Err(anyhow!("Cannot migrate snapshots containing resources."));
+                }
+                Some(snapshot)
+            }
+            Some(Message::LinkDied(_)) => {
+                return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+            }
+            None => None,
+        };
+
+        let (process_or_error_id, ret) = spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+        )
+        .await?;
+
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                id_ptr as usize,
+                &process_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::migrate::write_id")?;
+
+        if ret != 0 {
+            if let Some(snapshot) = snapshot {
+                caller
+                    .data_mut()
+                    .message_scratch_area()
+                    .replace(Message::Data(snapshot));
+            }
+            return Ok(ret);
+        }
+
+        let process_id = process_or_error_id;
+        let (messages, left): (Vec<_>, Vec<_>) =
+            caller.data_mut().mailbox().drain().into_iter().partition(
+                |message| matches!(message, Message::Data(data) if data.resources.is_empty()),
+            );
+        let has_snapshot = snapshot.is_some();
+        let messages: Vec<_> = snapshot
+            .into_iter()
+            .chain(messages.into_iter().filter_map(|message| match message {
+                Message::Data(message) => Some(message),
+                Message::LinkDied(_) => None,
+            }))
+            .collect();
+        let count = messages.len();
+        let state = caller.data();
+        // The original senders are kept, the migrated process didn't send the messages
+        let forwarded = state
+            .distributed()?
+            .node_client
+            .forward_messages(
+                NodeId(node_id),
+                EnvironmentId(state.environment_id()),
+                ProcessId(process_id),
+                messages,
+            )
+            .await;
+
+        let this = (state.distributed()?.node_id(), state.id());
+        for mut entry in state.registry().iter_mut() {
+            if *entry.value() == this {
+                *entry.value_mut() = (node_id, process_id);
+            }
+        }
+
+        let mut not_forwarded = match forwarded {
+            Ok(()) if left.is_empty() => return Ok(0),
+            Ok(()) => Vec::new(),
+            Err((error, not_forwarded)) => {
+                log::warn!("Failed to migrate messages to node {node_id}: {error:?}");
+                not_forwarded
+            }
+        };
+        // The snapshot goes first, it's only left over if nothing was forwarded
+        if has_snapshot && not_forwarded.len() == count {
+            let snapshot = not_forwarded.remove(0);
+            caller
+                .data_mut()
+                .message_scratch_area()
+                .replace(Message::Data(snapshot));
+        }
+        let mailbox = caller.data_mut().mailbox();
+        for message in left
+            .into_iter()
+            .chain(not_forwarded.into_iter().map(Message::Data))
+        {
+            mailbox.requeue(message);
+        }
+        Ok(21)
+    })
+}
+
+// Traps if the string argument of `len` bytes is longer than the node allows, before it's read
+// and validated.
+fn check_string_arg<T, E>(caller: &Caller<T>, len: u32, function: &str) -> Result<()>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    caller
+        .data()
+        .distributed()?
+        .node_client
+        .config()
+        .check_string_arg(len)
+        .or_trap(function)
+}
+
+// Joins the parent's registered name and a suffix into the name of the child.
+fn derive_name(parent_name: &str, suffix: &str) -> String {
+    format!("{parent_name}.{suffix}")
+}
+
+// Spawns a process on the node `node_id` and returns either the new process id and the code 0, or
+// an error id and the error code.
+#[allow(clippy::too_many_arguments)]
+async fn spawn_on_node<T, E>(
+    caller: &mut Caller<'_, T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+) -> Result<(u64, u32)>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    let spawn = match prepare_spawn(
+        caller,
+        node_id,
+        config_id,
+        module_id,
+        func_str_ptr,
+        func_str_len,
+        params_ptr,
+        params_len,
+    )? {
+        Ok(spawn) => spawn,
+        Err(error) => return Ok(error),
+    };
+    let result = caller
+        .data()
+        .distributed()?
+        .node_client
+        .spawn(NodeId(node_id), spawn)
+        .await;
+    spawn_result(caller, result)
+}
+
+// Reads the spawn arguments from guest memory. If the spawn is rejected before being sent to the
+// node, an error id and the error code are returned instead.
+#[allow(clippy::too_many_arguments)]
+fn prepare_spawn<T, E>(
+    caller: &mut Caller<'_, T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+) -> Result<Result<Spawn, (u64, u32)>>
+where
+    T: DistributedCtx<E> + ErrorCtx,
+    E: Environment,
+{
+    if !caller.data().can_spawn() {
+        return Err(anyhow!(
+            "Process doesn't have permissions to spawn sub-processes"
+        ));
+    }
+    let params_len_check = caller
+        .data()
+        .distributed()?
+        .node_client
+        .config()
+        .check_spawn_params(params_len);
+    if let Err(error) = params_len_check {
+        let error = anyhow!(error);
+        return Ok(Err((caller.data_mut().error_resources_mut().add(error), 6)));
+    }
+    let func_str_len_check = caller
+        .data()
+        .distributed()?
+        .node_client
+        .config()
+        .check_string_arg(func_str_len);
+    if let Err(error) = func_str_len_check {
+        let error = anyhow!(error);
+        return Ok(Err((
+            caller.data_mut().error_resources_mut().add(error),
+            16,
+        )));
+    }
+    let memory = get_memory(caller)?;
+    let func_str = memory
+        .data(&*caller)
+        .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
+        .or_trap("lunatic::distributed::spawn::func_str")?;
+
+    let function =
+        std::str::from_utf8(func_str).or_trap("lunatic::distributed::spawn::func_str_utf8")?;
+
+    let params = memory
+        .data(&*caller)
+        .get(params_ptr as usize..(params_ptr + params_len) as usize)
+        .or_trap("lunatic::distributed::spawn::params")?;
+    let params = params
+        .chunks_exact(17)
+        .map(|chunk| {
+            let value = u128::from_le_bytes(chunk[1..].try_into()?);
+            let result = match chunk[0] {
+                0x7F => Val::I32(value as i32),
+                0x7E => Val::I64(value as i64),
+                0x7B => Val::V128(value),
+                _ => return Err(anyhow!("Unsupported type ID")),
+            };
+            Ok(result)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let state = caller.data();
+
+    let config = match config_id {
+        -1 => state.config().clone(),
+        config_id => Arc::new(
+            caller
+                .data()
+                .config_resources()
+                .get(config_id as u64)
+                .or_trap("lunatic::process::spawn: Config ID doesn't exist")?
+                .clone(),
+        ),
+    };
+    let lifecycle = config.get_lifecycle();
+    let config: Vec<u8> =
+        bincode::serialize(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
+
+    log::debug!("Spawn on node {node_id}, mod {module_id}, fn {function}, params {params:?}");
+
+    Ok(Ok(Spawn {
+        environment_id: EnvironmentId(state.environment_id()),
+        function: function.to_string(),
+        module_id: ModuleId(module_id),
+        module_version: None,
+        params,
+        config: SpawnConfig::Inline(config),
+        idempotency_key: None,
+        cancel_token: state.cancel_token(),
+        lifecycle,
+    }))
+}
+
+// Turns the result of a spawn into either the new process id and the code 0, or an error id and
+// the error code.
+fn spawn_result<T, E>(
+    caller: &mut Caller<'_, T>,
+    result: Result<ProcessId, ClientError>,
+) -> Result<(u64, u32)>
+where
+    T: DistributedCtx<E> + ErrorCtx,
+    E: Environment,
+{
+    match result {
+        Ok(process_id) => Ok((process_id.into(), 0)),
+        Err(error) => {
+            let (code, message): (u32, String) = match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
+                ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
+                ClientError::PermissionDenied => {
+                    Ok((5, "Module is not allowed on node.".to_string()))
+                }
+                ClientError::ModuleCompilation(cause) => {
+                    Ok((7, format!("Module compilation failed: {cause}")))
+                }
+                ClientError::Cancelled => Ok((8, "Spawn was cancelled on node.".to_string())),
+                ClientError::UntrustedModule => Ok((
+                    9,
+                    "Module is not signed by a trusted publisher.".to_string(),
+                )),
+                ClientError::SpawnQueueFull => Ok((12, "Spawn queue of node is full.".to_string())),
+                ClientError::ControlUnavailable => Ok((
+                    13,
+                    "Node is not connected to the control server.".to_string(),
+                )),
+                ClientError::DedupWindowFull => {
+                    Ok((14, "Node remembers too many idempotency keys.".to_string()))
+                }
+                ClientError::NodeDraining => {
+                    Ok((15, "Node is drained for maintenance.".to_string()))
+                }
+                ClientError::InvalidJoinToken => Ok((
+                    17,
+                    "Node rejected the join token of the cluster.".to_string(),
+                )),
+                ClientError::FunctionNotFound => {
+                    Ok((18, "Function does not exist in module.".to_string()))
+                }
+                ClientError::SpawnFailed(cause) => {
+                    Ok((19, format!("Spawn failed on node: {cause}")))
+                }
+                ClientError::Connection(cause) => Ok((9027, cause)),
+                _ => Err(anyhow!("unreachable")),
+            }?;
+            Ok((
+                caller
+                    .data_mut()
+                    .error_resources_mut()
+                    .add(anyhow!(message)),
+                code,
+            ))
+        }
+    }
+}
+
+// Sends the message in scratch area to a process running on a node with id `node_id`.
+//
+// There are no guarantees that the message will be received.
+//
+// Returns:
+// * 0      If message sent
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If the message was cancelled on the node
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+fn send<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    send_message(caller, node_id, process_id, false)
+}
+
+// Same as `send`, but doesn't wait for the message to be sent over the network. The message is
+// queued in the outgoing buffer of the node and the function returns right away. Messages to the
+// same process keep their order, also relative to messages sent with `send`.
+//
+// Delivery is best effort, errors like a process that doesn't exist are not reported.
+//
+// Returns:
+// * 0      If the message was queued
+// * 1      If the outgoing buffer is full, the message stays in the scratch area so that the send
+//          can be retried
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+fn try_send<T, E>(mut caller: Caller<T>, node_id: u64, process_id: u64) -> Result<u32>
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::distributed::try_send::no_message")?;
+    let message = caller.data().priority_boost().apply(message);
+    let mut message = match message {
+        Message::Data(message) => message,
+        Message::LinkDied(_) => {
+            return Err(anyhow!("Only Message::Data can be sent across nodes."))
+        }
+    };
+    if !message.resources.is_empty() {
+        return Err(anyhow!("Cannot send resources to remote nodes."));
+    }
+
+    let state = caller.data();
+    let distributed = state.distributed()?;
+    let queued = distributed.node_client.try_message_process(
+        NodeId(node_id),
+        EnvironmentId(state.environment_id()),
+        ProcessId(process_id),
+        message.tag,
+        message.priority,
+        std::mem::take(&mut message.buffer),
+        Some(MessageSender {
+            node_id: distributed.node_id(),
+            process_id: state.id(),
+        }),
+    );
+    match queued {
+        Ok(()) => Ok(0),
+        Err(buffer) => {
+            message.buffer = buffer;
+            caller
+                .data_mut()
+                .message_scratch_area()
+                .replace(Message::Data(message));
+            Ok(1)
+        }
+    }
+}
+
+// Sends the message in scratch area to a process running on a node with id `node_id`, with
+// at-least-once delivery.
+//
+// The message is sent again until the receiving process acknowledges it with `ack_message`, so
+// it can receive the same message more than once. Connection errors are not reported, the
+// message is sent again once the node is reachable.
+//
+// Returns:
+// * 0      If message sent
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If the message was cancelled on the node
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+fn send_reliable<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::send_reliable::no_message")?;
+        let message = caller.data().priority_boost().apply(message);
+        let message = match message {
+            Message::Data(message) => message,
+            Message::LinkDied(_) => {
+                return Err(anyhow!("Only Message::Data can be sent across nodes."))
+            }
+        };
+        if !message.resources.is_empty() {
+            return Err(anyhow!("Cannot send resources to remote nodes."));
+        }
+
+        let state = caller.data();
+        let distributed = state.distributed()?;
+        match distributed
+            .node_client
+            .message_process_reliably(
+                NodeId(node_id),
+                EnvironmentId(state.environment_id()),
+                ProcessId(process_id),
+                message.tag,
+                message.priority,
+                message.buffer,
+                MessageSender {
+                    node_id: distributed.node_id(),
+                    process_id: state.id(),
+                },
+            )
+            .await
+        {
+            Ok(_) => Ok(0),
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::ProcessNotFound => Ok(1),
+                ClientError::NodeNotFound => Ok(2),
+                ClientError::Cancelled => Ok(3),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Kills the process `process_id` running on a node with id `node_id`, in the same environment as
+// the calling process.
+//
+// Kill requests are control-plane requests. If the node has a separate control-plane listener
+// they are sent to it, so they are not delayed by data traffic to the node.
+//
+// Returns:
+// * 0      If the process was killed
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If the request was cancelled on the node
+// * 9027   If node connection error occurred
+fn kill<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        match state
+            .distributed()?
+            .node_client
+            .kill(
+                NodeId(node_id),
+                EnvironmentId(state.environment_id()),
+                ProcessId(process_id),
+            )
+            .await
+        {
+            Ok(_) => Ok(0),
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::ProcessNotFound => Ok(1),
+                ClientError::NodeNotFound => Ok(2),
+                ClientError::Cancelled => Ok(3),
+                ClientError::Connection(_) => Ok(9027),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Registers the calling process to receive a message tagged with `tag` when the process
+// `process_id` running on the node `node_id`, in the same environment, exits. If the process
+// doesn't exist the message is sent right away. The node ID 0, or the ID of the current node,
+// refers to processes on the current node, also outside of a cluster.
+//
+// The message contains the process ID as a little endian u64, followed by one byte with the exit
+// reason: 0 if the process finished normally, 1 if it failed or was killed, 2 if it didn't exist,
+// and 3 if it called `lunatic::process::exit`. The reason 3 is followed by the exit code as a
+// little endian u32 and the detail. Registrations can't be removed, and the message is sent at
+// most once.
+//
+// Returns:
+// * 0      If the registration was accepted
+// * 2      If node_id does not exist
+// * 3      If the request was cancelled on the node
+// * 9027   If node connection error occurred
+fn notify_on_exit<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+    tag: i64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = match state.distributed() {
+            Ok(distributed) if node_id != 0 && node_id != distributed.node_id() => {
+                distributed.node_client.clone()
+            }
+            // The process is on this node
+            _ => {
+                let watcher = WasmProcess::new(state.id(), state.signal_mailbox().0.clone());
+                lunatic_process::notify_on_exit(
+                    state.environment().as_ref(),
+                    process_id,
+                    tag,
+                    Arc::new(watcher),
+                );
+                return Ok(0);
+            }
+        };
+        match node_client
+            .notify_on_exit(
+                NodeId(node_id),
+                EnvironmentId(state.environment_id()),
+                ProcessId(process_id),
+                tag,
+                ProcessId(state.id()),
+            )
+            .await
+        {
+            Ok(_) => Ok(0),
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::NodeNotFound => Ok(2),
+                ClientError::Cancelled => Ok(3),
+                ClientError::Connection(_) => Ok(9027),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Links the calling process to the process `process_id` running on the node `node_id`, in the
+// same environment, like `lunatic::process::link` does for processes on the current node. The
+// node ID 0, or the ID of the current node, refers to processes on the current node. If `tag` is
+// not 0, the link carries it.
+//
+// If the linked process fails, the calling process receives a `LinkDied` signal with the tag, or
+// dies too if it doesn't trap link deaths. The same happens if the node of the process leaves the
+// cluster. Links can't be removed with `lunatic::process::unlink` across nodes yet.
+//
+// Returns:
+// * 0      If the processes were linked
+// * 1      If the process doesn't exist, the calling process receives a `LinkDied` signal
+// * 2      If node_id does not exist
+// * 9027   If node connection error occurred
+fn link<T, E>(
+    caller: Caller<T>,
+    tag: i64,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let tag = match tag {
+            0 => None,
+            tag => Some(tag),
+        };
+        let state = caller.data();
+        let signal_mailbox = state.signal_mailbox().0.clone();
+        let this_process = Arc::new(WasmProcess::new(state.id(), signal_mailbox.clone()));
+        let node_client = match state.distributed() {
+            Ok(distributed) if node_id != 0 && node_id != distributed.node_id() => {
+                distributed.node_client.clone()
+            }
+            // The process is on this node
+            _ => {
+                return match state.environment().get_process(process_id) {
+                    Some(process) => {
+                        process.send(Signal::Link(tag, this_process));
+                        signal_mailbox.send(Signal::Link(tag, process)).ok();
+                        Ok(0)
+                    }
+                    None => {
+                        signal_mailbox
+                            .send(Signal::LinkDied(process_id, tag, DeathReason::NoProcess))
+                            .ok();
+                        Ok(1)
+                    }
+                };
+            }
+        };
+        let environment_id = EnvironmentId(state.environment_id());
+        // Linked before the request, so that a `LinkDied` from the other node can't arrive first
+        node_client
+            .remote_links()
+            .add(NodeId(node_id), ProcessId(process_id), tag, this_process);
+        let process = RemoteLink {
+            node_client: node_client.clone(),
+            node_id: NodeId(node_id),
+            environment_id,
+            process_id: ProcessId(process_id),
+        };
+        signal_mailbox
+            .send(Signal::Link(tag, Arc::new(process)))
+            .ok();
+        let result = node_client
+            .link(
+                NodeId(node_id),
+                environment_id,
+                ProcessId(process_id),
+                tag,
+                ProcessId(state.id()),
+            )
+            .await;
+        if let Err(error) = result {
+            node_client.remote_links().remove(
+                NodeId(node_id),
+                ProcessId(process_id),
+                ProcessId(state.id()),
+            );
+            // Both signals remove the link again
+            let signal = match error {
+                ClientError::ProcessNotFound => {
+                    Signal::LinkDied(process_id, tag, DeathReason::NoProcess)
+                }
+                _ => Signal::UnLink { process_id },
+            };
+            signal_mailbox.send(signal).ok();
+            return match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::ProcessNotFound => Ok(1),
+                ClientError::NodeNotFound => Ok(2),
+                ClientError::Connection(_) => Ok(9027),
+                _ => Err(anyhow!("unreachable")),
+            };
+        }
+        Ok(0)
+    })
+}
+
+// Monitors the process `process_id` running on the node `node_id`, in the same environment. The
+// node ID 0, or the ID of the current node, refers to processes on the current node.
+//
+// When the process exits, the calling process receives one message tagged with `tag`, in the
+// format of the `notify_on_exit` message. If the node of the process leaves the cluster first, the
+// message has the reason 4. Unlike links, the calling process doesn't die with the monitored
+// process, and the monitor can be removed with `demonitor`.
+//
+// Returns:
+// * 0      If the monitor was added - The ID of the monitor is written to `monitor_id_ptr`
+// * 2      If node_id does not exist
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If the process is not running in a cluster.
+// * If any memory outside the guest heap space is referenced.
+fn monitor<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+    tag: i64,
+    monitor_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        let node_id = match node_id {
+            0 => node_client.node_id(),
+            node_id => NodeId(node_id),
+        };
+        let environment_id = EnvironmentId(state.environment_id());
+        let watcher = WasmProcess::new(state.id(), state.signal_mailbox().0.clone());
+        let monitor_id =
+            node_client
+                .monitors()
+                .add(node_id, ProcessId(process_id), tag, Arc::new(watcher));
+        let result = if node_id == node_client.node_id() {
+            let watcher = MonitorWatcher {
+                node_client: node_client.clone(),
+                monitor_id,
+                node_id,
+                environment_id,
+            };
+            lunatic_process::notify_on_exit(
+                state.environment().as_ref(),
+                process_id,
+                0,
+                Arc::new(watcher),
+            );
+            0
+        } else {
+            match node_client
+                .monitor(node_id, environment_id, ProcessId(process_id), monitor_id)
+                .await
+            {
+                Ok(_) => 0,
+                Err(error) => {
+                    node_client.monitors().remove(monitor_id);
+                    match error {
+                        ClientError::Unexpected(cause) => return Err(anyhow!(cause)),
+                        ClientError::NodeNotFound => 2,
+                        ClientError::Connection(_) => 9027,
+                        _ => return Err(anyhow!("unreachable")),
+                    }
+                }
+            }
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                monitor_id_ptr as usize,
+                &monitor_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::monitor")?;
+        Ok(result)
+    })
+}
+
+// Removes the monitor `monitor_id` added by `monitor`. Once removed, the monitor doesn't deliver
+// its message anymore.
+//
+// Returns:
+// * 0      If the monitor was removed
+// * 1      If the monitor doesn't exist or its message was already delivered
+//
+// Traps:
+// * If the process is not running in a cluster.
+fn demonitor<T, E>(
+    caller: Caller<T>,
+    monitor_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        let node_id = match node_client.monitors().remove(monitor_id) {
+            Some(node_id) => node_id,
+            None => return Ok(1),
+        };
+        if node_id != node_client.node_id() {
+            // Messages of the monitor are dropped already, telling the node only saves it work
+            let environment_id = EnvironmentId(state.environment_id());
+            if let Err(error) = node_client
+                .demonitor(node_id, environment_id, monitor_id)
+                .await
+            {
+                log::debug!("Failed to remove monitor {monitor_id} on node {node_id}: {error:?}");
+            }
+        }
+        Ok(0)
+    })
+}
+
+// Creates a new cancellation token and makes the calling process hold it, replacing the token it
+// held before. Processes spawned afterwards by a holder, on this node or on other nodes, hold the
+// token too. Processes that never create a token don't pass one on.
+//
+// Returns the token.
+fn create_cancel_token<T, E>(caller: Caller<T>) -> u64
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let state = caller.data();
+    let token = uuid::Uuid::new_v4().as_u128() as u64;
+    state
+        .environment()
+        .cancel_tokens()
+        .attach(token, state.id());
+    token
+}
+
+// Kills all processes in the environment that hold the cancellation token, on this node and on
+// all other nodes of the cluster. This includes the calling process if it holds the token.
+//
+// Returns the number of killed processes. Processes on nodes that can't be reached are not killed
+// and not counted.
+fn cancel<T, E>(caller: Caller<T>, token: u64) -> Box<dyn Future<Output = Result<u64>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let mut killed = 0;
+        if let Ok(distributed) = state.distributed() {
+            killed += distributed
+                .node_client
+                .cancel_on_all_nodes(EnvironmentId(state.environment_id()), token)
+                .await;
+        }
+        // The calling process could be killed, so other nodes are cancelled first
+        killed += lunatic_process::cancel::cancel(state.environment().as_ref(), token) as u64;
+        Ok(killed)
+    })
+}
+
+// Same as `send`, but attaches a reply capability to the message. The receiving process can take
+// the capability with `take_reply_cap` (it's always the resource with index 0) and use it once to
+// reply to the calling process with `reply_cap`, without learning its node or process id.
+//
+// Returns:
+// * 0      If message sent
+// * 1      If process_id does not exist
+// * 2      If node_id does not exist
+// * 3      If the message was cancelled on the node
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+fn send_with_reply_cap<T, E>(
+    caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    send_message(caller, node_id, process_id, true)
+}
+
+fn send_message<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+    with_reply_cap: bool,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::send::no_message")?;
+        let message = caller.data().priority_boost().apply(message);
+
+        if let Message::Data(DataMessage {
+            tag,
+            priority,
+            buffer,
+            resources,
+            ..
+        }) = message
+        {
+            if !resources.is_empty() {
+                return Err(anyhow!("Cannot send resources to remote nodes."));
+            }
+
+            let state = caller.data();
+            let node_client = &state.distributed()?.node_client;
+            let reply_cap = if with_reply_cap {
+                Some(node_client.mint_reply_capability(
+                    EnvironmentId(state.environment_id()),
+                    ProcessId(state.id()),
+                    state.signal_mailbox().0.clone(),
+                ))
+            } else {
+                None
+            };
+            let token = reply_cap.as_ref().map(|reply_cap| reply_cap.token);
+            match node_client
+                .message_process(
+                    NodeId(node_id),
+                    EnvironmentId(state.environment_id()),
+                    ProcessId(process_id),
+                    tag,
+                    priority,
+                    buffer,
+                    reply_cap,
+                    Some(MessageSender {
+                        node_id: state.distributed()?.node_id(),
+                        process_id: state.id(),
+                    }),
+                )
+                .await
+            {
+                Ok(_) => Ok(0),
+                Err(error) => {
+                    // Nobody received the capability if the message was rejected
+                    let rejected = matches!(
+                        error,
+                        ClientError::ProcessNotFound
+                            | ClientError::NodeNotFound
+                            | ClientError::Cancelled
+                    );
+                    if let (true, Some(token)) = (rejected, token) {
+                        node_client.redeem_reply_capability(token);
+                    }
+                    match error {
+                        ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                        ClientError::ProcessNotFound => Ok(1),
+                        ClientError::NodeNotFound => Ok(2),
+                        ClientError::Cancelled => Ok(3),
+                        ClientError::Connection(_) => Ok(9027),
+                        _ => Err(anyhow!("unreachable")),
+                    }
+                }
+            }
+        } else {
+            Err(anyhow!("Only Message::Data can be sent across nodes."))
+        }
+    })
+}
+
+// Sends the message in scratch area to all processes in `targets_ptr, targets_len` with a
+// best-effort two-phase delivery. Each target is a pair of little endian u64 values
+// `(node_id, process_id)`, the processes are in the same environment as the calling process.
+//
+// The message is first staged on all nodes and only committed once every node staged it. If a
+// stage fails, e.g. because a process doesn't exist, the stages are rolled back and no process
+// receives the message. The commits are not all-or-nothing: nodes roll back stages that are not
+// committed within their transaction timeout by themselves, and a commit can fail after other
+// nodes already delivered the message. Guests that need every target to receive the message must
+// handle codes 3, 4 and 9027.
+//
+// Returns:
+// * 0      If the message was delivered to all processes
+// * 1      If a process_id does not exist, nothing was delivered
+// * 2      If a node_id does not exist, nothing was delivered
+// * 3      If a request was cancelled on a node, processes on other nodes may have received the
+//          message
+// * 4      If the stage on a node expired before the commit arrived, processes on other nodes
+//          may have received the message
+// * 9027   If node connection error occurred, processes on other nodes may have received the
+//          message
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources.
+// * If any memory outside the guest heap space is referenced.
+fn send_two_phase<T, E>(
+    mut caller: Caller<T>,
+    targets_ptr: u32,
+    targets_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let targets: Vec<(NodeId, ProcessId)> = memory
+            .data(&caller)
+            .get(targets_ptr as usize..(targets_ptr as usize + targets_len as usize * 16))
+            .or_trap("lunatic::distributed::send_two_phase::targets_ptr")?
+            .chunks_exact(16)
+            .map(|target| {
+                (
+                    NodeId(u64::from_le_bytes(target[..8].try_into().expect("8 bytes"))),
+                    ProcessId(u64::from_le_bytes(target[8..].try_into().expect("8 bytes"))),
+                )
+            })
+            .collect();
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::send_two_phase::no_message")?;
+        let message = caller.data().priority_boost().apply(message);
+
+        if let Message::Data(DataMessage {
+            tag,
+            priority,
+            buffer,
+            resources,
+            ..
+        }) = message
+        {
+            if !resources.is_empty() {
+                return Err(anyhow!("Cannot send resources to remote nodes."));
+            }
+
+            let state = caller.data();
+            let sender = MessageSender {
+                node_id: state.distributed()?.node_id(),
+                process_id: state.id(),
+            };
+            match state
+                .distributed()?
+                .node_client
+                .send_two_phase(
+                    EnvironmentId(state.environment_id()),
+                    &targets,
+                    tag,
+                    priority,
+                    buffer,
+                    Some(sender),
+                )
+                .await
+            {
+                Ok(_) => Ok(0),
+                Err(error) => match error {
+                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                    ClientError::ProcessNotFound => Ok(1),
+                    ClientError::NodeNotFound => Ok(2),
+                    ClientError::Cancelled => Ok(3),
+                    ClientError::TransactionExpired => Ok(4),
+                    ClientError::Connection(_) => Ok(9027),
+                    _ => Err(anyhow!("unreachable")),
+                },
+            }
+        } else {
+            Err(anyhow!("Only Message::Data can be sent across nodes."))
+        }
+    })
+}
+
+// Publishes the message in scratch area to all processes subscribed to the topic
+// `topic_ptr, topic_len` in the same environment on the node with id `node_id`. The number of
+// processes the message was delivered to is written to `delivered_ptr`.
+//
+// Returns:
+// * 0      If message published
+// * 2      If node_id does not exist
+// * 3      If the message was cancelled on the node
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources.
+// * If the topic is not a valid UTF-8 string, or longer than the node allows.
+// * If any memory outside the guest heap space is referenced.
+fn publish<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    topic_ptr: u32,
+    topic_len: u32,
+    delivered_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        check_string_arg(&caller, topic_len, "lunatic::distributed::publish")?;
+        let topic = memory
+            .data(&caller)
+            .get(topic_ptr as usize..(topic_ptr + topic_len) as usize)
+            .or_trap("lunatic::distributed::publish::topic_ptr")?;
+        let topic = std::str::from_utf8(topic)
+            .or_trap("lunatic::distributed::publish::topic_utf8")?
+            .to_string();
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::publish::no_message")?;
+        let message = caller.data().priority_boost().apply(message);
+
+        if let Message::Data(DataMessage {
+            tag,
+            priority,
+            buffer,
+            resources,
+            ..
+        }) = message
+        {
+            if !resources.is_empty() {
+                return Err(anyhow!("Cannot send resources to remote nodes."));
+            }
+
+            let state = caller.data();
+            let result = state
+                .distributed()?
+                .node_client
+                .publish(
+                    NodeId(node_id),
+                    EnvironmentId(state.environment_id()),
+                    topic,
+                    tag,
+                    priority,
+                    buffer,
+                )
+                .await;
+            match result {
+                Ok(delivered) => {
+                    memory
+                        .write(
+                            &mut caller,
+                            delivered_ptr as usize,
+                            &delivered.to_le_bytes(),
+                        )
+                        .or_trap("lunatic::distributed::publish::delivered_ptr")?;
+                    Ok(0)
+                }
+                Err(error) => match error {
+                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                    ClientError::NodeNotFound => Ok(2),
+                    ClientError::Cancelled => Ok(3),
+                    ClientError::Connection(_) => Ok(9027),
+                    _ => Err(anyhow!("unreachable")),
+                },
+            }
+        } else {
+            Err(anyhow!("Only Message::Data can be sent across nodes."))
+        }
+    })
+}
+
+// Sends the message to a process on a node with id `node_id` and waits for a reply,
+// but doesn't look through existing messages in the mailbox queue while waiting.
+// This is an optimization that only makes sense with tagged messages.
+// In a request/reply scenario we can tag the request message with an
+// unique tag and just wait on it specifically.
+//
+// This operation needs to be an atomic host function, if we jumped back into the guest we could
+// miss out on the incoming message before `receive` is called.
+//
+// If timeout is specified (value different from u64::MAX), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    If message arrived.
+// * 1    If process_id does not exist
+// * 2    If node_id does not exist
+// * 3    If the message was cancelled on the node
+// * 9027 If call timed out.
+//
+// Traps:
+// * If it's called with wrong data in the scratch area.
+// * If the message contains resources
+fn send_receive_skip_search<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::send::no_message")?;
+        let message = caller.data().priority_boost().apply(message);
+
+        let mut _tags = [0; 1];
+        let tags = if let Some(tag) = message.tag() {
+            _tags = [tag];
+            Some(&_tags[..])
+        } else {
+            None
+        };
+
+        if let Message::Data(DataMessage {
+            tag,
+            priority,
+            buffer,
+            resources,
+            ..
+        }) = message
+        {
+            if !resources.is_empty() {
+                return Err(anyhow!("Cannot send resources to remote nodes."));
+            }
+
+            let state = caller.data();
+            let code = match state
+                .distributed()?
+                .node_client
+                .message_process(
+                    NodeId(node_id),
+                    EnvironmentId(state.environment_id()),
+                    ProcessId(process_id),
+                    tag,
+                    priority,
+                    buffer,
+                    None,
+                    Some(MessageSender {
+                        node_id: state.distributed()?.node_id(),
+                        process_id: state.id(),
+                    }),
+                )
+                .await
+            {
+                Ok(_) => Ok(0),
+                Err(error) => match error {
+                    ClientError::ProcessNotFound => Ok(1),
+                    ClientError::NodeNotFound => Ok(2),
+                    ClientError::Cancelled => Ok(3),
+                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                    _ => Err(anyhow!("unreachable")),
+                },
+            }?;
+
+            if code != 0 {
+                return Ok(code);
+            }
+
+            let pop_skip_search = caller.data_mut().mailbox().pop_skip_search(tags);
+            if let Ok(message) = match timeout_duration {
+                // Without timeout
+                u64::MAX => Ok(pop_skip_search.await),
+                // With timeout
+                t => timeout(Duration::from_millis(t), pop_skip_search).await,
+            } {
+                // Put the message into the scratch area
+                caller.data_mut().message_scratch_area().replace(message);
+                Ok(0)
+            } else {
+                Ok(9027)
+            }
+        } else {
+            Err(anyhow!("Only Message::Data can be sent across nodes."))
+        }
+    })
+}
+
+// Sends the request in `request_ptr` to a process on the node `node_id` and waits for the reply,
+// which is written to `reply_ptr`, up to `reply_len` bytes. The full size of the reply is written
+// as an u32 to `reply_size_ptr`.
+//
+// The called process receives an untagged message with a reply capability, and replies with
+// `take_reply_cap` and `reply_cap`. The reply is matched to the call by the capability and
+// never enters the mailbox, so it can't be confused with other messages, whatever their tag.
+//
+// If timeout is specified (value different from u64::MAX), the function will return on timeout
+// expiration with value 4. A reply arriving later is rejected.
+//
+// Returns:
+// * 0    If the reply was written.
+// * 1    If process_id does not exist
+// * 2    If node_id does not exist
+// * 3    If the request was cancelled on the node
+// * 4    If the call timed out.
+// * 9027 If a node connection error occurred
+//
+// Traps:
+// * If the process is not running in a cluster.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn call<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+    request_ptr: u32,
+    request_len: u32,
+    reply_ptr: u32,
+    reply_len: u32,
+    timeout_duration: u64,
+    reply_size_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let request = memory
+            .data(&caller)
+            .get(request_ptr as usize..(request_ptr as usize + request_len as usize))
+            .or_trap("lunatic::distributed::call::request_ptr")?
+            .to_vec();
+        let timeout = match timeout_duration {
+            u64::MAX => None,
+            t => Some(Duration::from_millis(t)),
+        };
+
+        let state = caller.data();
+        let distributed = state.distributed()?;
+        let reply = distributed
+            .node_client
+            .call(
+                NodeId(node_id),
+                EnvironmentId(state.environment_id()),
+                ProcessId(process_id),
+                state.priority_boost().raise(Priority::Normal),
+                request,
+                Some(MessageSender {
+                    node_id: distributed.node_id(),
+                    process_id: state.id(),
+                }),
+                timeout,
+            )
+            .await;
+        let reply = match reply {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return Ok(4),
+            Err(error) => {
+                return match error {
+                    ClientError::ProcessNotFound => Ok(1),
+                    ClientError::NodeNotFound => Ok(2),
+                    ClientError::Cancelled => Ok(3),
+                    ClientError::Connection(_) => Ok(9027),
+                    error => Err(anyhow!("{error:?}")),
+                }
+            }
+        };
+
+        let written = reply.len().min(reply_len as usize);
+        memory
+            .write(&mut caller, reply_ptr as usize, &reply[..written])
+            .or_trap("lunatic::distributed::call::reply_ptr")?;
+        memory
+            .write(
+                &mut caller,
+                reply_size_ptr as usize,
+                &(reply.len() as u32).to_le_bytes(),
+            )
+            .or_trap("lunatic::distributed::call::reply_size_ptr")?;
+        Ok(0)
+    })
+}
+
+// Takes the reply capability from the message that is currently in the scratch area by index,
+// puts it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a reply capability).
+// * If no data message is in the scratch area.
+fn take_reply_cap<T, E>(mut caller: Caller<T>, index: u64) -> Result<u64>
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::distributed::take_reply_cap")?;
+    let reply_cap = match message {
+        Message::Data(data) => data
+            .take_downcast::<ReplyCapability>(index as usize)
+            .or_trap("lunatic::distributed::take_reply_cap")?,
+        Message::LinkDied(_) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+    };
+    Ok(caller
+        .data_mut()
+        .reply_capability_resources_mut()
+        .add(reply_cap.as_ref().clone()))
+}
+
+// Writes the node and process id of the sender of the message that is currently in the scratch
+// area to `node_id_ptr` and `process_id_ptr`.
+//
+// Returns:
+// * 0      If the sender is known
+// * 1      If the message didn't arrive from another node or the sender didn't provide it
+//
+// Traps:
+// * If no message is in the scratch area.
+// * If any memory outside the guest heap space is referenced.
+fn sender_info<T, E>(mut caller: Caller<T>, node_id_ptr: u32, process_id_ptr: u32) -> Result<u32>
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let sender = match caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::distributed::sender_info")?
+    {
+        Message::Data(message) => message.sender,
+        Message::LinkDied(_) => None,
+    };
+    match sender {
+        Some(sender) => {
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(
+                    &mut caller,
+                    node_id_ptr as usize,
+                    &sender.node_id.to_le_bytes(),
+                )
+                .or_trap("lunatic::distributed::sender_info::node_id_ptr")?;
+            memory
+                .write(
+                    &mut caller,
+                    process_id_ptr as usize,
+                    &sender.process_id.to_le_bytes(),
+                )
+                .or_trap("lunatic::distributed::sender_info::process_id_ptr")?;
+            Ok(0)
+        }
+        None => Ok(1),
+    }
+}
+
+// Writes the id of the message that is currently in the scratch area to `message_id_ptr`, if it
+// was sent with `send_reliable`. The id is used to acknowledge the message with `ack_message`. A
+// message that is delivered again keeps its id.
+//
+// Returns:
+// * 0      If the message was sent with at-least-once delivery
+// * 1      If the message doesn't need to be acknowledged
+//
+// Traps:
+// * If no message is in the scratch area.
+// * If any memory outside the guest heap space is referenced.
+fn message_id<T, E>(mut caller: Caller<T>, message_id_ptr: u32) -> Result<u32>
+where
+    T: DistributedCtx<E> + ProcessCtx<T>,
+    E: Environment,
+{
+    let receipt = match caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::distributed::message_id")?
+    {
+        Message::Data(message) => message.receipt,
+        Message::LinkDied(_) => None,
+    };
+    match receipt {
+        Some(receipt) => {
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(&mut caller, message_id_ptr as usize, &receipt.to_le_bytes())
+                .or_trap("lunatic::distributed::message_id::message_id_ptr")?;
+            Ok(0)
+        }
+        None => Ok(1),
+    }
+}
+
+// Acknowledges the receipt of the message with `message_id`, see `message_id`. The sending node
+// stops sending the message again.
+//
+// Acknowledging is idempotent, acknowledging the same message again does nothing.
+//
+// Returns:
+// * 0      If the message was acknowledged
+// * 1      If the message is unknown or was already acknowledged
+// * 9027   If node connection error occurred, the acknowledgement can be retried
+fn ack_message<T, E>(
+    caller: Caller<T>,
+    message_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        match node_client
+            .ack_message(EnvironmentId(state.environment_id()), message_id)
+            .await
+        {
+            Ok(true) => Ok(0),
+            Ok(false) => Ok(1),
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::Connection(_) => Ok(9027),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Acknowledges all messages the process received from the node that sent the message with
+// `message_id`, up to and including that message, in one request. See `ack_message`.
+//
+// Messages from the node with lower ids that the process didn't receive yet are not acknowledged,
+// the sending node keeps sending them again until they are acknowledged.
+//
+// Returns:
+// * 0      If the messages were acknowledged
+// * 1      If the message is unknown or was already acknowledged
+// * 9027   If node connection error occurred, the acknowledgement can be retried
+fn ack_range<T, E>(
+    caller: Caller<T>,
+    message_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        match node_client
+            .ack_messages_up_to(EnvironmentId(state.environment_id()), message_id)
+            .await
+        {
+            Ok(0) => Ok(1),
+            Ok(_) => Ok(0),
+            Err(error) => match error {
+                ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                ClientError::Connection(_) => Ok(9027),
+                _ => Err(anyhow!("unreachable")),
+            },
+        }
+    })
+}
+
+// Sends the message in scratch area as a reply to the process that minted the capability
+// `reply_cap_id`. The capability is consumed, even if sending fails.
+//
+// Returns:
+// * 0      If message sent
+// * 1      If the capability was already used or doesn't exist
+// * 2      If the node that minted the capability does not exist
+// * 3      If the reply was cancelled on the node
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+fn reply_cap<T, E>(
+    mut caller: Caller<T>,
+    reply_cap_id: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    E: Environment,
     for<'a> &'a T: Send,
 {
     Box::new(async move {
@@ -422,14 +3346,14 @@ where
             .data_mut()
             .message_scratch_area()
             .take()
-            .or_trap("lunatic::message::send::no_message")?;
-
-        let mut _tags = [0; 1];
-        let tags = if let Some(tag) = message.tag() {
-            _tags = [tag];
-            Some(&_tags[..])
-        } else {
-            None
+            .or_trap("lunatic::distributed::reply_cap::no_message")?;
+        let reply_cap = match caller
+            .data_mut()
+            .reply_capability_resources_mut()
+            .remove(reply_cap_id)
+        {
+            Some(reply_cap) => reply_cap,
+            None => return Ok(1),
         };
 
         if let Message::Data(DataMessage {
@@ -444,37 +3368,26 @@ where
             }
 
             let state = caller.data();
-            let code = match state
+            match state
                 .distributed()?
                 .node_client
-                .message_process(node_id, state.environment_id(), process_id, tag, buffer)
+                .reply(
+                    reply_cap,
+                    EnvironmentId(state.environment_id()),
+                    tag,
+                    buffer,
+                )
                 .await
             {
                 Ok(_) => Ok(0),
                 Err(error) => match error {
-                    ClientError::ProcessNotFound => Ok(1),
-                    ClientError::NodeNotFound => Ok(2),
                     ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+                    ClientError::InvalidCapability | ClientError::ProcessNotFound => Ok(1),
+                    ClientError::NodeNotFound => Ok(2),
+                    ClientError::Cancelled => Ok(3),
+                    ClientError::Connection(_) => Ok(9027),
                     _ => Err(anyhow!("unreachable")),
                 },
-            }?;
-
-            if code != 0 {
-                return Ok(code);
-            }
-
-            let pop_skip_search = caller.data_mut().mailbox().pop_skip_search(tags);
-            if let Ok(message) = match timeout_duration {
-                // Without timeout
-                u64::MAX => Ok(pop_skip_search.await),
-                // With timeout
-                t => timeout(Duration::from_millis(t), pop_skip_search).await,
-            } {
-                // Put the message into the scratch area
-                caller.data_mut().message_scratch_area().replace(message);
-                Ok(0)
-            } else {
-                Ok(9027)
             }
         } else {
             Err(anyhow!("Only Message::Data can be sent across nodes."))
@@ -482,7 +3395,10 @@ where
     })
 }
 
-// Returns the id of the node that the current process is running on
+// Returns the id of the node that the current process is running on.
+//
+// Node ids start at 1, the value 0 is reserved and returned if the process is not running in a
+// cluster. Use `is_distributed` to check it explicitly.
 fn node_id<T, E>(caller: Caller<T>) -> u64
 where
     T: DistributedCtx<E>,
@@ -496,7 +3412,234 @@ where
         .unwrap_or(0)
 }
 
-// Returns id of the module that the current process is spawned from
+// Writes the distributed capabilities of the current process to `descriptor_ptr`, up to
+// `descriptor_len` bytes. The descriptor lists which groups of functions in this namespace the
+// runtime supports and which of them the process is permitted to use, the encoding is versioned
+// and described on `Capabilities::encode`. Outside of a cluster no capability is supported.
+//
+// Returns the length of the encoded descriptor, if it's larger than `descriptor_len` only a part
+// of it was written.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn capabilities<T, E>(
+    mut caller: Caller<T>,
+    descriptor_ptr: u32,
+    descriptor_len: u32,
+) -> Result<u32>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let state = caller.data();
+    let descriptor = Capabilities::new(
+        state.distributed().is_ok(),
+        state.can_spawn(),
+        state.can_drain_node(),
+    )
+    .encode();
+    let written = descriptor.len().min(descriptor_len as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, descriptor_ptr as usize, &descriptor[..written])
+        .or_trap("lunatic::distributed::capabilities")?;
+    Ok(descriptor.len() as u32)
+}
+
+// Writes the load and capacity of the node `node_id` to `info_ptr`. The node ID 0 refers to the
+// current node. The info is 45 bytes long, the encoding is versioned and described on
+// `NodeStats::encode`. It contains the number of processes, the memory used by them and its limit,
+// the load average and number of CPUs of the host, and the number of compiled modules.
+//
+// Returns:
+// * 0      If the info was written
+// * 2      If node_id does not exist
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If the process is not running in a cluster.
+// * If any memory outside the guest heap space is referenced.
+fn node_info<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    info_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let node_client = state.distributed()?.node_client.clone();
+        let node_id = match node_id {
+            0 => node_client.node_id(),
+            node_id => NodeId(node_id),
+        };
+        let environment_id = EnvironmentId(state.environment_id());
+        let stats = match node_client.node_info(node_id, environment_id).await {
+            Ok(stats) => stats,
+            Err(ClientError::Unexpected(cause)) => return Err(anyhow!(cause)),
+            Err(ClientError::NodeNotFound) => return Ok(2),
+            Err(ClientError::Connection(_)) => return Ok(9027),
+            Err(_) => return Err(anyhow!("unreachable")),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, info_ptr as usize, &stats.encode())
+            .or_trap("lunatic::distributed::node_info")?;
+        Ok(0)
+    })
+}
+
+// Returns the state of the connection of the current node to the control server.
+//
+// Returns:
+// * 0      If the node is connected to the control server
+// * 1      If the connection is lost, the node keeps spawning with the last known nodes and
+//          already compiled modules
+// * 2      If the connection is lost and the node refuses spawns until it's reconnected
+//
+// Traps:
+// * If the process is not running in a cluster.
+fn control_status<T, E>(caller: Caller<T>) -> Result<u32>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let status = caller.data().distributed()?.control.status();
+    Ok(match status {
+        ControlStatus::Connected => 0,
+        ControlStatus::Degraded => 1,
+        ControlStatus::Strict => 2,
+    })
+}
+
+// Drains the node that the current process is running on for maintenance.
+//
+// The node refuses spawns from other nodes from now on, spawns with fallback move on to another
+// node. Processes that are already running on the node are not affected and run to completion.
+// Singletons held by processes on the node are released, so that they can be claimed on another
+// node. Draining can't be undone, the node is expected to be restarted afterwards.
+//
+// Only processes spawned from a configuration that allows it can drain the node, see
+// `lunatic::process::config_set_can_drain_node`.
+//
+// Traps:
+// * If the process is not running in a cluster.
+// * If the process doesn't have permissions to drain the node.
+fn drain_node<T, E>(caller: Caller<T>) -> Result<()>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    if !caller.data().can_drain_node() {
+        return Err(anyhow!("Process doesn't have permissions to drain the node"));
+    }
+    caller.data().distributed()?.control.drain();
+    Ok(())
+}
+
+// Returns 1 if the node that the current process is running on is drained, otherwise 0.
+//
+// Traps:
+// * If the process is not running in a cluster.
+fn is_draining<T, E>(caller: Caller<T>) -> Result<u32>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    Ok(caller.data().distributed()?.control.is_draining() as u32)
+}
+
+// Writes a page of the cluster topology, as seen by the control server, to `buffer_ptr`.
+//
+// The topology lists the nodes ordered by id, each with the nodes it has direct connections to.
+// The page starts at the node with index `offset` and contains as many nodes as fit into
+// `buffer_len` bytes, but never more than the control server returns at once. Each node is
+// encoded as its id (u64), the number of its peers (u32) and the ids of the peers (u64 each), all
+// little endian. Nodes that didn't report their connections yet have no peers.
+//
+// Returns:
+// * 0 If the page was written. The number of nodes in the page (u32) followed by the total number
+//     of nodes (u32) are written to `counts_ptr`. If the page is empty while the offset is below
+//     the total, the buffer is too small for the next node.
+// * 1 If the control server returned an error, the error ID is written to `error_ptr`.
+//
+// Traps:
+// * If the process is not running in a cluster.
+// * If any memory outside the guest heap space is referenced.
+fn topology_snapshot<T, E>(
+    mut caller: Caller<T>,
+    offset: u32,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    counts_ptr: u32,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let control = caller.data().distributed()?.control.clone();
+        match control.topology(offset as u64).await {
+            Ok((nodes, total)) => {
+                let mut buffer = Vec::new();
+                let mut written = 0u32;
+                for (node_id, peers) in nodes {
+                    let size = 12 + 8 * peers.len();
+                    if buffer.len() + size > buffer_len as usize {
+                        break;
+                    }
+                    buffer.extend(node_id.to_le_bytes());
+                    buffer.extend((peers.len() as u32).to_le_bytes());
+                    for peer in peers {
+                        buffer.extend(peer.to_le_bytes());
+                    }
+                    written += 1;
+                }
+                memory
+                    .write(&mut caller, buffer_ptr as usize, &buffer)
+                    .or_trap("lunatic::distributed::topology_snapshot::buffer_ptr")?;
+                let mut counts = written.to_le_bytes().to_vec();
+                counts.extend((total as u32).to_le_bytes());
+                memory
+                    .write(&mut caller, counts_ptr as usize, &counts)
+                    .or_trap("lunatic::distributed::topology_snapshot::counts_ptr")?;
+                Ok(0)
+            }
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::topology_snapshot::error_ptr")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
+// Returns the next value of a sequence shared by all processes on the current node. Values start
+// at 0, increase monotonically and are never returned twice on the same node. Combined with the
+// node id they are unique in the cluster.
+//
+// Traps:
+// * If the process is not running in a cluster.
+fn node_sequence_next<T, E>(caller: Caller<T>) -> Result<u64>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    Ok(caller.data().distributed()?.sequence.next())
+}
+
+// Returns id of the module that the current process is spawned from.
+//
+// Module ids are assigned by the control server and start at 1. The value 0 is reserved and
+// returned if the module was not registered with a control server, e.g. outside of a cluster.
 fn module_id<T, E>(caller: Caller<T>) -> u64
 where
     T: DistributedCtx<E>,
@@ -504,3 +3647,80 @@ where
 {
     caller.data().module_id()
 }
+
+// Runs a self-test of the distributed stack of the node and writes the report to `report_ptr`, up
+// to `report_len` bytes. The test doesn't change the state of the cluster and finishes within
+// `timeout_ms` milliseconds, checks that didn't finish until then are reported as timed out.
+//
+// `checks` is a bitset of the checks to run:
+// * 0x1    List the nodes of the cluster on the control server
+// * 0x2    Ping each node known to this node
+// * 0x4    Spawn the function `func_str` of the current module with the config of the current
+//          process and no params on a node that answered the ping, send it an empty message and
+//          kill it again. Skipped if `func_str_len` is 0.
+//
+// The report is versioned and described on `SelfTestReport::encode`. Each result has the status
+// 0 (passed), 1 (failed), 2 (timed out) or 3 (skipped).
+//
+// Returns the length of the encoded report, if it's larger than `report_len` only a part of it
+// was written.
+//
+// Traps:
+// * If the process is not running in a cluster.
+// * If the round trip runs and the process doesn't have permission to spawn sub-processes.
+// * If the function string is longer than the node allows or not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn self_test<T, E>(
+    mut caller: Caller<T>,
+    checks: u32,
+    timeout_ms: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    report_ptr: u32,
+    report_len: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let config = SelfTestConfig {
+            checks,
+            timeout: Duration::from_millis(timeout_ms),
+        };
+        let spawn = if func_str_len > 0 && config.runs(SelfTestCheck::RoundTrip) {
+            check_string_arg(&caller, func_str_len, "lunatic::distributed::self_test")?;
+            let module_id = caller.data().module_id();
+            match prepare_spawn(
+                &mut caller,
+                0,
+                -1,
+                module_id,
+                func_str_ptr,
+                func_str_len,
+                0,
+                0,
+            )? {
+                Ok(spawn) => Some(spawn),
+                Err(_) => return Err(anyhow!("unreachable")),
+            }
+        } else {
+            None
+        };
+        let environment_id = EnvironmentId(caller.data().environment_id());
+        let report = caller
+            .data()
+            .distributed()?
+            .node_client
+            .self_test(environment_id, spawn, config)
+            .await
+            .encode();
+        let written = report.len().min(report_len as usize);
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, report_ptr as usize, &report[..written])
+            .or_trap("lunatic::distributed::self_test")?;
+        Ok(report.len() as u32)
+    })
+}
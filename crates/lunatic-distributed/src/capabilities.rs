@@ -0,0 +1,145 @@
+/*!
+Distributed capabilities of a process, for guests to detect which parts of the
+`lunatic::distributed` API they can use.
+
+A capability is supported if the runtime provides the host functions behind it and the node is
+part of a cluster, outside of a cluster the functions trap. It's permitted if the process is also
+allowed to use it, e.g. spawning requires a config that can spawn processes.
+*/
+
+/// A group of host functions in the `lunatic::distributed` namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// `spawn` and its variants.
+    Spawn,
+    /// `send`, `try_send`, `kill` and `notify_on_exit`.
+    Send,
+    /// `send_reliable`, `message_id`, `ack_message` and `ack_range`.
+    ReliableDelivery,
+    /// `call`, `reply_cap`, `take_reply_cap` and replying to a capability.
+    Call,
+    /// `send_two_phase`.
+    Transactions,
+    /// `cancel` and `cancel_request`.
+    Cancel,
+    /// `publish`.
+    Publish,
+    /// `counter_add` and `counter_get`.
+    Counters,
+    /// `compare_and_swap`.
+    Registers,
+    /// `claim_singleton` and `release_singleton`.
+    Singletons,
+    /// `group_join`, `group_leave`, `group_send`, `group_kill` and `group_monitor`.
+    Groups,
+    /// `drain_node` and `is_draining`.
+    Drain,
+}
+
+impl Capability {
+    /// All capabilities, ordered by their bit.
+    pub const ALL: [Capability; 12] = [
+        Capability::Spawn,
+        Capability::Send,
+        Capability::ReliableDelivery,
+        Capability::Call,
+        Capability::Transactions,
+        Capability::Cancel,
+        Capability::Publish,
+        Capability::Counters,
+        Capability::Registers,
+        Capability::Singletons,
+        Capability::Groups,
+        Capability::Drain,
+    ];
+
+    /// Bit of the capability in the encoded bitsets. Bits are never reused, new capabilities get
+    /// the next free one.
+    pub fn bit(&self) -> u64 {
+        1 << (*self as u64)
+    }
+}
+
+/// Capabilities supported by the runtime and permitted to a process, as bitsets of
+/// [`Capability::bit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub supported: u64,
+    pub permitted: u64,
+}
+
+impl Capabilities {
+    /// Version of the encoding, increased when its layout changes. New capabilities only add
+    /// bits and keep the version.
+    pub const VERSION: u8 = 1;
+
+    /// Capabilities of a process that is allowed to spawn processes if `can_spawn` is set and to
+    /// drain the node if `can_drain` is set, on a node that is part of a cluster if `in_cluster` is
+    /// set.
+    pub fn new(in_cluster: bool, can_spawn: bool, can_drain: bool) -> Self {
+        if !in_cluster {
+            return Self::default();
+        }
+        let supported = Capability::ALL
+            .iter()
+            .fold(0, |bits, capability| bits | capability.bit());
+        let mut permitted = supported;
+        if !can_spawn {
+            permitted &= !Capability::Spawn.bit();
+        }
+        // `is_draining` is always permitted, but the capability stands for draining the node
+        if !can_drain {
+            permitted &= !Capability::Drain.bit();
+        }
+        Self {
+            supported,
+            permitted,
+        }
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.supported & capability.bit() != 0
+    }
+
+    pub fn permits(&self, capability: Capability) -> bool {
+        self.permitted & capability.bit() != 0
+    }
+
+    // Encodes the capabilities as little endian values:
+    // [version: u8][supported: u64][permitted: u64]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![Self::VERSION];
+        data.extend(self.supported.to_le_bytes());
+        data.extend(self.permitted.to_le_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, Capability};
+
+    #[test]
+    fn descriptor_reflects_cluster_and_permissions() {
+        let capabilities = Capabilities::new(true, false, false);
+        assert!(Capability::ALL
+            .iter()
+            .all(|capability| capabilities.supports(*capability)));
+        assert!(!capabilities.permits(Capability::Spawn));
+        assert!(!capabilities.permits(Capability::Drain));
+        assert!(capabilities.permits(Capability::Groups));
+
+        let data = capabilities.encode();
+        assert_eq!(data[0], Capabilities::VERSION);
+        assert_eq!(&data[1..9], &0xfffu64.to_le_bytes());
+        assert_eq!(&data[9..17], &0x7feu64.to_le_bytes());
+        assert_eq!(data.len(), 17);
+
+        assert!(Capabilities::new(true, true, false).permits(Capability::Spawn));
+        assert!(Capabilities::new(true, false, true).permits(Capability::Drain));
+        // Outside of a cluster nothing is supported
+        let capabilities = Capabilities::new(false, true, true);
+        assert_eq!(capabilities, Capabilities::default());
+        assert!(!capabilities.permits(Capability::Send));
+    }
+}
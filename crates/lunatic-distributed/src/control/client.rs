@@ -2,18 +2,34 @@ use anyhow::{anyhow, Result};
 use async_cell::sync::AsyncCell;
 use bytes::Bytes;
 use dashmap::DashMap;
-use lunatic_process::runtimes::RawWasm;
+use lunatic_process::{
+    message::{DataMessage, Message},
+    runtimes::RawWasm,
+    state::SignalSender,
+    Signal,
+};
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::{atomic, atomic::AtomicU64, Arc, RwLock},
+    sync::{
+        atomic,
+        atomic::{AtomicBool, AtomicU64},
+        Arc, OnceLock, RwLock,
+    },
     time::Duration,
 };
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 use crate::{
-    control::message::{Registered, Registration, Request, Response},
-    quic::{self, RecvStream},
+    control::{
+        gossip::{GossipConfig, MemberState, MemberUpdate, Membership},
+        message::{ModuleBytes, Registered, Registration, Request, Response},
+        node_events::NodeEvents,
+        pool::ConnectionPool,
+        status::{reconnect_delay, ControlConnection, ControlStatus, OutageMode},
+    },
+    join_token::JoinToken,
+    quic::{self, RecvStream, SendStream},
     NodeInfo,
 };
 
@@ -28,58 +44,142 @@ pub struct InnerClient {
     next_message_id: AtomicU64,
     next_query_id: AtomicU64,
     node_addr: SocketAddr,
+    // Address of the control-plane listener of this node, if it has one
+    node_control_addr: Option<SocketAddr>,
     node_name: String,
     control_addr: SocketAddr,
-    tx: UnboundedSender<(u64, Request)>,
+    pool: ConnectionPool,
     pending_requests: DashMap<u64, Arc<AsyncCell<Response>>>,
     node_queries: DashMap<u64, Vec<u64>>,
     nodes: DashMap<u64, NodeInfo>,
     node_ids: RwLock<Vec<u64>>,
     attributes: HashMap<String, String>,
+    // Counter deltas that were not sent to the control server yet
+    pending_counters: DashMap<String, i64>,
+    node_events: NodeEvents,
+    connection: ControlConnection,
+    // Set once the node is drained for maintenance, it's never unset
+    draining: AtomicBool,
+    // Set after the registration if the node gossips the membership
+    membership: OnceLock<Membership>,
 }
 
+/// Number of connections to the control server a node opens by default.
+pub const DEFAULT_POOL_SIZE: usize = 2;
+
+/// How often deltas added to counters are sent to the control server.
+const COUNTER_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often singleton claims of processes on this node are renewed, and how often processes
+/// waiting for a singleton check if it became available. Must be shorter than the grace period
+/// of the control server.
+const SINGLETON_RENEW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a node tells the control server that it's alive by default. Must be shorter than the
+/// heartbeat timeout of the control server.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
 impl Client {
+    #[allow(clippy::too_many_arguments)]
     pub async fn register(
         node_addr: SocketAddr,
+        node_control_addr: Option<SocketAddr>,
         node_name: String,
         attributes: HashMap<String, String>,
         control_addr: SocketAddr,
         quic_client: quic::Client,
         signing_request: String,
+        join_token: Option<JoinToken>,
+        outage_mode: OutageMode,
+        pool_size: usize,
+        heartbeat_interval: Duration,
+        gossip: Option<GossipConfig>,
     ) -> Result<(u64, Self, String)> {
-        let (tx, rx) = mpsc::unbounded_channel();
+        let (client, receivers) = Client::new(
+            node_addr,
+            node_control_addr,
+            node_name,
+            attributes,
+            control_addr,
+            outage_mode,
+            pool_size,
+        );
+        // Spawn reader tasks before register
+        let connector = Connector::Quic {
+            quic_client,
+            addr: control_addr,
+            name: CTRL_SERVER_NAME.to_string(),
+        };
+        client.start_connections(connector, receivers);
+        tokio::task::spawn(refresh_nodes_task(client.clone()));
+        let Registered {
+            node_id,
+            signed_cert,
+        } = client
+            .send_registration(signing_request, join_token)
+            .await?;
+        if let Some(config) = gossip {
+            let this = NodeInfo {
+                id: node_id,
+                address: client.inner.node_addr,
+                name: client.inner.node_name.clone(),
+                control_address: client.inner.node_control_addr,
+            };
+            client
+                .inner
+                .membership
+                .set(Membership::new(this, config))
+                .ok();
+        }
+        client.refresh_nodes().await?;
+        tokio::task::spawn(flush_counters_task(client.clone(), node_id));
+        tokio::task::spawn(heartbeat_task(client.clone(), node_id, heartbeat_interval));
+
+        Ok((node_id, client, signed_cert))
+    }
 
+    fn new(
+        node_addr: SocketAddr,
+        node_control_addr: Option<SocketAddr>,
+        node_name: String,
+        attributes: HashMap<String, String>,
+        control_addr: SocketAddr,
+        outage_mode: OutageMode,
+        pool_size: usize,
+    ) -> (Self, Vec<UnboundedReceiver<(u64, Request)>>) {
+        let (pool, receivers) = ConnectionPool::new(pool_size);
         let client = Client {
             inner: Arc::new(InnerClient {
                 next_message_id: AtomicU64::new(1),
                 control_addr,
                 node_addr,
-                node_name: node_name.clone(),
-                tx,
+                node_control_addr,
+                node_name,
+                pool,
                 pending_requests: DashMap::new(),
                 node_queries: DashMap::new(),
                 next_query_id: AtomicU64::new(1),
                 nodes: Default::default(),
                 node_ids: Default::default(),
                 attributes,
+                pending_counters: DashMap::new(),
+                node_events: NodeEvents::default(),
+                connection: ControlConnection::new(outage_mode),
+                draining: AtomicBool::new(false),
+                membership: OnceLock::new(),
             }),
         };
-        // Spawn reader task before register
-        tokio::task::spawn(connection_task(
-            client.clone(),
-            quic_client,
-            control_addr,
-            CTRL_SERVER_NAME.to_string(),
-            rx,
-        ));
-        tokio::task::spawn(refresh_nodes_task(client.clone()));
-        let Registered {
-            node_id,
-            signed_cert,
-        } = client.send_registration(signing_request).await?;
-        client.refresh_nodes().await?;
+        (client, receivers)
+    }
 
-        Ok((node_id, client, signed_cert))
+    fn start_connections(
+        &self,
+        connector: Connector,
+        receivers: Vec<UnboundedReceiver<(u64, Request)>>,
+    ) {
+        for (index, rx) in receivers.into_iter().enumerate() {
+            tokio::task::spawn(connection_task(self.clone(), index, connector.clone(), rx));
+        }
     }
 
     pub fn next_message_id(&self) -> u64 {
@@ -100,20 +200,35 @@ impl Client {
 
     pub async fn send(&self, req: Request) -> Result<Response> {
         let msg_id = self.next_message_id();
-        self.inner.tx.send((msg_id, req))?;
+        // Wait for the response before sending, it can arrive on any connection of the pool
         let cell = AsyncCell::shared();
         self.inner.pending_requests.insert(msg_id, cell.clone());
+        if let Err(error) = self.inner.pool.send(msg_id, req) {
+            self.inner.pending_requests.remove(&msg_id);
+            return Err(error);
+        }
         let response = cell.take().await;
         self.inner.pending_requests.remove(&msg_id);
         Ok(response)
     }
 
-    async fn send_registration(&self, signing_request: String) -> Result<Registered> {
+    /// Number of connections to the control server.
+    pub fn pool_size(&self) -> usize {
+        self.inner.pool.size()
+    }
+
+    async fn send_registration(
+        &self,
+        signing_request: String,
+        join_token: Option<JoinToken>,
+    ) -> Result<Registered> {
         let reg = Registration {
             node_address: self.inner.node_addr,
+            control_address: self.inner.node_control_addr,
             node_name: self.inner.node_name.clone(),
             attributes: self.inner.attributes.clone(),
             signing_request,
+            join_token,
         };
         let resp = self.send(Request::Register(reg)).await?;
         match resp {
@@ -123,6 +238,44 @@ impl Client {
         }
     }
 
+    // Client that is not connected to any control server
+    #[cfg(test)]
+    pub(crate) fn detached() -> Self {
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (client, _) = Client::new(
+            address,
+            None,
+            String::new(),
+            HashMap::new(),
+            address,
+            OutageMode::default(),
+            1,
+        );
+        client
+    }
+
+    // Client with `pool_size` connections that open streams on the in-memory `connection`
+    #[cfg(test)]
+    pub(crate) fn in_memory(connection: quic::Connection, pool_size: usize) -> Self {
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (client, receivers) = Client::new(
+            address,
+            None,
+            String::new(),
+            HashMap::new(),
+            address,
+            OutageMode::default(),
+            pool_size,
+        );
+        client.start_connections(Connector::InMemory(connection), receivers);
+        client
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_node_ids(&self, node_ids: Vec<u64>) {
+        *self.inner.node_ids.write().unwrap() = node_ids;
+    }
+
     fn process_response(&self, id: u64, resp: Response) {
         if let Some(e) = self.inner.pending_requests.get(&id) {
             e.set(resp);
@@ -131,25 +284,124 @@ impl Client {
 
     pub async fn refresh_nodes(&self) -> Result<()> {
         if let Response::Nodes(nodes) = self.send(Request::ListNodes).await? {
-            let mut node_ids = vec![];
-            for node in nodes {
-                let id = node.id;
-                node_ids.push(id);
-                if !self.inner.nodes.contains_key(&id) {
-                    self.inner.nodes.insert(id, node);
+            match self.membership() {
+                // The listed nodes only seed the membership
+                Some(membership) => {
+                    let updates = nodes
+                        .into_iter()
+                        .map(|node| MemberUpdate {
+                            node,
+                            incarnation: 0,
+                            state: MemberState::Alive,
+                        })
+                        .collect();
+                    if membership.apply(updates) {
+                        self.sync_members(membership);
+                    }
                 }
-            }
-            if let Ok(mut self_node_ids) = self.inner.node_ids.write() {
-                *self_node_ids = node_ids;
+                None => self.set_nodes(nodes),
             }
         }
         Ok(())
     }
 
+    fn set_nodes(&self, nodes: Vec<NodeInfo>) {
+        let mut node_ids = vec![];
+        for node in nodes {
+            let id = node.id;
+            node_ids.push(id);
+            if !self.inner.nodes.contains_key(&id) {
+                self.inner.nodes.insert(id, node);
+            }
+        }
+        // Nodes removed by the control server, e.g. because their heartbeats stopped
+        self.inner.nodes.retain(|id, _| node_ids.contains(id));
+        if let Ok(mut self_node_ids) = self.inner.node_ids.write() {
+            for id in node_ids.iter().filter(|id| !self_node_ids.contains(id)) {
+                self.inner.node_events.node_connected(*id);
+            }
+            for id in self_node_ids.iter().filter(|id| !node_ids.contains(id)) {
+                self.inner.node_events.node_disconnected(*id);
+            }
+            *self_node_ids = node_ids;
+        }
+    }
+
+    /// Membership gossiped between the nodes, if the node uses it instead of the nodes listed by
+    /// the control server.
+    pub fn membership(&self) -> Option<&Membership> {
+        self.inner.membership.get()
+    }
+
+    /// Applies updates of the membership gossiped by another node.
+    pub fn apply_gossip(&self, updates: Vec<MemberUpdate>) {
+        if let Some(membership) = self.membership() {
+            if membership.apply(updates) {
+                self.sync_members(membership);
+            }
+        }
+    }
+
+    /// Declares suspected members dead that didn't refute the suspicion in time.
+    pub fn expire_suspects(&self) {
+        if let Some(membership) = self.membership() {
+            if membership.expire_suspects() {
+                self.sync_members(membership);
+            }
+        }
+    }
+
+    fn sync_members(&self, membership: &Membership) {
+        let mut nodes = membership.live_members();
+        nodes.push(membership.this());
+        nodes.sort_unstable_by_key(|node| node.id);
+        self.set_nodes(nodes);
+    }
+
     pub async fn deregister(&self, node_id: u64) {
         self.send(Request::Deregister(node_id)).await.ok();
     }
 
+    pub async fn heartbeat(&self, node_id: u64) -> Result<()> {
+        match self.send(Request::Heartbeat(node_id)).await? {
+            Response::None => Ok(()),
+            Response::Error(error) => Err(anyhow!(error)),
+            _ => Err(anyhow!("Invalid response type on heartbeat.")),
+        }
+    }
+
+    /// Nodes joining or leaving the cluster, as seen by this node.
+    pub fn node_events(&self) -> &NodeEvents {
+        &self.inner.node_events
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        self.inner.connection.status()
+    }
+
+    /// Returns false while the control server is not connected and the node is configured to
+    /// refuse spawns in that case.
+    pub fn accepts_spawns(&self) -> bool {
+        self.inner.connection.accepts_spawns()
+    }
+
+    /// Drains the node for maintenance.
+    ///
+    /// The node refuses spawns from other nodes from now on, processes that are already running
+    /// are not affected. Singletons held by processes on this node are released with their next
+    /// renewal, so that they can be claimed on another node.
+    pub fn drain(&self) {
+        if !self.inner.draining.swap(true, atomic::Ordering::Relaxed) {
+            log::info!("Draining node, refusing new spawns");
+            #[cfg(feature = "metrics")]
+            metrics::gauge!("lunatic.distributed.draining", 1.0);
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(atomic::Ordering::Relaxed)
+    }
+
     pub fn node_info(&self, node_id: u64) -> Option<NodeInfo> {
         self.inner.nodes.get(&node_id).map(|e| e.clone())
     }
@@ -173,6 +425,19 @@ impl Client {
         }
     }
 
+    /// Returns the IDs of the nodes registered with the attribute `key` set to `value`.
+    pub async fn nodes_with_attribute(&self, key: &str, value: &str) -> Result<Vec<u64>> {
+        let request = Request::NodesWithAttribute {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        match self.send(request).await? {
+            Response::Nodes(nodes) => Ok(nodes.into_iter().map(|node| node.id).collect()),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on nodes_with_attribute.")),
+        }
+    }
+
     pub fn query_result(&self, query_id: &u64) -> Option<(u64, Vec<u64>)> {
         self.inner.node_queries.remove(query_id)
     }
@@ -181,7 +446,12 @@ impl Client {
         self.inner.node_ids.read().unwrap().len()
     }
 
-    pub async fn get_module(&self, module_id: u64) -> Option<Vec<u8>> {
+    /// Returns `None` right away if the control server is not connected, instead of waiting for
+    /// the connection to come back.
+    pub async fn get_module(&self, module_id: u64) -> Option<ModuleBytes> {
+        if !self.inner.connection.is_connected() {
+            return None;
+        }
         if let Ok(Response::Module(module)) = self.send(Request::GetModule(module_id)).await {
             module
         } else {
@@ -189,13 +459,306 @@ impl Client {
         }
     }
 
-    pub async fn add_module(&self, module: Vec<u8>) -> Result<RawWasm> {
-        if let Response::ModuleId(id) = self.send(Request::AddModule(module.clone())).await? {
+    /// Atomically replaces the value of the register `key` with `new` if it's equal to `expected`
+    /// and returns the observed value. Missing registers are empty.
+    pub async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        match self
+            .send(Request::CompareAndSwap { key, expected, new })
+            .await?
+        {
+            Response::Value(observed) => Ok(observed),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on compare_and_swap.")),
+        }
+    }
+
+    /// Adds `delta` to the cluster wide counter `name`.
+    ///
+    /// Deltas are collected locally and periodically sent to the control server, so other nodes
+    /// observe them with a delay.
+    pub fn counter_add(&self, name: &str, delta: i64) {
+        let mut pending = self
+            .inner
+            .pending_counters
+            .entry(name.to_string())
+            .or_default();
+        *pending = pending.wrapping_add(delta);
+    }
+
+    /// Returns the sum of all contributions to the counter `name`, including the deltas of this
+    /// node that were not sent to the control server yet.
+    pub async fn counter_get(&self, name: &str) -> Result<i64> {
+        match self.send(Request::GetCounter(name.to_string())).await? {
+            Response::Counter(value) => {
+                let pending = self
+                    .inner
+                    .pending_counters
+                    .get(name)
+                    .map(|pending| *pending)
+                    .unwrap_or_default();
+                Ok(value.wrapping_add(pending))
+            }
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on counter_get.")),
+        }
+    }
+
+    async fn flush_counters(&self, node_id: u64) -> Result<()> {
+        let names: Vec<String> = self
+            .inner
+            .pending_counters
+            .iter()
+            .map(|pending| pending.key().clone())
+            .collect();
+        let deltas: Vec<(String, i64)> = names
+            .into_iter()
+            .filter_map(|name| self.inner.pending_counters.remove(&name))
+            .collect();
+        if deltas.is_empty() {
+            return Ok(());
+        }
+        if let Err(error) = self
+            .send(Request::AddToCounters {
+                node_id,
+                deltas: deltas.clone(),
+            })
+            .await
+        {
+            // Keep the deltas for the next flush
+            for (name, delta) in deltas {
+                self.counter_add(&name, delta);
+            }
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Claims the singleton `role` for the process and returns `(node_id, process_id)` of the
+    /// holder after the claim. The claim succeeded if the holder is the process itself.
+    ///
+    /// A successful claim is renewed in the background and released once the process finishes or
+    /// the node is drained.
+    /// `process` is the signal mailbox of the claiming process, used to detect when it finishes.
+    pub async fn claim_singleton(
+        &self,
+        role: &str,
+        node_id: u64,
+        process_id: u64,
+        process: SignalSender,
+    ) -> Result<(u64, u64)> {
+        let holder = self.send_claim(role, node_id, process_id).await?;
+        if holder == (node_id, process_id) {
+            tokio::task::spawn(hold_singleton_task(
+                self.clone(),
+                role.to_string(),
+                node_id,
+                process_id,
+                process,
+            ));
+        }
+        Ok(holder)
+    }
+
+    /// Releases the singleton `role` if it's held by the process.
+    pub async fn release_singleton(&self, role: &str, node_id: u64, process_id: u64) -> Result<()> {
+        let request = Request::ReleaseSingleton {
+            role: role.to_string(),
+            node_id,
+            process_id,
+        };
+        match self.send(request).await? {
+            Response::None => Ok(()),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on release_singleton.")),
+        }
+    }
+
+    /// Sends a message tagged with `tag` and containing the role name to the process once the
+    /// singleton `role` becomes available. Nothing is sent if the process finishes first.
+    pub fn notify_when_available(&self, role: &str, tag: i64, process: SignalSender) {
+        tokio::task::spawn(watch_singleton_task(
+            self.clone(),
+            role.to_string(),
+            tag,
+            process,
+        ));
+    }
+
+    /// Adds the process to the process group `group`.
+    ///
+    /// The process leaves the group once it finishes. `process` is the signal mailbox of the
+    /// joining process, used to detect when it finishes.
+    pub async fn join_group(
+        &self,
+        group: &str,
+        node_id: u64,
+        process_id: u64,
+        process: SignalSender,
+    ) -> Result<()> {
+        let request = Request::JoinGroup {
+            group: group.to_string(),
+            node_id,
+            process_id,
+        };
+        match self.send(request).await? {
+            Response::None => {
+                tokio::task::spawn(leave_group_on_exit_task(
+                    self.clone(),
+                    group.to_string(),
+                    node_id,
+                    process_id,
+                    process,
+                ));
+                Ok(())
+            }
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on join_group.")),
+        }
+    }
+
+    /// Removes the process from the process group `group`.
+    pub async fn leave_group(&self, group: &str, node_id: u64, process_id: u64) -> Result<()> {
+        let request = Request::LeaveGroup {
+            group: group.to_string(),
+            node_id,
+            process_id,
+        };
+        match self.send(request).await? {
+            Response::None => Ok(()),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on leave_group.")),
+        }
+    }
+
+    /// Returns `(node_id, process_id)` of the members of the process group `group`, on all
+    /// nodes.
+    pub async fn group_members(&self, group: &str) -> Result<Vec<(u64, u64)>> {
+        match self.send(Request::GetGroup(group.to_string())).await? {
+            Response::Members(members) => Ok(members),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on group_members.")),
+        }
+    }
+
+    /// Replaces the `(node_id, process_id)` registered under the cluster-wide name `name` with
+    /// `new` if the current one is `expected`, and returns the one registered before. `None`
+    /// stands for a free name, so the name is reserved only if `None` is returned for
+    /// `expected = None`.
+    pub async fn swap_name(
+        &self,
+        name: &str,
+        expected: Option<(u64, u64)>,
+        new: Option<(u64, u64)>,
+    ) -> Result<Option<(u64, u64)>> {
+        let request = Request::SwapName {
+            name: name.to_string(),
+            expected,
+            new,
+        };
+        match self.send(request).await? {
+            Response::Name(observed) => Ok(observed),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on swap_name.")),
+        }
+    }
+
+    async fn send_claim(&self, role: &str, node_id: u64, process_id: u64) -> Result<(u64, u64)> {
+        let request = Request::ClaimSingleton {
+            role: role.to_string(),
+            node_id,
+            process_id,
+        };
+        match self.send(request).await? {
+            Response::Singleton(Some(holder)) => Ok(holder),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on claim_singleton.")),
+        }
+    }
+
+    async fn get_singleton(&self, role: &str) -> Result<Option<(u64, u64)>> {
+        match self.send(Request::GetSingleton(role.to_string())).await? {
+            Response::Singleton(holder) => Ok(holder),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on get_singleton.")),
+        }
+    }
+
+    /// Reports the nodes this node has direct connections to, used by the control server to
+    /// assemble the cluster topology.
+    pub async fn report_peers(&self, node_id: u64, peers: Vec<u64>) -> Result<()> {
+        match self.send(Request::ReportPeers { node_id, peers }).await? {
+            Response::None => Ok(()),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on report_peers.")),
+        }
+    }
+
+    /// Returns a page of the cluster topology as seen by the control server, starting at the
+    /// node with index `offset`, and the total number of nodes. See `Server::topology`.
+    pub async fn topology(&self, offset: u64) -> Result<(Vec<(u64, Vec<u64>)>, u64)> {
+        match self.send(Request::GetTopology(offset)).await? {
+            Response::Topology(nodes, total) => Ok((nodes, total)),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on topology.")),
+        }
+    }
+
+    /// Adds the module to the control server. The `signature` is checked by nodes that only
+    /// accept modules of a trusted publisher.
+    pub async fn add_module(&self, module: Vec<u8>, signature: Option<Vec<u8>>) -> Result<RawWasm> {
+        let request = Request::AddModule(ModuleBytes {
+            bytes: module.clone(),
+            signature,
+        });
+        if let Response::ModuleId(id) = self.send(request).await? {
             Ok(RawWasm::new(Some(id), module))
         } else {
             Err(anyhow::anyhow!("Invalid response type on add_module."))
         }
     }
+
+    /// Adds the module to the control server as the next version of the module `name` and
+    /// returns it together with its version. Spawns of `name` use the new version from then on,
+    /// unless they are pinned to an earlier one.
+    pub async fn add_module_version(
+        &self,
+        name: &str,
+        module: Vec<u8>,
+        signature: Option<Vec<u8>>,
+    ) -> Result<(RawWasm, u32)> {
+        let request = Request::AddModuleVersion {
+            name: name.to_string(),
+            module: ModuleBytes {
+                bytes: module.clone(),
+                signature,
+            },
+        };
+        match self.send(request).await? {
+            Response::ModuleVersion { module_id, version } => {
+                Ok((RawWasm::new(Some(module_id), module), version))
+            }
+            _ => Err(anyhow!("Invalid response type on add_module_version.")),
+        }
+    }
+
+    /// Returns the module id of a version of the module `name`, or of the latest version if
+    /// `version` is `None`.
+    pub async fn resolve_module(&self, name: &str, version: Option<u32>) -> Result<u64> {
+        let request = Request::ResolveModule {
+            name: name.to_string(),
+            version,
+        };
+        match self.send(request).await? {
+            Response::ModuleId(module_id) => Ok(module_id),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on resolve_module.")),
+        }
+    }
 }
 
 async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
@@ -222,27 +785,317 @@ async fn refresh_nodes_task(client: Client) -> Result<()> {
     }
 }
 
+async fn flush_counters_task(client: Client, node_id: u64) -> Result<()> {
+    loop {
+        tokio::time::sleep(COUNTER_FLUSH_INTERVAL).await;
+        client.flush_counters(node_id).await.ok();
+    }
+}
+
+async fn heartbeat_task(client: Client, node_id: u64, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        // Failed heartbeats are not retried, the next one follows soon enough
+        if let Err(error) = client.heartbeat(node_id).await {
+            log::warn!("Heartbeat to the control server failed: {error}");
+        }
+    }
+}
+
+async fn hold_singleton_task(
+    client: Client,
+    role: String,
+    node_id: u64,
+    process_id: u64,
+    process: SignalSender,
+) {
+    loop {
+        tokio::time::sleep(SINGLETON_RENEW_INTERVAL).await;
+        if process.is_closed() || client.is_draining() {
+            client
+                .release_singleton(&role, node_id, process_id)
+                .await
+                .ok();
+            break;
+        }
+        match client.send_claim(&role, node_id, process_id).await {
+            Ok(holder) if holder != (node_id, process_id) => {
+                log::warn!("Process {process_id} lost the singleton {role} to {holder:?}");
+                break;
+            }
+            // Failed renewals are retried, the claim is kept for the grace period
+            _ => {}
+        }
+    }
+}
+
+async fn watch_singleton_task(client: Client, role: String, tag: i64, process: SignalSender) {
+    loop {
+        tokio::time::sleep(SINGLETON_RENEW_INTERVAL).await;
+        if process.is_closed() {
+            break;
+        }
+        if let Ok(None) = client.get_singleton(&role).await {
+            let message = DataMessage::new_from_vec(Some(tag), role.into_bytes());
+            process.send(Signal::Message(Message::Data(message))).ok();
+            break;
+        }
+    }
+}
+
+async fn leave_group_on_exit_task(
+    client: Client,
+    group: String,
+    node_id: u64,
+    process_id: u64,
+    process: SignalSender,
+) {
+    process.closed().await;
+    if let Err(error) = client.leave_group(&group, node_id, process_id).await {
+        log::warn!("Process {process_id} failed to leave the group {group}: {error}");
+    }
+}
+
+// Opens the connections of the pool
+#[derive(Clone)]
+enum Connector {
+    Quic {
+        quic_client: quic::Client,
+        addr: SocketAddr,
+        name: String,
+    },
+    #[cfg(test)]
+    InMemory(quic::Connection),
+}
+
+impl Connector {
+    async fn connect(&self) -> (SendStream, RecvStream) {
+        match self {
+            Connector::Quic {
+                quic_client,
+                addr,
+                name,
+            } => connect_with_backoff(quic_client, *addr, name).await,
+            #[cfg(test)]
+            Connector::InMemory(connection) => connection.open_stream().await.unwrap(),
+        }
+    }
+}
+
 async fn connection_task(
     client: Client,
-    quic_client: quic::Client,
-    addr: SocketAddr,
-    name: String,
+    index: usize,
+    connector: Connector,
     mut rx: UnboundedReceiver<(u64, Request)>,
 ) {
-    let (mut send, recv) = quic::try_connect_forever(&quic_client, addr, &name).await;
-    tokio::spawn(reader_task(client.clone(), recv));
-    while let Some(msg) = rx.recv().await {
-        if let Ok(data) = bincode::serialize(&msg) {
-            let size = (data.len() as u32).to_le_bytes();
-            let size: Bytes = Bytes::copy_from_slice(&size[..]);
-            let bytes: Bytes = data.into();
-            while let Err(e) = send.send(&mut [size.clone(), bytes.clone()]).await {
+    // Request that failed to send and is retried on the next connection
+    let mut unsent: Option<[Bytes; 2]> = None;
+    loop {
+        let (mut send, recv) = connector.connect().await;
+        client.inner.pool.set_connected(index, true);
+        client.inner.connection.opened();
+        let mut reader = tokio::spawn(reader_task(client.clone(), recv));
+        loop {
+            let frame = match unsent.take() {
+                Some(frame) => frame,
+                None => tokio::select! {
+                    // The reader only stops if the connection is lost
+                    _ = &mut reader => break,
+                    msg = rx.recv() => match msg {
+                        Some(msg) => match bincode::serialize(&msg) {
+                            Ok(data) => {
+                                let size = (data.len() as u32).to_le_bytes();
+                                [Bytes::copy_from_slice(&size[..]), data.into()]
+                            }
+                            Err(_) => continue,
+                        },
+                        None => return,
+                    },
+                },
+            };
+            if let Err(e) = send.send(&mut frame.clone()).await {
                 log::debug!("Cannot send data to control node: {e}, reconnecting...");
-                let (new_send, new_recv) =
-                    quic::try_connect_forever(&quic_client, addr, &name).await;
-                tokio::spawn(reader_task(client.clone(), new_recv));
-                send = new_send;
+                unsent = Some(frame);
+                break;
             }
         }
+        reader.abort();
+        client.inner.pool.set_connected(index, false);
+        client.inner.connection.closed();
+        // Requests still queued on this connection fail over to the other ones
+        let queued: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        for (msg_id, request) in queued {
+            client.inner.pool.send(msg_id, request).ok();
+        }
+    }
+}
+
+async fn connect_with_backoff(
+    quic_client: &quic::Client,
+    addr: SocketAddr,
+    name: &str,
+) -> (quic::SendStream, RecvStream) {
+    let mut attempt = 0;
+    loop {
+        match quic_client.connect(addr, name, 1).await {
+            Ok(connection) => return connection,
+            Err(e) => {
+                let delay = reconnect_delay(attempt);
+                log::debug!("Failed to connect to control node {addr}: {e}, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use dashmap::DashMap;
+
+    use super::Client;
+    use crate::{
+        control::{
+            message::{ModuleBytes, Request, Response},
+            server::{handle_request, root_cert, serve_in_memory, Server},
+        },
+        quic::{self, ConnectionConfig},
+    };
+
+    #[tokio::test]
+    async fn concurrent_module_fetches_use_multiple_connections() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let module_id = match server.add_module(ModuleBytes {
+            bytes: vec![1, 2, 3],
+            signature: None,
+        }) {
+            Response::ModuleId(module_id) => module_id,
+            response => panic!("unexpected response {response:?}"),
+        };
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        let client = Client::in_memory(connection, 3);
+
+        // Connection index -> number of requests handled on it
+        let handled: Arc<DashMap<usize, usize>> = Arc::default();
+        let mut connections = 0;
+        while connections < client.pool_size() {
+            let (mut send, mut recv) = acceptor.accept().await.unwrap();
+            let (server, handled, index) = (server.clone(), handled.clone(), connections);
+            tokio::spawn(async move {
+                while let Ok(bytes) = recv.receive().await {
+                    let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+                    *handled.entry(index).or_default() += 1;
+                    // Respond slowly, so that the fetches overlap
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    handle_request(server.clone(), &mut send, msg_id, request)
+                        .await
+                        .unwrap();
+                }
+            });
+            connections += 1;
+        }
+
+        let fetches: Vec<_> = (0..12)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.get_module(module_id).await })
+            })
+            .collect();
+        for fetch in fetches {
+            let module = fetch.await.unwrap().unwrap();
+            assert_eq!(module.bytes, vec![1, 2, 3]);
+        }
+        assert_eq!(handled.len(), 3);
+        assert!(handled.iter().all(|handled| *handled.value() == 4));
+    }
+
+    #[tokio::test]
+    async fn exited_group_members_are_removed() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        serve_in_memory(server, acceptor);
+        let client = Client::in_memory(connection, 1);
+
+        let (worker, worker_mailbox) = tokio::sync::mpsc::unbounded_channel();
+        let (other, _other_mailbox) = tokio::sync::mpsc::unbounded_channel();
+        client.join_group("workers", 1, 10, worker).await.unwrap();
+        client.join_group("workers", 2, 20, other).await.unwrap();
+        assert_eq!(
+            client.group_members("workers").await.unwrap(),
+            vec![(1, 10), (2, 20)]
+        );
+
+        // The worker finishes, which closes its mailbox
+        drop(worker_mailbox);
+        let mut members = client.group_members("workers").await.unwrap();
+        while members.len() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            members = client.group_members("workers").await.unwrap();
+        }
+        assert_eq!(members, vec![(2, 20)]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_name_reservations_have_one_winner() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        serve_in_memory(server, acceptor);
+        let client = Client::in_memory(connection, 1);
+
+        let reservations: Vec<_> = (1..=8)
+            .map(|node_id| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let observed = client
+                        .swap_name("pool.worker.1", None, Some((node_id, 0)))
+                        .await;
+                    (node_id, observed.unwrap())
+                })
+            })
+            .collect();
+        let mut winners = Vec::new();
+        for reservation in reservations {
+            let (node_id, observed) = reservation.await.unwrap();
+            if observed.is_none() {
+                winners.push(node_id);
+            }
+        }
+        assert_eq!(winners.len(), 1);
+
+        // The winner spawns the worker, which is then resolved by its derived name
+        let winner = winners[0];
+        let observed = client
+            .swap_name("pool.worker.1", Some((winner, 0)), Some((winner, 5)))
+            .await;
+        assert_eq!(observed.unwrap(), Some((winner, 0)));
+        let resolved = client.swap_name("pool.worker.1", None, None).await;
+        assert_eq!(resolved.unwrap(), Some((winner, 5)));
+    }
+
+    #[tokio::test]
+    async fn draining_releases_singletons() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        serve_in_memory(server, acceptor);
+        let client = Client::in_memory(connection, 1);
+
+        // The holder keeps running while the node is drained
+        let (holder, _holder_mailbox) = tokio::sync::mpsc::unbounded_channel();
+        let claimed = client.claim_singleton("leader", 1, 10, holder).await;
+        assert_eq!(claimed.unwrap(), (1, 10));
+        client.drain();
+        assert!(client.is_draining());
+
+        let mut singleton = client.get_singleton("leader").await.unwrap();
+        while singleton.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            singleton = client.get_singleton("leader").await.unwrap();
+        }
+        // A process on another node can take over
+        let (other, _other_mailbox) = tokio::sync::mpsc::unbounded_channel();
+        let claimed = client.claim_singleton("leader", 2, 20, other).await;
+        assert_eq!(claimed.unwrap(), (2, 20));
     }
 }
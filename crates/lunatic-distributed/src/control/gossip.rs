@@ -0,0 +1,391 @@
+/*!
+SWIM-style gossip of the cluster membership, as an alternative to taking the nodes listed by the
+control server.
+
+Every protocol period a node probes one other member, in a round robin over the shuffled members.
+If the member doesn't answer in time, the node asks a few other members to probe it. If none of
+them gets an answer either, the member is suspected, and declared dead if it doesn't refute the
+suspicion within the suspicion timeout. Changes of members are piggybacked on the probes and their
+answers, each one a limited number of times.
+
+Members are identified by the node ID the control server assigns on registration, so nodes still
+register with it. The nodes it lists only seed the membership, nodes keep learning about members
+joining and leaving through gossip while the control server is unreachable.
+*/
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::NodeInfo;
+
+/// Maximum number of updates piggybacked on one message.
+const MAX_UPDATES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// State of a member as gossiped between nodes. Only the member itself increases its
+/// incarnation, to refute a suspicion.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    pub node: NodeInfo,
+    pub incarnation: u64,
+    pub state: MemberState,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GossipConfig {
+    // How often a member is probed
+    pub period: Duration,
+    // How long a probe waits for an answer, directly and through other members
+    pub probe_timeout: Duration,
+    // Number of members asked to probe a member that didn't answer
+    pub indirect_probes: usize,
+    // How long a suspected member has to refute the suspicion before it's declared dead
+    pub suspicion_timeout: Duration,
+    // Each update is piggybacked on this many messages times the log2 of the number of members
+    pub retransmit_mult: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(500),
+            indirect_probes: 3,
+            suspicion_timeout: Duration::from_secs(5),
+            retransmit_mult: 3,
+        }
+    }
+}
+
+struct Member {
+    node: NodeInfo,
+    incarnation: u64,
+    state: MemberState,
+    changed_at: Instant,
+}
+
+struct State {
+    this: NodeInfo,
+    incarnation: u64,
+    // Members other than this node. Dead members are kept, so that older updates can't revive
+    // them.
+    members: HashMap<u64, Member>,
+    // Updates that are still piggybacked and how many more messages they are sent with
+    pending: Vec<(MemberUpdate, usize)>,
+    // Members left to probe in this round
+    probe_order: Vec<u64>,
+}
+
+pub struct Membership {
+    config: GossipConfig,
+    state: Mutex<State>,
+}
+
+impl Membership {
+    /// Membership of the node `this`, which announces itself with its first messages.
+    pub fn new(this: NodeInfo, config: GossipConfig) -> Self {
+        let mut state = State {
+            this,
+            incarnation: 0,
+            members: HashMap::new(),
+            pending: Vec::new(),
+            probe_order: Vec::new(),
+        };
+        let update = state.this_update();
+        state.queue(config.retransmit_mult, update);
+        Self {
+            config,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    /// Applies updates received from another node. A newer incarnation of a member always wins,
+    /// within the same incarnation suspected wins over alive and dead over both. Suspicions of
+    /// this node are refuted.
+    ///
+    /// Returns true if members joined or left.
+    pub fn apply(&self, updates: Vec<MemberUpdate>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let mut changed = false;
+        for update in updates {
+            if update.node.id == state.this.id {
+                if update.state != MemberState::Alive && update.incarnation >= state.incarnation {
+                    state.incarnation = update.incarnation + 1;
+                    let refutation = state.this_update();
+                    state.queue(self.config.retransmit_mult, refutation);
+                }
+                continue;
+            }
+            let was_live = state.is_live(update.node.id);
+            let newer = match state.members.get(&update.node.id) {
+                Some(member) => {
+                    (update.incarnation, update.state) > (member.incarnation, member.state)
+                }
+                None => true,
+            };
+            if !newer {
+                continue;
+            }
+            state.members.insert(
+                update.node.id,
+                Member {
+                    node: update.node.clone(),
+                    incarnation: update.incarnation,
+                    state: update.state,
+                    changed_at: Instant::now(),
+                },
+            );
+            changed |= was_live != state.is_live(update.node.id);
+            state.queue(self.config.retransmit_mult, update);
+        }
+        changed
+    }
+
+    /// Returns the updates to piggyback on the next message.
+    pub fn updates(&self) -> Vec<MemberUpdate> {
+        let mut state = self.state.lock().unwrap();
+        let updates: Vec<MemberUpdate> = state
+            .pending
+            .iter_mut()
+            .take(MAX_UPDATES)
+            .map(|(update, remaining)| {
+                *remaining -= 1;
+                update.clone()
+            })
+            .collect();
+        state.pending.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+
+    /// Returns the next member to probe. Each live member is probed once per round, in a new
+    /// random order each round.
+    pub fn next_target(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.probe_order.pop() {
+                Some(node_id) if state.is_live(node_id) => return Some(node_id),
+                Some(_) => continue,
+                None => {
+                    let mut order: Vec<u64> = state.live_ids().collect();
+                    if order.is_empty() {
+                        return None;
+                    }
+                    order.sort_by_cached_key(|_| uuid::Uuid::new_v4().as_u128());
+                    state.probe_order = order;
+                }
+            }
+        }
+    }
+
+    /// Returns the members asked to probe `target` if it didn't answer.
+    pub fn helpers(&self, target: u64) -> Vec<u64> {
+        let state = self.state.lock().unwrap();
+        let mut helpers: Vec<u64> = state
+            .live_ids()
+            .filter(|node_id| *node_id != target)
+            .collect();
+        helpers.sort_by_cached_key(|_| uuid::Uuid::new_v4().as_u128());
+        helpers.truncate(self.config.indirect_probes);
+        helpers
+    }
+
+    /// Suspects the member because it didn't answer a probe.
+    pub fn suspect(&self, node_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(update) = state.change(node_id, MemberState::Alive, MemberState::Suspect) {
+            log::debug!("Suspecting node {node_id}, it didn't answer a probe");
+            state.queue(self.config.retransmit_mult, update);
+        }
+    }
+
+    /// Declares members dead that didn't refute a suspicion within the suspicion timeout.
+    /// Returns true if any member was declared dead.
+    pub fn expire_suspects(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<u64> = state
+            .members
+            .values()
+            .filter(|member| {
+                member.state == MemberState::Suspect
+                    && member.changed_at.elapsed() > self.config.suspicion_timeout
+            })
+            .map(|member| member.node.id)
+            .collect();
+        for node_id in expired.iter() {
+            if let Some(update) = state.change(*node_id, MemberState::Suspect, MemberState::Dead) {
+                log::info!("Node {node_id} didn't refute its suspicion and is declared dead");
+                state.queue(self.config.retransmit_mult, update);
+            }
+        }
+        !expired.is_empty()
+    }
+
+    pub fn this(&self) -> NodeInfo {
+        self.state.lock().unwrap().this.clone()
+    }
+
+    /// Returns the members that are alive or suspected, without this node.
+    pub fn live_members(&self) -> Vec<NodeInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .members
+            .values()
+            .filter(|member| member.state != MemberState::Dead)
+            .map(|member| member.node.clone())
+            .collect()
+    }
+}
+
+impl State {
+    fn this_update(&self) -> MemberUpdate {
+        MemberUpdate {
+            node: self.this.clone(),
+            incarnation: self.incarnation,
+            state: MemberState::Alive,
+        }
+    }
+
+    fn is_live(&self, node_id: u64) -> bool {
+        self.members
+            .get(&node_id)
+            .is_some_and(|member| member.state != MemberState::Dead)
+    }
+
+    fn live_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.members
+            .values()
+            .filter(|member| member.state != MemberState::Dead)
+            .map(|member| member.node.id)
+    }
+
+    // Moves the member from the state `from` to `to` in its current incarnation
+    fn change(&mut self, node_id: u64, from: MemberState, to: MemberState) -> Option<MemberUpdate> {
+        let member = self.members.get_mut(&node_id)?;
+        if member.state != from {
+            return None;
+        }
+        member.state = to;
+        member.changed_at = Instant::now();
+        Some(MemberUpdate {
+            node: member.node.clone(),
+            incarnation: member.incarnation,
+            state: to,
+        })
+    }
+
+    // Piggybacks the update, replacing an older one of the same member
+    fn queue(&mut self, retransmit_mult: usize, update: MemberUpdate) {
+        self.pending
+            .retain(|(pending, _)| pending.node.id != update.node.id);
+        let members = self.members.len() + 2;
+        let retransmits = retransmit_mult * (usize::BITS - members.leading_zeros()) as usize;
+        // Newer updates are sent first
+        self.pending.insert(0, (update, retransmits));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{GossipConfig, MemberState, MemberUpdate, Membership};
+    use crate::NodeInfo;
+
+    fn node(id: u64) -> NodeInfo {
+        NodeInfo {
+            id,
+            address: format!("127.0.0.1:{}", 1000 + id).parse().unwrap(),
+            name: format!("node-{id}"),
+            control_address: None,
+        }
+    }
+
+    fn update(id: u64, incarnation: u64, state: MemberState) -> MemberUpdate {
+        MemberUpdate {
+            node: node(id),
+            incarnation,
+            state,
+        }
+    }
+
+    fn live_ids(membership: &Membership) -> Vec<u64> {
+        let mut ids: Vec<u64> = membership
+            .live_members()
+            .into_iter()
+            .map(|node| node.id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn suspected_members_die_unless_they_refute() {
+        let membership = Membership::new(
+            node(1),
+            GossipConfig {
+                suspicion_timeout: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+        // Announces itself first
+        assert_eq!(membership.updates()[0].node.id, 1);
+        assert!(membership.apply(vec![
+            update(2, 0, MemberState::Alive),
+            update(3, 0, MemberState::Alive),
+        ]));
+        assert_eq!(live_ids(&membership), vec![2, 3]);
+        // Each live member is probed once per round
+        let mut round = vec![
+            membership.next_target().unwrap(),
+            membership.next_target().unwrap(),
+        ];
+        round.sort_unstable();
+        assert_eq!(round, vec![2, 3]);
+        assert_eq!(membership.helpers(2), vec![3]);
+
+        membership.suspect(2);
+        membership.suspect(3);
+        // Node 2 refutes the suspicion with a newer incarnation, older updates are ignored
+        assert!(!membership.apply(vec![update(2, 1, MemberState::Alive)]));
+        assert!(!membership.apply(vec![update(2, 0, MemberState::Suspect)]));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(membership.expire_suspects());
+        assert_eq!(live_ids(&membership), vec![2]);
+        // Dead members are not revived by the updates that seeded them
+        assert!(!membership.apply(vec![update(3, 0, MemberState::Alive)]));
+        assert_eq!(membership.next_target(), Some(2));
+        assert!(membership
+            .updates()
+            .iter()
+            .any(|update| update.node.id == 3 && update.state == MemberState::Dead));
+    }
+
+    #[test]
+    fn suspicions_of_this_node_are_refuted() {
+        let membership = Membership::new(node(1), GossipConfig::default());
+        membership.apply(vec![update(1, 0, MemberState::Suspect)]);
+        let refutation = membership
+            .updates()
+            .into_iter()
+            .find(|update| update.node.id == 1)
+            .unwrap();
+        assert_eq!(refutation.state, MemberState::Alive);
+        assert_eq!(refutation.incarnation, 1);
+        assert!(live_ids(&membership).is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, net::SocketAddr};
 
-use crate::NodeInfo;
+use crate::{join_token::JoinToken, NodeInfo};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
@@ -12,8 +12,82 @@ pub enum Request {
     Deregister(u64),
     ListNodes,
     LookupNodes(String),
-    AddModule(Vec<u8>),
+    // Returns the nodes registered with the attribute `key` set to `value`
+    NodesWithAttribute {
+        key: String,
+        value: String,
+    },
+    AddModule(ModuleBytes),
     GetModule(u64),
+    // Adds the module as the next version of the module `name`
+    AddModuleVersion {
+        name: String,
+        module: ModuleBytes,
+    },
+    // Returns the id of a version of the module `name`, the latest one if `version` is `None`
+    ResolveModule {
+        name: String,
+        version: Option<u32>,
+    },
+    // Atomically replaces the value stored under `key` with `new` if the current value is equal
+    // to `expected`. A missing key has the same value as an empty one.
+    CompareAndSwap {
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    },
+    // Adds the deltas to the contributions of node `node_id` to the named counters
+    AddToCounters {
+        node_id: u64,
+        deltas: Vec<(String, i64)>,
+    },
+    // Returns the sum of all contributions to the counter
+    GetCounter(String),
+    // Claims the singleton `role` for the process, or renews the claim if the process already
+    // holds it. Responds with the holder after the claim.
+    ClaimSingleton {
+        role: String,
+        node_id: u64,
+        process_id: u64,
+    },
+    // Releases the singleton `role` if it's held by the process
+    ReleaseSingleton {
+        role: String,
+        node_id: u64,
+        process_id: u64,
+    },
+    // Returns the current holder of the singleton
+    GetSingleton(String),
+    // Adds the process to the process group `group`
+    JoinGroup {
+        group: String,
+        node_id: u64,
+        process_id: u64,
+    },
+    // Removes the process from the process group `group`
+    LeaveGroup {
+        group: String,
+        node_id: u64,
+        process_id: u64,
+    },
+    // Returns the members of the process group
+    GetGroup(String),
+    // Replaces the `(node_id, process_id)` registered under `name` with `new` if the current one
+    // is `expected`, `None` meaning the name is free. Returns the one registered before
+    SwapName {
+        name: String,
+        expected: Option<(u64, u64)>,
+        new: Option<(u64, u64)>,
+    },
+    // Replaces the nodes that node `node_id` has direct connections to
+    ReportPeers {
+        node_id: u64,
+        peers: Vec<u64>,
+    },
+    // Returns a page of the cluster topology, starting at the node with the given index
+    GetTopology(u64),
+    // Tells the control server that the node is still alive
+    Heartbeat(u64),
 }
 
 impl Request {
@@ -23,8 +97,24 @@ impl Request {
             Request::Deregister(_) => "Deregister",
             Request::ListNodes => "ListNodes",
             Request::LookupNodes(_) => "LookupNodes",
+            Request::NodesWithAttribute { .. } => "NodesWithAttribute",
             Request::AddModule(_) => "AddModule",
             Request::GetModule(_) => "GetModule",
+            Request::AddModuleVersion { .. } => "AddModuleVersion",
+            Request::ResolveModule { .. } => "ResolveModule",
+            Request::CompareAndSwap { .. } => "CompareAndSwap",
+            Request::AddToCounters { .. } => "AddToCounters",
+            Request::GetCounter(_) => "GetCounter",
+            Request::ClaimSingleton { .. } => "ClaimSingleton",
+            Request::ReleaseSingleton { .. } => "ReleaseSingleton",
+            Request::GetSingleton(_) => "GetSingleton",
+            Request::JoinGroup { .. } => "JoinGroup",
+            Request::LeaveGroup { .. } => "LeaveGroup",
+            Request::GetGroup(_) => "GetGroup",
+            Request::SwapName { .. } => "SwapName",
+            Request::ReportPeers { .. } => "ReportPeers",
+            Request::GetTopology(_) => "GetTopology",
+            Request::Heartbeat(_) => "Heartbeat",
         }
     }
 }
@@ -33,8 +123,21 @@ impl Request {
 pub enum Response {
     Register(Registered),
     Nodes(Vec<NodeInfo>),
-    Module(Option<Vec<u8>>),
+    Module(Option<ModuleBytes>),
     ModuleId(u64),
+    ModuleVersion { module_id: u64, version: u32 },
+    // The value observed by a `CompareAndSwap` before it was applied
+    Value(Vec<u8>),
+    Counter(i64),
+    // `(node_id, process_id)` of the process holding a singleton, `None` if it's available
+    Singleton(Option<(u64, u64)>),
+    // `(node_id, process_id)` of the members of a process group, ordered
+    Members(Vec<(u64, u64)>),
+    // `(node_id, process_id)` registered under a name before a `SwapName`, `None` if it was free
+    Name(Option<(u64, u64)>),
+    // Nodes ordered by id with the nodes they have direct connections to, and the total number
+    // of nodes in the topology
+    Topology(Vec<(u64, Vec<u64>)>, u64),
     Error(String),
     None,
 }
@@ -42,9 +145,19 @@ pub enum Response {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Registration {
     pub node_address: SocketAddr,
+    pub control_address: Option<SocketAddr>,
     pub node_name: String,
     pub signing_request: String,
     pub attributes: HashMap<String, String>,
+    // Join token of the cluster, required if the control server is configured with one
+    pub join_token: Option<JoinToken>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleBytes {
+    pub bytes: Vec<u8>,
+    // Signature of the publisher over `bytes`, `None` if the module is unsigned
+    pub signature: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -1,7 +1,11 @@
 pub mod client;
+pub mod gossip;
 pub mod message;
+pub mod node_events;
 mod parser;
+mod pool;
 pub mod server;
+pub mod status;
 
 pub use client::Client;
 pub use parser::{Scanner, TokenType};
@@ -0,0 +1,180 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use lunatic_process::{
+    message::{DataMessage, Message},
+    state::SignalSender,
+    Signal,
+};
+
+/// How long changes of a node are collected before subscribers are notified.
+pub const DEFAULT_NODE_EVENTS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Notifies subscribed processes when nodes join or leave the cluster.
+///
+/// Changes are collected for a short window and only the difference between the state last
+/// reported and the state at the end of the window is sent, so a node that flaps between
+/// connected and disconnected doesn't flood the subscribers with events.
+///
+/// Events are data messages with the tag of the subscription, containing the node ID as a little
+/// endian u64, followed by one byte that is 1 if the node connected and 0 if it disconnected.
+#[derive(Clone)]
+pub struct NodeEvents {
+    inner: Arc<InnerNodeEvents>,
+}
+
+struct InnerNodeEvents {
+    window: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Nodes that subscribers were told are connected
+    reported: HashSet<u64>,
+    // Node ID -> latest state, of nodes that changed since the last notification
+    changed: HashMap<u64, bool>,
+    subscribers: Vec<(i64, SignalSender)>,
+    notify_scheduled: bool,
+}
+
+impl NodeEvents {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            inner: Arc::new(InnerNodeEvents {
+                window,
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// Sends node events tagged with `tag` to the process until its mailbox is closed.
+    pub fn subscribe(&self, tag: i64, process: SignalSender) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.subscribers.push((tag, process));
+    }
+
+    pub fn node_connected(&self, node_id: u64) {
+        self.changed(node_id, true);
+    }
+
+    pub fn node_disconnected(&self, node_id: u64) {
+        self.changed(node_id, false);
+    }
+
+    fn changed(&self, node_id: u64, connected: bool) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.changed.insert(node_id, connected);
+        if !state.notify_scheduled {
+            state.notify_scheduled = true;
+            let events = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(events.inner.window).await;
+                events.notify();
+            });
+        }
+    }
+
+    fn notify(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.notify_scheduled = false;
+        let changed = std::mem::take(&mut state.changed);
+        let mut events = Vec::new();
+        for (node_id, connected) in changed {
+            let reported = if connected {
+                !state.reported.insert(node_id)
+            } else {
+                state.reported.remove(&node_id)
+            };
+            if reported != connected {
+                events.push((node_id, connected));
+            }
+        }
+        state
+            .subscribers
+            .retain(|(_, process)| !process.is_closed());
+        for (tag, process) in state.subscribers.iter() {
+            for &(node_id, connected) in events.iter() {
+                let message = node_event(*tag, node_id, connected);
+                process.send(Signal::Message(Message::Data(message))).ok();
+            }
+        }
+    }
+}
+
+impl Default for NodeEvents {
+    fn default() -> Self {
+        Self::new(DEFAULT_NODE_EVENTS_WINDOW)
+    }
+}
+
+fn node_event(tag: i64, node_id: u64, connected: bool) -> DataMessage {
+    let mut data = node_id.to_le_bytes().to_vec();
+    data.push(connected as u8);
+    DataMessage::new_from_vec(Some(tag), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lunatic_process::{message::Message, Signal};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use super::NodeEvents;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    fn events(receiver: &mut UnboundedReceiver<Signal>) -> Vec<(i64, u64, bool)> {
+        let mut events = Vec::new();
+        while let Ok(signal) = receiver.try_recv() {
+            match signal {
+                Signal::Message(Message::Data(message)) => events.push((
+                    message.tag.unwrap(),
+                    u64::from_le_bytes(message.buffer[..8].try_into().unwrap()),
+                    message.buffer[8] == 1,
+                )),
+                _ => panic!("unexpected signal"),
+            }
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn subscriber_receives_connect_and_disconnect() {
+        let node_events = NodeEvents::new(WINDOW);
+        let (process, mut receiver) = unbounded_channel();
+        node_events.subscribe(7, process);
+
+        node_events.node_connected(2);
+        tokio::time::sleep(WINDOW * 2).await;
+        assert_eq!(events(&mut receiver), vec![(7, 2, true)]);
+
+        node_events.node_disconnected(2);
+        tokio::time::sleep(WINDOW * 2).await;
+        assert_eq!(events(&mut receiver), vec![(7, 2, false)]);
+    }
+
+    #[tokio::test]
+    async fn flapping_node_is_coalesced() {
+        let node_events = NodeEvents::new(WINDOW);
+        let (process, mut receiver) = unbounded_channel();
+        node_events.subscribe(1, process);
+
+        for _ in 0..10 {
+            node_events.node_connected(3);
+            node_events.node_disconnected(3);
+        }
+        tokio::time::sleep(WINDOW * 2).await;
+        assert!(events(&mut receiver).is_empty());
+
+        node_events.node_connected(3);
+        node_events.node_disconnected(3);
+        node_events.node_connected(3);
+        tokio::time::sleep(WINDOW * 2).await;
+        assert_eq!(events(&mut receiver), vec![(1, 3, true)]);
+    }
+}
@@ -218,9 +218,11 @@ mod tests {
             1,
             Registration {
                 node_address: "127.0.0.1:10000".parse().unwrap(),
+                control_address: None,
                 node_name: "test01".to_string(),
                 attributes: metadata.clone(),
                 signing_request: "request01".to_string(),
+                join_token: None,
             },
         );
 
@@ -229,9 +231,11 @@ mod tests {
             2,
             Registration {
                 node_address: "127.0.0.1:10001".parse().unwrap(),
+                control_address: None,
                 node_name: "test02".to_string(),
                 attributes: metadata.clone(),
                 signing_request: "request01".to_string(),
+                join_token: None,
             },
         );
 
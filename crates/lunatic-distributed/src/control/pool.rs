@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::message::Request;
+
+/// Connections to the control server that requests are spread over.
+///
+/// Each connection is driven by its own task that sends the requests queued for it and reads the
+/// responses. Requests are queued on the connections in turn, skipping connections that are
+/// currently down as long as another one is up. Responses are matched to requests only by their
+/// message id, so it doesn't matter which connection a response arrives on.
+pub(crate) struct ConnectionPool {
+    connections: Vec<PooledConnection>,
+    next: AtomicUsize,
+}
+
+struct PooledConnection {
+    sender: UnboundedSender<(u64, Request)>,
+    connected: AtomicBool,
+}
+
+impl ConnectionPool {
+    /// Creates a pool of `size` connections, at least one, and returns the queues of requests
+    /// for each connection, to be handed to the connection tasks.
+    pub fn new(size: usize) -> (Self, Vec<UnboundedReceiver<(u64, Request)>>) {
+        let (connections, receivers) = (0..size.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                let connection = PooledConnection {
+                    sender,
+                    connected: AtomicBool::new(false),
+                };
+                (connection, receiver)
+            })
+            .unzip();
+        let pool = Self {
+            connections,
+            next: AtomicUsize::new(0),
+        };
+        (pool, receivers)
+    }
+
+    pub fn size(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Queues the request on the next connection that is up, or on the next connection in turn
+    /// if all of them are down. Returns the index of the connection.
+    pub fn send(&self, msg_id: u64, request: Request) -> Result<usize> {
+        let size = self.connections.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = (start..start + size)
+            .map(|index| index % size)
+            .find(|&index| self.connections[index].connected.load(Ordering::Relaxed))
+            .unwrap_or(start % size);
+        self.connections[index]
+            .sender
+            .send((msg_id, request))
+            .map_err(|_| anyhow!("Control connection {index} is closed"))?;
+        Ok(index)
+    }
+
+    pub fn set_connected(&self, index: usize, connected: bool) {
+        self.connections[index]
+            .connected
+            .store(connected, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionPool;
+    use crate::control::message::Request;
+
+    #[test]
+    fn requests_avoid_connections_that_are_down() {
+        let (pool, mut receivers) = ConnectionPool::new(3);
+        for index in 0..3 {
+            pool.set_connected(index, true);
+        }
+        let used: Vec<usize> = (0..6)
+            .map(|msg_id| pool.send(msg_id, Request::ListNodes).unwrap())
+            .collect();
+        assert_eq!(used, vec![0, 1, 2, 0, 1, 2]);
+
+        pool.set_connected(1, false);
+        for msg_id in 6..12 {
+            assert_ne!(pool.send(msg_id, Request::ListNodes).unwrap(), 1);
+        }
+        // Without any connection up, requests wait on the connections in turn
+        for index in 0..3 {
+            pool.set_connected(index, false);
+        }
+        pool.send(12, Request::ListNodes).unwrap();
+
+        let queued: usize = receivers
+            .iter_mut()
+            .map(|receiver| std::iter::from_fn(|| receiver.try_recv().ok()).count())
+            .sum();
+        assert_eq!(queued, 13);
+
+        // Closed connections can't take requests
+        drop(receivers);
+        assert!(pool.send(13, Request::ListNodes).is_err());
+    }
+}
@@ -1,21 +1,25 @@
 use std::{
+    collections::{BTreeSet, HashMap},
     net::SocketAddr,
     path::Path,
     sync::{
         atomic::{self, AtomicU64},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use crate::{control::message::Response, NodeInfo};
 use crate::{
-    control::message::{Registered, Registration},
+    control::message::{ModuleBytes, Registered, Registration},
+    join_token::{self, JoinToken},
     quic::SendStream,
 };
 use anyhow::Result;
 use bytes::Bytes;
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 use rcgen::*;
+use sha2::{Digest, Sha256};
 
 use super::parser::Parser;
 
@@ -29,12 +33,138 @@ struct InnerServer {
     nodes: DashMap<u64, Registration>,
     addr_to_node: DashMap<SocketAddr, u64>,
     next_module_id: AtomicU64,
-    modules: DashMap<u64, Vec<u8>>,
+    modules: DashMap<u64, StoredModule>,
+    // Module name -> module ids of its versions, version `n` is at index `n - 1`
+    module_versions: DashMap<String, Vec<u64>>,
+    compress_modules: bool,
+    registers: DashMap<String, Vec<u8>>,
+    // Counter name -> contribution of each node
+    counters: DashMap<String, HashMap<u64, i64>>,
+    counter_retention: CounterRetention,
+    // Role name -> current holder
+    singletons: DashMap<String, SingletonClaim>,
+    singleton_grace: Duration,
+    // Group name -> `(node_id, process_id)` of its members
+    groups: DashMap<String, BTreeSet<(u64, u64)>>,
+    // Cluster-wide name -> `(node_id, process_id)` registered under it
+    names: DashMap<String, (u64, u64)>,
+    // Node ID -> nodes it reported direct connections to
+    peers: DashMap<u64, Vec<u64>>,
+    // Node ID -> time of its last heartbeat or registration
+    last_seen: DashMap<u64, Instant>,
+    // Nodes are removed if they don't send a heartbeat for this long
+    heartbeat_timeout: Option<Duration>,
     ca_cert: Certificate,
+    // Nodes can only register if they present this token
+    join_token: Option<JoinToken>,
 }
 
+// Module bytes as kept in memory, optionally compressed
+#[derive(Clone)]
+struct StoredModule {
+    bytes: Vec<u8>,
+    compressed: bool,
+    // SHA-256 of the uncompressed bytes, checked before the module is handed out
+    hash: [u8; 32],
+    signature: Option<Vec<u8>>,
+}
+
+impl StoredModule {
+    fn new(module: ModuleBytes, compress: bool) -> Self {
+        let hash = Sha256::digest(&module.bytes).into();
+        let bytes = if compress {
+            lz4_flex::compress_prepend_size(&module.bytes)
+        } else {
+            module.bytes
+        };
+        Self {
+            bytes,
+            compressed: compress,
+            hash,
+            signature: module.signature,
+        }
+    }
+
+    fn load(&self) -> Result<ModuleBytes, String> {
+        let bytes = if self.compressed {
+            lz4_flex::decompress_size_prepended(&self.bytes)
+                .map_err(|error| format!("Failed to decompress module: {error}"))?
+        } else {
+            self.bytes.clone()
+        };
+        if Sha256::digest(&bytes)[..] != self.hash {
+            return Err("Stored module bytes don't match their hash".to_string());
+        }
+        Ok(ModuleBytes {
+            bytes,
+            signature: self.signature.clone(),
+        })
+    }
+}
+
+struct SingletonClaim {
+    node_id: u64,
+    process_id: u64,
+    // The claim is released if it's not renewed before this point
+    expires_at: Instant,
+}
+
+/// What happens with the contributions of a node to counters after it's removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CounterRetention {
+    /// The last known contribution stays part of the counter.
+    #[default]
+    Keep,
+    /// The contribution is subtracted from the counter.
+    Drop,
+}
+
+/// Maximum size of a value stored in a register, registers are meant for small values used to
+/// coordinate nodes.
+pub const MAX_REGISTER_VALUE_SIZE: usize = 4 * 1024;
+
+/// Maximum number of nodes in a page of the cluster topology.
+pub const TOPOLOGY_PAGE_SIZE: usize = 256;
+
+/// How long a singleton claim is kept without being renewed by the node of the holder, so that a
+/// holder briefly losing the connection to the control server keeps its role.
+pub const DEFAULT_SINGLETON_GRACE: Duration = Duration::from_secs(5);
+
+/// How long the control server keeps a node that stopped sending heartbeats, if nodes are removed
+/// at all.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl Server {
     pub fn new(ca_cert: Certificate) -> Self {
+        Self::with_counter_retention(ca_cert, CounterRetention::default())
+    }
+
+    pub fn with_counter_retention(
+        ca_cert: Certificate,
+        counter_retention: CounterRetention,
+    ) -> Self {
+        Self::with_options(
+            ca_cert,
+            counter_retention,
+            DEFAULT_SINGLETON_GRACE,
+            false,
+            None,
+            None,
+        )
+    }
+
+    /// Modules are stored LZ4 compressed if `compress_modules` is set, which is transparent to
+    /// nodes fetching them. If `join_token` is set, only nodes presenting it can register. If
+    /// `heartbeat_timeout` is set, nodes that don't send a heartbeat within it are removed by
+    /// [`Server::remove_silent_nodes`].
+    pub fn with_options(
+        ca_cert: Certificate,
+        counter_retention: CounterRetention,
+        singleton_grace: Duration,
+        compress_modules: bool,
+        join_token: Option<JoinToken>,
+        heartbeat_timeout: Option<Duration>,
+    ) -> Self {
         Self {
             inner: Arc::new(InnerServer {
                 next_node_id: AtomicU64::new(1),
@@ -42,7 +172,20 @@ impl Server {
                 nodes: DashMap::new(),
                 addr_to_node: DashMap::new(),
                 modules: DashMap::new(),
+                module_versions: DashMap::new(),
+                compress_modules,
+                registers: DashMap::new(),
+                counters: DashMap::new(),
+                counter_retention,
+                singletons: DashMap::new(),
+                singleton_grace,
+                groups: DashMap::new(),
+                names: DashMap::new(),
+                peers: DashMap::new(),
+                last_seen: DashMap::new(),
+                heartbeat_timeout,
                 ca_cert,
+                join_token,
             }),
         }
     }
@@ -60,6 +203,13 @@ impl Server {
     }
 
     pub fn register(&self, reg: Registration) -> Response {
+        if !join_token::accepts(self.inner.join_token.as_ref(), reg.join_token.as_ref()) {
+            log::warn!(
+                "Rejected registration of node {}, invalid join token",
+                reg.node_address
+            );
+            return Response::Error("Invalid join token.".to_string());
+        }
         let node_id = self.next_node_id();
         let signed_cert = CertificateSigningRequest::from_pem(&reg.signing_request)
             .and_then(|sign_request| sign_request.serialize_pem_with_signer(&self.inner.ca_cert));
@@ -69,10 +219,16 @@ impl Server {
                 // details of connection status & reconnecting/registering.
                 if let Some(proc_id) = self.inner.addr_to_node.get(&reg.node_address) {
                     self.inner.nodes.remove(&proc_id);
+                    self.inner.peers.remove(&proc_id);
+                    self.inner.last_seen.remove(&proc_id);
+                    self.remove_counter_contributions(*proc_id);
+                    self.release_singletons_of(*proc_id);
+                    self.remove_group_members_of(*proc_id);
                 }
 
                 self.inner.addr_to_node.insert(reg.node_address, node_id);
                 self.inner.nodes.insert(node_id, reg);
+                self.inner.last_seen.insert(node_id, Instant::now());
 
                 Response::Register(Registered {
                     node_id,
@@ -85,9 +241,44 @@ impl Server {
 
     pub fn deregister(&self, node_id: u64) -> Response {
         self.inner.nodes.remove(&node_id);
+        self.inner.peers.remove(&node_id);
+        self.inner.last_seen.remove(&node_id);
+        self.remove_counter_contributions(node_id);
+        self.release_singletons_of(node_id);
+        self.remove_group_members_of(node_id);
+        self.release_names_of(node_id);
+        Response::None
+    }
+
+    pub fn heartbeat(&self, node_id: u64) -> Response {
+        if !self.inner.nodes.contains_key(&node_id) {
+            return Response::Error(format!("Node {node_id} is not registered."));
+        }
+        self.inner.last_seen.insert(node_id, Instant::now());
         Response::None
     }
 
+    /// Removes the nodes that didn't send a heartbeat within the heartbeat timeout, like
+    /// [`Server::deregister`] does, and returns their IDs. Without a timeout no node is removed.
+    pub fn remove_silent_nodes(&self) -> Vec<u64> {
+        let timeout = match self.inner.heartbeat_timeout {
+            Some(timeout) => timeout,
+            None => return Vec::new(),
+        };
+        let silent: Vec<u64> = self
+            .inner
+            .last_seen
+            .iter()
+            .filter(|last_seen| last_seen.elapsed() > timeout)
+            .map(|last_seen| *last_seen.key())
+            .collect();
+        for node_id in silent.iter() {
+            log::warn!("Removing node {node_id}, it didn't send a heartbeat for {timeout:?}");
+            self.deregister(*node_id);
+        }
+        silent
+    }
+
     pub fn list_nodes(&self) -> Response {
         Response::Nodes(
             self.inner
@@ -97,6 +288,7 @@ impl Server {
                     id: *e.key(),
                     address: e.value().node_address,
                     name: e.value().node_name.clone(),
+                    control_address: e.value().control_address,
                 })
                 .collect(),
         )
@@ -114,6 +306,7 @@ impl Server {
                         id: *e.key(),
                         address: e.node_address,
                         name: e.node_name.clone(),
+                        control_address: e.control_address,
                     })
                     .collect(),
             ),
@@ -121,14 +314,310 @@ impl Server {
         }
     }
 
-    pub fn add_module(&self, bytes: Vec<u8>) -> Response {
+    pub fn nodes_with_attribute(&self, key: &str, value: &str) -> Response {
+        Response::Nodes(
+            self.inner
+                .nodes
+                .iter()
+                .filter(|e| e.attributes.get(key).map(String::as_str) == Some(value))
+                .map(|e| NodeInfo {
+                    id: *e.key(),
+                    address: e.node_address,
+                    name: e.node_name.clone(),
+                    control_address: e.control_address,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn add_module(&self, module: ModuleBytes) -> Response {
         let module_id = self.next_module_id();
-        self.inner.modules.insert(module_id, bytes);
+        let module = StoredModule::new(module, self.inner.compress_modules);
+        self.inner.modules.insert(module_id, module);
         Response::ModuleId(module_id)
     }
 
+    /// Adds the module as a new version of the module `name`. Earlier versions are kept, so that
+    /// nodes can still fetch them for processes that are spawned from them.
+    pub fn add_module_version(&self, name: String, module: ModuleBytes) -> Response {
+        let module_id = self.next_module_id();
+        let module = StoredModule::new(module, self.inner.compress_modules);
+        self.inner.modules.insert(module_id, module);
+        let mut versions = self.inner.module_versions.entry(name).or_default();
+        versions.push(module_id);
+        Response::ModuleVersion {
+            module_id,
+            version: versions.len() as u32,
+        }
+    }
+
+    pub fn resolve_module(&self, name: &str, version: Option<u32>) -> Response {
+        let versions = match self.inner.module_versions.get(name) {
+            Some(versions) => versions,
+            None => return Response::Error(format!("Module {name} doesn't exist")),
+        };
+        let module_id = match version {
+            None => versions.last(),
+            Some(version) => version
+                .checked_sub(1)
+                .and_then(|index| versions.get(index as usize)),
+        };
+        match module_id {
+            Some(module_id) => Response::ModuleId(*module_id),
+            None => Response::Error(format!(
+                "Version {version:?} of module {name} doesn't exist"
+            )),
+        }
+    }
+
     pub fn get_module(&self, id: u64) -> Response {
-        Response::Module(self.inner.modules.get(&id).map(|e| e.clone()))
+        // Clone the stored module, so the shard isn't locked while it's decompressed.
+        let module = match self.inner.modules.get(&id) {
+            Some(module) => module.clone(),
+            None => return Response::Module(None),
+        };
+        match module.load() {
+            Ok(module) => Response::Module(Some(module)),
+            Err(error) => {
+                log::error!("Module {id} is corrupted: {error}");
+                Response::Error(format!("Module {id} is corrupted: {error}"))
+            }
+        }
+    }
+
+    /// Replaces the value of register `key` with `new` if it's currently equal to `expected` and
+    /// returns the value observed before the swap. The swap succeeded if the observed value is
+    /// equal to `expected`.
+    ///
+    /// Missing registers hold an empty value, swapping in an empty value removes the register.
+    pub fn compare_and_swap(&self, key: String, expected: Vec<u8>, new: Vec<u8>) -> Response {
+        if new.len() > MAX_REGISTER_VALUE_SIZE {
+            return Response::Error(format!(
+                "Register value of {} bytes exceeds the limit of {MAX_REGISTER_VALUE_SIZE} bytes",
+                new.len()
+            ));
+        }
+        // Holding the entry locks the shard, so no other swap can interleave with this one.
+        let observed = match self.inner.registers.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let observed = entry.get().clone();
+                if observed == expected {
+                    if new.is_empty() {
+                        entry.remove();
+                    } else {
+                        entry.insert(new);
+                    }
+                }
+                observed
+            }
+            Entry::Vacant(entry) => {
+                if expected.is_empty() && !new.is_empty() {
+                    entry.insert(new);
+                }
+                Vec::new()
+            }
+        };
+        Response::Value(observed)
+    }
+
+    pub fn add_to_counters(&self, node_id: u64, deltas: Vec<(String, i64)>) -> Response {
+        for (name, delta) in deltas {
+            let mut contributions = self.inner.counters.entry(name).or_default();
+            let contribution = contributions.entry(node_id).or_default();
+            *contribution = contribution.wrapping_add(delta);
+        }
+        Response::None
+    }
+
+    pub fn get_counter(&self, name: &str) -> Response {
+        let value = self
+            .inner
+            .counters
+            .get(name)
+            .map(|contributions| {
+                contributions
+                    .values()
+                    .fold(0i64, |sum, contribution| sum.wrapping_add(*contribution))
+            })
+            .unwrap_or_default();
+        Response::Counter(value)
+    }
+
+    /// Claims the singleton `role` for the process if it's available, or renews the claim if the
+    /// process already holds it. Returns the holder after the claim, the claim succeeded if it's
+    /// the process itself.
+    ///
+    /// Claims that were not renewed within the grace period are released, so the holder's node
+    /// needs to claim the role again periodically.
+    pub fn claim_singleton(&self, role: String, node_id: u64, process_id: u64) -> Response {
+        let expires_at = Instant::now() + self.inner.singleton_grace;
+        let mut claim = self
+            .inner
+            .singletons
+            .entry(role)
+            .or_insert_with(|| SingletonClaim {
+                node_id,
+                process_id,
+                expires_at,
+            });
+        let is_holder = claim.node_id == node_id && claim.process_id == process_id;
+        if is_holder || claim.expires_at <= Instant::now() {
+            *claim = SingletonClaim {
+                node_id,
+                process_id,
+                expires_at,
+            };
+        }
+        Response::Singleton(Some((claim.node_id, claim.process_id)))
+    }
+
+    pub fn release_singleton(&self, role: &str, node_id: u64, process_id: u64) -> Response {
+        self.inner.singletons.remove_if(role, |_, claim| {
+            claim.node_id == node_id && claim.process_id == process_id
+        });
+        Response::None
+    }
+
+    pub fn get_singleton(&self, role: &str) -> Response {
+        let holder = self
+            .inner
+            .singletons
+            .get(role)
+            .filter(|claim| claim.expires_at > Instant::now())
+            .map(|claim| (claim.node_id, claim.process_id));
+        Response::Singleton(holder)
+    }
+
+    /// Adds the process to the process group `group`, creating the group if it doesn't exist.
+    /// Joining a group the process is already a member of does nothing.
+    ///
+    /// Members are removed when they leave the group, which their node does once they finish,
+    /// and when their node leaves the cluster.
+    pub fn join_group(&self, group: String, node_id: u64, process_id: u64) -> Response {
+        self.inner
+            .groups
+            .entry(group)
+            .or_default()
+            .insert((node_id, process_id));
+        Response::None
+    }
+
+    pub fn leave_group(&self, group: &str, node_id: u64, process_id: u64) -> Response {
+        if let Entry::Occupied(mut members) = self.inner.groups.entry(group.to_string()) {
+            members.get_mut().remove(&(node_id, process_id));
+            if members.get().is_empty() {
+                members.remove();
+            }
+        }
+        Response::None
+    }
+
+    pub fn swap_name(
+        &self,
+        name: String,
+        expected: Option<(u64, u64)>,
+        new: Option<(u64, u64)>,
+    ) -> Response {
+        // Holding the entry locks the shard, so two nodes can't reserve the same name.
+        let observed = match self.inner.names.entry(name) {
+            Entry::Occupied(mut entry) => {
+                let observed = *entry.get();
+                if Some(observed) == expected {
+                    match new {
+                        Some(new) => {
+                            entry.insert(new);
+                        }
+                        None => {
+                            entry.remove();
+                        }
+                    }
+                }
+                Some(observed)
+            }
+            Entry::Vacant(entry) => {
+                if let (None, Some(new)) = (expected, new) {
+                    entry.insert(new);
+                }
+                None
+            }
+        };
+        Response::Name(observed)
+    }
+
+    pub fn group_members(&self, group: &str) -> Response {
+        let members = self
+            .inner
+            .groups
+            .get(group)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default();
+        Response::Members(members)
+    }
+
+    pub fn report_peers(&self, node_id: u64, peers: Vec<u64>) -> Response {
+        if self.inner.nodes.contains_key(&node_id) {
+            self.inner.peers.insert(node_id, peers);
+        }
+        Response::None
+    }
+
+    /// Returns the registered nodes ordered by id, starting at the node with index `offset`,
+    /// together with the nodes each of them reported direct connections to.
+    ///
+    /// Nodes that didn't report their connections yet have no peers. At most
+    /// [`TOPOLOGY_PAGE_SIZE`] nodes are returned, the rest can be fetched with a larger offset.
+    pub fn topology(&self, offset: u64) -> Response {
+        let mut node_ids: Vec<u64> = self.inner.nodes.iter().map(|node| *node.key()).collect();
+        node_ids.sort_unstable();
+        let nodes = node_ids
+            .iter()
+            .skip(offset as usize)
+            .take(TOPOLOGY_PAGE_SIZE)
+            .map(|node_id| {
+                let mut peers: Vec<u64> = self
+                    .inner
+                    .peers
+                    .get(node_id)
+                    .map(|peers| peers.clone())
+                    .unwrap_or_default();
+                // Peers could have left the cluster since the report
+                peers.retain(|peer| peer != node_id && self.inner.nodes.contains_key(peer));
+                peers.sort_unstable();
+                peers.dedup();
+                (*node_id, peers)
+            })
+            .collect();
+        Response::Topology(nodes, node_ids.len() as u64)
+    }
+
+    fn release_singletons_of(&self, node_id: u64) {
+        self.inner
+            .singletons
+            .retain(|_, claim| claim.node_id != node_id);
+    }
+
+    fn remove_group_members_of(&self, node_id: u64) {
+        for mut members in self.inner.groups.iter_mut() {
+            members.retain(|(member_node_id, _)| *member_node_id != node_id);
+        }
+        self.inner.groups.retain(|_, members| !members.is_empty());
+    }
+
+    fn release_names_of(&self, node_id: u64) {
+        self.inner
+            .names
+            .retain(|_, (name_node_id, _)| *name_node_id != node_id);
+    }
+
+    fn remove_counter_contributions(&self, node_id: u64) {
+        if self.inner.counter_retention == CounterRetention::Drop {
+            for mut contributions in self.inner.counters.iter_mut() {
+                contributions.remove(&node_id);
+            }
+            self.inner
+                .counters
+                .retain(|_, contributions| !contributions.is_empty());
+        }
     }
 }
 
@@ -196,14 +685,50 @@ fn default_server_certificates(root_cert: &Certificate) -> Result<(String, Strin
     Ok((cert_pem, key_pem))
 }
 
-pub async fn control_server(socket: SocketAddr, ca_cert: Certificate) -> Result<()> {
+/// Runs the control server on `socket`. If `client_ca` is set, only nodes that present a client
+/// certificate signed by it can connect. If `join_token` is set, only nodes that present it can
+/// register. If `heartbeat_timeout` is set, nodes that stop sending heartbeats are removed.
+#[allow(clippy::too_many_arguments)]
+pub async fn control_server(
+    socket: SocketAddr,
+    ca_cert: Certificate,
+    counter_retention: CounterRetention,
+    singleton_grace: Duration,
+    compress_modules: bool,
+    client_ca: Option<String>,
+    join_token: Option<JoinToken>,
+    heartbeat_timeout: Option<Duration>,
+) -> Result<()> {
     let (cert_pem, key_pem) = default_server_certificates(&ca_cert)?;
-    let mut quic_server = crate::quic::new_quic_server(socket, &cert_pem, &key_pem)?;
-    let server = Server::new(ca_cert);
+    let mut quic_server = match client_ca {
+        Some(client_ca) => {
+            crate::quic::new_mtls_quic_server(socket, &cert_pem, &key_pem, &client_ca)?
+        }
+        None => crate::quic::new_quic_server(socket, &cert_pem, &key_pem)?,
+    };
+    let server = Server::with_options(
+        ca_cert,
+        counter_retention,
+        singleton_grace,
+        compress_modules,
+        join_token,
+        heartbeat_timeout,
+    );
+    if let Some(timeout) = heartbeat_timeout {
+        tokio::spawn(remove_silent_nodes_task(server.clone(), timeout));
+    }
     crate::quic::handle_accept_control(&mut quic_server, server.clone()).await?;
     Ok(())
 }
 
+async fn remove_silent_nodes_task(server: Server, timeout: Duration) {
+    loop {
+        // Nodes are removed at most half a timeout late
+        tokio::time::sleep(timeout / 2).await;
+        server.remove_silent_nodes();
+    }
+}
+
 pub async fn handle_request(
     server: Server,
     send: &mut SendStream,
@@ -215,9 +740,45 @@ pub async fn handle_request(
         Register(reg) => server.register(reg),
         Deregister(node_id) => server.deregister(node_id),
         ListNodes => server.list_nodes(),
-        AddModule(bytes) => server.add_module(bytes),
+        AddModule(module) => server.add_module(module),
         GetModule(id) => server.get_module(id),
+        AddModuleVersion { name, module } => server.add_module_version(name, module),
+        ResolveModule { name, version } => server.resolve_module(&name, version),
         LookupNodes(query) => server.lookup_nodes(query),
+        NodesWithAttribute { key, value } => server.nodes_with_attribute(&key, &value),
+        CompareAndSwap { key, expected, new } => server.compare_and_swap(key, expected, new),
+        AddToCounters { node_id, deltas } => server.add_to_counters(node_id, deltas),
+        GetCounter(name) => server.get_counter(&name),
+        ClaimSingleton {
+            role,
+            node_id,
+            process_id,
+        } => server.claim_singleton(role, node_id, process_id),
+        ReleaseSingleton {
+            role,
+            node_id,
+            process_id,
+        } => server.release_singleton(&role, node_id, process_id),
+        GetSingleton(role) => server.get_singleton(&role),
+        JoinGroup {
+            group,
+            node_id,
+            process_id,
+        } => server.join_group(group, node_id, process_id),
+        LeaveGroup {
+            group,
+            node_id,
+            process_id,
+        } => server.leave_group(&group, node_id, process_id),
+        GetGroup(group) => server.group_members(&group),
+        SwapName {
+            name,
+            expected,
+            new,
+        } => server.swap_name(name, expected, new),
+        ReportPeers { node_id, peers } => server.report_peers(node_id, peers),
+        GetTopology(offset) => server.topology(offset),
+        Heartbeat(node_id) => server.heartbeat(node_id),
     };
     let data = bincode::serialize(&(msg_id, response))?;
     let size = (data.len() as u32).to_le_bytes();
@@ -226,3 +787,585 @@ pub async fn handle_request(
     send.send(&mut [size, bytes]).await?;
     Ok(msg_id)
 }
+
+// Handles the requests on all streams opened on an in-memory connection to the server
+#[cfg(test)]
+pub(crate) fn serve_in_memory(server: Server, mut acceptor: crate::quic::InMemoryAcceptor) {
+    tokio::spawn(async move {
+        while let Some((mut send, mut recv)) = acceptor.accept().await {
+            let server = server.clone();
+            tokio::spawn(async move {
+                while let Ok(bytes) = recv.receive().await {
+                    let (msg_id, request) = bincode::deserialize(&bytes).unwrap();
+                    handle_request(server.clone(), &mut send, msg_id, request)
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sha2::{Digest, Sha256};
+
+    use super::{root_cert, CounterRetention, Server, DEFAULT_SINGLETON_GRACE, TOPOLOGY_PAGE_SIZE};
+    use crate::{
+        control::message::{ModuleBytes, Registration, Response},
+        join_token::JoinToken,
+    };
+
+    fn server() -> Server {
+        Server::new(root_cert(true, None, None).unwrap())
+    }
+
+    fn counter(server: &Server, name: &str) -> i64 {
+        match server.get_counter(name) {
+            Response::Counter(value) => value,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn claim(server: &Server, role: &str, node_id: u64, process_id: u64) -> (u64, u64) {
+        match server.claim_singleton(role.to_string(), node_id, process_id) {
+            Response::Singleton(Some(holder)) => holder,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn holder(server: &Server, role: &str) -> Option<(u64, u64)> {
+        match server.get_singleton(role) {
+            Response::Singleton(holder) => holder,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn members(server: &Server, group: &str) -> Vec<(u64, u64)> {
+        match server.group_members(group) {
+            Response::Members(members) => members,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn swap_name(
+        server: &Server,
+        name: &str,
+        expected: Option<(u64, u64)>,
+        new: Option<(u64, u64)>,
+    ) -> Option<(u64, u64)> {
+        match server.swap_name(name.to_string(), expected, new) {
+            Response::Name(observed) => observed,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn cas(server: &Server, key: &str, expected: &[u8], new: &[u8]) -> Vec<u8> {
+        match server.compare_and_swap(key.to_string(), expected.to_vec(), new.to_vec()) {
+            Response::Value(observed) => observed,
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[test]
+    fn compare_and_swap_succeeds() {
+        let server = server();
+        // Missing keys are empty
+        assert_eq!(cas(&server, "leader", b"", b"node-1"), b"");
+        assert_eq!(cas(&server, "leader", b"node-1", b"node-2"), b"node-1");
+        assert_eq!(cas(&server, "leader", b"node-2", b"node-2"), b"node-2");
+    }
+
+    #[test]
+    fn compare_and_swap_fails_on_mismatch() {
+        let server = server();
+        cas(&server, "version", b"", b"1");
+        assert_eq!(cas(&server, "version", b"0", b"2"), b"1");
+        assert_eq!(cas(&server, "version", b"", b"2"), b"1");
+        // The value was left unchanged
+        assert_eq!(cas(&server, "version", b"1", b"1"), b"1");
+    }
+
+    #[test]
+    fn compare_and_swap_with_contention() {
+        let server = server();
+        let threads = 8;
+        let increments = 100;
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let server = server.clone();
+                std::thread::spawn(move || {
+                    let mut done = 0;
+                    let mut current = Vec::new();
+                    while done < increments {
+                        let value: u64 = if current.is_empty() {
+                            0
+                        } else {
+                            u64::from_le_bytes(current.clone().try_into().unwrap())
+                        };
+                        let new = (value + 1).to_le_bytes();
+                        let observed = cas(&server, "counter", &current, &new);
+                        if observed == current {
+                            done += 1;
+                            current = new.to_vec();
+                        } else {
+                            current = observed;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let total = (threads * increments) as u64;
+        let expected = total.to_le_bytes();
+        assert_eq!(cas(&server, "counter", &expected, &expected), expected);
+    }
+
+    #[test]
+    fn counters_aggregate_all_nodes() {
+        let server = server();
+        server.add_to_counters(1, vec![("requests".to_string(), 3)]);
+        server.add_to_counters(
+            2,
+            vec![("requests".to_string(), 4), ("errors".to_string(), 1)],
+        );
+        server.add_to_counters(1, vec![("requests".to_string(), -1)]);
+
+        assert_eq!(counter(&server, "requests"), 6);
+        assert_eq!(counter(&server, "errors"), 1);
+        assert_eq!(counter(&server, "missing"), 0);
+
+        // Contributions are kept by default
+        server.deregister(2);
+        assert_eq!(counter(&server, "requests"), 6);
+    }
+
+    #[test]
+    fn counters_drop_contributions_of_removed_nodes() {
+        let server = Server::with_counter_retention(
+            root_cert(true, None, None).unwrap(),
+            CounterRetention::Drop,
+        );
+        server.add_to_counters(1, vec![("requests".to_string(), 3)]);
+        server.add_to_counters(
+            2,
+            vec![("requests".to_string(), 4), ("errors".to_string(), 1)],
+        );
+
+        server.deregister(2);
+        assert_eq!(counter(&server, "requests"), 3);
+        assert_eq!(counter(&server, "errors"), 0);
+    }
+
+    #[test]
+    fn singleton_is_claimed_once() {
+        let server = server();
+        assert_eq!(holder(&server, "leader"), None);
+        assert_eq!(claim(&server, "leader", 1, 10), (1, 10));
+        // Claiming again renews the claim
+        assert_eq!(claim(&server, "leader", 1, 10), (1, 10));
+        assert_eq!(holder(&server, "leader"), Some((1, 10)));
+        // Roles are independent
+        assert_eq!(claim(&server, "scheduler", 2, 20), (2, 20));
+    }
+
+    #[test]
+    fn singleton_claim_with_contention() {
+        let server = server();
+        let handles: Vec<_> = (1..=16)
+            .map(|node_id| {
+                let server = server.clone();
+                std::thread::spawn(move || claim(&server, "leader", node_id, 1))
+            })
+            .collect();
+        let holders: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        let winner = holder(&server, "leader").unwrap();
+        // Every claimer observed the same single winner
+        assert!(holders.iter().all(|holder| *holder == winner));
+    }
+
+    #[test]
+    fn singleton_is_reelected_after_holder_exits() {
+        let server = server();
+        assert_eq!(claim(&server, "leader", 1, 10), (1, 10));
+        assert_eq!(claim(&server, "leader", 2, 20), (1, 10));
+
+        // Only the holder can release the role
+        server.release_singleton("leader", 2, 20);
+        assert_eq!(holder(&server, "leader"), Some((1, 10)));
+        server.release_singleton("leader", 1, 10);
+        assert_eq!(holder(&server, "leader"), None);
+        assert_eq!(claim(&server, "leader", 2, 20), (2, 20));
+
+        // Removing the node of the holder releases the role
+        server.deregister(2);
+        assert_eq!(claim(&server, "leader", 3, 30), (3, 30));
+    }
+
+    #[test]
+    fn group_members_are_tracked_across_nodes() {
+        let server = server();
+        server.join_group("workers".to_string(), 2, 20);
+        server.join_group("workers".to_string(), 1, 10);
+        server.join_group("workers".to_string(), 1, 11);
+        // Joining twice keeps a single membership
+        server.join_group("workers".to_string(), 1, 10);
+        server.join_group("other".to_string(), 1, 10);
+        assert_eq!(members(&server, "workers"), vec![(1, 10), (1, 11), (2, 20)]);
+
+        server.leave_group("workers", 1, 11);
+        assert_eq!(members(&server, "workers"), vec![(1, 10), (2, 20)]);
+
+        // Removing a node removes its processes from all groups
+        server.deregister(1);
+        assert_eq!(members(&server, "workers"), vec![(2, 20)]);
+        assert_eq!(members(&server, "other"), vec![]);
+        server.leave_group("workers", 2, 20);
+        assert!(server.inner.groups.is_empty());
+    }
+
+    #[test]
+    fn singleton_survives_disconnect_within_grace_period() {
+        let server = Server::with_options(
+            root_cert(true, None, None).unwrap(),
+            CounterRetention::default(),
+            Duration::from_millis(200),
+            false,
+            None,
+            None,
+        );
+        assert_eq!(claim(&server, "leader", 1, 10), (1, 10));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(claim(&server, "leader", 2, 20), (1, 10));
+        // The holder reconnects and renews within the grace period
+        assert_eq!(claim(&server, "leader", 1, 10), (1, 10));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(claim(&server, "leader", 2, 20), (1, 10));
+
+        // Not renewed within the grace period
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(holder(&server, "leader"), None);
+        assert_eq!(claim(&server, "leader", 2, 20), (2, 20));
+    }
+
+    #[test]
+    fn compressed_modules_are_fetched_intact() {
+        let server = Server::with_options(
+            root_cert(true, None, None).unwrap(),
+            CounterRetention::Keep,
+            DEFAULT_SINGLETON_GRACE,
+            true,
+            None,
+            None,
+        );
+        // Repetitive like real wasm sections, so that compression has an effect
+        let bytes: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let module_id = match server.add_module(ModuleBytes {
+            bytes: bytes.clone(),
+            signature: Some(vec![1, 2, 3]),
+        }) {
+            Response::ModuleId(module_id) => module_id,
+            _ => panic!("unexpected response"),
+        };
+
+        let stored = server.inner.modules.get(&module_id).unwrap().clone();
+        assert!(stored.bytes.len() < bytes.len() / 4);
+        assert_eq!(stored.hash[..], Sha256::digest(&bytes)[..]);
+
+        match server.get_module(module_id) {
+            Response::Module(Some(module)) => {
+                assert_eq!(module.bytes, bytes);
+                assert_eq!(module.signature, Some(vec![1, 2, 3]));
+            }
+            _ => panic!("unexpected response"),
+        }
+        assert!(matches!(
+            server.get_module(module_id + 1),
+            Response::Module(None)
+        ));
+
+        // Corrupted storage is detected instead of handing out different bytes
+        server.inner.modules.get_mut(&module_id).unwrap().hash[0] ^= 1;
+        assert!(matches!(server.get_module(module_id), Response::Error(_)));
+    }
+
+    #[test]
+    fn names_are_reserved_once() {
+        let server = server();
+        add_node(&server, 1);
+        add_node(&server, 2);
+
+        // Both nodes race for the same derived name, only the first reservation wins
+        assert_eq!(
+            swap_name(&server, "pool.worker.1", None, Some((1, 0))),
+            None
+        );
+        assert_eq!(
+            swap_name(&server, "pool.worker.1", None, Some((2, 0))),
+            Some((1, 0))
+        );
+        // Once spawned, the reservation is replaced by the process
+        assert_eq!(
+            swap_name(&server, "pool.worker.1", Some((1, 0)), Some((1, 7))),
+            Some((1, 0))
+        );
+        assert_eq!(
+            swap_name(&server, "pool.worker.2", None, Some((2, 0))),
+            None
+        );
+        assert_eq!(
+            swap_name(&server, "pool.worker.2", Some((2, 0)), Some((2, 3))),
+            Some((2, 0))
+        );
+
+        // Names resolve to the spawned workers
+        assert_eq!(
+            swap_name(&server, "pool.worker.1", None, None),
+            Some((1, 7))
+        );
+        assert_eq!(
+            swap_name(&server, "pool.worker.2", None, None),
+            Some((2, 3))
+        );
+
+        // A failed spawn releases its reservation
+        assert_eq!(
+            swap_name(&server, "pool.worker.3", None, Some((2, 0))),
+            None
+        );
+        assert_eq!(
+            swap_name(&server, "pool.worker.3", Some((2, 0)), None),
+            Some((2, 0))
+        );
+        assert_eq!(swap_name(&server, "pool.worker.3", None, None), None);
+
+        // Names of removed nodes become available again
+        server.deregister(1);
+        assert_eq!(swap_name(&server, "pool.worker.1", None, None), None);
+        assert_eq!(
+            swap_name(&server, "pool.worker.2", None, None),
+            Some((2, 3))
+        );
+    }
+
+    #[test]
+    fn new_spawns_use_latest_module_version() {
+        let server = server();
+        let add = |bytes: Vec<u8>| {
+            let module = ModuleBytes {
+                bytes,
+                signature: None,
+            };
+            match server.add_module_version("worker".to_string(), module) {
+                Response::ModuleVersion { module_id, version } => (module_id, version),
+                _ => panic!("unexpected response"),
+            }
+        };
+        let resolve = |version| match server.resolve_module("worker", version) {
+            Response::ModuleId(module_id) => Some(module_id),
+            _ => None,
+        };
+
+        let (v1, version) = add(vec![1]);
+        assert_eq!(version, 1);
+        assert_eq!(resolve(None), Some(v1));
+
+        let (v2, version) = add(vec![2]);
+        assert_eq!(version, 2);
+        assert_eq!(resolve(None), Some(v2));
+        // Pinned spawns keep using the old version
+        assert_eq!(resolve(Some(1)), Some(v1));
+        assert_eq!(resolve(Some(0)), None);
+        assert_eq!(resolve(Some(3)), None);
+        assert!(matches!(
+            server.resolve_module("other", None),
+            Response::Error(_)
+        ));
+
+        // Nodes running processes of v1 can still fetch it
+        match server.get_module(v1) {
+            Response::Module(Some(module)) => assert_eq!(module.bytes, vec![1]),
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn add_node(server: &Server, node_id: u64) {
+        let registration = Registration {
+            node_address: format!("127.0.0.1:{}", 1000 + node_id).parse().unwrap(),
+            control_address: None,
+            node_name: format!("node-{node_id}"),
+            signing_request: String::new(),
+            attributes: Default::default(),
+            join_token: None,
+        };
+        server.inner.nodes.insert(node_id, registration);
+    }
+
+    fn node_ids(response: Response) -> Vec<u64> {
+        match response {
+            Response::Nodes(nodes) => {
+                let mut ids: Vec<u64> = nodes.into_iter().map(|node| node.id).collect();
+                ids.sort_unstable();
+                ids
+            }
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[test]
+    fn nodes_are_filtered_by_attribute() {
+        let server = server();
+        for node_id in 1..=3 {
+            add_node(&server, node_id);
+        }
+        let attributes = [
+            (1, "region", "eu"),
+            (2, "region", "us"),
+            (3, "region", "eu"),
+            (3, "gpu", "true"),
+        ];
+        for (node_id, key, value) in attributes {
+            let mut node = server.inner.nodes.get_mut(&node_id).unwrap();
+            node.attributes.insert(key.to_string(), value.to_string());
+        }
+
+        assert_eq!(
+            node_ids(server.nodes_with_attribute("region", "eu")),
+            vec![1, 3]
+        );
+        assert_eq!(
+            node_ids(server.nodes_with_attribute("gpu", "true")),
+            vec![3]
+        );
+        assert!(node_ids(server.nodes_with_attribute("gpu", "false")).is_empty());
+        assert!(node_ids(server.nodes_with_attribute("eu", "region")).is_empty());
+    }
+
+    fn topology(server: &Server, offset: u64) -> (Vec<(u64, Vec<u64>)>, u64) {
+        match server.topology(offset) {
+            Response::Topology(nodes, total) => (nodes, total),
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[test]
+    fn topology_reflects_reported_connections() {
+        let server = server();
+        for node_id in 1..=4 {
+            add_node(&server, node_id);
+        }
+        // 1 and 2 are connected to everyone, 3 and 4 can only reach each other through them
+        server.report_peers(1, vec![2, 3, 4]);
+        server.report_peers(2, vec![1, 3, 4]);
+        server.report_peers(3, vec![2, 1]);
+        assert_eq!(
+            topology(&server, 0),
+            (
+                vec![
+                    (1, vec![2, 3, 4]),
+                    (2, vec![1, 3, 4]),
+                    (3, vec![1, 2]),
+                    // Didn't report yet
+                    (4, vec![]),
+                ],
+                4
+            )
+        );
+
+        // Reports replace the previous ones and removed nodes disappear from the graph
+        server.report_peers(4, vec![1, 2]);
+        server.deregister(2);
+        // Unknown nodes can't report
+        server.report_peers(5, vec![1]);
+        assert_eq!(topology(&server, 1), (vec![(3, vec![1]), (4, vec![1])], 3));
+    }
+
+    #[test]
+    fn topology_is_paginated() {
+        let server = server();
+        let count = TOPOLOGY_PAGE_SIZE as u64 + 10;
+        for node_id in 1..=count {
+            add_node(&server, node_id);
+        }
+        let (first, total) = topology(&server, 0);
+        assert_eq!((first.len(), total), (TOPOLOGY_PAGE_SIZE, count));
+        let (second, _) = topology(&server, first.len() as u64);
+        assert_eq!(second.len(), 10);
+        assert_eq!(second[0].0, TOPOLOGY_PAGE_SIZE as u64 + 1);
+        assert!(topology(&server, count).0.is_empty());
+    }
+
+    #[test]
+    fn registration_requires_join_token() {
+        let server = Server::with_options(
+            root_cert(true, None, None).unwrap(),
+            CounterRetention::default(),
+            DEFAULT_SINGLETON_GRACE,
+            false,
+            Some(JoinToken::new("secret".to_string())),
+            None,
+        );
+        let registration = |join_token: Option<&str>| {
+            let cert = rcgen::generate_simple_self_signed(vec!["node".to_string()]).unwrap();
+            Registration {
+                node_address: "127.0.0.1:10000".parse().unwrap(),
+                control_address: None,
+                node_name: "node".to_string(),
+                signing_request: cert.serialize_request_pem().unwrap(),
+                attributes: Default::default(),
+                join_token: join_token.map(|token| JoinToken::new(token.to_string())),
+            }
+        };
+        for join_token in [None, Some("wrong")] {
+            match server.register(registration(join_token)) {
+                Response::Error(error) => assert_eq!(error, "Invalid join token."),
+                _ => panic!("unexpected response"),
+            }
+        }
+        assert!(matches!(
+            server.list_nodes(),
+            Response::Nodes(nodes) if nodes.is_empty()
+        ));
+        assert!(matches!(
+            server.register(registration(Some("secret"))),
+            Response::Register(_)
+        ));
+    }
+
+    #[test]
+    fn nodes_without_heartbeats_are_removed() {
+        let server = Server::with_options(
+            root_cert(true, None, None).unwrap(),
+            CounterRetention::default(),
+            DEFAULT_SINGLETON_GRACE,
+            false,
+            None,
+            Some(Duration::from_millis(200)),
+        );
+        add_node(&server, 1);
+        add_node(&server, 2);
+        server.heartbeat(1);
+        server.heartbeat(2);
+        server.join_group("workers".to_string(), 2, 20);
+        std::thread::sleep(Duration::from_millis(150));
+        server.heartbeat(1);
+        assert!(server.remove_silent_nodes().is_empty());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.remove_silent_nodes(), vec![2]);
+        assert!(matches!(
+            server.list_nodes(),
+            Response::Nodes(nodes) if nodes.len() == 1 && nodes[0].id == 1
+        ));
+        assert_eq!(members(&server, "workers"), vec![]);
+        // Removed nodes have to register again
+        assert!(matches!(server.heartbeat(2), Response::Error(_)));
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// What a node does while it's not connected to the control server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutageMode {
+    /// Keep handling spawns with the last known nodes and already compiled modules. Spawns of
+    /// modules that would need to be fetched from the control server fail.
+    #[default]
+    Degraded,
+    /// Refuse spawns from other nodes until the connection is back.
+    Strict,
+}
+
+/// State of the connection to the control server. The node is connected while at least one of
+/// its pooled connections is open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlStatus {
+    Connected,
+    /// Disconnected and serving according to [`OutageMode::Degraded`].
+    Degraded,
+    /// Disconnected and refusing spawns according to [`OutageMode::Strict`].
+    Strict,
+}
+
+// Delay of the first reconnect attempt, doubled with each failed attempt
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+pub(crate) struct ControlConnection {
+    mode: OutageMode,
+    // Number of open connections to the control server
+    open: AtomicUsize,
+}
+
+impl ControlConnection {
+    pub fn new(mode: OutageMode) -> Self {
+        Self {
+            mode,
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn opened(&self) {
+        if self.open.fetch_add(1, Ordering::Relaxed) == 0 {
+            log::info!("Connected to control server");
+        }
+    }
+
+    pub fn closed(&self) {
+        if self.open.fetch_sub(1, Ordering::Relaxed) != 1 {
+            return;
+        }
+        match self.mode {
+            OutageMode::Degraded => log::warn!(
+                "Lost connection to control server, serving with last known nodes and modules"
+            ),
+            OutageMode::Strict => log::warn!(
+                "Lost connection to control server, refusing spawns until it's reconnected"
+            ),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.open.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn accepts_spawns(&self) -> bool {
+        self.status() != ControlStatus::Strict
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        match (self.is_connected(), self.mode) {
+            (true, _) => ControlStatus::Connected,
+            (false, OutageMode::Degraded) => ControlStatus::Degraded,
+            (false, OutageMode::Strict) => ControlStatus::Strict,
+        }
+    }
+}
+
+// How long to wait before the reconnect attempt `attempt` (starting at 0).
+pub(crate) fn reconnect_delay(attempt: u32) -> Duration {
+    MIN_RECONNECT_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_RECONNECT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{reconnect_delay, ControlConnection, ControlStatus, OutageMode};
+
+    #[test]
+    fn outage_follows_configured_mode() {
+        let degraded = ControlConnection::new(OutageMode::Degraded);
+        let strict = ControlConnection::new(OutageMode::Strict);
+        for connection in [&degraded, &strict] {
+            connection.opened();
+            assert_eq!(connection.status(), ControlStatus::Connected);
+            connection.closed();
+        }
+        assert_eq!(degraded.status(), ControlStatus::Degraded);
+        assert!(degraded.accepts_spawns());
+        assert_eq!(strict.status(), ControlStatus::Strict);
+        assert!(!strict.accepts_spawns());
+
+        strict.opened();
+        assert!(strict.is_connected());
+        assert!(strict.accepts_spawns());
+        assert_eq!(strict.status(), ControlStatus::Connected);
+    }
+
+    #[test]
+    fn connected_while_any_pooled_connection_is_open() {
+        let connection = ControlConnection::new(OutageMode::Strict);
+        connection.opened();
+        connection.opened();
+        connection.closed();
+        assert_eq!(connection.status(), ControlStatus::Connected);
+        connection.closed();
+        assert_eq!(connection.status(), ControlStatus::Strict);
+    }
+
+    #[test]
+    fn reconnects_back_off() {
+        assert_eq!(reconnect_delay(0), Duration::from_millis(100));
+        assert_eq!(reconnect_delay(1), Duration::from_millis(200));
+        assert_eq!(reconnect_delay(3), Duration::from_millis(800));
+        assert_eq!(reconnect_delay(10), Duration::from_secs(10));
+        assert_eq!(reconnect_delay(u32::MAX), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,369 @@
+/*!
+Discovery of the control server through DNS, so that nodes don't need its address configured.
+
+Two sources are supported:
+
+- `dns-srv:<name>` looks up the SRV records of `<name>` with the first nameserver of
+  `/etc/resolv.conf`. A Kubernetes headless service publishes them for its named ports, e.g.
+  `_control._udp.lunatic.default.svc.cluster.local`.
+- `mdns:<service>` asks for instances of `<service>` with multicast DNS on the local network,
+  e.g. `_lunatic-control._udp.local`, and collects the answers for the discovery timeout. The
+  control server has to be advertised by an mDNS responder of the host, like Avahi.
+
+Only the small part of the DNS wire format needed for this is implemented. Records are returned
+ordered by priority and weight, without the weighted random selection of RFC 2782.
+*/
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::{net::UdpSocket, time::Instant};
+
+pub const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+const MDNS_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Recursion desired
+const FLAG_RD: u16 = 0x0100;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Discovery {
+    DnsSrv(String),
+    Mdns(String),
+}
+
+impl Discovery {
+    /// Parses `dns-srv:<name>` or `mdns:<service>`, returns `None` for anything else, like a
+    /// plain address.
+    pub fn parse(source: &str) -> Option<Self> {
+        if let Some(name) = source.strip_prefix("dns-srv:") {
+            Some(Discovery::DnsSrv(name.trim_end_matches('.').to_lowercase()))
+        } else {
+            source
+                .strip_prefix("mdns:")
+                .map(|service| Discovery::Mdns(service.trim_end_matches('.').to_lowercase()))
+        }
+    }
+
+    /// Returns the discovered addresses, the preferred ones first. Fails if nothing was found
+    /// within `timeout`.
+    pub async fn resolve(&self, timeout: Duration) -> Result<Vec<SocketAddr>> {
+        let records = match self {
+            Discovery::DnsSrv(name) => {
+                let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+                let nameserver = nameserver(&resolv_conf)
+                    .ok_or_else(|| anyhow!("No nameserver in /etc/resolv.conf"))?;
+                query_srv(name, nameserver, timeout).await?
+            }
+            Discovery::Mdns(service) => query_mdns(service, timeout).await?,
+        };
+        let addresses = records.addresses().await;
+        if addresses.is_empty() {
+            bail!("Nothing discovered with {self:?}");
+        }
+        log::info!("Discovered {addresses:?} with {self:?}");
+        Ok(addresses)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Srv {
+    name: String,
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Records {
+    srv: Vec<Srv>,
+    // Addresses of hosts, from A and AAAA records
+    hosts: Vec<(String, IpAddr)>,
+}
+
+impl Records {
+    // Addresses of the SRV targets. Targets without an address in the responses are resolved
+    // with the system resolver.
+    async fn addresses(mut self) -> Vec<SocketAddr> {
+        self.srv
+            .sort_by_key(|srv| (srv.priority, u16::MAX - srv.weight));
+        let mut addresses = Vec::new();
+        for srv in self.srv.iter() {
+            let mut found = false;
+            for (_, ip) in self.hosts.iter().filter(|(host, _)| *host == srv.target) {
+                addresses.push(SocketAddr::new(*ip, srv.port));
+                found = true;
+            }
+            if !found {
+                match tokio::net::lookup_host((srv.target.as_str(), srv.port)).await {
+                    Ok(resolved) => addresses.extend(resolved),
+                    Err(error) => log::debug!("Failed to resolve {}: {error}", srv.target),
+                }
+            }
+        }
+        addresses.dedup();
+        addresses
+    }
+}
+
+async fn query_srv(name: &str, nameserver: IpAddr, timeout: Duration) -> Result<Records> {
+    let socket = match nameserver {
+        IpAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        IpAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+    };
+    let id = uuid::Uuid::new_v4().as_u128() as u16;
+    socket
+        .send_to(&query(id, FLAG_RD, name, TYPE_SRV), (nameserver, 53))
+        .await?;
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; 4096];
+    loop {
+        let (size, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| anyhow!("No answer from nameserver {nameserver} for {name}"))??;
+        if from.ip() != nameserver {
+            continue;
+        }
+        match parse(&buffer[..size])? {
+            (answer_id, records) if answer_id == id => return Ok(records),
+            // Late answer to an earlier query
+            _ => continue,
+        }
+    }
+}
+
+// Queries from a port other than 5353 are answered directly to the querying socket (RFC 6762,
+// section 6.7), so no multicast group needs to be joined.
+async fn query_mdns(service: &str, timeout: Duration) -> Result<Records> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket
+        .send_to(&query(0, 0, service, TYPE_PTR), MDNS_ADDRESS)
+        .await?;
+    let deadline = Instant::now() + timeout;
+    let mut records = Records::default();
+    let mut buffer = [0; 9000];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+    {
+        let (size, from) = received?;
+        match parse(&buffer[..size]) {
+            Ok((_, answer)) => {
+                // Instances of the service are named `<instance>.<service>`
+                let suffix = format!(".{service}");
+                records.srv.extend(
+                    answer
+                        .srv
+                        .into_iter()
+                        .filter(|srv| srv.name.ends_with(&suffix)),
+                );
+                records.hosts.extend(answer.hosts);
+            }
+            Err(error) => log::debug!("Invalid mDNS answer from {from}: {error}"),
+        }
+    }
+    Ok(records)
+}
+
+// Returns the first nameserver of the `resolv.conf`
+fn nameserver(resolv_conf: &str) -> Option<IpAddr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            // Drops the zone of link-local IPv6 addresses
+            (Some("nameserver"), Some(address)) => address.split('%').next()?.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn query(id: u16, flags: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    for field in [id, flags, 1, 0, 0, 0] {
+        packet.extend_from_slice(&field.to_be_bytes());
+    }
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+// Returns the ID of the response and the SRV, A and AAAA records of all its sections
+fn parse(packet: &[u8]) -> Result<(u16, Records)> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        bail!("Not a response");
+    }
+    match flags & 0x000f {
+        0 => (),
+        3 => bail!("No such name"),
+        rcode => bail!("Response code {rcode}"),
+    }
+    if flags & 0x0200 != 0 {
+        bail!("Truncated response");
+    }
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+
+    let mut position = 12;
+    for _ in 0..questions {
+        position = read_name(packet, position)?.1 + 4;
+    }
+    let mut result = Records::default();
+    for _ in 0..records {
+        let (name, next) = read_name(packet, position)?;
+        let rtype = read_u16(packet, next)?;
+        let length = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        let rdata = packet
+            .get(data..data + length)
+            .ok_or_else(|| anyhow!("Record out of bounds"))?;
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = rdata.try_into().unwrap();
+                result.hosts.push((name, IpAddr::from(octets)));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                result.hosts.push((name, IpAddr::from(octets)));
+            }
+            (TYPE_SRV, 7..) => result.srv.push(Srv {
+                name,
+                priority: read_u16(packet, data)?,
+                weight: read_u16(packet, data + 2)?,
+                port: read_u16(packet, data + 4)?,
+                // The target may be compressed with a pointer outside of the record
+                target: read_name(packet, data + 6)?.0,
+            }),
+            _ => (),
+        }
+        position = data + length;
+    }
+    Ok((id, result))
+}
+
+fn read_u16(packet: &[u8], position: usize) -> Result<u16> {
+    packet
+        .get(position..position + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| anyhow!("Unexpected end of packet"))
+}
+
+// Returns the lowercase name at `position` and the position after it, following compression
+// pointers
+fn read_name(packet: &[u8], mut position: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Pointers only point backwards in valid packets, this also stops pointer loops
+    let mut jumps = 0;
+    loop {
+        let length = *packet
+            .get(position)
+            .ok_or_else(|| anyhow!("Unexpected end of packet"))? as usize;
+        match length {
+            0 => break,
+            _ if length & 0xc0 == 0xc0 => {
+                jumps += 1;
+                if jumps > 32 {
+                    bail!("Too many compression pointers");
+                }
+                end.get_or_insert(position + 2);
+                position = (read_u16(packet, position)? & 0x3fff) as usize;
+            }
+            _ => {
+                let label = packet
+                    .get(position + 1..position + 1 + length)
+                    .ok_or_else(|| anyhow!("Unexpected end of packet"))?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                position += 1 + length;
+            }
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(position + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{nameserver, parse, query, Discovery, Records, Srv, TYPE_SRV};
+
+    // Answer to a query, with the name of the answer and the domain of the target compressed
+    fn response(id: u16) -> Vec<u8> {
+        let mut packet = query(id, 0x8180, "_control._udp.lunatic.svc", TYPE_SRV);
+        // One answer and one additional record
+        packet[7] = 1;
+        packet[11] = 1;
+        // Name of the answer points at the question
+        packet.extend_from_slice(&[0xc0, 12]);
+        packet.extend_from_slice(&[0, 33, 0, 1, 0, 0, 0, 30, 0, 15]);
+        packet.extend_from_slice(&[0, 10, 0, 5, 0x0c, 0xea]);
+        // `node-1.` followed by a pointer to `lunatic.svc` in the question
+        packet.extend_from_slice(&[6, b'n', b'o', b'd', b'e', b'-', b'1', 0xc0, 12 + 14]);
+        let target = packet.len() - 9;
+        packet.extend_from_slice(&[0xc0, target as u8]);
+        packet.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 10, 0, 0, 7]);
+        packet
+    }
+
+    #[tokio::test]
+    async fn srv_answers_are_parsed() {
+        let (id, records) = parse(&response(42)).unwrap();
+        assert_eq!(id, 42);
+        assert_eq!(
+            records,
+            Records {
+                srv: vec![Srv {
+                    name: "_control._udp.lunatic.svc".to_string(),
+                    priority: 10,
+                    weight: 5,
+                    port: 3306,
+                    target: "node-1.lunatic.svc".to_string(),
+                }],
+                hosts: vec![("node-1.lunatic.svc".to_string(), [10, 0, 0, 7].into())],
+            }
+        );
+        let address: SocketAddr = "10.0.0.7:3306".parse().unwrap();
+        assert_eq!(records.addresses().await, vec![address]);
+
+        let mut truncated = response(42);
+        truncated.truncate(truncated.len() - 3);
+        assert!(parse(&truncated).is_err());
+        // A pointer to itself
+        let mut looping = query(1, 0x8000, "a", TYPE_SRV);
+        looping[7] = 1;
+        looping.extend_from_slice(&[0xc0, looping.len() as u8]);
+        assert!(parse(&looping).is_err());
+    }
+
+    #[test]
+    fn discovery_sources_are_parsed() {
+        assert_eq!(
+            Discovery::parse("dns-srv:_control._udp.Lunatic.svc."),
+            Some(Discovery::DnsSrv("_control._udp.lunatic.svc".to_string()))
+        );
+        assert_eq!(
+            Discovery::parse("mdns:_lunatic-control._udp.local"),
+            Some(Discovery::Mdns("_lunatic-control._udp.local".to_string()))
+        );
+        assert_eq!(Discovery::parse("127.0.0.1:3030"), None);
+        assert_eq!(
+            nameserver("# generated\nsearch svc\nnameserver fe80::1%eth0\nnameserver 10.0.0.1"),
+            Some("fe80::1".parse().unwrap())
+        );
+    }
+}
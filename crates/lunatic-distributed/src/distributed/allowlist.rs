@@ -0,0 +1,155 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+/// Modules that are allowed to be spawned on this node.
+///
+/// Modules can be allowed by their id or by the SHA-256 hash of their bytes. An empty allowlist
+/// allows all modules to be spawned.
+#[derive(Clone, Default)]
+pub struct ModuleAllowlist {
+    inner: Arc<RwLock<AllowedModules>>,
+}
+
+#[derive(Default, Debug, PartialEq, Eq)]
+struct AllowedModules {
+    ids: HashSet<u64>,
+    hashes: HashSet<String>,
+}
+
+impl AllowedModules {
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.hashes.is_empty()
+    }
+}
+
+impl ModuleAllowlist {
+    /// Loads the allowlist from a file.
+    ///
+    /// Each line contains either a module id or a hex encoded SHA-256 hash of the module bytes.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> Result<Self> {
+        let allowlist = ModuleAllowlist::default();
+        allowlist.reload(path)?;
+        Ok(allowlist)
+    }
+
+    /// Replaces the allowed modules with the content of the file.
+    pub fn reload(&self, path: &Path) -> Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        let allowed = parse(&content)?;
+        if let Ok(mut inner) = self.inner.write() {
+            *inner = allowed;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the module can be spawned on this node.
+    ///
+    /// The module bytes are only hashed if the module id itself is not allowed. If the allowlist
+    /// can't be read because a writer panicked, no module is allowed.
+    pub fn is_allowed(&self, module_id: u64, bytes: &[u8]) -> bool {
+        if let Ok(inner) = self.inner.read() {
+            if inner.is_empty() || inner.ids.contains(&module_id) {
+                return true;
+            }
+            if inner.hashes.is_empty() {
+                return false;
+            }
+            let hash = format!("{:x}", Sha256::digest(bytes));
+            return inner.hashes.contains(&hash);
+        }
+        false
+    }
+}
+
+fn parse(content: &str) -> Result<AllowedModules> {
+    let mut allowed = AllowedModules::default();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.len() == 64 && line.chars().all(|c| c.is_ascii_hexdigit()) {
+            allowed.hashes.insert(line.to_ascii_lowercase());
+        } else {
+            let id = line
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid module allowlist entry `{line}`"))?;
+            allowed.ids.insert(id);
+        }
+    }
+    Ok(allowed)
+}
+
+/// Reloads the allowlist every time the file is modified.
+pub async fn watch_allowlist(allowlist: ModuleAllowlist, path: PathBuf) {
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let current = modified(&path);
+        if current != last_modified {
+            last_modified = current;
+            match allowlist.reload(&path) {
+                Ok(_) => log::info!("Module allowlist reloaded from {}", path.display()),
+                Err(e) => log::warn!("Failed to reload module allowlist: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(content: &str) -> ModuleAllowlist {
+        ModuleAllowlist {
+            inner: Arc::new(RwLock::new(parse(content).unwrap())),
+        }
+    }
+
+    #[test]
+    fn empty_allows_all() {
+        let allowlist = allowlist("# nothing here\n\n");
+        assert!(allowlist.is_allowed(1, b"module"));
+    }
+
+    #[test]
+    fn allow_by_id() {
+        let allowlist = allowlist("1\n2");
+        assert!(allowlist.is_allowed(2, b"module"));
+        assert!(!allowlist.is_allowed(3, b"module"));
+    }
+
+    #[test]
+    fn allow_by_hash() {
+        let hash = format!("{:x}", Sha256::digest(b"vetted"));
+        let allowlist = allowlist(&hash.to_ascii_uppercase());
+        assert!(allowlist.is_allowed(7, b"vetted"));
+        assert!(!allowlist.is_allowed(7, b"unvetted"));
+    }
+
+    #[test]
+    fn poisoned_allows_none() {
+        let allowlist = allowlist("");
+        let poisoned = allowlist.clone();
+        let _ = std::thread::spawn(move || {
+            let _inner = poisoned.inner.write().unwrap();
+            panic!("poison the allowlist");
+        })
+        .join();
+        assert!(!allowlist.is_allowed(1, b"module"));
+    }
+
+    #[test]
+    fn invalid_entry() {
+        assert!(parse("not-a-module").is_err());
+    }
+}
@@ -0,0 +1,314 @@
+/*!
+Benchmark harness for the distributed layer.
+
+Runs a fixed number of requests from a [`Client`] to a node with a configurable concurrency and
+payload size, and reports the throughput and latency. The target can be a real node of the
+cluster, or an in-memory echo node created with [`echo_node`] that answers every request right
+away, which gives a baseline without any network or process overhead.
+
+Only available with the `bench` feature.
+*/
+
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use lunatic_process::{lifecycle::Lifecycle, message::Priority};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    message::{pack_response, ClientError, Request, Response, Spawn},
+    spawn_config::SpawnConfig,
+    Client,
+};
+use crate::{
+    quic::{self, ConnectionConfig, InMemoryAcceptor},
+    EnvironmentId, ModuleId, NodeId, ProcessId,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    /// Spawns a process and waits for its id.
+    Spawn,
+    /// Queues a message without waiting for the node, see `Client::try_message_process`.
+    Send,
+    /// Sends a message and waits for the node to confirm the delivery.
+    SendReceive,
+}
+
+impl FromStr for Operation {
+    type Err = String;
+
+    fn from_str(operation: &str) -> Result<Self, Self::Err> {
+        match operation {
+            "spawn" => Ok(Operation::Spawn),
+            "send" => Ok(Operation::Send),
+            "send_receive" => Ok(Operation::SendReceive),
+            _ => Err(format!("unknown operation {operation}")),
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Spawn => write!(f, "spawn"),
+            Operation::Send => write!(f, "send"),
+            Operation::SendReceive => write!(f, "send_receive"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    pub operation: Operation,
+    // Number of requests in flight at the same time
+    pub concurrency: usize,
+    // Total number of requests
+    pub requests: usize,
+    // Size of message data
+    pub payload_size: usize,
+    pub environment_id: EnvironmentId,
+    // Target process of messages
+    pub process_id: ProcessId,
+    // Module, function and serialized config spawned processes start from
+    pub module_id: ModuleId,
+    pub function: String,
+    pub spawn_config: Vec<u8>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            operation: Operation::SendReceive,
+            concurrency: 16,
+            requests: 10_000,
+            payload_size: 64,
+            environment_id: EnvironmentId(1),
+            process_id: ProcessId(1),
+            module_id: ModuleId(1),
+            function: "_start".to_string(),
+            spawn_config: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub operation: Operation,
+    pub concurrency: usize,
+    pub payload_size: usize,
+    pub requests: usize,
+    pub errors: usize,
+    pub duration: Duration,
+    // Successful requests per second
+    pub throughput: f64,
+    pub latency_p50: Duration,
+    pub latency_p99: Duration,
+    pub latency_max: Duration,
+}
+
+impl Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "operation={} concurrency={} payload_size={} requests={} errors={} duration_ms={} \
+             throughput={:.0} latency_p50_us={} latency_p99_us={} latency_max_us={}",
+            self.operation,
+            self.concurrency,
+            self.payload_size,
+            self.requests,
+            self.errors,
+            self.duration.as_millis(),
+            self.throughput,
+            self.latency_p50.as_micros(),
+            self.latency_p99.as_micros(),
+            self.latency_max.as_micros(),
+        )
+    }
+}
+
+/// Runs the benchmark against `node_id`.
+pub async fn run(client: &Client, node_id: NodeId, config: &BenchConfig) -> BenchResult {
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let workers: Vec<_> = (0..config.concurrency.max(1))
+        .map(|_| {
+            let client = client.clone();
+            let config = config.clone();
+            let next = next.clone();
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let mut errors = 0;
+                while next.fetch_add(1, Ordering::Relaxed) < config.requests {
+                    let started = Instant::now();
+                    match request(&client, node_id, &config).await {
+                        Ok(()) => latencies.push(started.elapsed()),
+                        Err(_) => errors += 1,
+                    }
+                }
+                (latencies, errors)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut errors = 0;
+    for worker in workers {
+        let (worker_latencies, worker_errors) = worker.await.unwrap_or_default();
+        latencies.extend(worker_latencies);
+        errors += worker_errors;
+    }
+    let duration = start.elapsed();
+    latencies.sort();
+    let percentile = |p: usize| match latencies.len() {
+        0 => Duration::ZERO,
+        len => latencies[(len - 1) * p / 100],
+    };
+    BenchResult {
+        operation: config.operation,
+        concurrency: config.concurrency,
+        payload_size: config.payload_size,
+        requests: config.requests,
+        errors,
+        duration,
+        throughput: latencies.len() as f64 / duration.as_secs_f64(),
+        latency_p50: percentile(50),
+        latency_p99: percentile(99),
+        latency_max: latencies.last().copied().unwrap_or_default(),
+    }
+}
+
+async fn request(
+    client: &Client,
+    node_id: NodeId,
+    config: &BenchConfig,
+) -> Result<(), ClientError> {
+    match config.operation {
+        Operation::Spawn => {
+            let spawn = Spawn {
+                environment_id: config.environment_id,
+                module_id: config.module_id,
+                module_version: None,
+                function: config.function.clone(),
+                params: Vec::new(),
+                config: SpawnConfig::Inline(config.spawn_config.clone()),
+                idempotency_key: None,
+                cancel_token: None,
+                lifecycle: Lifecycle::Unspecified,
+            };
+            client.spawn(node_id, spawn).await.map(|_| ())
+        }
+        Operation::Send => {
+            let mut data = vec![0; config.payload_size];
+            // Wait for room in the buffer instead of counting a full buffer as an error
+            while let Err(returned) = client.try_message_process(
+                node_id,
+                config.environment_id,
+                config.process_id,
+                None,
+                Priority::Normal,
+                data,
+                None,
+            ) {
+                data = returned;
+                tokio::task::yield_now().await;
+            }
+            Ok(())
+        }
+        Operation::SendReceive => {
+            client
+                .message_process(
+                    node_id,
+                    config.environment_id,
+                    config.process_id,
+                    None,
+                    Priority::Normal,
+                    vec![0; config.payload_size],
+                    None,
+                    None,
+                )
+                .await
+        }
+    }
+}
+
+/// Connects `client` to an in-memory node with the id `node_id` that answers every request
+/// successfully without doing any work.
+pub fn echo_node(client: &Client, node_id: NodeId) {
+    let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+    client.connect_in_memory(node_id, connection);
+    tokio::spawn(answer_streams(acceptor));
+}
+
+async fn answer_streams(mut acceptor: InMemoryAcceptor) {
+    while let Some((mut send, mut recv)) = acceptor.accept().await {
+        tokio::spawn(async move {
+            while let Ok(bytes) = recv.receive().await {
+                let (msg_id, request): (u64, Request) = match bincode::deserialize(&bytes) {
+                    Ok(request) => request,
+                    Err(_) => return,
+                };
+                let response = match request {
+                    Request::Spawn(_) => Response::Spawned(ProcessId(1)),
+                    _ => Response::Sent,
+                };
+                if send
+                    .send(&mut pack_response(msg_id, response))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{echo_node, run, BenchConfig, Operation};
+    use crate::{
+        control,
+        distributed::{Client, ClientConfig},
+        quic, NodeId,
+    };
+
+    #[tokio::test]
+    async fn harness_produces_results() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        echo_node(&client, NodeId(2));
+
+        for operation in [Operation::Spawn, Operation::Send, Operation::SendReceive] {
+            let config = BenchConfig {
+                operation,
+                concurrency: 4,
+                requests: 100,
+                ..Default::default()
+            };
+            let result = run(&client, NodeId(2), &config).await;
+            assert_eq!(result.requests, 100);
+            assert_eq!(result.errors, 0);
+            assert!(result.throughput > 0.0);
+            assert!(result.latency_max >= result.latency_p50);
+            assert!(result
+                .to_string()
+                .starts_with(&format!("operation={operation}")));
+        }
+    }
+}
@@ -1,90 +1,344 @@
 use anyhow::Result;
-use async_cell::sync::AsyncCell;
 use bytes::Bytes;
-use dashmap::DashMap;
-use std::sync::{atomic, atomic::AtomicU64, Arc};
-use tokio::sync::mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use lunatic_process::{
+    message::{DataMessage, Message, MessageSender, Priority},
+    state::SignalSender,
+    Signal,
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot, Mutex,
+};
 
 use crate::{
-    control,
-    distributed::message::{ClientError, Request, Response},
-    quic::{self, RecvStream},
-    NodeInfo,
+    control::{self, gossip::MemberUpdate},
+    distributed::message::{ClientError, Plane, Request, Response},
+    join_token::JoinToken,
+    quic::{self, RecvStream, SendStream},
+    timestamp::Clock,
+    EnvironmentId, NodeId, NodeInfo, ProcessId,
+};
+
+use super::{
+    delivery_log::{DeliveryLog, LoggedMessage, Receipts},
+    links::RemoteLinks,
+    message::{Payload, ReplyCapability, Spawn},
+    monitors::Monitors,
+    node_stats::NodeStats,
+    pending_spawns::{PendingSpawns, SpawnPoll},
+    placement::{Placement, StablePlacement},
+    request_tracker::RequestTracker,
+    self_test::{CheckStatus, SelfTestCheck, SelfTestConfig, SelfTestReport},
+    spawn_config::{config_handle, SpawnConfig},
 };
 
-use super::message::Spawn;
+// How often `spawn_with_fallback` checks if the target node is still part of the cluster
+const NODE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often the nodes this node is connected to are reported to the control server
+const PEER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Gossip isn't sent on behalf of an environment
+const GOSSIP_ENVIRONMENT: EnvironmentId = EnvironmentId(0);
 
 struct SendRequest {
     msg_id: u64,
-    node_id: u64,
+    node_id: NodeId,
     request: Request,
 }
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    // Connections to other nodes are closed after this duration without any outgoing requests,
+    // and re-established on the next request. If `None`, connections are kept open forever.
+    pub idle_timeout: Option<Duration>,
+    // Maximum size in bytes of the encoded params (17 bytes per param) of a remote spawn.
+    pub max_spawn_params_size: usize,
+    // Send only a handle of the spawn config to nodes that already received the same config.
+    pub reference_spawn_configs: bool,
+    // Results of spawns started with `spawn_async` are dropped if not polled within this
+    // duration after the spawn finished.
+    pub spawn_token_ttl: Duration,
+    // Maximum number of messages queued by `try_message_process` that were not sent yet.
+    pub max_buffered_sends: usize,
+    // Message data larger than this many bytes is LZ4 compressed. If `None`, data is never
+    // compressed.
+    pub compress_messages_above: Option<usize>,
+    // Picks the node a spawn is moved to by `spawn_with_fallback`.
+    pub placement: Arc<dyn Placement>,
+    // Messages sent with at-least-once delivery are sent again if they are not acknowledged
+    // within this duration.
+    pub redelivery_window: Duration,
+    // The log of messages sent with at-least-once delivery is compacted in this interval. If
+    // `None`, it's never compacted.
+    pub log_compaction_interval: Option<Duration>,
+    // The log is only compacted if acknowledged messages left room for at least this many
+    // messages unused.
+    pub log_compaction_threshold: usize,
+    // Maximum length in bytes of string arguments, like function names, read from guests by the
+    // distributed host functions.
+    pub max_string_arg_len: usize,
+    // Join token attached to requests sent to other nodes, and required from requests of other
+    // nodes
+    pub join_token: Option<JoinToken>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            max_spawn_params_size: 64 * 1024,
+            reference_spawn_configs: true,
+            spawn_token_ttl: Duration::from_secs(60),
+            max_buffered_sends: 1024,
+            compress_messages_above: None,
+            placement: Arc::new(StablePlacement),
+            redelivery_window: Duration::from_secs(5),
+            log_compaction_interval: Some(Duration::from_secs(60)),
+            log_compaction_threshold: 1024,
+            max_string_arg_len: 4096,
+            join_token: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Returns an error if a string argument of `len` bytes is longer than allowed.
+    pub fn check_string_arg(&self, len: u32) -> Result<(), StringArgTooLong> {
+        if len as usize > self.max_string_arg_len {
+            return Err(StringArgTooLong {
+                len: len as usize,
+                max_len: self.max_string_arg_len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns an error if `len` bytes of encoded spawn params, 17 bytes per param, are more than
+    /// allowed.
+    pub fn check_spawn_params(&self, len: u32) -> Result<(), SpawnParamsTooLarge> {
+        if len as usize > self.max_spawn_params_size {
+            return Err(SpawnParamsTooLarge {
+                len: len as usize,
+                max_len: self.max_spawn_params_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Encoded spawn params are bigger than [`ClientConfig::max_spawn_params_size`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpawnParamsTooLarge {
+    pub len: usize,
+    pub max_len: usize,
+}
+
+impl std::fmt::Display for SpawnParamsTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Spawn params take {} bytes ({} params), but at most {} bytes are allowed.",
+            self.len,
+            self.len / 17,
+            self.max_len
+        )
+    }
+}
+
+impl std::error::Error for SpawnParamsTooLarge {}
+
+/// A string argument read from a guest is longer than [`ClientConfig::max_string_arg_len`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StringArgTooLong {
+    pub len: usize,
+    pub max_len: usize,
+}
+
+impl std::fmt::Display for StringArgTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "String argument takes {} bytes, but at most {} bytes are allowed.",
+            self.len, self.max_len
+        )
+    }
+}
+
+impl std::error::Error for StringArgTooLong {}
+
 #[derive(Clone)]
 pub struct Client {
     inner: Arc<InnerClient>,
 }
 
 pub struct InnerClient {
-    next_message_id: AtomicU64,
-    node_message_buffers: DashMap<u64, UnboundedSender<(u64, Request)>>,
-    pending_requests: DashMap<u64, Arc<AsyncCell<Response>>>,
+    node_id: NodeId,
+    // Each environment talking to a node gets its own channel (QUIC stream) on a connection
+    // shared with all other channels to the same node and plane. Keyed by
+    // `(node_id, plane, environment_id)`.
+    node_message_buffers: DashMap<(NodeId, Plane, EnvironmentId), UnboundedSender<(u64, Request)>>,
+    node_connections: DashMap<(NodeId, Plane), Arc<Mutex<Option<quic::Connection>>>>,
+    requests: RequestTracker,
     control_client: control::Client,
     quic_client: quic::Client,
     tx: UnboundedSender<SendRequest>,
+    config: ClientConfig,
+    // Reply capabilities minted by this node, mapped to `(environment_id, process_id)`.
+    reply_capabilities: DashMap<u128, (EnvironmentId, ProcessId)>,
+    // Unused reply capabilities of each process, forgotten once the process finishes.
+    reply_capabilities_of: DashMap<(EnvironmentId, ProcessId), HashSet<u128>>,
+    // Calls waiting for their reply, keyed by the token of the capability sent with the call.
+    pending_calls: DashMap<u128, oneshot::Sender<Vec<u8>>>,
+    // `(node_id, config_handle)` of spawn configs that were sent inline to nodes.
+    known_spawn_configs: DashSet<(NodeId, u64)>,
+    // Spawns started with `spawn_async` that were not polled to completion yet.
+    pending_spawns: PendingSpawns,
+    // Message ids of messages queued by `try_message_process` that were not sent yet.
+    buffered_sends: DashSet<u64>,
+    // Messages sent with at-least-once delivery that were not acknowledged yet.
+    delivery_log: DeliveryLog,
+    // Messages received with at-least-once delivery that were not acknowledged yet.
+    receipts: Receipts,
+    // Processes on this node linked to processes on other nodes.
+    remote_links: RemoteLinks,
+    monitors: Monitors,
+    clock: Clock,
 }
 
 impl Client {
-    // TODO node_id?
     pub async fn new(
-        _node_id: u64,
+        node_id: NodeId,
         control_client: control::Client,
         quic_client: quic::Client,
+        config: ClientConfig,
     ) -> Result<Client> {
         let (tx, rx) = mpsc::unbounded_channel();
+        let pending_spawns = PendingSpawns::new(config.spawn_token_ttl);
         let client = Client {
             inner: Arc::new(InnerClient {
-                next_message_id: AtomicU64::new(1),
+                node_id,
                 node_message_buffers: DashMap::new(),
-                pending_requests: DashMap::new(),
+                node_connections: DashMap::new(),
+                requests: RequestTracker::default(),
                 control_client,
                 quic_client,
                 tx,
+                config,
+                reply_capabilities: DashMap::new(),
+                reply_capabilities_of: DashMap::new(),
+                pending_calls: DashMap::new(),
+                known_spawn_configs: DashSet::new(),
+                pending_spawns,
+                buffered_sends: DashSet::new(),
+                delivery_log: DeliveryLog::default(),
+                receipts: Receipts::default(),
+                remote_links: RemoteLinks::default(),
+                monitors: Monitors::default(),
+                clock: Clock::new(node_id),
             }),
         };
         tokio::spawn(forward_node_messages(client.clone(), rx));
+        tokio::spawn(report_peers_task(client.clone()));
+        tokio::spawn(redeliver_task(client.clone()));
+        tokio::spawn(node_left_task(client.clone()));
+        if client.inner.control_client.membership().is_some() {
+            tokio::spawn(gossip_task(client.clone()));
+        }
+        if let Some(interval) = client.inner.config.log_compaction_interval {
+            tokio::spawn(compact_log_task(client.clone(), interval));
+        }
         Ok(client)
     }
 
+    /// Returns the nodes this node currently has an open connection to, on any plane.
+    pub fn connected_nodes(&self) -> Vec<u64> {
+        let mut node_ids: Vec<u64> = self
+            .inner
+            .node_connections
+            .iter()
+            .filter(|entry| {
+                // A locked slot is being connected
+                entry
+                    .value()
+                    .try_lock()
+                    .map(|connection| connection.iter().any(|conn| !conn.is_closed()))
+                    .unwrap_or(false)
+            })
+            .map(|entry| entry.key().0 .0)
+            .collect();
+        node_ids.sort_unstable();
+        node_ids.dedup();
+        node_ids
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.inner.config
+    }
+
+    pub fn remote_links(&self) -> &RemoteLinks {
+        &self.inner.remote_links
+    }
+
+    pub fn monitors(&self) -> &Monitors {
+        &self.inner.monitors
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.inner.node_id
+    }
+
+    /// Clock of this node, timestamps sent to other nodes are created and converted with it.
+    pub fn clock(&self) -> &Clock {
+        &self.inner.clock
+    }
+
+    /// Sends all requests to `node_id` over the in-memory connection instead of connecting to
+    /// the node.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) fn connect_in_memory(&self, node_id: NodeId, connection: quic::Connection) {
+        for plane in [Plane::Control, Plane::Data] {
+            self.inner.node_connections.insert(
+                (node_id, plane),
+                Arc::new(Mutex::new(Some(connection.clone()))),
+            );
+        }
+    }
+
     pub fn next_message_id(&self) -> u64 {
-        self.inner
-            .next_message_id
-            .fetch_add(1, atomic::Ordering::Relaxed)
+        self.inner.requests.next_id()
     }
 
-    async fn request(&self, node_id: u64, request: Request) -> Result<Response, ClientError> {
-        let msg_id = self.next_message_id();
+    async fn request(&self, node_id: NodeId, request: Request) -> Result<Response, ClientError> {
+        let pending = self.inner.requests.register();
         self.inner
             .tx
             .send(SendRequest {
-                msg_id,
+                msg_id: pending.msg_id(),
                 node_id,
                 request,
             })
             .map_err(|e| ClientError::Unexpected(e.to_string()))?;
-        let cell = AsyncCell::shared();
-        self.inner.pending_requests.insert(msg_id, cell.clone());
-        let response = cell.take().await;
-        self.inner.pending_requests.remove(&msg_id);
-        Ok(response)
+        pending.response().await
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn message_process(
         &self,
-        node_id: u64,
-        environment_id: u64,
-        process_id: u64,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
         tag: Option<i64>,
+        priority: Priority,
         data: Vec<u8>,
+        reply_cap: Option<ReplyCapability>,
+        sender: Option<MessageSender>,
     ) -> Result<(), ClientError> {
         match self
             .request(
@@ -93,7 +347,12 @@ impl Client {
                     environment_id,
                     process_id,
                     tag,
-                    data,
+                    priority,
+                    data: Payload::new(data, self.inner.config.compress_messages_above),
+                    reply_cap,
+                    sender: sender
+                        .map(|sender| (NodeId(sender.node_id), ProcessId(sender.process_id))),
+                    delivery_id: None,
                 },
             )
             .await
@@ -106,90 +365,2584 @@ impl Client {
         }
     }
 
-    fn process_response(&self, id: u64, resp: Response) {
-        if let Some(e) = self.inner.pending_requests.get(&id) {
-            e.set(resp);
+    /// Sends `messages` to the process in their order, keeping their original senders.
+    ///
+    /// Sending stops at the first message the node doesn't accept, so the process never receives
+    /// a message without the ones before it. The error is returned together with that message
+    /// and all messages after it.
+    pub async fn forward_messages(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        messages: Vec<DataMessage>,
+    ) -> Result<(), (ClientError, Vec<DataMessage>)> {
+        let mut messages = VecDeque::from(messages);
+        while let Some(message) = messages.front() {
+            let sent = self
+                .message_process(
+                    node_id,
+                    environment_id,
+                    process_id,
+                    message.tag,
+                    message.priority,
+                    message.buffer.clone(),
+                    None,
+                    message.sender,
+                )
+                .await;
+            if let Err(error) = sent {
+                return Err((error, messages.into()));
+            }
+            messages.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Queues the message without waiting for it to be sent or delivered. If `max_buffered_sends`
+    /// messages are already waiting to be sent, the data is returned instead.
+    ///
+    /// Messages take the same path as the ones sent with `message_process`, so the order of
+    /// messages to the same process is kept. Delivery errors are only logged by the receiving
+    /// node.
+    #[allow(clippy::too_many_arguments)]
+    pub fn try_message_process(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+        sender: Option<MessageSender>,
+    ) -> Result<(), Vec<u8>> {
+        if self.inner.buffered_sends.len() >= self.inner.config.max_buffered_sends {
+            return Err(data);
+        }
+        let msg_id = self.next_message_id();
+        self.inner.buffered_sends.insert(msg_id);
+        let request = Request::Message {
+            environment_id,
+            process_id,
+            tag,
+            priority,
+            data: Payload::new(data, self.inner.config.compress_messages_above),
+            reply_cap: None,
+            sender: sender.map(|sender| (NodeId(sender.node_id), ProcessId(sender.process_id))),
+            delivery_id: None,
+        };
+        let queued = self.inner.tx.send(SendRequest {
+            msg_id,
+            node_id,
+            request,
+        });
+        if queued.is_err() {
+            self.inner.buffered_sends.remove(&msg_id);
+        }
+        Ok(())
+    }
+
+    /// Sends the message with at-least-once delivery and returns its id in the delivery log.
+    ///
+    /// The message is sent again every `redelivery_window` until the receiving process
+    /// acknowledges it. Connection errors are not returned, the message is sent again later. If
+    /// the node can't deliver the message, e.g. because the process doesn't exist, it's dropped
+    /// from the log and the error is returned.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn message_process_reliably(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+        sender: MessageSender,
+    ) -> Result<u64, ClientError> {
+        let message = LoggedMessage {
+            node_id,
+            environment_id,
+            process_id,
+            tag,
+            priority,
+            data,
+            sender: (NodeId(sender.node_id), ProcessId(sender.process_id)),
+        };
+        let delivery_id = self.inner.delivery_log.record(message.clone());
+        self.deliver(delivery_id, message).await?;
+        Ok(delivery_id)
+    }
+
+    // Sends a message of the delivery log, dropping it from the log if it can't be delivered.
+    async fn deliver(&self, delivery_id: u64, message: LoggedMessage) -> Result<(), ClientError> {
+        let request = Request::Message {
+            environment_id: message.environment_id,
+            process_id: message.process_id,
+            tag: message.tag,
+            priority: message.priority,
+            data: Payload::new(message.data, self.inner.config.compress_messages_above),
+            reply_cap: None,
+            sender: Some(message.sender),
+            delivery_id: Some(delivery_id),
+        };
+        let error = match self.request(message.node_id, request).await {
+            Ok(Response::Sent) | Err(ClientError::Connection(_)) => return Ok(()),
+            Ok(Response::Error(error)) | Err(error) => error,
+            Ok(_) => ClientError::Unexpected("Invalid response type for send".to_string()),
         };
+        self.inner.delivery_log.ack(delivery_id);
+        Err(error)
+    }
+
+    /// Drops the message from the delivery log once the receiving process acknowledged it.
+    /// Returns false if the message is not in the log anymore.
+    pub fn ack_delivery(&self, delivery_id: u64) -> bool {
+        self.inner.delivery_log.ack(delivery_id)
     }
 
-    pub async fn spawn(&self, node_id: u64, spawn: Spawn) -> Result<u64, ClientError> {
-        match self.request(node_id, Request::Spawn(spawn)).await {
-            Ok(Response::Spawned(id)) => Ok(id),
+    /// Drops the messages from the delivery log once the receiving process acknowledged them.
+    pub fn ack_deliveries(&self, delivery_ids: &[u64]) -> usize {
+        self.inner.delivery_log.ack_many(delivery_ids)
+    }
+
+    /// Returns the receipt of a message that `node_id` sent with at-least-once delivery to
+    /// `receiver`.
+    pub fn issue_receipt(
+        &self,
+        node_id: NodeId,
+        delivery_id: u64,
+        receiver: (EnvironmentId, ProcessId),
+    ) -> u64 {
+        self.inner.receipts.issue(node_id, delivery_id, receiver)
+    }
+
+    pub fn remove_receipt(&self, receipt: u64) {
+        self.inner.receipts.remove(receipt);
+    }
+
+    /// Acknowledges the message with the receipt to the node that sent it. Returns false if the
+    /// receipt is unknown, e.g. because the message was already acknowledged, or if the sending
+    /// node left the cluster.
+    ///
+    /// The receipt is kept if the acknowledgement can't be sent, so that it can be retried.
+    pub async fn ack_message(
+        &self,
+        environment_id: EnvironmentId,
+        receipt: u64,
+    ) -> Result<bool, ClientError> {
+        let (node_id, delivery_id) = match self.inner.receipts.get(receipt) {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        match self
+            .request(
+                node_id,
+                Request::Ack {
+                    environment_id,
+                    delivery_id,
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => {
+                self.inner.receipts.remove(receipt);
+                Ok(true)
+            }
+            // Without the sending node there is nobody to acknowledge the message to
+            Ok(Response::Error(ClientError::NodeNotFound)) | Err(ClientError::NodeNotFound) => {
+                self.inner.receipts.remove(receipt);
+                Ok(false)
+            }
             Ok(Response::Error(error)) | Err(error) => Err(error),
             Ok(_) => Err(ClientError::Unexpected(
-                "Invalid response type for spawn".to_string(),
+                "Invalid response type for ack".to_string(),
             )),
         }
     }
-}
 
-async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
-    loop {
-        match recv.receive().await {
-            Ok(bytes) => {
-                let (msg_id, response) =
-                    bincode::deserialize::<(u64, super::message::Response)>(&bytes)?;
-                client.process_response(msg_id, response);
-                Ok(())
+    /// Acknowledges all messages the receiver of the receipt got from the same node, up to and
+    /// including the message with the receipt, in one request. Returns the number of
+    /// acknowledged messages, 0 if the receipt is unknown or if the sending node left the
+    /// cluster.
+    ///
+    /// Messages from the node that the receiver didn't get yet are not acknowledged and are sent
+    /// again. The receipts are kept if the acknowledgement can't be sent, so that it can be
+    /// retried.
+    pub async fn ack_messages_up_to(
+        &self,
+        environment_id: EnvironmentId,
+        receipt: u64,
+    ) -> Result<usize, ClientError> {
+        let (node_id, messages) = match self.inner.receipts.up_to(receipt) {
+            Some(messages) => messages,
+            None => return Ok(0),
+        };
+        let delivery_ids = messages
+            .iter()
+            .map(|(_, delivery_id)| *delivery_id)
+            .collect();
+        let remove_receipts = || {
+            for (receipt, _) in &messages {
+                self.inner.receipts.remove(*receipt);
             }
-            Err(e) => {
-                log::debug!("Node connection error: {e}");
-                Err(e)
+        };
+        match self
+            .request(
+                node_id,
+                Request::AckMany {
+                    environment_id,
+                    delivery_ids,
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => {
+                remove_receipts();
+                Ok(messages.len())
             }
-        }?;
+            Ok(Response::Error(ClientError::NodeNotFound)) | Err(ClientError::NodeNotFound) => {
+                remove_receipts();
+                Ok(0)
+            }
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for ack".to_string(),
+            )),
+        }
     }
-}
 
-async fn forward_node_messages(client: Client, mut rx: UnboundedReceiver<SendRequest>) {
-    while let Some(SendRequest {
-        msg_id,
-        node_id,
-        request,
-    }) = rx.recv().await
-    {
-        if let Some(node_buf) = client.inner.node_message_buffers.get(&node_id) {
-            node_buf.value().send((msg_id, request)).ok();
-        } else {
-            let (send, recv) = unbounded_channel();
-            send.send((msg_id, request)).ok();
-            client.inner.node_message_buffers.insert(node_id, send);
-            tokio::spawn(manage_node_connection(node_id, client.clone(), recv));
+    /// Creates a capability that can be used once to reply to the process `process_id`.
+    ///
+    /// Unused capabilities are invalidated once the process finishes. `process` is the signal
+    /// mailbox of the process, used to detect when it finishes.
+    pub fn mint_reply_capability(
+        &self,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        process: SignalSender,
+    ) -> ReplyCapability {
+        let token = uuid::Uuid::new_v4().as_u128();
+        self.inner
+            .reply_capabilities
+            .insert(token, (environment_id, process_id));
+        match self
+            .inner
+            .reply_capabilities_of
+            .entry((environment_id, process_id))
+        {
+            Entry::Occupied(mut tokens) => {
+                tokens.get_mut().insert(token);
+            }
+            Entry::Vacant(tokens) => {
+                tokens.insert(HashSet::from([token]));
+                tokio::spawn(forget_reply_capabilities_task(
+                    self.clone(),
+                    environment_id,
+                    process_id,
+                    process,
+                ));
+            }
+        }
+        ReplyCapability {
+            node_id: self.inner.node_id,
+            token,
         }
     }
-}
 
-async fn try_node_info_forever(node_id: u64, client: &Client) -> NodeInfo {
-    loop {
-        let node_info = client.inner.control_client.node_info(node_id);
-        if node_info.is_none() {
-            client.inner.control_client.refresh_nodes().await.ok();
-        } else {
-            return node_info.unwrap();
+    /// Invalidates the capability and returns the `(environment_id, process_id)` it was minted
+    /// for, or `None` if it was already used or never minted on this node.
+    pub fn redeem_reply_capability(&self, token: u128) -> Option<(EnvironmentId, ProcessId)> {
+        let (_, target) = self.inner.reply_capabilities.remove(&token)?;
+        if let Some(mut tokens) = self.inner.reply_capabilities_of.get_mut(&target) {
+            tokens.remove(&token);
         }
+        Some(target)
     }
-}
 
-async fn manage_node_connection(
-    node_id: u64,
-    client: Client,
-    mut rx: UnboundedReceiver<(u64, Request)>,
-) {
-    let quic_client = client.inner.quic_client.clone();
-    let NodeInfo { address, name, .. } = try_node_info_forever(node_id, &client).await;
-    let (mut send, recv) = quic::try_connect_forever(&quic_client, address, &name).await;
-    tokio::spawn(reader_task(client.clone(), recv));
-    while let Some(msg) = rx.recv().await {
-        if let Ok(data) = bincode::serialize(&msg) {
-            let size = (data.len() as u32).to_le_bytes();
-            let size: Bytes = Bytes::copy_from_slice(&size[..]);
-            let bytes: Bytes = data.into();
-            while let Err(e) = send.send(&mut [size.clone(), bytes.clone()]).await {
-                log::debug!("Cannot send data to node: {e}, reconnecting...");
-                let (new_send, new_recv) =
-                    quic::try_connect_forever(&quic_client, address, &name).await;
-                tokio::spawn(reader_task(client.clone(), new_recv));
-                send = new_send;
+    /// Sends `data` to the process together with a reply capability and waits for the reply.
+    /// Returns `None` if no reply arrived within `timeout`.
+    ///
+    /// The reply is matched to the call by the capability token and handed over directly, it
+    /// never enters the mailbox of the calling process. Replies arriving after the call gave up
+    /// are rejected like an already used capability.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn call(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        priority: Priority,
+        data: Vec<u8>,
+        sender: Option<MessageSender>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>, ClientError> {
+        let token = uuid::Uuid::new_v4().as_u128();
+        let (reply_sender, reply) = oneshot::channel();
+        self.inner.pending_calls.insert(token, reply_sender);
+        let _call = PendingCall {
+            calls: &self.inner.pending_calls,
+            token,
+        };
+        let reply_cap = ReplyCapability {
+            node_id: self.inner.node_id,
+            token,
+        };
+        self.message_process(
+            node_id,
+            environment_id,
+            process_id,
+            None,
+            priority,
+            data,
+            Some(reply_cap),
+            sender,
+        )
+        .await?;
+        let reply = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, reply).await {
+                Ok(reply) => reply,
+                Err(_) => return Ok(None),
+            },
+            None => reply.await,
+        };
+        reply
+            .map(Some)
+            .map_err(|_| ClientError::Unexpected("Call was forgotten".to_string()))
+    }
+
+    /// Hands the reply to the call waiting for it. If no call waits for `token`, the data is
+    /// returned so that it can be delivered as a regular reply.
+    pub fn complete_call(&self, token: u128, data: Vec<u8>) -> Result<(), Vec<u8>> {
+        match self.inner.pending_calls.remove(&token) {
+            Some((_, call)) => {
+                // The caller could have stopped waiting in the meantime
+                call.send(data).ok();
+                Ok(())
             }
+            None => Err(data),
+        }
+    }
+
+    pub async fn reply(
+        &self,
+        reply_cap: ReplyCapability,
+        environment_id: EnvironmentId,
+        tag: Option<i64>,
+        data: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                reply_cap.node_id,
+                Request::Reply {
+                    environment_id,
+                    token: reply_cap.token,
+                    tag,
+                    data,
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for reply".to_string(),
+            )),
+        }
+    }
+
+    /// Publishes the message to the subscribers of `topic` in the environment on `node_id`, and
+    /// returns the number of subscribers it was delivered to.
+    pub async fn publish(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        topic: String,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+    ) -> Result<u64, ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::Publish {
+                    environment_id,
+                    topic,
+                    tag,
+                    priority,
+                    data,
+                },
+            )
+            .await
+        {
+            Ok(Response::Published(delivered)) => Ok(delivered),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for publish".to_string(),
+            )),
+        }
+    }
+
+    /// Kills the process `process_id` in the environment on `node_id`.
+    pub async fn kill(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::Kill {
+                    environment_id,
+                    process_id,
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for kill".to_string(),
+            )),
+        }
+    }
+
+    /// Asks `node_id` to send an exit notification tagged with `tag` to the process
+    /// `watcher_id` on this node when the process `process_id` exits. If the process doesn't
+    /// exist, the notification is sent right away.
+    pub async fn notify_on_exit(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: i64,
+        watcher_id: ProcessId,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::NotifyOnExit {
+                    environment_id,
+                    process_id,
+                    tag,
+                    watcher: (self.inner.node_id, watcher_id),
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for notify_on_exit".to_string(),
+            )),
         }
     }
+
+    /// Links the process `process_id` on `node_id` to the process `local_id` on this node. The
+    /// caller links `local_id` to a [`RemoteLink`](super::links::RemoteLink) of the process.
+    pub async fn link(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: Option<i64>,
+        local_id: ProcessId,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::Link {
+                    environment_id,
+                    process_id,
+                    tag,
+                    linked: (self.inner.node_id, local_id),
+                },
+            )
+            .await
+        {
+            Ok(Response::Linked) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for link".to_string(),
+            )),
+        }
+    }
+
+    /// Tells the process `process_id` on `node_id` that the linked process `linked_id` on this
+    /// node died.
+    pub async fn link_died(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        linked_id: ProcessId,
+        tag: Option<i64>,
+        failed: bool,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::LinkDied {
+                    environment_id,
+                    process_id,
+                    linked: (self.inner.node_id, linked_id),
+                    tag,
+                    failed,
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for link_died".to_string(),
+            )),
+        }
+    }
+
+    /// Monitors the process `process_id` on `node_id` for the monitor `monitor_id` of this node,
+    /// see [`Monitors`].
+    pub async fn monitor(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        monitor_id: u64,
+    ) -> Result<(), ClientError> {
+        let request = Request::Monitor {
+            environment_id,
+            process_id,
+            monitor_id,
+            node_id: self.inner.node_id,
+        };
+        self.monitor_request(node_id, request, "monitor").await
+    }
+
+    /// Tells `node_id` that the monitor `monitor_id` of this node was removed.
+    pub async fn demonitor(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        monitor_id: u64,
+    ) -> Result<(), ClientError> {
+        let request = Request::Demonitor {
+            environment_id,
+            monitor_id,
+            node_id: self.inner.node_id,
+        };
+        self.monitor_request(node_id, request, "demonitor").await
+    }
+
+    /// Delivers the message of the monitor `monitor_id` of `node_id`.
+    pub async fn process_down(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        monitor_id: u64,
+        data: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        let request = Request::ProcessDown {
+            environment_id,
+            monitor_id,
+            data,
+        };
+        self.monitor_request(node_id, request, "process_down").await
+    }
+
+    async fn monitor_request(
+        &self,
+        node_id: NodeId,
+        request: Request,
+        kind: &str,
+    ) -> Result<(), ClientError> {
+        match self.request(node_id, request).await {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(format!(
+                "Invalid response type for {kind}"
+            ))),
+        }
+    }
+
+    /// Kills all processes in the environment on `node_id` that hold the cancellation token and
+    /// returns how many were killed.
+    pub async fn cancel(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+        token: u64,
+    ) -> Result<u64, ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::Cancel {
+                    environment_id,
+                    token,
+                },
+            )
+            .await
+        {
+            Ok(Response::Cancelled(killed)) => Ok(killed),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for cancel".to_string(),
+            )),
+        }
+    }
+
+    /// Cancels the token on all other nodes the control server knows about and returns how many
+    /// processes were killed. Nodes that can't be reached are skipped, their processes can't be
+    /// cancelled.
+    pub async fn cancel_on_all_nodes(&self, environment_id: EnvironmentId, token: u64) -> u64 {
+        let mut killed = 0;
+        for node_id in self.inner.control_client.node_ids().into_iter().map(NodeId) {
+            if node_id == self.inner.node_id {
+                continue;
+            }
+            match self.cancel(node_id, environment_id, token).await {
+                Ok(count) => killed += count,
+                Err(error) => {
+                    log::warn!("Failed to cancel token {token} on node {node_id}: {error:?}")
+                }
+            }
+        }
+        killed
+    }
+
+    /// Sends the message to all members of the process group `group` and returns how many of
+    /// them it was delivered to.
+    ///
+    /// Members are sent the message one after another. Members that can't be reached, e.g.
+    /// because they finished in the meantime, are skipped.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_to_group(
+        &self,
+        environment_id: EnvironmentId,
+        group: &str,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+        sender: Option<MessageSender>,
+    ) -> Result<u64, ClientError> {
+        let mut delivered = 0;
+        for (node_id, process_id) in self.group_members(group).await? {
+            let sent = self
+                .message_process(
+                    node_id,
+                    environment_id,
+                    process_id,
+                    tag,
+                    priority,
+                    data.clone(),
+                    None,
+                    sender,
+                )
+                .await;
+            match sent {
+                Ok(()) => delivered += 1,
+                Err(error) => log::warn!(
+                    "Failed to send to process {process_id} of group {group} on node {node_id}: \
+                     {error:?}"
+                ),
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Kills all members of the process group `group` and returns how many were killed.
+    pub async fn kill_group(
+        &self,
+        environment_id: EnvironmentId,
+        group: &str,
+    ) -> Result<u64, ClientError> {
+        let mut killed = 0;
+        for (node_id, process_id) in self.group_members(group).await? {
+            match self.kill(node_id, environment_id, process_id).await {
+                Ok(()) => killed += 1,
+                Err(error) => log::warn!(
+                    "Failed to kill process {process_id} of group {group} on node {node_id}: \
+                     {error:?}"
+                ),
+            }
+        }
+        Ok(killed)
+    }
+
+    /// Asks the nodes of all members of the process group `group` to send an exit notification
+    /// tagged with `tag` to the process `watcher_id` on this node when the member exits. Returns
+    /// how many members are watched.
+    ///
+    /// Only the current members are watched, processes joining the group later are not.
+    pub async fn notify_on_exit_of_group(
+        &self,
+        environment_id: EnvironmentId,
+        group: &str,
+        tag: i64,
+        watcher_id: ProcessId,
+    ) -> Result<u64, ClientError> {
+        let mut watched = 0;
+        for (node_id, process_id) in self.group_members(group).await? {
+            match self
+                .notify_on_exit(node_id, environment_id, process_id, tag, watcher_id)
+                .await
+            {
+                Ok(()) => watched += 1,
+                Err(error) => log::warn!(
+                    "Failed to watch process {process_id} of group {group} on node {node_id}: \
+                     {error:?}"
+                ),
+            }
+        }
+        Ok(watched)
+    }
+
+    // Group membership is kept by the control server
+    async fn group_members(&self, group: &str) -> Result<Vec<(NodeId, ProcessId)>, ClientError> {
+        match self.inner.control_client.group_members(group).await {
+            Ok(members) => Ok(members
+                .into_iter()
+                .map(|(node_id, process_id)| (NodeId(node_id), ProcessId(process_id)))
+                .collect()),
+            Err(error) => {
+                log::warn!("Failed to get the members of group {group}: {error}");
+                Err(ClientError::ControlUnavailable)
+            }
+        }
+    }
+
+    /// Delivers the message to all `(node_id, process_id)` targets in the environment with a
+    /// best-effort two-phase delivery.
+    ///
+    /// The message is first staged on every node. Only if all stages succeed it's committed,
+    /// otherwise the stages are rolled back and no process receives the message. The commits are
+    /// not atomic: if a commit fails, e.g. because the node rolled back the stage after its
+    /// timeout or the connection broke, nodes that already committed delivered the message and it
+    /// can't be recalled. The error of the failed commit is returned in that case.
+    pub async fn send_two_phase(
+        &self,
+        environment_id: EnvironmentId,
+        targets: &[(NodeId, ProcessId)],
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+        sender: Option<MessageSender>,
+    ) -> Result<(), ClientError> {
+        let transaction_id = uuid::Uuid::new_v4().as_u128();
+        // All processes on a node are staged with one request.
+        let mut nodes: Vec<(NodeId, Vec<ProcessId>)> = Vec::new();
+        for &(node_id, process_id) in targets {
+            match nodes.iter_mut().find(|(node, _)| *node == node_id) {
+                Some((_, process_ids)) => process_ids.push(process_id),
+                None => nodes.push((node_id, vec![process_id])),
+            }
+        }
+
+        let mut staged = Vec::with_capacity(nodes.len());
+        let mut result = Ok(());
+        for (node_id, process_ids) in nodes {
+            let stage = Request::Stage {
+                environment_id,
+                transaction_id,
+                process_ids,
+                tag,
+                priority,
+                data: data.clone(),
+                sender: sender.map(|sender| (NodeId(sender.node_id), ProcessId(sender.process_id))),
+            };
+            match self.request(node_id, stage).await {
+                Ok(Response::Sent) => staged.push(node_id),
+                Ok(Response::Error(error)) | Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+                Ok(_) => {
+                    result = Err(ClientError::Unexpected(
+                        "Invalid response type for stage".to_string(),
+                    ));
+                    break;
+                }
+            }
+        }
+
+        let mut staged = staged.into_iter();
+        if result.is_ok() {
+            for node_id in staged.by_ref() {
+                let commit = Request::Commit {
+                    environment_id,
+                    transaction_id,
+                };
+                match self.request(node_id, commit).await {
+                    Ok(Response::Sent) => (),
+                    Ok(Response::Error(error)) | Err(error) => {
+                        result = Err(error);
+                        break;
+                    }
+                    Ok(_) => {
+                        result = Err(ClientError::Unexpected(
+                            "Invalid response type for commit".to_string(),
+                        ));
+                        break;
+                    }
+                }
+            }
+        }
+        // Roll back all stages that were not committed.
+        for node_id in staged {
+            let rollback = Request::Rollback {
+                environment_id,
+                transaction_id,
+            };
+            self.request(node_id, rollback).await.ok();
+        }
+        result
+    }
+
+    fn process_response(&self, id: u64, resp: Response) {
+        self.inner.requests.complete(id, resp);
+    }
+
+    pub async fn spawn(&self, node_id: NodeId, spawn: Spawn) -> Result<ProcessId, ClientError> {
+        let ids = self.spawn_processes(node_id, spawn, None).await?;
+        ids.first()
+            .copied()
+            .ok_or_else(|| ClientError::Unexpected("Invalid response type for spawn".to_string()))
+    }
+
+    /// Spawns the process on `node_id`, or on another node if `node_id` leaves the cluster before
+    /// the spawn finished or is drained. Returns the node the process was spawned on.
+    ///
+    /// A spawn is only moved after the control server stopped listing the node or the node
+    /// refused it, so a node that is just slow to respond doesn't end up with a duplicate of the
+    /// process. Nodes are tried at most once.
+    pub async fn spawn_with_fallback(
+        &self,
+        node_id: NodeId,
+        spawn: Spawn,
+    ) -> Result<(NodeId, ProcessId), ClientError> {
+        let mut tried = Vec::new();
+        let mut node_id = node_id;
+        loop {
+            tried.push(node_id);
+            let reason = tokio::select! {
+                result = self.spawn(node_id, spawn.clone()) => match result {
+                    Err(ClientError::NodeDraining) => "is draining",
+                    result => return result.map(|process_id| (node_id, process_id)),
+                },
+                _ = self.node_left(node_id) => "left during spawn",
+            };
+            let candidates: Vec<NodeId> = self
+                .inner
+                .control_client
+                .node_ids()
+                .into_iter()
+                .map(NodeId)
+                .filter(|node_id| !tried.contains(node_id))
+                .collect();
+            let fallback = self
+                .inner
+                .config
+                .placement
+                .place(spawn.lifecycle, &candidates);
+            match fallback {
+                Some(fallback) => {
+                    log::debug!("Node {node_id} {reason}, retrying on node {fallback}");
+                    node_id = fallback;
+                }
+                None => return Err(ClientError::NodeNotFound),
+            }
+        }
+    }
+
+    // Resolves once the control server doesn't list the node anymore.
+    async fn node_left(&self, node_id: NodeId) {
+        while self
+            .inner
+            .control_client
+            .node_ids()
+            .contains(&node_id.into())
+        {
+            tokio::time::sleep(NODE_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Spawns `count` processes from the same spawn with a single request. Each process gets its
+    /// index appended to the params as an i32. Returns the process ids ordered by index.
+    pub async fn spawn_replicated(
+        &self,
+        node_id: NodeId,
+        spawn: Spawn,
+        count: u32,
+    ) -> Result<Vec<ProcessId>, ClientError> {
+        self.spawn_processes(node_id, spawn, Some(count)).await
+    }
+
+    async fn spawn_processes(
+        &self,
+        node_id: NodeId,
+        spawn: Spawn,
+        replicas: Option<u32>,
+    ) -> Result<Vec<ProcessId>, ClientError> {
+        let handle = match &spawn.config {
+            SpawnConfig::Inline(config) if self.inner.config.reference_spawn_configs => {
+                config_handle(config)
+            }
+            _ => return self.spawn_request(node_id, spawn, replicas).await,
+        };
+        if self.inner.known_spawn_configs.contains(&(node_id, handle)) {
+            let by_reference = Spawn {
+                environment_id: spawn.environment_id,
+                module_id: spawn.module_id,
+                module_version: spawn.module_version.clone(),
+                function: spawn.function.clone(),
+                params: spawn.params.clone(),
+                config: SpawnConfig::Reference(handle),
+                idempotency_key: spawn.idempotency_key,
+                cancel_token: spawn.cancel_token,
+                lifecycle: spawn.lifecycle,
+            };
+            match self.spawn_request(node_id, by_reference, replicas).await {
+                // The node forgot the config, send it inline again.
+                Err(ClientError::UnknownConfig) => {
+                    self.inner.known_spawn_configs.remove(&(node_id, handle));
+                }
+                result => return result,
+            }
+        }
+        let result = self.spawn_request(node_id, spawn, replicas).await;
+        if result.is_ok() {
+            self.inner.known_spawn_configs.insert((node_id, handle));
+        }
+        result
+    }
+
+    /// Starts the spawn in the background and returns a token that can be passed to
+    /// `spawn_poll` to check if it finished.
+    pub fn spawn_async(&self, node_id: NodeId, spawn: Spawn) -> u64 {
+        let client = self.clone();
+        self.inner
+            .pending_spawns
+            .start(async move { client.spawn(node_id, spawn).await })
+    }
+
+    /// Returns the state of a spawn started with `spawn_async`, or `None` if the token is unknown
+    /// or its result expired.
+    pub fn spawn_poll(&self, token: u64) -> Option<SpawnPoll> {
+        self.inner.pending_spawns.poll(token)
+    }
+
+    /// Checks that `node_id` handles requests, without any side effects on the node.
+    pub async fn ping(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(node_id, Request::Ping { environment_id })
+            .await
+        {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for ping".to_string(),
+            )),
+        }
+    }
+
+    /// Probes `node_id` with updates of the gossiped membership and returns the updates it
+    /// answered with. If `target` is set, `node_id` probes the target instead, and only answers
+    /// if the target did.
+    pub async fn gossip(
+        &self,
+        node_id: NodeId,
+        updates: Vec<MemberUpdate>,
+        target: Option<NodeId>,
+    ) -> Result<Vec<MemberUpdate>, ClientError> {
+        let request = Request::Gossip {
+            environment_id: GOSSIP_ENVIRONMENT,
+            updates,
+            target,
+        };
+        match self.request(node_id, request).await {
+            Ok(Response::Gossip(updates)) => Ok(updates),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for gossip".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the load and capacity of `node_id`.
+    pub async fn node_info(
+        &self,
+        node_id: NodeId,
+        environment_id: EnvironmentId,
+    ) -> Result<NodeStats, ClientError> {
+        match self
+            .request(node_id, Request::NodeInfo { environment_id })
+            .await
+        {
+            Ok(Response::NodeInfo(stats)) => Ok(stats),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for node_info".to_string(),
+            )),
+        }
+    }
+
+    // Returns true if `target` answered a probe directly, or through one of the members asked to
+    // probe it.
+    async fn probe(&self, target: NodeId, membership: &control::gossip::Membership) -> bool {
+        let timeout = membership.config().probe_timeout;
+        let direct = self.gossip(target, membership.updates(), None);
+        if let Ok(Ok(updates)) = tokio::time::timeout(timeout, direct).await {
+            self.inner.control_client.apply_gossip(updates);
+            return true;
+        }
+        // Indirect probes take a round trip more
+        let probes: Vec<_> = membership
+            .helpers(target.0)
+            .into_iter()
+            .map(|helper| {
+                let (client, updates) = (self.clone(), membership.updates());
+                tokio::spawn(async move {
+                    let probe = client.gossip(NodeId(helper), updates, Some(target));
+                    tokio::time::timeout(timeout * 2, probe).await
+                })
+            })
+            .collect();
+        let mut answered = false;
+        for probe in probes {
+            if let Ok(Ok(Ok(updates))) = probe.await {
+                self.inner.control_client.apply_gossip(updates);
+                answered = true;
+            }
+        }
+        answered
+    }
+
+    /// Runs the checks of the self-test enabled in `config`, see [`SelfTestCheck`]. The round
+    /// trip spawns `spawn` and kills the process again, without a spawn it's skipped.
+    ///
+    /// Returns once all checks finished, at the latest after the timeout of the config.
+    pub async fn self_test(
+        &self,
+        environment_id: EnvironmentId,
+        spawn: Option<Spawn>,
+        config: SelfTestConfig,
+    ) -> SelfTestReport {
+        let deadline = tokio::time::Instant::now() + config.timeout;
+        let mut report = SelfTestReport::default();
+
+        if config.runs(SelfTestCheck::Control) {
+            let status =
+                match tokio::time::timeout_at(deadline, self.inner.control_client.refresh_nodes())
+                    .await
+                {
+                    Ok(Ok(())) => CheckStatus::Passed,
+                    Ok(Err(_)) => CheckStatus::Failed,
+                    Err(_) => CheckStatus::TimedOut,
+                };
+            report.push(SelfTestCheck::Control, 0, status);
+        }
+
+        let peers: Vec<NodeId> = self
+            .inner
+            .control_client
+            .node_ids()
+            .into_iter()
+            .map(NodeId)
+            .filter(|node_id| *node_id != self.inner.node_id)
+            .collect();
+        // Pinged concurrently, so that one slow node doesn't use up the time of the others
+        let pings: Vec<_> = peers
+            .iter()
+            .map(|node_id| {
+                let (client, node_id) = (self.clone(), *node_id);
+                tokio::spawn(async move {
+                    tokio::time::timeout_at(deadline, client.ping(node_id, environment_id)).await
+                })
+            })
+            .collect();
+        let mut responding = Vec::new();
+        for (node_id, ping) in peers.iter().zip(pings) {
+            let status = match ping.await {
+                Ok(Ok(Ok(()))) => {
+                    responding.push(*node_id);
+                    CheckStatus::Passed
+                }
+                Ok(Ok(Err(_))) | Err(_) => CheckStatus::Failed,
+                Ok(Err(_)) => CheckStatus::TimedOut,
+            };
+            if config.runs(SelfTestCheck::Peers) {
+                report.push(SelfTestCheck::Peers, node_id.0, status);
+            }
+        }
+        if config.runs(SelfTestCheck::Peers) && peers.is_empty() {
+            report.push(SelfTestCheck::Peers, 0, CheckStatus::Skipped);
+        }
+
+        if config.runs(SelfTestCheck::RoundTrip) {
+            let (node_id, status) = match (responding.first(), spawn) {
+                (Some(node_id), Some(spawn)) => {
+                    // Keeps running after the deadline, so that the process is still killed
+                    let (client, node_id) = (self.clone(), *node_id);
+                    let round_trip =
+                        tokio::spawn(async move { client.round_trip(node_id, spawn).await });
+                    let status = match tokio::time::timeout_at(deadline, round_trip).await {
+                        Ok(Ok(Ok(()))) => CheckStatus::Passed,
+                        Ok(Ok(Err(_))) | Ok(Err(_)) => CheckStatus::Failed,
+                        Err(_) => CheckStatus::TimedOut,
+                    };
+                    (node_id.0, status)
+                }
+                _ => (0, CheckStatus::Skipped),
+            };
+            report.push(SelfTestCheck::RoundTrip, node_id, status);
+        }
+        report
+    }
+
+    // Spawns the process on `node_id`, sends it an empty message and kills it.
+    async fn round_trip(&self, node_id: NodeId, spawn: Spawn) -> Result<(), ClientError> {
+        let environment_id = spawn.environment_id;
+        let process_id = self.spawn(node_id, spawn).await?;
+        let sent = self
+            .message_process(
+                node_id,
+                environment_id,
+                process_id,
+                None,
+                Priority::Normal,
+                Vec::new(),
+                None,
+                None,
+            )
+            .await;
+        // Killed also if the message couldn't be sent, to not leave the process behind
+        let killed = self.kill(node_id, environment_id, process_id).await;
+        sent.and(killed)
+    }
+
+    async fn spawn_request(
+        &self,
+        node_id: NodeId,
+        spawn: Spawn,
+        replicas: Option<u32>,
+    ) -> Result<Vec<ProcessId>, ClientError> {
+        let request = match replicas {
+            Some(count) => Request::SpawnReplicated { spawn, count },
+            None => Request::Spawn(spawn),
+        };
+        match self.request(node_id, request).await {
+            Ok(Response::Spawned(id)) => Ok(vec![id]),
+            Ok(Response::SpawnedMany(ids)) => Ok(ids),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for spawn".to_string(),
+            )),
+        }
+    }
+}
+
+// Forgets the call once it's done, also if the caller stops waiting before the reply arrives.
+struct PendingCall<'a> {
+    calls: &'a DashMap<u128, oneshot::Sender<Vec<u8>>>,
+    token: u128,
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        self.calls.remove(&self.token);
+    }
+}
+
+async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
+    loop {
+        match recv.receive().await {
+            Ok(bytes) => {
+                let (msg_id, response) =
+                    bincode::deserialize::<(u64, super::message::Response)>(&bytes)?;
+                client.process_response(msg_id, response);
+                Ok(())
+            }
+            Err(e) => {
+                log::debug!("Node connection error: {e}");
+                Err(e)
+            }
+        }?;
+    }
+}
+
+// Reports are sent even if the connections didn't change, so that a restarted control server
+// learns the topology again.
+// Invalidates the unused reply capabilities of the process once it finishes.
+async fn forget_reply_capabilities_task(
+    client: Client,
+    environment_id: EnvironmentId,
+    process_id: ProcessId,
+    process: SignalSender,
+) {
+    process.closed().await;
+    let key = (environment_id, process_id);
+    if let Some((_, tokens)) = client.inner.reply_capabilities_of.remove(&key) {
+        for token in tokens {
+            client.inner.reply_capabilities.remove(&token);
+        }
+    }
+}
+
+async fn report_peers_task(client: Client) {
+    loop {
+        tokio::time::sleep(PEER_REPORT_INTERVAL).await;
+        let peers = client.connected_nodes();
+        client
+            .inner
+            .control_client
+            .report_peers(client.inner.node_id.0, peers)
+            .await
+            .ok();
+    }
+}
+
+// Links to processes on nodes that left the cluster die and their monitors are delivered, as
+// their `LinkDied` or `ProcessDown` will never arrive
+async fn node_left_task(client: Client) {
+    let (sender, mut receiver) = unbounded_channel();
+    client
+        .inner
+        .control_client
+        .node_events()
+        .subscribe(0, sender);
+    while let Some(signal) = receiver.recv().await {
+        if let Signal::Message(Message::Data(event)) = signal {
+            if event.buffer.get(8) == Some(&0) {
+                let node_id = u64::from_le_bytes(event.buffer[..8].try_into().unwrap());
+                let links = client.inner.remote_links.node_left(NodeId(node_id));
+                let monitors = client.inner.monitors.node_left(NodeId(node_id));
+                if links > 0 || monitors > 0 {
+                    log::debug!(
+                        "Node {node_id} left, {links} links and {monitors} monitors of its \
+                         processes died"
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Probes one member of the gossiped membership per protocol period
+async fn gossip_task(client: Client) {
+    let control_client = client.inner.control_client.clone();
+    let membership = match control_client.membership() {
+        Some(membership) => membership,
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(membership.config().period).await;
+        if let Some(target) = membership.next_target() {
+            if !client.probe(NodeId(target), membership).await {
+                membership.suspect(target);
+            }
+        }
+        control_client.expire_suspects();
+    }
+}
+
+async fn redeliver_task(client: Client) {
+    let window = client.inner.config.redelivery_window;
+    loop {
+        // Messages are sent again at most half a window late
+        tokio::time::sleep(window / 2).await;
+        for (delivery_id, message) in client.inner.delivery_log.due(window) {
+            let client = client.clone();
+            tokio::spawn(async move { client.deliver(delivery_id, message).await.ok() });
+        }
+    }
+}
+
+async fn compact_log_task(client: Client, interval: Duration) {
+    let threshold = client.inner.config.log_compaction_threshold;
+    loop {
+        tokio::time::sleep(interval).await;
+        if client.inner.delivery_log.compact(threshold) {
+            log::debug!(
+                "Compacted delivery log, {} messages pending",
+                client.inner.delivery_log.pending()
+            );
+        }
+    }
+}
+
+async fn forward_node_messages(client: Client, mut rx: UnboundedReceiver<SendRequest>) {
+    while let Some(SendRequest {
+        msg_id,
+        node_id,
+        request,
+    }) = rx.recv().await
+    {
+        let channel = (node_id, request.plane(), request.environment_id());
+        // The buffer of a channel that is closing doesn't take new requests anymore, they go to a
+        // new channel instead of being dropped.
+        let msg = match client.inner.node_message_buffers.get(&channel) {
+            Some(node_buf) => match node_buf.value().send((msg_id, request)) {
+                Ok(()) => continue,
+                Err(mpsc::error::SendError(msg)) => msg,
+            },
+            None => (msg_id, request),
+        };
+        let (send, recv) = unbounded_channel();
+        send.send(msg).ok();
+        client.inner.node_message_buffers.insert(channel, send);
+        tokio::spawn(manage_node_channel(channel, client.clone(), recv));
+    }
+}
+
+async fn try_node_info_forever(node_id: NodeId, client: &Client) -> NodeInfo {
+    loop {
+        if let Some(node_info) = client.inner.control_client.node_info(node_id.into()) {
+            return node_info;
+        }
+        if let Err(e) = client.inner.control_client.refresh_nodes().await {
+            // Don't spin while the control server can't be reached
+            log::debug!("Cannot refresh nodes to find node {node_id}: {e}");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+// Returns the address of the listener on the node that accepts requests of the plane. Nodes
+// without a separate control-plane listener accept all requests on their main address.
+fn plane_address(node_info: &NodeInfo, plane: Plane) -> SocketAddr {
+    match (plane, node_info.control_address) {
+        (Plane::Control, Some(control_address)) => control_address,
+        _ => node_info.address,
+    }
+}
+
+// Opens a new channel to the node, reusing the connection to the node if one is already open for
+// the plane.
+async fn open_node_channel(
+    node_id: NodeId,
+    plane: Plane,
+    client: &Client,
+) -> (SendStream, RecvStream) {
+    let quic_client = client.inner.quic_client.clone();
+    let slot = client
+        .inner
+        .node_connections
+        .entry((node_id, plane))
+        .or_default()
+        .clone();
+    let mut node_info = None;
+    loop {
+        let mut connection = slot.lock().await;
+        if let Some(conn) = connection.clone().filter(|conn| !conn.is_closed()) {
+            match conn.open_stream().await {
+                Ok(streams) => return streams,
+                Err(e) => {
+                    log::debug!("Cannot open channel to node {node_id}: {e}, reconnecting...");
+                    *connection = None;
+                }
+            }
+        }
+        match node_info.take() {
+            Some(node_info) => {
+                let address = plane_address(&node_info, plane);
+                let name = node_info.name;
+                *connection =
+                    Some(quic::try_open_connection_forever(&quic_client, address, &name).await);
+            }
+            // The node info is resolved without holding the lock, the node could be unknown to
+            // the control server for a while. Another channel could connect in the meantime.
+            None => {
+                drop(connection);
+                node_info = Some(try_node_info_forever(node_id, client).await);
+            }
+        }
+    }
+}
+
+// Drops the connection to the node once the last channel to the node on the plane is closed.
+fn drop_unused_connection(client: &Client, node_id: NodeId, plane: Plane) {
+    // Checked while holding the connection entry, a channel opened right after the check gets a
+    // new connection instead of losing the one it's about to use.
+    client
+        .inner
+        .node_connections
+        .remove_if(&(node_id, plane), |_, _| {
+            !client
+                .inner
+                .node_message_buffers
+                .iter()
+                .any(|entry| entry.key().0 == node_id && entry.key().1 == plane)
+        });
+}
+
+async fn manage_node_channel(
+    channel: (NodeId, Plane, EnvironmentId),
+    client: Client,
+    mut rx: UnboundedReceiver<(u64, Request)>,
+) {
+    let (node_id, plane, _) = channel;
+    let (mut send, recv) = open_node_channel(node_id, plane, &client).await;
+    tokio::spawn(reader_task(client.clone(), recv));
+    let mut idle = false;
+    loop {
+        let msg = match client.inner.config.idle_timeout {
+            Some(idle_timeout) if !idle => {
+                match tokio::time::timeout(idle_timeout, rx.recv()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        log::debug!("Closing idle channel {channel:?}");
+                        // New requests will open a new channel, but the ones already in the
+                        // buffer still need to be sent over this one.
+                        client.inner.node_message_buffers.remove(&channel);
+                        rx.close();
+                        idle = true;
+                        continue;
+                    }
+                }
+            }
+            _ => rx.recv().await,
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => break,
+        };
+        let (msg_id, request) = &msg;
+        if let Ok(data) = bincode::serialize(&(msg_id, request, &client.inner.config.join_token)) {
+            let size = (data.len() as u32).to_le_bytes();
+            let size: Bytes = Bytes::copy_from_slice(&size[..]);
+            let bytes: Bytes = data.into();
+            while let Err(e) = send.send(&mut [size.clone(), bytes.clone()]).await {
+                log::debug!("Cannot send data to node: {e}, reconnecting...");
+                let (new_send, new_recv) = open_node_channel(node_id, plane, &client).await;
+                tokio::spawn(reader_task(client.clone(), new_recv));
+                send = new_send;
+            }
+        }
+        client.inner.buffered_sends.remove(&msg.0);
+    }
+    // Let the other side finish responding to in-flight requests and close the stream.
+    send.finish().await.ok();
+    drop_unused_connection(&client, node_id, plane);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use lunatic_process::{
+        env::Environment,
+        lifecycle::Lifecycle,
+        message::{DataMessage, MessageSender, Priority},
+        Process,
+    };
+    use tokio::sync::{mpsc::unbounded_channel, Mutex};
+
+    use super::{
+        drop_unused_connection, open_node_channel, plane_address, Client, ClientConfig,
+        SpawnParamsTooLarge, StringArgTooLong,
+    };
+    use crate::{
+        control::{
+            self,
+            message::Registration,
+            server::{root_cert, serve_in_memory, Server},
+        },
+        distributed::{
+            message::{pack_response, ClientError, Plane, Request, Response, Spawn},
+            self_test::{CheckStatus, SelfTestCheck, SelfTestConfig},
+            spawn_config::SpawnConfig,
+        },
+        quic::{self, handle_in_memory_node_connection, ConnectionConfig},
+        test_node::test_node,
+        EnvironmentId, ModuleId, NodeId, NodeInfo, ProcessId,
+    };
+
+    fn spawn() -> Spawn {
+        Spawn {
+            environment_id: EnvironmentId(1),
+            module_id: ModuleId(1),
+            module_version: None,
+            function: "hello".to_string(),
+            params: vec![],
+            config: SpawnConfig::Inline(vec![]),
+            idempotency_key: None,
+            cancel_token: None,
+            lifecycle: Lifecycle::Unspecified,
+        }
+    }
+
+    #[test]
+    fn over_long_string_args_are_rejected() {
+        let config = ClientConfig::default();
+        assert!(config.check_string_arg(4096).is_ok());
+        let error = config.check_string_arg(64 * 1024 * 1024).unwrap_err();
+        assert_eq!(
+            error,
+            StringArgTooLong {
+                len: 64 * 1024 * 1024,
+                max_len: 4096
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "String argument takes 67108864 bytes, but at most 4096 bytes are allowed."
+        );
+    }
+
+    #[test]
+    fn over_limit_spawn_params_are_rejected() {
+        let config = ClientConfig::default();
+        // 3855 params of 17 bytes each fit into the default limit of 64 KiB, one more doesn't
+        assert!(config.check_spawn_params(3855 * 17).is_ok());
+        let error = config.check_spawn_params(3856 * 17).unwrap_err();
+        assert_eq!(
+            error,
+            SpawnParamsTooLarge {
+                len: 65552,
+                max_len: 65536
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Spawn params take 65552 bytes (3856 params), but at most 65536 bytes are allowed."
+        );
+    }
+
+    fn spawn_request() -> Request {
+        Request::Spawn(spawn())
+    }
+
+    fn ping_request() -> Request {
+        Request::Ping {
+            environment_id: EnvironmentId(1),
+        }
+    }
+
+    #[test]
+    fn requests_are_routed_to_listener_of_their_plane() {
+        let node_info = NodeInfo {
+            id: 1,
+            address: "127.0.0.1:10000".parse().unwrap(),
+            name: "node".to_string(),
+            control_address: Some("127.0.0.1:10001".parse().unwrap()),
+        };
+        assert_eq!(
+            plane_address(&node_info, ping_request().plane()),
+            "127.0.0.1:10001".parse().unwrap()
+        );
+        assert_eq!(
+            plane_address(&node_info, spawn_request().plane()),
+            "127.0.0.1:10000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn single_listener_receives_all_requests() {
+        let node_info = NodeInfo {
+            id: 1,
+            address: "127.0.0.1:10000".parse().unwrap(),
+            name: "node".to_string(),
+            control_address: None,
+        };
+        for request in [ping_request(), spawn_request()] {
+            assert_eq!(
+                plane_address(&node_info, request.plane()),
+                "127.0.0.1:10000".parse().unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn buffered_sends_arrive_in_order() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig {
+                max_buffered_sends: 16,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        client.inner.node_connections.insert(
+            (NodeId(2), Plane::Data),
+            Arc::new(Mutex::new(Some(connection))),
+        );
+
+        let messages = 1000;
+        let node = tokio::spawn(async move {
+            let (_send, mut recv) = acceptor.accept().await.unwrap();
+            let mut tags = Vec::new();
+            while tags.len() < messages {
+                let bytes = recv.receive().await.unwrap();
+                match bincode::deserialize::<(u64, Request)>(&bytes).unwrap() {
+                    (_, Request::Message { tag, .. }) => tags.push(tag.unwrap()),
+                    (_, request) => panic!("unexpected request {request:?}"),
+                }
+            }
+            tags
+        });
+
+        let mut would_block = 0;
+        for tag in 0..messages as i64 {
+            let mut data = vec![0; 64];
+            while let Err(returned) = client.try_message_process(
+                NodeId(2),
+                EnvironmentId(1),
+                ProcessId(1),
+                Some(tag),
+                Priority::Normal,
+                data,
+                None,
+            ) {
+                data = returned;
+                would_block += 1;
+                tokio::task::yield_now().await;
+            }
+        }
+        let tags = node.await.unwrap();
+        assert_eq!(tags, (0..messages as i64).collect::<Vec<_>>());
+        // The buffer filled up faster than it was sent
+        assert!(would_block > 0);
+    }
+
+    #[tokio::test]
+    async fn overlapping_requests_get_their_own_responses() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        client.inner.node_connections.insert(
+            (NodeId(2), Plane::Data),
+            Arc::new(Mutex::new(Some(connection))),
+        );
+
+        let requests = 100;
+        let spawns: Vec<_> = (0..requests)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let spawn = Spawn {
+                        function: i.to_string(),
+                        ..spawn()
+                    };
+                    (i, client.spawn(NodeId(2), spawn).await.unwrap())
+                })
+            })
+            .collect();
+
+        // All requests share one stream, the node answers them in reverse order
+        let (mut send, mut recv) = acceptor.accept().await.unwrap();
+        let mut received = Vec::new();
+        while received.len() < requests as usize {
+            let bytes = recv.receive().await.unwrap();
+            match bincode::deserialize(&bytes).unwrap() {
+                (msg_id, Request::Spawn(spawn)) => {
+                    received.push((msg_id, spawn.function.parse::<u64>().unwrap()))
+                }
+                (_, request) => panic!("unexpected request {request:?}"),
+            }
+        }
+        for (msg_id, i) in received.into_iter().rev() {
+            send.send(&mut pack_response(msg_id, Response::Spawned(ProcessId(i))))
+                .await
+                .unwrap();
+        }
+
+        for spawn in spawns {
+            let (i, process_id) = spawn.await.unwrap();
+            assert_eq!(process_id, ProcessId(i));
+        }
+        assert_eq!(client.inner.requests.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_channel_does_not_stall_others_on_same_connection() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        client.inner.node_connections.insert(
+            (NodeId(2), Plane::Data),
+            Arc::new(Mutex::new(Some(connection))),
+        );
+
+        // Each environment gets its own channel on the one connection
+        let spawn_in = |environment_id| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let spawn = Spawn {
+                    environment_id: EnvironmentId(environment_id),
+                    ..spawn()
+                };
+                client.spawn(NodeId(2), spawn).await.unwrap()
+            })
+        };
+        let slow = spawn_in(1);
+        let (mut slow_send, mut slow_recv) = acceptor.accept().await.unwrap();
+        let bytes = slow_recv.receive().await.unwrap();
+        let (slow_msg_id, _): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+
+        // The first channel isn't answered, the second one still gets through
+        let fast = spawn_in(2);
+        let (mut fast_send, mut fast_recv) = acceptor.accept().await.unwrap();
+        let bytes = fast_recv.receive().await.unwrap();
+        match bincode::deserialize(&bytes).unwrap() {
+            (msg_id, Request::Spawn(spawn)) => {
+                assert_eq!(spawn.environment_id, EnvironmentId(2));
+                let response = Response::Spawned(ProcessId(20));
+                fast_send
+                    .send(&mut pack_response(msg_id, response))
+                    .await
+                    .unwrap();
+            }
+            (_, request) => panic!("unexpected request {request:?}"),
+        }
+        assert_eq!(fast.await.unwrap(), ProcessId(20));
+        assert!(!slow.is_finished());
+
+        let response = Response::Spawned(ProcessId(10));
+        slow_send
+            .send(&mut pack_response(slow_msg_id, response))
+            .await
+            .unwrap();
+        assert_eq!(slow.await.unwrap(), ProcessId(10));
+        assert_eq!(client.inner.node_connections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_node_does_not_hold_connection_lock() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        // Node 2 is not known to the control server, so the channel waits for its node info
+        let opening = {
+            let client = client.clone();
+            tokio::spawn(async move { open_node_channel(NodeId(2), Plane::Data, &client).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let slot = client
+            .inner
+            .node_connections
+            .get(&(NodeId(2), Plane::Data))
+            .unwrap()
+            .clone();
+        assert!(slot.try_lock().is_ok());
+
+        // Channels opened once there is a connection use it right away
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        *slot.lock().await = Some(connection);
+        assert!(!opening.is_finished());
+        opening.abort();
+        let client = client.clone();
+        let channel =
+            tokio::spawn(async move { open_node_channel(NodeId(2), Plane::Data, &client).await });
+        assert!(acceptor.accept().await.is_some());
+        channel.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connection_is_kept_while_channels_are_left() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let (connection, _acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        let slot = (NodeId(2), Plane::Data);
+        client
+            .inner
+            .node_connections
+            .insert(slot, Arc::new(Mutex::new(Some(connection))));
+
+        // Another environment still has a channel to the node
+        let (buffer, _) = unbounded_channel();
+        let channel = (NodeId(2), Plane::Data, EnvironmentId(2));
+        client.inner.node_message_buffers.insert(channel, buffer);
+        drop_unused_connection(&client, NodeId(2), Plane::Data);
+        assert!(client.inner.node_connections.contains_key(&slot));
+
+        client.inner.node_message_buffers.remove(&channel);
+        drop_unused_connection(&client, NodeId(2), Plane::Data);
+        assert!(!client.inner.node_connections.contains_key(&slot));
+    }
+
+    // Answers every request on the stream until the client closes it, returns the number of
+    // answered requests.
+    async fn answer_until_closed(mut send: quic::SendStream, mut recv: quic::RecvStream) -> usize {
+        let mut answered = 0;
+        while let Ok(bytes) = recv.receive().await {
+            let (msg_id, _): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+            send.send(&mut pack_response(msg_id, Response::Sent))
+                .await
+                .unwrap();
+            answered += 1;
+        }
+        answered
+    }
+
+    #[tokio::test]
+    async fn idle_channels_are_closed_and_reopened() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig {
+                idle_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        let slot = (NodeId(2), Plane::Control);
+        let channel = (NodeId(2), Plane::Control, EnvironmentId(1));
+        let connect = || {
+            client
+                .inner
+                .node_connections
+                .insert(slot, Arc::new(Mutex::new(Some(connection.clone()))));
+        };
+        // Answers the requests on the next channel until it's closed
+        let next_channel = |mut acceptor: quic::InMemoryAcceptor| {
+            tokio::spawn(async move {
+                let (send, recv) = acceptor.accept().await.unwrap();
+                let answered = answer_until_closed(send, recv).await;
+                (acceptor, answered)
+            })
+        };
+
+        connect();
+        let first = next_channel(acceptor);
+        client
+            .kill(NodeId(2), EnvironmentId(1), ProcessId(1))
+            .await
+            .unwrap();
+        // The channel is closed once it's idle, together with the connection
+        let (acceptor, answered) = first.await.unwrap();
+        assert_eq!(answered, 1);
+        assert!(!client.inner.node_message_buffers.contains_key(&channel));
+        while client.inner.node_connections.contains_key(&slot) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // The next request opens a new channel
+        connect();
+        let second = next_channel(acceptor);
+        client
+            .kill(NodeId(2), EnvironmentId(1), ProcessId(2))
+            .await
+            .unwrap();
+
+        // A request for a channel that is closing goes to a new channel instead of being dropped
+        let (closing, _) = unbounded_channel();
+        client.inner.node_message_buffers.insert(channel, closing);
+        let (acceptor, answered) = second.await.unwrap();
+        assert_eq!(answered, 1);
+        let third = next_channel(acceptor);
+        client
+            .kill(NodeId(2), EnvironmentId(1), ProcessId(3))
+            .await
+            .unwrap();
+        assert_eq!(third.await.unwrap().1, 1);
+    }
+
+    #[tokio::test]
+    async fn reply_capability_is_used_once() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let target = (EnvironmentId(1), ProcessId(7));
+        let (process, mailbox) = unbounded_channel();
+
+        let reply_cap = client.mint_reply_capability(target.0, target.1, process.clone());
+        assert_eq!(reply_cap.node_id, NodeId(1));
+        assert_eq!(
+            client.redeem_reply_capability(reply_cap.token),
+            Some(target)
+        );
+        assert_eq!(client.redeem_reply_capability(reply_cap.token), None);
+        // Tokens that were never minted are rejected
+        assert_eq!(client.redeem_reply_capability(reply_cap.token + 1), None);
+        assert!(client.inner.reply_capabilities.is_empty());
+        assert!(client
+            .inner
+            .reply_capabilities_of
+            .get(&target)
+            .unwrap()
+            .is_empty());
+
+        // Unused capabilities are forgotten once the process finishes
+        let unused = client.mint_reply_capability(target.0, target.1, process);
+        assert_eq!(client.inner.reply_capabilities.len(), 1);
+        drop(mailbox);
+        while !client.inner.reply_capabilities.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(client.inner.reply_capabilities_of.is_empty());
+        assert_eq!(client.redeem_reply_capability(unused.token), None);
+    }
+
+    #[tokio::test]
+    async fn call_receives_its_reply() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        client.inner.node_connections.insert(
+            (NodeId(2), Plane::Data),
+            Arc::new(Mutex::new(Some(connection))),
+        );
+
+        // The called process doubles every byte of the request and replies with the capability,
+        // as the server of this node would when the reply arrives.
+        let node = tokio::spawn({
+            let client = client.clone();
+            async move {
+                let (mut send, mut recv) = acceptor.accept().await.unwrap();
+                for _ in 0..2 {
+                    let bytes = recv.receive().await.unwrap();
+                    let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+                    let (data, reply_cap) = match request {
+                        Request::Message {
+                            tag: None,
+                            data,
+                            reply_cap: Some(reply_cap),
+                            ..
+                        } => (data.into_data().unwrap(), reply_cap),
+                        request => panic!("unexpected request {request:?}"),
+                    };
+                    send.send(&mut pack_response(msg_id, Response::Sent))
+                        .await
+                        .unwrap();
+                    let reply = data.iter().map(|byte| byte * 2).collect();
+                    assert!(client.complete_call(reply_cap.token, reply).is_ok());
+                }
+            }
+        });
+
+        for request in [vec![1, 2, 3], vec![4]] {
+            let reply = client
+                .call(
+                    NodeId(2),
+                    EnvironmentId(1),
+                    ProcessId(1),
+                    Priority::Normal,
+                    request.clone(),
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            let expected: Vec<u8> = request.iter().map(|byte| byte * 2).collect();
+            assert_eq!(reply, Some(expected));
+        }
+        node.await.unwrap();
+        assert!(client.inner.pending_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn late_replies_to_calls_are_rejected() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        client.inner.node_connections.insert(
+            (NodeId(2), Plane::Data),
+            Arc::new(Mutex::new(Some(connection))),
+        );
+
+        let node = tokio::spawn(async move {
+            let (mut send, mut recv) = acceptor.accept().await.unwrap();
+            let bytes = recv.receive().await.unwrap();
+            let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+            send.send(&mut pack_response(msg_id, Response::Sent))
+                .await
+                .unwrap();
+            match request {
+                Request::Message {
+                    reply_cap: Some(reply_cap),
+                    ..
+                } => reply_cap.token,
+                request => panic!("unexpected request {request:?}"),
+            }
+        });
+        let reply = client
+            .call(
+                NodeId(2),
+                EnvironmentId(1),
+                ProcessId(1),
+                Priority::Normal,
+                vec![1],
+                None,
+                Some(Duration::from_millis(10)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply, None);
+        let token = node.await.unwrap();
+        assert_eq!(client.complete_call(token, vec![2]), Err(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn spawn_falls_back_when_target_node_leaves() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let control_client = control::Client::detached();
+        control_client.set_node_ids(vec![2, 3]);
+        let client = Client::new(
+            NodeId(1),
+            control_client.clone(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let mut acceptors = Vec::new();
+        for node_id in [2, 3] {
+            let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+            client.inner.node_connections.insert(
+                (NodeId(node_id), Plane::Data),
+                Arc::new(Mutex::new(Some(connection))),
+            );
+            acceptors.push(acceptor);
+        }
+
+        let spawning = tokio::spawn({
+            let client = client.clone();
+            async move { client.spawn_with_fallback(NodeId(2), spawn()).await }
+        });
+        // The target node receives the spawn, but leaves the cluster before responding.
+        let (_send, mut recv) = acceptors[0].accept().await.unwrap();
+        recv.receive().await.unwrap();
+        control_client.set_node_ids(vec![3]);
+
+        let (mut send, mut recv) = acceptors[1].accept().await.unwrap();
+        let bytes = recv.receive().await.unwrap();
+        let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(request, Request::Spawn(_)));
+        send.send(&mut pack_response(msg_id, Response::Spawned(ProcessId(9))))
+            .await
+            .unwrap();
+
+        let spawned = spawning.await.unwrap().unwrap();
+        assert_eq!(spawned, (NodeId(3), ProcessId(9)));
+    }
+
+    #[tokio::test]
+    async fn persistent_spawns_fall_back_to_oldest_node() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let control_client = control::Client::detached();
+        control_client.set_node_ids(vec![2, 4, 3]);
+        let client = Client::new(
+            NodeId(1),
+            control_client.clone(),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let mut acceptors = Vec::new();
+        for node_id in [2, 3] {
+            let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+            client.inner.node_connections.insert(
+                (NodeId(node_id), Plane::Data),
+                Arc::new(Mutex::new(Some(connection))),
+            );
+            acceptors.push(acceptor);
+        }
+
+        let spawning = tokio::spawn({
+            let client = client.clone();
+            let spawn = Spawn {
+                lifecycle: Lifecycle::Persistent,
+                ..spawn()
+            };
+            async move { client.spawn_with_fallback(NodeId(2), spawn).await }
+        });
+        let (_send, mut recv) = acceptors[0].accept().await.unwrap();
+        recv.receive().await.unwrap();
+        control_client.set_node_ids(vec![4, 3]);
+
+        // Node 3 joined the cluster before node 4
+        let (mut send, mut recv) = acceptors[1].accept().await.unwrap();
+        let bytes = recv.receive().await.unwrap();
+        let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+        match request {
+            Request::Spawn(spawn) => assert_eq!(spawn.lifecycle, Lifecycle::Persistent),
+            request => panic!("unexpected request {request:?}"),
+        }
+        send.send(&mut pack_response(msg_id, Response::Spawned(ProcessId(9))))
+            .await
+            .unwrap();
+
+        let spawned = spawning.await.unwrap().unwrap();
+        assert_eq!(spawned, (NodeId(3), ProcessId(9)));
+    }
+
+    #[tokio::test]
+    async fn spawns_move_off_draining_nodes() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let control_client = control::Client::detached();
+        control_client.set_node_ids(vec![2, 3]);
+        let client = Client::new(
+            NodeId(1),
+            control_client,
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let mut acceptors = Vec::new();
+        for node_id in [2, 3] {
+            let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+            client.inner.node_connections.insert(
+                (NodeId(node_id), Plane::Data),
+                Arc::new(Mutex::new(Some(connection))),
+            );
+            acceptors.push(acceptor);
+        }
+
+        let spawning = tokio::spawn({
+            let client = client.clone();
+            async move { client.spawn_with_fallback(NodeId(2), spawn()).await }
+        });
+        // Node 2 stays in the cluster, but refuses the spawn
+        let mut draining = acceptors[0].accept().await.unwrap();
+        let bytes = draining.1.receive().await.unwrap();
+        let (msg_id, _): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+        let refuse = |msg_id| pack_response(msg_id, Response::Error(ClientError::NodeDraining));
+        draining.0.send(&mut refuse(msg_id)).await.unwrap();
+
+        let mut other = acceptors[1].accept().await.unwrap();
+        let bytes = other.1.receive().await.unwrap();
+        let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(request, Request::Spawn(_)));
+        other
+            .0
+            .send(&mut pack_response(msg_id, Response::Spawned(ProcessId(9))))
+            .await
+            .unwrap();
+        assert_eq!(spawning.await.unwrap().unwrap(), (NodeId(3), ProcessId(9)));
+
+        // Without another node the spawn fails, the requests reuse the open channels
+        let spawning = tokio::spawn({
+            let client = client.clone();
+            async move { client.spawn_with_fallback(NodeId(2), spawn()).await }
+        });
+        for (send, recv) in [&mut draining, &mut other] {
+            let bytes = recv.receive().await.unwrap();
+            let (msg_id, _): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+            send.send(&mut refuse(msg_id)).await.unwrap();
+        }
+        assert!(matches!(
+            spawning.await.unwrap(),
+            Err(ClientError::NodeNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancel_reaches_all_other_nodes() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let control_client = control::Client::detached();
+        control_client.set_node_ids(vec![1, 2, 3]);
+        let client = Client::new(
+            NodeId(1),
+            control_client,
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let mut nodes = Vec::new();
+        for (node_id, holders) in [(2, 2), (3, 1)] {
+            let (connection, mut acceptor) =
+                quic::Connection::in_memory(ConnectionConfig::default());
+            client.inner.node_connections.insert(
+                (NodeId(node_id), Plane::Control),
+                Arc::new(Mutex::new(Some(connection))),
+            );
+            nodes.push(tokio::spawn(async move {
+                let (mut send, mut recv) = acceptor.accept().await.unwrap();
+                let bytes = recv.receive().await.unwrap();
+                let (msg_id, request): (u64, Request) = bincode::deserialize(&bytes).unwrap();
+                assert!(matches!(request, Request::Cancel { token: 42, .. }));
+                send.send(&mut pack_response(msg_id, Response::Cancelled(holders)))
+                    .await
+                    .unwrap();
+            }));
+        }
+
+        // The descendants of the token on nodes 2 and 3 are killed, this node isn't asked
+        assert_eq!(client.cancel_on_all_nodes(EnvironmentId(1), 42).await, 3);
+        for node in nodes {
+            node.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reliable_messages_are_replayed_until_acked() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client,
+            ClientConfig {
+                redelivery_window: Duration::from_millis(20),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        client.inner.node_connections.insert(
+            (NodeId(2), Plane::Data),
+            Arc::new(Mutex::new(Some(connection))),
+        );
+        let (deliveries_tx, mut deliveries) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut send, mut recv) = acceptor.accept().await.unwrap();
+            while let Ok(bytes) = recv.receive().await {
+                match bincode::deserialize(&bytes).unwrap() {
+                    (msg_id, Request::Message { delivery_id, .. }) => {
+                        deliveries_tx.send(delivery_id.unwrap()).unwrap();
+                        send.send(&mut pack_response(msg_id, Response::Sent))
+                            .await
+                            .unwrap();
+                    }
+                    (_, request) => panic!("unexpected request {request:?}"),
+                }
+            }
+        });
+
+        let sender = MessageSender {
+            node_id: 1,
+            process_id: 7,
+        };
+        let delivery_id = client
+            .message_process_reliably(
+                NodeId(2),
+                EnvironmentId(1),
+                ProcessId(1),
+                None,
+                Priority::Normal,
+                vec![1],
+                sender,
+            )
+            .await
+            .unwrap();
+        // Sent once, then replayed because nobody acked it
+        for _ in 0..3 {
+            assert_eq!(deliveries.recv().await, Some(delivery_id));
+        }
+
+        assert!(client.ack_delivery(delivery_id));
+        // Duplicate acks are ignored
+        assert!(!client.ack_delivery(delivery_id));
+        assert_eq!(client.inner.delivery_log.pending(), 0);
+        // A replay may have been in flight while acking
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        while deliveries.try_recv().is_ok() {}
+        let replayed = tokio::time::timeout(Duration::from_millis(100), deliveries.recv()).await;
+        assert!(replayed.is_err());
+    }
+
+    #[tokio::test]
+    async fn group_broadcast_reaches_all_members() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let server =
+            control::server::Server::new(control::server::root_cert(true, None, None).unwrap());
+        for (node_id, process_id) in [(2, 1), (2, 2), (3, 5)] {
+            server.join_group("workers".to_string(), node_id, process_id);
+        }
+        server.join_group("others".to_string(), 3, 6);
+        let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        control::server::serve_in_memory(server, acceptor);
+        let client = Client::new(
+            NodeId(1),
+            control::Client::in_memory(connection, 1),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut nodes = Vec::new();
+        for (node_id, members) in [(2, 2), (3, 1)] {
+            let (connection, mut acceptor) =
+                quic::Connection::in_memory(ConnectionConfig::default());
+            client.inner.node_connections.insert(
+                (NodeId(node_id), Plane::Data),
+                Arc::new(Mutex::new(Some(connection))),
+            );
+            nodes.push(tokio::spawn(async move {
+                let (mut send, mut recv) = acceptor.accept().await.unwrap();
+                let mut received = Vec::new();
+                while received.len() < members {
+                    let bytes = recv.receive().await.unwrap();
+                    match bincode::deserialize(&bytes).unwrap() {
+                        (msg_id, Request::Message { process_id, .. }) => {
+                            received.push((node_id, process_id.0));
+                            send.send(&mut pack_response(msg_id, Response::Sent))
+                                .await
+                                .unwrap();
+                        }
+                        (_, request) => panic!("unexpected request {request:?}"),
+                    }
+                }
+                received
+            }));
+        }
+
+        let delivered = client
+            .send_to_group(
+                EnvironmentId(1),
+                "workers",
+                Some(1),
+                Priority::Normal,
+                vec![1, 2, 3],
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(delivered, 3);
+        let mut received = Vec::new();
+        for node in nodes {
+            received.extend(node.await.unwrap());
+        }
+        assert_eq!(received, vec![(2, 1), (2, 2), (3, 5)]);
+    }
+
+    #[tokio::test]
+    async fn self_test_passes_on_healthy_cluster() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        for node in 1..=2 {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let registered = server.register(Registration {
+                node_address: format!("127.0.0.1:{}", 10000 + node).parse().unwrap(),
+                control_address: None,
+                node_name: format!("node-{node}"),
+                signing_request: cert.serialize_request_pem().unwrap(),
+                attributes: Default::default(),
+                join_token: None,
+            });
+            assert!(matches!(
+                registered,
+                control::message::Response::Register(_)
+            ));
+        }
+        let (connection, acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        serve_in_memory(server, acceptor);
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let quic_client = quic::new_quic_client(&cert.serialize_pem().unwrap()).unwrap();
+        let client = Client::new(
+            NodeId(1),
+            control::Client::in_memory(connection, 1),
+            quic_client,
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let (connection, mut acceptor) = quic::Connection::in_memory(ConnectionConfig::default());
+        for plane in [Plane::Control, Plane::Data] {
+            client.inner.node_connections.insert(
+                (NodeId(2), plane),
+                Arc::new(Mutex::new(Some(connection.clone()))),
+            );
+        }
+
+        // Node 2 answers like a healthy node
+        let handled = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let node_handled = handled.clone();
+        tokio::spawn(async move {
+            while let Some((mut send, mut recv)) = acceptor.accept().await {
+                let handled = node_handled.clone();
+                tokio::spawn(async move {
+                    while let Ok(bytes) = recv.receive().await {
+                        let (msg_id, request): (u64, Request) =
+                            bincode::deserialize(&bytes).unwrap();
+                        handled.lock().unwrap().push(request.kind());
+                        let response = match request {
+                            Request::Spawn(_) => Response::Spawned(ProcessId(7)),
+                            Request::Ping { .. } => Response::Sent,
+                            Request::Message { process_id, .. }
+                            | Request::Kill { process_id, .. } => {
+                                assert_eq!(process_id, ProcessId(7));
+                                Response::Sent
+                            }
+                            request => panic!("unexpected request {request:?}"),
+                        };
+                        send.send(&mut pack_response(msg_id, response))
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let report = client
+            .self_test(EnvironmentId(1), Some(spawn()), SelfTestConfig::default())
+            .await;
+        let checks: Vec<_> = report
+            .results
+            .iter()
+            .map(|result| (result.check, result.node_id, result.status))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                (SelfTestCheck::Control, 0, CheckStatus::Passed),
+                (SelfTestCheck::Peers, 2, CheckStatus::Passed),
+                (SelfTestCheck::RoundTrip, 2, CheckStatus::Passed),
+            ]
+        );
+        assert_eq!(report.failures(), 0);
+        // The spawned process doesn't outlive the test
+        assert!(handled.lock().unwrap().contains(&"Kill"));
+
+        // Only the enabled checks run
+        let config = SelfTestConfig {
+            checks: SelfTestCheck::Peers.bit(),
+            ..Default::default()
+        };
+        let report = client.self_test(EnvironmentId(1), None, config).await;
+        assert_eq!(report.results.len(), 1);
+        assert_eq!(report.results[0].check, SelfTestCheck::Peers);
+    }
+
+    #[tokio::test]
+    async fn migrated_messages_follow_the_process() {
+        let quic_client = quic::Client::in_memory(ConnectionConfig::default());
+        let address: SocketAddr = "127.0.0.1:3030".parse().unwrap();
+        let node = test_node(quic_client.clone(), ClientConfig::default()).await;
+        let envs = node.envs.clone();
+        tokio::spawn(handle_in_memory_node_connection(
+            node,
+            1,
+            quic_client.serve_in_memory(address),
+        ));
+        let client = Client::new(
+            NodeId(1),
+            control::Client::detached(),
+            quic_client.clone(),
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+        let connection = quic_client.open_connection(address, "node").await.unwrap();
+        client.connect_in_memory(NodeId(2), connection);
+
+        // The process spawned on the other node by the migration
+        let env = envs.get_or_create(1);
+        let (received, mut receive) = unbounded_channel();
+        let (_, process) = lunatic_process::spawn(env.clone(), |_this, mailbox| async move {
+            for _ in 0..3 {
+                let message = mailbox.pop(None).await;
+                received.send(message.tag()).unwrap();
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        assert!(env.add_process(process.id(), Arc::new(process.clone())));
+
+        // Messages drained from the mailbox of the migrating process
+        let messages = || {
+            (1..=3)
+                .map(|tag| {
+                    let mut message = DataMessage::new_from_vec(Some(tag), vec![]);
+                    message.sender = Some(MessageSender {
+                        node_id: 1,
+                        process_id: 5,
+                    });
+                    message
+                })
+                .collect::<Vec<_>>()
+        };
+        let forwarded = client
+            .forward_messages(
+                NodeId(2),
+                EnvironmentId(1),
+                ProcessId(process.id()),
+                messages(),
+            )
+            .await;
+        assert!(forwarded.is_ok());
+        for tag in 1..=3 {
+            let received = tokio::time::timeout(Duration::from_secs(5), receive.recv())
+                .await
+                .unwrap();
+            assert_eq!(received, Some(Some(tag)));
+        }
+
+        // Nothing is lost if the process is gone, all messages are handed back
+        let (error, remaining) = client
+            .forward_messages(NodeId(2), EnvironmentId(1), ProcessId(1000), messages())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ClientError::ProcessNotFound));
+        let tags: Vec<_> = remaining.iter().map(|message| message.tag).collect();
+        assert_eq!(tags, vec![Some(1), Some(2), Some(3)]);
+    }
 }
@@ -0,0 +1,159 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What the node server does with connections from other nodes that arrive once the limit is
+/// reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AtConnectionLimit {
+    /// The connection is closed right away.
+    #[default]
+    Reject,
+    /// The connection waits until another connection is closed. No further connections are
+    /// accepted in the meantime.
+    Wait,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimitConfig {
+    // Maximum number of connections from other nodes that are open at the same time
+    pub max_connections: usize,
+    pub at_limit: AtConnectionLimit,
+}
+
+/// Limits the number of connections from other nodes the node server keeps open at the same time.
+/// Without a config the number of connections is not limited, but still counted.
+#[derive(Clone, Default)]
+pub struct ConnectionLimit {
+    slots: Option<(AtConnectionLimit, Arc<Semaphore>)>,
+    open: Arc<AtomicUsize>,
+}
+
+/// A slot held while a connection is open.
+pub struct ConnectionSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let _open = self.open.fetch_sub(1, Ordering::SeqCst) - 1;
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("lunatic.distributed.connections", _open as f64);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(config: ConnectionLimitConfig) -> Self {
+        Self {
+            slots: Some((
+                config.at_limit,
+                Arc::new(Semaphore::new(config.max_connections)),
+            )),
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns a slot for a new connection, or `None` if the connection should be rejected.
+    /// Waits for a free slot if the limit is reached and connections wait.
+    pub async fn admit(&self) -> Option<ConnectionSlot> {
+        let permit = match &self.slots {
+            Some((at_limit, slots)) => match at_limit {
+                AtConnectionLimit::Reject => match slots.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        #[cfg(feature = "metrics")]
+                        metrics::increment_counter!("lunatic.distributed.connections.rejected");
+                        return None;
+                    }
+                },
+                AtConnectionLimit::Wait => Some(slots.clone().acquire_owned().await.ok()?),
+            },
+            None => None,
+        };
+        let _open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("lunatic.distributed.connections", _open as f64);
+        Some(ConnectionSlot {
+            _permit: permit,
+            open: self.open.clone(),
+        })
+    }
+
+    /// Returns the number of connections that are open.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{describe_counter, describe_gauge, Unit};
+
+    describe_gauge!(
+        "lunatic.distributed.connections",
+        Unit::Count,
+        "Number of open connections from other nodes"
+    );
+    describe_counter!(
+        "lunatic.distributed.connections.rejected",
+        Unit::Count,
+        "Number of connections from other nodes rejected because of the limit since startup"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AtConnectionLimit, ConnectionLimit, ConnectionLimitConfig};
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_rejected() {
+        let limit = ConnectionLimit::new(ConnectionLimitConfig {
+            max_connections: 2,
+            at_limit: AtConnectionLimit::Reject,
+        });
+        let first = limit.admit().await.unwrap();
+        let _second = limit.admit().await.unwrap();
+        assert_eq!(limit.open(), 2);
+        assert!(limit.admit().await.is_none());
+        assert_eq!(limit.open(), 2);
+
+        drop(first);
+        assert_eq!(limit.open(), 1);
+        assert!(limit.admit().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait() {
+        let limit = ConnectionLimit::new(ConnectionLimitConfig {
+            max_connections: 1,
+            at_limit: AtConnectionLimit::Wait,
+        });
+        let first = limit.admit().await.unwrap();
+        let waiting = limit.clone();
+        let mut second = tokio::spawn(async move { waiting.admit().await.is_some() });
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_err());
+
+        drop(first);
+        assert!(second.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connections_are_counted_without_limit() {
+        let limit = ConnectionLimit::default();
+        let mut slots = Vec::new();
+        for _ in 0..10 {
+            slots.push(limit.admit().await.unwrap());
+        }
+        assert_eq!(limit.open(), 10);
+        slots.clear();
+        assert_eq!(limit.open(), 0);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+use super::message::{ClientError, Response};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DedupConfig {
+    // How long the response to a request with an idempotency key is remembered
+    pub window: Duration,
+    // Maximum number of remembered keys, further keyed requests are rejected
+    pub max_entries: usize,
+}
+
+/// Remembers the responses to requests with an idempotency key, so that a retried request gets
+/// the response of the first one instead of being handled again.
+///
+/// Keys are forgotten once they are older than the window, or right away if the request failed.
+/// A key is never forgotten earlier to make room for a new one, if the window holds `max_entries`
+/// keys new keyed requests are rejected with `ClientError::DedupWindowFull` instead. Without a
+/// config requests are never deduplicated.
+#[derive(Clone, Default)]
+pub struct DedupWindow {
+    inner: Option<Arc<InnerDedupWindow>>,
+}
+
+struct InnerDedupWindow {
+    config: DedupConfig,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    responses: HashMap<u128, Arc<OnceCell<Response>>>,
+    // Keys in the order they were first seen
+    order: VecDeque<(Instant, u128)>,
+    hits: u64,
+}
+
+/// A snapshot of the deduplication window counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    pub size: usize,
+    pub hits: u64,
+}
+
+impl DedupWindow {
+    pub fn new(config: DedupConfig) -> Self {
+        Self {
+            inner: Some(Arc::new(InnerDedupWindow {
+                config,
+                entries: Mutex::new(Entries::default()),
+            })),
+        }
+    }
+
+    /// Runs `request` unless a request with the same `key` was already handled within the
+    /// window, in which case its response is returned. Concurrent requests with the same key wait
+    /// for the first one to finish.
+    pub async fn run<F>(&self, key: Option<u128>, request: F) -> Response
+    where
+        F: Future<Output = Response>,
+    {
+        let (inner, key) = match (&self.inner, key) {
+            (Some(inner), Some(key)) => (inner, key),
+            _ => return request.await,
+        };
+        let response = match inner.entry(key) {
+            Ok(response) => response,
+            Err(error) => return Response::Error(error),
+        };
+        let result = response.get_or_init(|| request).await.clone();
+        // Failed requests are not remembered, a retry should be handled again
+        if let Response::Error(_) = result {
+            inner.forget(key, &response);
+        }
+        result
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        match &self.inner {
+            Some(inner) => {
+                let entries = inner.entries.lock().unwrap();
+                DedupStats {
+                    size: entries.responses.len(),
+                    hits: entries.hits,
+                }
+            }
+            None => DedupStats::default(),
+        }
+    }
+}
+
+impl InnerDedupWindow {
+    fn forget(&self, key: u128, response: &Arc<OnceCell<Response>>) {
+        let mut entries = self.entries.lock().unwrap();
+        // The key could already be forgotten and reused by another request
+        if let Some(remembered) = entries.responses.get(&key) {
+            if Arc::ptr_eq(remembered, response) {
+                entries.responses.remove(&key);
+                entries.order.retain(|(_, remembered)| *remembered != key);
+            }
+        }
+    }
+
+    fn entry(&self, key: u128) -> Result<Arc<OnceCell<Response>>, ClientError> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        while let Some(&(seen_at, oldest)) = entries.order.front() {
+            // Requests that are still running are kept, so that retries wait for them
+            let running = Arc::strong_count(&entries.responses[&oldest]) > 1;
+            if running || now.duration_since(seen_at) < self.config.window {
+                break;
+            }
+            entries.order.pop_front();
+            entries.responses.remove(&oldest);
+        }
+
+        let response = match entries.responses.get(&key) {
+            Some(response) => {
+                let response = response.clone();
+                entries.hits += 1;
+                #[cfg(feature = "metrics")]
+                metrics::increment_counter!("lunatic.distributed.dedup.hits");
+                response
+            }
+            None if entries.responses.len() >= self.config.max_entries => {
+                #[cfg(feature = "metrics")]
+                metrics::increment_counter!("lunatic.distributed.dedup.rejected");
+                return Err(ClientError::DedupWindowFull);
+            }
+            None => {
+                let response = Arc::new(OnceCell::new());
+                entries.responses.insert(key, response.clone());
+                entries.order.push_back((now, key));
+                response
+            }
+        };
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(
+            "lunatic.distributed.dedup.size",
+            entries.responses.len() as f64
+        );
+        Ok(response)
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{describe_counter, describe_gauge, Unit};
+
+    describe_counter!(
+        "lunatic.distributed.dedup.hits",
+        Unit::Count,
+        "Number of requests answered with the response of an earlier request with the same key"
+    );
+    describe_counter!(
+        "lunatic.distributed.dedup.rejected",
+        Unit::Count,
+        "Number of keyed requests rejected because the deduplication window was full"
+    );
+    describe_gauge!(
+        "lunatic.distributed.dedup.size",
+        Unit::Count,
+        "Number of idempotency keys remembered in the deduplication window"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    use super::{DedupConfig, DedupStats, DedupWindow};
+    use crate::{
+        distributed::message::{ClientError, Response},
+        ProcessId,
+    };
+
+    // Spawns a new process id every time it's run
+    async fn spawn(next: &AtomicU64) -> Response {
+        Response::Spawned(ProcessId(next.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    fn spawned(response: Response) -> u64 {
+        match response {
+            Response::Spawned(ProcessId(id)) => id,
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_deduplicated_within_window() {
+        let window = DedupWindow::new(DedupConfig {
+            window: Duration::from_millis(50),
+            max_entries: 10,
+        });
+        let next = AtomicU64::new(1);
+
+        assert_eq!(spawned(window.run(Some(7), spawn(&next)).await), 1);
+        assert_eq!(spawned(window.run(Some(7), spawn(&next)).await), 1);
+        assert_eq!(spawned(window.run(Some(8), spawn(&next)).await), 2);
+        // Requests without a key are always handled
+        assert_eq!(spawned(window.run(None, spawn(&next)).await), 3);
+        assert_eq!(window.stats(), DedupStats { size: 2, hits: 1 });
+
+        // Beyond the window the key is forgotten and the request is handled again
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(spawned(window.run(Some(7), spawn(&next)).await), 4);
+        assert_eq!(window.stats(), DedupStats { size: 1, hits: 1 });
+    }
+
+    #[tokio::test]
+    async fn full_window_rejects_instead_of_evicting() {
+        let window = DedupWindow::new(DedupConfig {
+            window: Duration::from_secs(60),
+            max_entries: 1,
+        });
+        let next = AtomicU64::new(1);
+
+        assert_eq!(spawned(window.run(Some(1), spawn(&next)).await), 1);
+        assert!(matches!(
+            window.run(Some(2), spawn(&next)).await,
+            Response::Error(ClientError::DedupWindowFull)
+        ));
+        // The remembered key is still deduplicated
+        assert_eq!(spawned(window.run(Some(1), spawn(&next)).await), 1);
+        assert_eq!(next.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,309 @@
+/*!
+At-least-once delivery of messages to processes on other nodes.
+
+The sending node keeps every message sent with at-least-once delivery in its [`DeliveryLog`]
+until the receiving process acknowledges it. Messages that are not acknowledged within the
+redelivery window are sent again, so a process can receive the same message more than once.
+
+The receiving node hands out a receipt for each such message, see [`Receipts`]. Acknowledging
+the receipt sends the acknowledgement back to the sending node, which drops the message from its
+log. Acknowledging is idempotent, an acknowledgement for a message that is not in the log anymore
+is ignored.
+
+A process can also acknowledge all messages it received from a node up to a message at once, see
+[`Receipts::up_to`]. Messages the process didn't receive yet are left out, so a gap in the ids
+never drops a message that was not delivered.
+
+The log is only kept in memory, messages that were not acknowledged are lost if the sending node
+stops. Its memory is not released when messages are acknowledged, [`DeliveryLog::compact`]
+reclaims it once enough of it is unused.
+*/
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use lunatic_process::message::Priority;
+
+use crate::{EnvironmentId, NodeId, ProcessId};
+
+#[derive(Clone, Debug)]
+pub struct LoggedMessage {
+    pub node_id: NodeId,
+    pub environment_id: EnvironmentId,
+    pub process_id: ProcessId,
+    pub tag: Option<i64>,
+    pub priority: Priority,
+    pub data: Vec<u8>,
+    pub sender: (NodeId, ProcessId),
+}
+
+/// Messages sent by this node that were not acknowledged yet.
+#[derive(Default)]
+pub struct DeliveryLog {
+    next_id: AtomicU64,
+    // Message id -> message and when it was last sent
+    messages: DashMap<u64, (LoggedMessage, Instant)>,
+}
+
+impl DeliveryLog {
+    /// Adds a message that is about to be sent and returns its id.
+    pub fn record(&self, message: LoggedMessage) -> u64 {
+        // Ids start at 1
+        let message_id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.messages.insert(message_id, (message, Instant::now()));
+        message_id
+    }
+
+    /// Drops the message from the log. Returns false if it's not in the log, e.g. because it was
+    /// already acknowledged.
+    pub fn ack(&self, message_id: u64) -> bool {
+        self.messages.remove(&message_id).is_some()
+    }
+
+    /// Drops all messages from the log and returns how many of them were still in it.
+    pub fn ack_many(&self, message_ids: &[u64]) -> usize {
+        message_ids
+            .iter()
+            .filter(|message_id| self.ack(**message_id))
+            .count()
+    }
+
+    /// Returns the messages that were last sent longer than `window` ago and marks them as sent
+    /// now.
+    pub fn due(&self, window: Duration) -> Vec<(u64, LoggedMessage)> {
+        let now = Instant::now();
+        self.messages
+            .iter_mut()
+            .filter_map(|mut entry| {
+                let key = *entry.key();
+                let (message, sent_at) = entry.value_mut();
+                if now.duration_since(*sent_at) < window {
+                    return None;
+                }
+                *sent_at = now;
+                Some((key, message.clone()))
+            })
+            .collect()
+    }
+
+    /// Returns the number of messages waiting for an acknowledgement.
+    pub fn pending(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns the number of messages the log can hold without allocating.
+    pub fn capacity(&self) -> usize {
+        self.messages.capacity()
+    }
+
+    /// Releases the memory of acknowledged messages if room for at least `threshold` messages is
+    /// unused. Returns true if the log was compacted.
+    ///
+    /// Messages waiting for an acknowledgement are kept. Only one part of the log is locked at a
+    /// time, so messages can be recorded and acknowledged while it's compacted.
+    pub fn compact(&self, threshold: usize) -> bool {
+        if self.capacity().saturating_sub(self.pending()) < threshold {
+            return false;
+        }
+        self.messages.shrink_to_fit();
+        true
+    }
+}
+
+/// Messages received by this node with at-least-once delivery that were not acknowledged yet.
+///
+/// Each message gets a receipt that is unique on this node. A message that is delivered again
+/// gets the receipt of its first delivery.
+#[derive(Default)]
+pub struct Receipts {
+    next_id: AtomicU64,
+    // Receipt -> sending node, id of the message in its log and the receiving process
+    receipts: DashMap<u64, (NodeId, u64, (EnvironmentId, ProcessId))>,
+    // Sending node and message id -> receipt
+    messages: DashMap<(NodeId, u64), u64>,
+}
+
+impl Receipts {
+    /// Returns the receipt of the message `message_id` sent by `node_id` to `receiver`.
+    pub fn issue(
+        &self,
+        node_id: NodeId,
+        message_id: u64,
+        receiver: (EnvironmentId, ProcessId),
+    ) -> u64 {
+        *self
+            .messages
+            .entry((node_id, message_id))
+            .or_insert_with(|| {
+                let receipt = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+                self.receipts
+                    .insert(receipt, (node_id, message_id, receiver));
+                receipt
+            })
+    }
+
+    /// Returns the sending node and message id of the receipt.
+    pub fn get(&self, receipt: u64) -> Option<(NodeId, u64)> {
+        self.receipts
+            .get(&receipt)
+            .map(|message| (message.0, message.1))
+    }
+
+    /// Returns the sending node of `receipt` and the receipts and message ids of all messages
+    /// its receiver got from that node, up to and including the message of `receipt`, ordered by
+    /// message id. Returns `None` if the receipt is unknown.
+    pub fn up_to(&self, receipt: u64) -> Option<(NodeId, Vec<(u64, u64)>)> {
+        let (node_id, up_to, receiver) = *self.receipts.get(&receipt)?;
+        let mut messages: Vec<(u64, u64)> = self
+            .receipts
+            .iter()
+            .filter(|entry| {
+                let (from, message_id, to) = *entry.value();
+                from == node_id && to == receiver && message_id <= up_to
+            })
+            .map(|entry| (*entry.key(), entry.value().1))
+            .collect();
+        messages.sort_unstable_by_key(|(_, message_id)| *message_id);
+        Some((node_id, messages))
+    }
+
+    pub fn remove(&self, receipt: u64) {
+        if let Some((_, (node_id, message_id, _))) = self.receipts.remove(&receipt) {
+            self.messages.remove(&(node_id, message_id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lunatic_process::message::Priority;
+
+    use super::{DeliveryLog, LoggedMessage, Receipts};
+    use crate::{EnvironmentId, NodeId, ProcessId};
+
+    #[test]
+    fn messages_are_due_until_acked() {
+        let log = DeliveryLog::default();
+        let message_id = log.record(LoggedMessage {
+            node_id: NodeId(2),
+            environment_id: EnvironmentId(1),
+            process_id: ProcessId(1),
+            tag: None,
+            priority: Priority::Normal,
+            data: vec![1],
+            sender: (NodeId(1), ProcessId(1)),
+        });
+        assert!(log.due(Duration::from_secs(60)).is_empty());
+        assert_eq!(log.due(Duration::ZERO)[0].0, message_id);
+
+        assert!(log.ack(message_id));
+        // Duplicate acks are ignored
+        assert!(!log.ack(message_id));
+        assert!(log.due(Duration::ZERO).is_empty());
+        assert_eq!(log.pending(), 0);
+    }
+
+    #[test]
+    fn compaction_keeps_unacked_messages() {
+        let log = DeliveryLog::default();
+        let message_ids: Vec<u64> = (0..10_000)
+            .map(|index| {
+                log.record(LoggedMessage {
+                    node_id: NodeId(2),
+                    environment_id: EnvironmentId(1),
+                    process_id: ProcessId(1),
+                    tag: Some(index),
+                    priority: Priority::Normal,
+                    data: vec![1],
+                    sender: (NodeId(1), ProcessId(1)),
+                })
+            })
+            .collect();
+        let (unacked, acked) = message_ids.split_at(10);
+        for message_id in acked {
+            assert!(log.ack(*message_id));
+        }
+        let capacity = log.capacity();
+        assert!(capacity >= 10_000);
+
+        // Not enough unused room yet
+        assert!(!log.compact(capacity));
+        assert!(log.compact(1024));
+        assert!(log.capacity() < capacity / 10);
+        assert_eq!(log.pending(), 10);
+        let mut due: Vec<u64> = log.due(Duration::ZERO).iter().map(|(id, _)| *id).collect();
+        due.sort_unstable();
+        assert_eq!(due, unacked);
+    }
+
+    #[test]
+    fn redelivered_messages_keep_their_receipt() {
+        let receipts = Receipts::default();
+        let receiver = (EnvironmentId(1), ProcessId(1));
+        let receipt = receipts.issue(NodeId(2), 7, receiver);
+        assert_eq!(receipts.issue(NodeId(2), 7, receiver), receipt);
+        assert_ne!(receipts.issue(NodeId(3), 7, receiver), receipt);
+        assert_eq!(receipts.get(receipt), Some((NodeId(2), 7)));
+
+        receipts.remove(receipt);
+        assert_eq!(receipts.get(receipt), None);
+        assert_ne!(receipts.issue(NodeId(2), 7, receiver), receipt);
+    }
+
+    #[test]
+    fn range_acks_drop_received_prefix() {
+        let log = DeliveryLog::default();
+        let receipts = Receipts::default();
+        let receiver = (EnvironmentId(1), ProcessId(1));
+        let record = |process_id| {
+            log.record(LoggedMessage {
+                node_id: NodeId(2),
+                environment_id: EnvironmentId(1),
+                process_id: ProcessId(process_id),
+                tag: None,
+                priority: Priority::Normal,
+                data: vec![1],
+                sender: (NodeId(1), ProcessId(1)),
+            })
+        };
+        // A stream of 100 messages to the receiver, with messages to another process in between
+        let mut stream = Vec::new();
+        for index in 0..100 {
+            stream.push(record(1));
+            if index % 10 == 0 {
+                let other = record(2);
+                receipts.issue(NodeId(1), other, (EnvironmentId(1), ProcessId(2)));
+            }
+        }
+        // The 30th message is still on its way
+        let stream_receipts: Vec<u64> = stream
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != 29)
+            .map(|(_, message_id)| receipts.issue(NodeId(1), *message_id, receiver))
+            .collect();
+        assert_eq!(log.pending(), 110);
+
+        // Acknowledges up to the 51st and the 100th message of the stream
+        for (batch, pending) in [(49, 60), (98, 11)] {
+            let (node_id, messages) = receipts.up_to(stream_receipts[batch]).unwrap();
+            assert_eq!(node_id, NodeId(1));
+            let message_ids: Vec<u64> = messages.iter().map(|(_, id)| *id).collect();
+            for (receipt, _) in messages {
+                receipts.remove(receipt);
+            }
+            log.ack_many(&message_ids);
+            assert_eq!(log.pending(), pending);
+        }
+        // Only the missing message and the messages to the other process are left
+        let mut due: Vec<u64> = log.due(Duration::ZERO).iter().map(|(id, _)| *id).collect();
+        due.sort_unstable();
+        assert!(due.contains(&stream[29]));
+        assert_eq!(due.len(), 11);
+    }
+}
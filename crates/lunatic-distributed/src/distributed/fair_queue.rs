@@ -0,0 +1,232 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+#[derive(Clone, Debug, Default)]
+pub struct FairQueueConfig {
+    // Number of requests from other nodes that are handled at the same time
+    pub workers: usize,
+    // Environment ID -> share of the workers, environments that are not listed have a weight of 1
+    pub weights: HashMap<u64, u32>,
+}
+
+/// Schedules requests from other nodes across environments with weighted fair queuing.
+///
+/// Each environment gets a share of the workers proportional to its weight, so an environment
+/// sending a lot of requests can't delay the requests of other environments behind its own. Inside
+/// of an environment requests are handled in the order they arrived. Without a config requests are
+/// never queued.
+#[derive(Clone, Default)]
+pub struct FairQueue {
+    inner: Option<Arc<InnerFairQueue>>,
+}
+
+struct InnerFairQueue {
+    workers: usize,
+    weights: HashMap<u64, u32>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    // Start tag of the last request that got a worker
+    virtual_time: u64,
+    // Environment ID -> finish tag of its last queued request
+    finish_tags: HashMap<u64, u64>,
+    // Ordered by finish tag, ties are broken by arrival
+    waiting: BinaryHeap<Reverse<(u64, u64, u64)>>,
+    next_arrival: u64,
+    // Arrival -> (start tag, channel to hand the worker to the request)
+    wakers: HashMap<u64, (u64, oneshot::Sender<FairSlot>)>,
+}
+
+// The virtual time it takes to handle a request of an environment with the weight 1
+const REQUEST_COST: u64 = 1 << 20;
+
+/// A worker held while a request is handled. Dropping it hands the worker to the next request.
+pub struct FairSlot {
+    queue: Option<Arc<InnerFairQueue>>,
+}
+
+impl FairQueue {
+    pub fn new(config: FairQueueConfig) -> Self {
+        Self {
+            inner: Some(Arc::new(InnerFairQueue {
+                workers: config.workers.max(1),
+                weights: config.weights,
+                state: Mutex::new(State::default()),
+            })),
+        }
+    }
+
+    /// Waits until a request of the environment is scheduled to be handled.
+    pub async fn admit(&self, environment_id: u64) -> FairSlot {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return FairSlot { queue: None },
+        };
+        let receiver = {
+            let mut state = inner.state.lock().unwrap();
+            let weight = inner.weights.get(&environment_id).copied().unwrap_or(1);
+            let last_finish = state
+                .finish_tags
+                .get(&environment_id)
+                .copied()
+                .unwrap_or_default();
+            let start = last_finish.max(state.virtual_time);
+            let finish = start + REQUEST_COST / weight.max(1) as u64;
+            state.finish_tags.insert(environment_id, finish);
+
+            if state.running < inner.workers && state.waiting.is_empty() {
+                state.running += 1;
+                state.virtual_time = start;
+                return FairSlot {
+                    queue: Some(inner.clone()),
+                };
+            }
+            let arrival = state.next_arrival;
+            state.next_arrival += 1;
+            let (sender, receiver) = oneshot::channel();
+            state
+                .waiting
+                .push(Reverse((finish, arrival, environment_id)));
+            state.wakers.insert(arrival, (start, sender));
+            receiver
+        };
+        // The sender is only dropped together with the queue, then there is nothing to wait for.
+        receiver.await.unwrap_or(FairSlot { queue: None })
+    }
+
+    /// Number of requests waiting for a worker.
+    pub fn waiting(&self) -> usize {
+        match &self.inner {
+            Some(inner) => inner.state.lock().unwrap().wakers.len(),
+            None => 0,
+        }
+    }
+}
+
+impl Drop for FairSlot {
+    fn drop(&mut self) {
+        let queue = match self.queue.take() {
+            Some(queue) => queue,
+            None => return,
+        };
+        let next = {
+            let mut state = queue.state.lock().unwrap();
+            match state.waiting.pop() {
+                Some(Reverse((_, arrival, _))) => {
+                    let (start, sender) = state.wakers.remove(&arrival).expect("waker of request");
+                    state.virtual_time = start;
+                    Some(sender)
+                }
+                None => {
+                    state.running -= 1;
+                    // Nothing is queued, finish tags of idle environments don't matter anymore.
+                    if state.running == 0 {
+                        state.finish_tags.clear();
+                        state.virtual_time = 0;
+                    }
+                    None
+                }
+            }
+        };
+        // A request that was cancelled while waiting drops the slot right away, which hands the
+        // worker to the next one.
+        if let Some(sender) = next {
+            sender.send(FairSlot { queue: Some(queue) }).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::sync::mpsc;
+
+    use super::{FairQueue, FairQueueConfig};
+
+    async fn wait_for_waiting(queue: &FairQueue, waiting: usize) {
+        while queue.waiting() != waiting {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // Queues `count` requests of the environment behind the currently running one, each reports
+    // its environment once it got a worker.
+    async fn enqueue(
+        queue: &FairQueue,
+        environment_id: u64,
+        count: usize,
+        served: &mpsc::UnboundedSender<u64>,
+    ) {
+        for _ in 0..count {
+            let waiting = queue.waiting();
+            let queue_ = queue.clone();
+            let served = served.clone();
+            tokio::spawn(async move {
+                let _slot = queue_.admit(environment_id).await;
+                served.send(environment_id).unwrap();
+            });
+            wait_for_waiting(queue, waiting + 1).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn low_volume_environment_is_served_promptly() {
+        let queue = FairQueue::new(FairQueueConfig {
+            workers: 1,
+            weights: HashMap::new(),
+        });
+        let running = queue.admit(1).await;
+        let (served, mut order) = mpsc::unbounded_channel();
+        enqueue(&queue, 1, 1000, &served).await;
+        enqueue(&queue, 2, 1, &served).await;
+
+        drop(running);
+        let mut position = 0;
+        while order.recv().await.unwrap() != 2 {
+            position += 1;
+        }
+        // With FIFO handling it would be served after all 1000 noisy requests.
+        assert!(position <= 1, "served at position {position}");
+        for _ in 0..1000 - position {
+            assert_eq!(order.recv().await, Some(1));
+        }
+        assert_eq!(queue.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn workers_are_shared_by_weight() {
+        let queue = FairQueue::new(FairQueueConfig {
+            workers: 1,
+            weights: HashMap::from([(1, 3)]),
+        });
+        let running = queue.admit(1).await;
+        let (served, mut order) = mpsc::unbounded_channel();
+        enqueue(&queue, 1, 40, &served).await;
+        enqueue(&queue, 2, 40, &served).await;
+
+        drop(running);
+        let mut first = Vec::new();
+        for _ in 0..20 {
+            first.push(order.recv().await.unwrap());
+        }
+        let weighted = first.iter().filter(|&&id| id == 1).count();
+        assert!((14..=16).contains(&weighted), "{first:?}");
+    }
+
+    #[tokio::test]
+    async fn unconfigured_queue_never_waits() {
+        let queue = FairQueue::default();
+        let _first = queue.admit(1).await;
+        let _second = queue.admit(1).await;
+        assert_eq!(queue.waiting(), 0);
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use super::message::{ClientError, Response};
+
+/// A request that is currently handled by the node server.
+#[derive(Clone, Debug)]
+pub struct InFlightRequest {
+    // Id of the QUIC connection the request arrived on
+    pub connection_id: u64,
+    pub msg_id: u64,
+    // Environment the request was sent for, guests only see requests of their own environment
+    pub environment_id: u64,
+    pub kind: &'static str,
+    // Code of the kind reported to guests, see `Request::kind_code`
+    pub kind_code: u64,
+    pub started_at: Instant,
+}
+
+impl InFlightRequest {
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+struct Handler {
+    request: InFlightRequest,
+    task: JoinHandle<()>,
+    // Set once the watchdog warned about the handler, so that it's only reported once
+    reported: bool,
+}
+
+// How often the watchdog checks for slow handlers
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of requests handled by the node server, so that stuck handlers can be found and
+/// cancelled without closing the whole connection.
+#[derive(Clone, Default)]
+pub struct InFlightRequests {
+    handlers: Arc<DashMap<(u64, u64), Handler>>,
+}
+
+impl InFlightRequests {
+    /// Runs `handler` as a separate task and returns its response.
+    ///
+    /// If the request is cancelled while running, `ClientError::Cancelled` is returned instead,
+    /// so that the client always receives a response.
+    pub async fn run<F>(
+        &self,
+        connection_id: u64,
+        msg_id: u64,
+        environment_id: u64,
+        kind: &'static str,
+        kind_code: u64,
+        handler: F,
+    ) -> Response
+    where
+        F: Future<Output = Response> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            tx.send(handler.await).ok();
+        });
+        let request = InFlightRequest {
+            connection_id,
+            msg_id,
+            environment_id,
+            kind,
+            kind_code,
+            started_at: Instant::now(),
+        };
+        self.handlers.insert(
+            (connection_id, msg_id),
+            Handler {
+                request,
+                task,
+                reported: false,
+            },
+        );
+
+        let response = rx.await;
+        let handler = self.handlers.remove(&(connection_id, msg_id));
+        match (response, handler) {
+            (Ok(response), _) => response,
+            // The handler was removed by `cancel`.
+            (Err(_), None) => Response::Error(ClientError::Cancelled),
+            (Err(_), Some(_)) => Response::Error(ClientError::Unexpected(format!(
+                "Handler of {kind} request panicked"
+            ))),
+        }
+    }
+
+    /// Returns all requests that are currently handled.
+    pub fn list(&self) -> Vec<InFlightRequest> {
+        self.handlers
+            .iter()
+            .map(|handler| handler.request.clone())
+            .collect()
+    }
+
+    /// Returns the requests for the environment `environment_id` that are currently handled.
+    pub fn list_in(&self, environment_id: u64) -> Vec<InFlightRequest> {
+        self.handlers
+            .iter()
+            .filter(|handler| handler.request.environment_id == environment_id)
+            .map(|handler| handler.request.clone())
+            .collect()
+    }
+
+    /// Logs a warning for every request that is handled for longer than `threshold` until the
+    /// requests are dropped.
+    ///
+    /// Slow handlers are only reported, they keep running until they finish or are cancelled.
+    pub async fn watch_slow(self, threshold: Duration) {
+        let interval = threshold.min(WATCHDOG_INTERVAL);
+        loop {
+            tokio::time::sleep(interval).await;
+            self.report_slow(threshold);
+        }
+    }
+
+    // Warns about handlers that exceeded `threshold` and were not reported yet, returns them.
+    fn report_slow(&self, threshold: Duration) -> Vec<InFlightRequest> {
+        let mut slow = Vec::new();
+        for mut handler in self.handlers.iter_mut() {
+            if handler.reported || handler.request.elapsed() < threshold {
+                continue;
+            }
+            handler.reported = true;
+            let request = handler.request.clone();
+            log::warn!(
+                "Handler of {} request {} on connection {} is running for {:?}",
+                request.kind,
+                request.msg_id,
+                request.connection_id,
+                request.elapsed()
+            );
+            #[cfg(feature = "metrics")]
+            metrics::increment_counter!(
+                "lunatic.distributed.slow_handlers",
+                "kind" => request.kind
+            );
+            slow.push(request);
+        }
+        slow
+    }
+
+    /// Aborts the handlers of all requests that arrived on the connection and returns how many
+    /// were aborted.
+    pub fn cancel_connection(&self, connection_id: u64) -> usize {
+        let requests: Vec<_> = self
+            .handlers
+            .iter()
+            .filter(|handler| handler.key().0 == connection_id)
+            .map(|handler| *handler.key())
+            .collect();
+        requests
+            .into_iter()
+            .filter(|(connection_id, msg_id)| self.cancel(*connection_id, *msg_id))
+            .count()
+    }
+
+    /// Aborts the handler of a request, returns `false` if the request is not in flight.
+    pub fn cancel(&self, connection_id: u64, msg_id: u64) -> bool {
+        match self.handlers.remove(&(connection_id, msg_id)) {
+            Some((_, handler)) => {
+                handler.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Aborts the handler of a request for the environment `environment_id`, returns `false` if
+    /// no such request is in flight.
+    pub fn cancel_in(&self, environment_id: u64, connection_id: u64, msg_id: u64) -> bool {
+        let handler = self
+            .handlers
+            .remove_if(&(connection_id, msg_id), |_, handler| {
+                handler.request.environment_id == environment_id
+            });
+        match handler {
+            Some((_, handler)) => {
+                handler.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{describe_counter, Unit};
+
+    describe_counter!(
+        "lunatic.distributed.slow_handlers",
+        Unit::Count,
+        "Number of requests from other nodes handled for longer than the watchdog threshold"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::InFlightRequests;
+    use crate::distributed::message::{ClientError, Response};
+
+    #[tokio::test]
+    async fn cancelled_request_responds_with_cancelled() {
+        let requests = InFlightRequests::default();
+        let running = requests.clone();
+        let response = tokio::spawn(async move {
+            running
+                .run(1, 7, 1, "Spawn", 0, async {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                    Response::Sent
+                })
+                .await
+        });
+
+        let in_flight = loop {
+            let in_flight = requests.list();
+            if !in_flight.is_empty() {
+                break in_flight;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].connection_id, 1);
+        assert_eq!(in_flight[0].msg_id, 7);
+        assert_eq!(in_flight[0].kind, "Spawn");
+
+        assert!(requests.cancel(1, 7));
+        assert!(!requests.cancel(1, 7));
+        let response = response.await.unwrap();
+        assert!(matches!(response, Response::Error(ClientError::Cancelled)));
+        assert!(requests.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn closed_connection_cancels_its_requests() {
+        let requests = InFlightRequests::default();
+        let responses: Vec<_> = [(1, 1), (1, 2), (2, 1)]
+            .into_iter()
+            .map(|(connection_id, msg_id)| {
+                let running = requests.clone();
+                tokio::spawn(async move {
+                    running
+                        .run(connection_id, msg_id, 1, "Spawn", 0, async {
+                            tokio::time::sleep(Duration::from_secs(3600)).await;
+                            Response::Sent
+                        })
+                        .await
+                })
+            })
+            .collect();
+        while requests.list().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(requests.cancel_connection(1), 2);
+        let in_flight = requests.list();
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].connection_id, 2);
+        for response in responses.into_iter().take(2) {
+            let response = response.await.unwrap();
+            assert!(matches!(response, Response::Error(ClientError::Cancelled)));
+        }
+        assert!(requests.cancel(2, 1));
+    }
+
+    #[tokio::test]
+    async fn requests_are_only_visible_to_their_environment() {
+        let requests = InFlightRequests::default();
+        let responses: Vec<_> = [(1, 1), (2, 2)]
+            .into_iter()
+            .map(|(msg_id, environment_id)| {
+                let running = requests.clone();
+                tokio::spawn(async move {
+                    running
+                        .run(1, msg_id, environment_id, "Spawn", 0, async {
+                            tokio::time::sleep(Duration::from_secs(3600)).await;
+                            Response::Sent
+                        })
+                        .await
+                })
+            })
+            .collect();
+        while requests.list().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let in_flight = requests.list_in(2);
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].msg_id, 2);
+        assert!(requests.list_in(3).is_empty());
+        // Requests of other environments can't be cancelled
+        assert!(!requests.cancel_in(2, 1, 1));
+        assert!(requests.cancel_in(2, 1, 2));
+        assert!(requests.cancel_in(1, 1, 1));
+        for response in responses {
+            let response = response.await.unwrap();
+            assert!(matches!(response, Response::Error(ClientError::Cancelled)));
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_request_is_removed() {
+        let requests = InFlightRequests::default();
+        let response = requests
+            .run(1, 1, 1, "Message", 1, async { Response::Sent })
+            .await;
+        assert!(matches!(response, Response::Sent));
+        assert!(requests.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watchdog_reports_slow_handler_once() {
+        let requests = InFlightRequests::default();
+        let running = requests.clone();
+        let slow = tokio::spawn(async move {
+            running
+                .run(1, 9, 1, "Spawn", 0, async {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                    Response::Sent
+                })
+                .await
+        });
+        requests
+            .run(1, 10, 1, "Message", 1, async { Response::Sent })
+            .await;
+        while requests.list().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        let threshold = Duration::from_millis(50);
+        assert!(requests.report_slow(threshold).is_empty());
+        tokio::time::sleep(threshold).await;
+        let reported = requests.report_slow(threshold);
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].msg_id, 9);
+        assert_eq!(reported[0].kind, "Spawn");
+        assert!(reported[0].elapsed() >= threshold);
+        // The handler keeps running, but is only reported once.
+        assert!(requests.report_slow(threshold).is_empty());
+        assert_eq!(requests.list().len(), 1);
+
+        assert!(requests.cancel(1, 9));
+        slow.await.unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+/*!
+Links between processes on different nodes.
+
+Each side of a link holds a [`RemoteLink`] in place of the linked process. When a process dies, it
+sends `LinkDied` to its links as usual, and the [`RemoteLink`] forwards it to the node of the
+linked process. If that node leaves the cluster instead, no `LinkDied` would ever arrive, so both
+nodes also remember their processes that are linked to processes on the other node in
+[`RemoteLinks`] and deliver a `LinkDied` with a failure to them themselves.
+*/
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use lunatic_process::{DeathReason, Process, Signal};
+
+use super::Client;
+use crate::{EnvironmentId, NodeId, ProcessId};
+
+/// Stands in for a process on the node `node_id` in the links of a process on this node.
+pub struct RemoteLink {
+    pub node_client: Client,
+    pub node_id: NodeId,
+    pub environment_id: EnvironmentId,
+    pub process_id: ProcessId,
+}
+
+impl Process for RemoteLink {
+    fn id(&self) -> u64 {
+        self.process_id.into()
+    }
+
+    fn send(&self, signal: Signal) {
+        if let Signal::LinkDied(id, tag, reason) = signal {
+            let failed = matches!(reason, DeathReason::Failure | DeathReason::NoProcess);
+            let linked_id = ProcessId(id);
+            // The process on this node is gone, the other node is told by the request below.
+            self.node_client
+                .remote_links()
+                .remove(self.node_id, self.process_id, linked_id);
+            let node_client = self.node_client.clone();
+            let (node_id, environment_id, process_id) =
+                (self.node_id, self.environment_id, self.process_id);
+            tokio::spawn(async move {
+                if let Err(error) = node_client
+                    .link_died(node_id, environment_id, process_id, linked_id, tag, failed)
+                    .await
+                {
+                    log::debug!(
+                        "Failed to tell process {process_id} on node {node_id} that its link \
+                         died: {error:?}"
+                    );
+                }
+            });
+        }
+    }
+}
+
+struct LinkedProcess {
+    // Process on the other node
+    remote_id: ProcessId,
+    tag: Option<i64>,
+    local: Arc<dyn Process>,
+}
+
+/// Processes on this node that are linked to processes on other nodes, by node.
+#[derive(Clone, Default)]
+pub struct RemoteLinks {
+    links: Arc<DashMap<NodeId, Vec<LinkedProcess>>>,
+}
+
+impl RemoteLinks {
+    pub fn add(
+        &self,
+        node_id: NodeId,
+        remote_id: ProcessId,
+        tag: Option<i64>,
+        local: Arc<dyn Process>,
+    ) {
+        self.links.entry(node_id).or_default().push(LinkedProcess {
+            remote_id,
+            tag,
+            local,
+        });
+    }
+
+    /// Forgets the link between `remote_id` on `node_id` and the process `local_id` on this node,
+    /// once one of them died.
+    pub fn remove(&self, node_id: NodeId, remote_id: ProcessId, local_id: ProcessId) {
+        let empty = match self.links.get_mut(&node_id) {
+            Some(mut links) => {
+                links.retain(|link| link.remote_id != remote_id || link.local.id() != local_id.0);
+                links.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            self.links.remove_if(&node_id, |_, links| links.is_empty());
+        }
+    }
+
+    /// Sends a `LinkDied` with a failure for each process on `node_id` to the processes on this
+    /// node linked to it, because the node left the cluster. Returns the number of links.
+    pub fn node_left(&self, node_id: NodeId) -> usize {
+        let links = match self.links.remove(&node_id) {
+            Some((_, links)) => links,
+            None => return 0,
+        };
+        for link in links.iter() {
+            link.local.send(Signal::LinkDied(
+                link.remote_id.0,
+                link.tag,
+                DeathReason::Failure,
+            ));
+        }
+        links.len()
+    }
+
+    /// Returns the number of links to processes on other nodes.
+    pub fn len(&self) -> usize {
+        self.links.iter().map(|links| links.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use lunatic_process::{DeathReason, Process, Signal};
+
+    use super::RemoteLinks;
+    use crate::{NodeId, ProcessId};
+
+    #[derive(Default)]
+    struct Linked {
+        id: u64,
+        died: Mutex<Vec<(u64, Option<i64>)>>,
+    }
+
+    impl Process for Linked {
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn send(&self, signal: Signal) {
+            if let Signal::LinkDied(id, tag, DeathReason::Failure) = signal {
+                self.died.lock().unwrap().push((id, tag));
+            }
+        }
+    }
+
+    #[test]
+    fn links_to_a_node_that_left_die_with_their_tag() {
+        let links = RemoteLinks::default();
+        let parent = Arc::new(Linked {
+            id: 1,
+            ..Default::default()
+        });
+        let other = Arc::new(Linked {
+            id: 2,
+            ..Default::default()
+        });
+        links.add(NodeId(2), ProcessId(10), Some(7), parent.clone());
+        links.add(NodeId(2), ProcessId(11), None, parent.clone());
+        links.add(NodeId(3), ProcessId(10), Some(8), other.clone());
+        // The link to 11 ended normally before the node left
+        links.remove(NodeId(2), ProcessId(11), ProcessId(1));
+        assert_eq!(links.len(), 2);
+
+        assert_eq!(links.node_left(NodeId(2)), 1);
+        assert_eq!(*parent.died.lock().unwrap(), vec![(10, Some(7))]);
+        assert!(other.died.lock().unwrap().is_empty());
+        assert_eq!(links.node_left(NodeId(2)), 0);
+
+        links.remove(NodeId(3), ProcessId(10), ProcessId(2));
+        assert!(links.is_empty());
+    }
+}
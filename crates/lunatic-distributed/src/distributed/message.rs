@@ -1,33 +1,345 @@
 use bytes::Bytes;
+use lunatic_process::{lifecycle::Lifecycle, message::Priority};
 use serde::{Deserialize, Serialize};
 
+use super::{node_stats::NodeStats, spawn_config::SpawnConfig};
+use crate::{control::gossip::MemberUpdate, EnvironmentId, ModuleId, NodeId, ProcessId};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
     Spawn(Spawn),
+    // Spawn `count` processes from the same template, each gets its index appended to the params
+    SpawnReplicated {
+        spawn: Spawn,
+        count: u32,
+    },
     Message {
-        environment_id: u64,
-        process_id: u64,
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Payload,
+        reply_cap: Option<ReplyCapability>,
+        // Node and process id of the sender, `None` if the sender didn't provide it
+        sender: Option<(NodeId, ProcessId)>,
+        // Id of the message in the delivery log of the sender, if it's sent with at-least-once
+        // delivery and has to be acknowledged
+        delivery_id: Option<u64>,
+    },
+    // Reply to the process that minted the capability with `token`. The `environment_id` is the
+    // environment of the replying process.
+    Reply {
+        environment_id: EnvironmentId,
+        token: u128,
+        tag: Option<i64>,
+        data: Vec<u8>,
+    },
+    // Deliver a copy of the message to all subscribers of `topic` in the environment
+    Publish {
+        environment_id: EnvironmentId,
+        topic: String,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+    },
+    // Kill the process in the environment
+    Kill {
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+    },
+    // Stage a copy of the message for each of the processes, without delivering it yet. Nothing
+    // is staged if one of the processes doesn't exist.
+    Stage {
+        environment_id: EnvironmentId,
+        transaction_id: u128,
+        process_ids: Vec<ProcessId>,
+        tag: Option<i64>,
+        priority: Priority,
+        data: Vec<u8>,
+        sender: Option<(NodeId, ProcessId)>,
+    },
+    // Deliver all messages staged for the transaction
+    Commit {
+        environment_id: EnvironmentId,
+        transaction_id: u128,
+    },
+    // Drop all messages staged for the transaction
+    Rollback {
+        environment_id: EnvironmentId,
+        transaction_id: u128,
+    },
+    // Send an exit notification tagged with `tag` to the `(node_id, process_id)` watcher when
+    // the process in the environment exits
+    NotifyOnExit {
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: i64,
+        watcher: (NodeId, ProcessId),
+    },
+    // Kill all processes in the environment holding the cancellation token
+    Cancel {
+        environment_id: EnvironmentId,
+        token: u64,
+    },
+    // Acknowledge a message sent with at-least-once delivery, dropping it from the delivery log.
+    // The `environment_id` is the environment of the acknowledging process.
+    Ack {
+        environment_id: EnvironmentId,
+        delivery_id: u64,
+    },
+    // Acknowledge many messages sent with at-least-once delivery at once, see `Ack`
+    AckMany {
+        environment_id: EnvironmentId,
+        delivery_ids: Vec<u64>,
+    },
+    // Check that the node handles requests, answered with `Sent` without any side effects
+    Ping {
+        environment_id: EnvironmentId,
+    },
+    // Link the process in the environment to the `linked` process, answered with `Linked`
+    Link {
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        tag: Option<i64>,
+        linked: (NodeId, ProcessId),
+    },
+    // Tell the process in the environment that the `linked` process died, `failed` if it didn't
+    // finish normally
+    LinkDied {
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        linked: (NodeId, ProcessId),
         tag: Option<i64>,
+        failed: bool,
+    },
+    // Monitor the process in the environment for the monitor `monitor_id` of `node_id`
+    Monitor {
+        environment_id: EnvironmentId,
+        process_id: ProcessId,
+        monitor_id: u64,
+        node_id: NodeId,
+    },
+    // Drop the monitor `monitor_id` of `node_id`
+    Demonitor {
+        environment_id: EnvironmentId,
+        monitor_id: u64,
+        node_id: NodeId,
+    },
+    // Deliver the message of the monitor, `data` is the exit notification of the process
+    ProcessDown {
+        environment_id: EnvironmentId,
+        monitor_id: u64,
         data: Vec<u8>,
     },
+    // Probe the node with updates of the membership, answered with `Gossip`. If `target` is set,
+    // the node probes the target instead and only answers if the target answered.
+    Gossip {
+        environment_id: EnvironmentId,
+        updates: Vec<MemberUpdate>,
+        target: Option<NodeId>,
+    },
+    // Returns the load and capacity of the node, answered with `NodeInfo`
+    NodeInfo {
+        environment_id: EnvironmentId,
+    },
+}
+
+/// Nodes can accept control-plane requests on a separate listener, so that they are not delayed
+/// by bulk data traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Plane {
+    Control,
+    Data,
 }
 
 impl Request {
+    /// Code of the kind reported to guests by `lunatic::distributed::in_flight_requests`. Codes
+    /// are never reused, new kinds get the next free one.
+    pub fn kind_code(&self) -> u64 {
+        match self {
+            Request::Spawn(_) => 0,
+            Request::Message { .. } => 1,
+            Request::Reply { .. } => 2,
+            Request::Publish { .. } => 3,
+            Request::Kill { .. } => 4,
+            Request::Stage { .. } => 5,
+            Request::Commit { .. } => 6,
+            Request::Rollback { .. } => 7,
+            Request::SpawnReplicated { .. } => 8,
+            Request::NotifyOnExit { .. } => 9,
+            Request::Cancel { .. } => 10,
+            Request::Ack { .. } => 11,
+            Request::AckMany { .. } => 12,
+            Request::Ping { .. } => 13,
+            Request::Link { .. } => 14,
+            Request::LinkDied { .. } => 15,
+            Request::Monitor { .. } => 16,
+            Request::Demonitor { .. } => 17,
+            Request::ProcessDown { .. } => 18,
+            Request::Gossip { .. } => 19,
+            Request::NodeInfo { .. } => 20,
+        }
+    }
+
     pub fn kind(&self) -> &'static str {
         match self {
             Request::Spawn(_) => "Spawn",
+            Request::SpawnReplicated { .. } => "SpawnReplicated",
             Request::Message { .. } => "Message",
+            Request::Reply { .. } => "Reply",
+            Request::Publish { .. } => "Publish",
+            Request::Kill { .. } => "Kill",
+            Request::Stage { .. } => "Stage",
+            Request::Commit { .. } => "Commit",
+            Request::Rollback { .. } => "Rollback",
+            Request::NotifyOnExit { .. } => "NotifyOnExit",
+            Request::Cancel { .. } => "Cancel",
+            Request::Ack { .. } => "Ack",
+            Request::AckMany { .. } => "AckMany",
+            Request::Ping { .. } => "Ping",
+            Request::Link { .. } => "Link",
+            Request::LinkDied { .. } => "LinkDied",
+            Request::Monitor { .. } => "Monitor",
+            Request::Demonitor { .. } => "Demonitor",
+            Request::ProcessDown { .. } => "ProcessDown",
+            Request::Gossip { .. } => "Gossip",
+            Request::NodeInfo { .. } => "NodeInfo",
+        }
+    }
+
+    pub fn plane(&self) -> Plane {
+        match self {
+            Request::Kill { .. }
+            | Request::NotifyOnExit { .. }
+            | Request::Cancel { .. }
+            | Request::Ack { .. }
+            | Request::AckMany { .. }
+            | Request::Ping { .. }
+            | Request::Link { .. }
+            | Request::LinkDied { .. }
+            | Request::Monitor { .. }
+            | Request::Demonitor { .. }
+            | Request::ProcessDown { .. }
+            | Request::Gossip { .. }
+            | Request::NodeInfo { .. } => Plane::Control,
+            Request::Spawn(_)
+            | Request::SpawnReplicated { .. }
+            | Request::Message { .. }
+            | Request::Reply { .. }
+            | Request::Publish { .. }
+            | Request::Stage { .. }
+            | Request::Commit { .. }
+            | Request::Rollback { .. } => Plane::Data,
+        }
+    }
+
+    pub fn environment_id(&self) -> EnvironmentId {
+        match self {
+            Request::Spawn(spawn) => spawn.environment_id,
+            Request::SpawnReplicated { spawn, .. } => spawn.environment_id,
+            Request::Message { environment_id, .. } => *environment_id,
+            Request::Reply { environment_id, .. } => *environment_id,
+            Request::Publish { environment_id, .. } => *environment_id,
+            Request::Kill { environment_id, .. } => *environment_id,
+            Request::Stage { environment_id, .. } => *environment_id,
+            Request::Commit { environment_id, .. } => *environment_id,
+            Request::Rollback { environment_id, .. } => *environment_id,
+            Request::NotifyOnExit { environment_id, .. } => *environment_id,
+            Request::Cancel { environment_id, .. } => *environment_id,
+            Request::Ack { environment_id, .. } => *environment_id,
+            Request::AckMany { environment_id, .. } => *environment_id,
+            Request::Ping { environment_id } => *environment_id,
+            Request::Link { environment_id, .. } => *environment_id,
+            Request::LinkDied { environment_id, .. } => *environment_id,
+            Request::Monitor { environment_id, .. } => *environment_id,
+            Request::Demonitor { environment_id, .. } => *environment_id,
+            Request::ProcessDown { environment_id, .. } => *environment_id,
+            Request::Gossip { environment_id, .. } => *environment_id,
+            Request::NodeInfo { environment_id } => *environment_id,
         }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Spawn {
-    pub environment_id: u64,
-    pub module_id: u64,
+    pub environment_id: EnvironmentId,
+    pub module_id: ModuleId,
+    // If set, the node spawns from this version of a named module instead of `module_id`
+    pub module_version: Option<VersionedModule>,
     pub function: String,
     pub params: Vec<Val>,
-    pub config: Vec<u8>,
+    pub config: SpawnConfig,
+    // Retries of a spawn with the same key get the response of the first one, see `DedupWindow`
+    pub idempotency_key: Option<u128>,
+    // Cancellation token of the spawning process, the spawned process holds it too
+    pub cancel_token: Option<u64>,
+    // How long the process is expected to run, used to pick a node if the spawn has to move
+    pub lifecycle: Lifecycle,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VersionedModule {
+    pub name: String,
+    // `None` for the latest version at the time of the spawn
+    pub version: Option<u32>,
+}
+
+/// Data of a message, LZ4 compressed by the sender if it's larger than the sender's threshold.
+///
+/// Only the data is compressed, the other fields of the request stay readable without
+/// decompressing it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    compressed: bool,
+    bytes: Vec<u8>,
+}
+
+impl Payload {
+    /// Compresses `data` if it's larger than `compress_above` bytes and compression makes it
+    /// smaller. Data is never compressed if `compress_above` is `None`.
+    pub fn new(data: Vec<u8>, compress_above: Option<usize>) -> Self {
+        match compress_above {
+            Some(threshold) if data.len() > threshold => {
+                let compressed = lz4_flex::compress_prepend_size(&data);
+                if compressed.len() < data.len() {
+                    return Self {
+                        compressed: true,
+                        bytes: compressed,
+                    };
+                }
+            }
+            _ => {}
+        }
+        Self {
+            compressed: false,
+            bytes: data,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Returns the data, decompressing it if necessary.
+    pub fn into_data(self) -> Result<Vec<u8>, ClientError> {
+        if self.compressed {
+            lz4_flex::decompress_size_prepended(&self.bytes).map_err(|error| {
+                ClientError::Unexpected(format!("Failed to decompress message data: {error}"))
+            })
+        } else {
+            Ok(self.bytes)
+        }
+    }
+}
+
+/// A one-shot capability authorizing a single reply to the process that minted it.
+///
+/// The `token` is random and only valid on the node that minted it, where it's invalidated on
+/// first use.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplyCapability {
+    pub node_id: NodeId,
+    pub token: u128,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,6 +349,32 @@ pub enum ClientError {
     NodeNotFound,
     ModuleNotFound,
     ProcessNotFound,
+    PermissionDenied,
+    InvalidCapability,
+    ModuleCompilation(String),
+    // The request was cancelled on the receiving node before it was handled
+    Cancelled,
+    // The spawn referenced a config that the receiving node doesn't know
+    UnknownConfig,
+    // The module is not signed by the publisher the receiving node trusts
+    UntrustedModule,
+    // Too many spawns are already waiting on the receiving node
+    SpawnQueueFull,
+    // The staged messages of the transaction were rolled back before the commit arrived
+    TransactionExpired,
+    // The receiving node lost its connection to the control server and refuses spawns until
+    // it's back
+    ControlUnavailable,
+    // The receiving node remembers too many idempotency keys to accept another keyed request
+    DedupWindowFull,
+    // The receiving node is drained for maintenance and refuses new spawns
+    NodeDraining,
+    // The receiving node is configured with a join token and the spawn or gossip didn't present it
+    InvalidJoinToken,
+    // The module doesn't export the function to spawn
+    FunctionNotFound,
+    // The receiving node failed to create the process, e.g. because the config is invalid
+    SpawnFailed(String),
 }
 
 impl Default for ClientError {
@@ -47,9 +385,18 @@ impl Default for ClientError {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Response {
-    Spawned(u64),
+    Spawned(ProcessId),
+    // Process ids of replicated spawns, ordered by index
+    SpawnedMany(Vec<ProcessId>),
     Sent,
+    // Number of subscribers a published message was delivered to
+    Published(u64),
     Linked,
+    // Number of processes killed by a cancellation
+    Cancelled(u64),
+    // Updates of the membership piggybacked on the answer to a probe
+    Gossip(Vec<MemberUpdate>),
+    NodeInfo(NodeStats),
     Error(ClientError),
 }
 
@@ -57,8 +404,13 @@ impl Response {
     pub fn kind(&self) -> &'static str {
         match self {
             Response::Spawned(_) => "Spawned",
+            Response::SpawnedMany(_) => "SpawnedMany",
             Response::Sent => "Sent",
+            Response::Published(_) => "Published",
             Response::Linked => "Linked",
+            Response::Cancelled(_) => "Cancelled",
+            Response::Gossip(_) => "Gossip",
+            Response::NodeInfo(_) => "NodeInfo",
             Response::Error(_) => "Error",
         }
     }
@@ -89,3 +441,56 @@ pub fn pack_response(msg_id: u64, resp: Response) -> [Bytes; 2] {
     let bytes: Bytes = data.into();
     [size, bytes]
 }
+
+#[cfg(test)]
+mod tests {
+    use lunatic_process::message::Priority;
+
+    use super::{Payload, Request};
+    use crate::{EnvironmentId, ProcessId};
+
+    fn message(data: Vec<u8>) -> Request {
+        Request::Message {
+            environment_id: EnvironmentId(1),
+            process_id: ProcessId(2),
+            tag: Some(3),
+            priority: Priority::Normal,
+            data: Payload::new(data, Some(256)),
+            reply_cap: None,
+            sender: None,
+            delivery_id: None,
+        }
+    }
+
+    #[test]
+    fn only_large_payloads_are_compressed() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 7) as u8).collect();
+        let frame = bincode::serialize(&message(data.clone())).unwrap();
+        assert!(frame.len() < data.len() / 4);
+
+        // The header is readable without decompressing the payload
+        match bincode::deserialize(&frame).unwrap() {
+            Request::Message {
+                environment_id,
+                process_id,
+                tag,
+                data: payload,
+                ..
+            } => {
+                assert_eq!(
+                    (environment_id, process_id, tag),
+                    (EnvironmentId(1), ProcessId(2), Some(3))
+                );
+                assert!(payload.is_compressed());
+                assert_eq!(payload.into_data().unwrap(), data);
+            }
+            request => panic!("unexpected request {request:?}"),
+        }
+
+        let tiny = Payload::new(vec![0; 16], Some(256));
+        assert!(!tiny.is_compressed());
+        assert_eq!(tiny.into_data().unwrap(), vec![0; 16]);
+        // Without a threshold nothing is compressed
+        assert!(!Payload::new(data, None).is_compressed());
+    }
+}
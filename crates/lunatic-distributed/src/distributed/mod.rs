@@ -1,5 +1,25 @@
+pub mod allowlist;
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod client;
+pub mod connection_limit;
+pub mod dedup;
+pub mod delivery_log;
+pub mod fair_queue;
+pub mod in_flight;
+pub mod links;
+pub mod monitors;
 pub mod message;
+pub mod node_stats;
+pub mod ordering;
+pub mod pending_spawns;
+pub mod placement;
+pub mod request_tracker;
+pub mod self_test;
 pub mod server;
+pub mod signature;
+pub mod spawn_config;
+pub mod spawn_queue;
+pub mod transaction;
 
-pub use client::Client;
+pub use client::{Client, ClientConfig};
@@ -0,0 +1,239 @@
+/*!
+Monitors of processes, possibly on other nodes.
+
+A monitor delivers one message to the monitoring process when the monitored process exits, in the
+format of [`lunatic_process::exit_notification`]. Unlike links, monitors are one-directional and
+can be removed again with a demonitor, after which no message is delivered anymore.
+
+The node of the monitoring process keeps the monitor until it's removed or the message is
+delivered. The node of the monitored process registers a [`MonitorWatcher`] to be notified when
+the process exits, and only remembers the monitor to drop it if it's removed before. If the node of
+a monitored process leaves the cluster, its monitors deliver a message with the reason
+[`NODE_LEFT`].
+*/
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use dashmap::{DashMap, DashSet};
+use lunatic_process::{
+    message::{DataMessage, Message},
+    Process, Signal,
+};
+
+use super::Client;
+use crate::{EnvironmentId, NodeId, ProcessId};
+
+/// Reason of the message delivered by a monitor if the node of the monitored process left the
+/// cluster, following the reasons of [`lunatic_process::exit_notification`].
+pub const NODE_LEFT: u8 = 4;
+
+struct Monitor {
+    node_id: NodeId,
+    process_id: ProcessId,
+    tag: i64,
+    watcher: Arc<dyn Process>,
+}
+
+#[derive(Default)]
+pub struct Monitors {
+    next_id: AtomicU64,
+    // Monitors held by processes on this node, by monitor ID
+    monitors: DashMap<u64, Monitor>,
+    // `(node_id, monitor_id)` of monitors held by processes on other nodes of processes on this
+    // node
+    watched: DashSet<(NodeId, u64)>,
+}
+
+impl Monitors {
+    /// Adds a monitor of `process_id` on `node_id` held by `watcher` and returns its ID.
+    pub fn add(
+        &self,
+        node_id: NodeId,
+        process_id: ProcessId,
+        tag: i64,
+        watcher: Arc<dyn Process>,
+    ) -> u64 {
+        let monitor_id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.monitors.insert(
+            monitor_id,
+            Monitor {
+                node_id,
+                process_id,
+                tag,
+                watcher,
+            },
+        );
+        monitor_id
+    }
+
+    /// Removes the monitor and returns the node of the monitored process, or `None` if it was
+    /// already removed or its message delivered.
+    pub fn remove(&self, monitor_id: u64) -> Option<NodeId> {
+        self.monitors
+            .remove(&monitor_id)
+            .map(|(_, monitor)| monitor.node_id)
+    }
+
+    /// Delivers the message of the monitor with the data of the exit notification, unless the
+    /// monitor was removed. Returns true if it was delivered.
+    pub fn deliver(&self, monitor_id: u64, data: Vec<u8>) -> bool {
+        match self.monitors.remove(&monitor_id) {
+            Some((_, monitor)) => {
+                let message = DataMessage::new_from_vec(Some(monitor.tag), data);
+                monitor
+                    .watcher
+                    .send(Signal::Message(Message::Data(message)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers the monitor `monitor_id` of `node_id`, until it's delivered or removed.
+    pub fn watch(&self, node_id: NodeId, monitor_id: u64) {
+        self.watched.insert((node_id, monitor_id));
+    }
+
+    /// Forgets the monitor `monitor_id` of `node_id`. Returns false if it was already forgotten.
+    pub fn unwatch(&self, node_id: NodeId, monitor_id: u64) -> bool {
+        self.watched.remove(&(node_id, monitor_id)).is_some()
+    }
+
+    /// Delivers the messages of all monitors of processes on `node_id` with the reason
+    /// [`NODE_LEFT`] and forgets the monitors held by its processes. Returns the number of
+    /// delivered messages.
+    pub fn node_left(&self, node_id: NodeId) -> usize {
+        self.watched
+            .retain(|(watcher_node, _)| *watcher_node != node_id);
+        let monitor_ids: Vec<u64> = self
+            .monitors
+            .iter()
+            .filter(|monitor| monitor.node_id == node_id)
+            .map(|monitor| *monitor.key())
+            .collect();
+        monitor_ids
+            .into_iter()
+            .filter(|monitor_id| {
+                let process_id = match self.monitors.get(monitor_id) {
+                    Some(monitor) => monitor.process_id,
+                    None => return false,
+                };
+                let mut data = process_id.0.to_le_bytes().to_vec();
+                data.push(NODE_LEFT);
+                self.deliver(*monitor_id, data)
+            })
+            .count()
+    }
+
+    /// Returns the number of monitors held by processes on this node.
+    pub fn len(&self) -> usize {
+        self.monitors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.monitors.is_empty()
+    }
+}
+
+/// Registered with the monitored process to be notified when it exits, and passes the
+/// notification on to the monitor on the node `node_id`.
+pub struct MonitorWatcher {
+    pub node_client: Client,
+    pub monitor_id: u64,
+    pub node_id: NodeId,
+    pub environment_id: EnvironmentId,
+}
+
+impl Process for MonitorWatcher {
+    fn id(&self) -> u64 {
+        self.monitor_id
+    }
+
+    fn send(&self, signal: Signal) {
+        if let Signal::Message(Message::Data(message)) = signal {
+            let monitors = self.node_client.monitors();
+            if self.node_id == self.node_client.node_id() {
+                monitors.deliver(self.monitor_id, message.buffer);
+                return;
+            }
+            // The monitor was removed or its node left
+            if !monitors.unwatch(self.node_id, self.monitor_id) {
+                return;
+            }
+            let node_client = self.node_client.clone();
+            let (node_id, environment_id, monitor_id) =
+                (self.node_id, self.environment_id, self.monitor_id);
+            tokio::spawn(async move {
+                if let Err(error) = node_client
+                    .process_down(node_id, environment_id, monitor_id, message.buffer)
+                    .await
+                {
+                    log::debug!(
+                        "Failed to deliver monitor {monitor_id} to node {node_id}: {error:?}"
+                    );
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use lunatic_process::{message::Message, Process, Signal};
+
+    use super::{Monitors, NODE_LEFT};
+    use crate::{NodeId, ProcessId};
+
+    #[derive(Default)]
+    struct Watcher {
+        messages: Mutex<Vec<(Option<i64>, Vec<u8>)>>,
+    }
+
+    impl Process for Watcher {
+        fn id(&self) -> u64 {
+            1
+        }
+
+        fn send(&self, signal: Signal) {
+            if let Signal::Message(Message::Data(message)) = signal {
+                self.messages
+                    .lock()
+                    .unwrap()
+                    .push((message.tag, message.buffer));
+            }
+        }
+    }
+
+    #[test]
+    fn monitors_deliver_once_unless_removed() {
+        let monitors = Monitors::default();
+        let watcher = Arc::new(Watcher::default());
+        let first = monitors.add(NodeId(2), ProcessId(10), 7, watcher.clone());
+        let removed = monitors.add(NodeId(2), ProcessId(11), 8, watcher.clone());
+        let other = monitors.add(NodeId(3), ProcessId(12), 9, watcher.clone());
+        assert_ne!(first, removed);
+
+        assert!(monitors.deliver(first, vec![1]));
+        assert!(!monitors.deliver(first, vec![1]));
+        assert_eq!(monitors.remove(removed), Some(NodeId(2)));
+        assert_eq!(monitors.node_left(NodeId(2)), 0);
+        assert_eq!(monitors.node_left(NodeId(3)), 1);
+        assert!(monitors.is_empty());
+        let mut node_left = 12u64.to_le_bytes().to_vec();
+        node_left.push(NODE_LEFT);
+        assert_eq!(
+            *watcher.messages.lock().unwrap(),
+            vec![(Some(7), vec![1]), (Some(9), node_left)]
+        );
+        assert_eq!(monitors.remove(other), None);
+
+        monitors.watch(NodeId(2), 1);
+        monitors.node_left(NodeId(2));
+        assert!(!monitors.unwatch(NodeId(2), 1));
+    }
+}
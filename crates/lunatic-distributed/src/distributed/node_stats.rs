@@ -0,0 +1,95 @@
+/*!
+Load and capacity of a node, for schedulers in guest code to pick the nodes they spawn on.
+*/
+
+use lunatic_process::env::{Environment, Environments};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodeStats {
+    // Processes in all environments of the node
+    pub process_count: u64,
+    // Memory used by all processes on the node, in bytes
+    pub memory_used: u64,
+    // Limit of the memory used by all processes, `None` if the node has no limit
+    pub memory_limit: Option<u64>,
+    // Load average of the host over the last minute, 0 if the host doesn't report it
+    pub cpu_load: f64,
+    pub cpus: u32,
+    // Compiled modules the node can spawn from without fetching or compiling them first
+    pub module_count: u64,
+}
+
+impl NodeStats {
+    /// Version of the encoding, increased when its layout changes.
+    pub const VERSION: u8 = 1;
+
+    /// Length of the encoded stats.
+    pub const ENCODED_LEN: usize = 45;
+
+    /// Current stats of the node with the environments `envs` and `module_count` compiled
+    /// modules.
+    pub fn collect<E: Environment>(envs: &dyn Environments<Env = E>, module_count: usize) -> Self {
+        let memory = envs.memory();
+        Self {
+            process_count: envs.process_count() as u64,
+            memory_used: memory.used() as u64,
+            memory_limit: memory.limit().map(|limit| limit as u64),
+            cpu_load: std::fs::read_to_string("/proc/loadavg")
+                .ok()
+                .and_then(|loadavg| parse_loadavg(&loadavg))
+                .unwrap_or(0.0),
+            cpus: std::thread::available_parallelism().map_or(1, |cpus| cpus.get() as u32),
+            module_count: module_count as u64,
+        }
+    }
+
+    // Encodes the stats as little endian values, without a memory limit it's 0:
+    // [version: u8][process_count: u64][memory_used: u64][memory_limit: u64][cpu_load: f64]
+    // [cpus: u32][module_count: u64]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::ENCODED_LEN);
+        data.push(Self::VERSION);
+        data.extend(self.process_count.to_le_bytes());
+        data.extend(self.memory_used.to_le_bytes());
+        data.extend(self.memory_limit.unwrap_or(0).to_le_bytes());
+        data.extend(self.cpu_load.to_le_bytes());
+        data.extend(self.cpus.to_le_bytes());
+        data.extend(self.module_count.to_le_bytes());
+        data
+    }
+}
+
+// Returns the load average over the last minute from the contents of `/proc/loadavg`
+fn parse_loadavg(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_loadavg, NodeStats};
+
+    #[test]
+    fn stats_are_encoded_in_order() {
+        let stats = NodeStats {
+            process_count: 3,
+            memory_used: 4096,
+            memory_limit: None,
+            cpu_load: 1.5,
+            cpus: 8,
+            module_count: 2,
+        };
+        let data = stats.encode();
+        assert_eq!(data.len(), NodeStats::ENCODED_LEN);
+        assert_eq!(data[0], NodeStats::VERSION);
+        assert_eq!(&data[1..9], &3u64.to_le_bytes());
+        assert_eq!(&data[9..17], &4096u64.to_le_bytes());
+        assert_eq!(&data[17..25], &0u64.to_le_bytes());
+        assert_eq!(&data[25..33], &1.5f64.to_le_bytes());
+        assert_eq!(&data[33..37], &8u32.to_le_bytes());
+        assert_eq!(&data[37..45], &2u64.to_le_bytes());
+
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/467 12345\n"), Some(0.52));
+        assert_eq!(parse_loadavg(""), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::{future::Future, pin::Pin};
+
+use tokio::sync::{mpsc, oneshot};
+
+use super::message::{ClientError, Response};
+
+/// How the node server handles the requests that arrive on one connection from another node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionOrdering {
+    /// Requests are handled concurrently, so a slow request doesn't hold up the others, but they
+    /// can take effect in any order.
+    #[default]
+    Concurrent,
+    /// Requests are handled one at a time in the order they were received. Requests of other
+    /// connections are still handled concurrently.
+    Sequential,
+}
+
+type Handler = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+/// Handles the requests of one connection one at a time, in the order they were queued.
+///
+/// A single task runs the handlers and stops once all clones are dropped, i.e. once the
+/// connection is closed.
+#[derive(Clone)]
+pub struct SequentialRequests {
+    sender: mpsc::UnboundedSender<(Handler, oneshot::Sender<Response>)>,
+}
+
+impl SequentialRequests {
+    pub fn new() -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(Handler, oneshot::Sender<_>)>();
+        tokio::spawn(async move {
+            while let Some((handler, response)) = receiver.recv().await {
+                response.send(handler.await).ok();
+            }
+        });
+        Self { sender }
+    }
+
+    /// Queues the handler right away and returns its response once it ran after all handlers
+    /// queued before it.
+    pub fn run<F>(&self, handler: F) -> impl Future<Output = Response>
+    where
+        F: Future<Output = Response> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        // If the task is gone, the response sender is dropped right away
+        self.sender.send((Box::pin(handler), sender)).ok();
+        async move {
+            receiver.await.unwrap_or_else(|_| {
+                Response::Error(ClientError::Unexpected(
+                    "Sequential request handler stopped".to_string(),
+                ))
+            })
+        }
+    }
+}
+
+impl Default for SequentialRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::SequentialRequests;
+    use crate::{distributed::message::Response, ProcessId};
+
+    #[tokio::test]
+    async fn sequential_requests_keep_their_order() {
+        let requests = SequentialRequests::new();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        // Earlier requests take longer, handled concurrently they would finish in reverse order
+        let responses: Vec<_> = (0..5u64)
+            .map(|index| {
+                let handled = handled.clone();
+                let response = requests.run(async move {
+                    tokio::time::sleep(Duration::from_millis((5 - index) * 10)).await;
+                    handled.lock().unwrap().push(index);
+                    Response::Spawned(ProcessId(index))
+                });
+                tokio::spawn(response)
+            })
+            .collect();
+        for (index, response) in responses.into_iter().enumerate() {
+            match response.await.unwrap() {
+                Response::Spawned(process_id) => assert_eq!(process_id, ProcessId(index as u64)),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+        assert_eq!(*handled.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}
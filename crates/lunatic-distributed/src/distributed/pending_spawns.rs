@@ -0,0 +1,113 @@
+use std::{
+    future::Future,
+    sync::{atomic, atomic::AtomicU64, Arc},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+
+use super::message::ClientError;
+use crate::ProcessId;
+
+/// State of a spawn started with [`PendingSpawns::start`].
+#[derive(Clone, Debug)]
+pub enum SpawnPoll {
+    Pending,
+    Ready(Result<ProcessId, ClientError>),
+}
+
+/// Keeps track of spawns running in the background, so that guests can start a remote spawn
+/// and check later if it finished.
+///
+/// Results that are not polled within `ttl` after the spawn finished are dropped.
+#[derive(Clone)]
+pub struct PendingSpawns {
+    next_token: Arc<AtomicU64>,
+    spawns: Arc<DashMap<u64, Option<Result<ProcessId, ClientError>>>>,
+    ttl: Duration,
+}
+
+impl PendingSpawns {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            next_token: Arc::new(AtomicU64::new(1)),
+            spawns: Arc::new(DashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Runs `spawn` as a separate task and returns a token that can be used to poll it.
+    pub fn start<F>(&self, spawn: F) -> u64
+    where
+        F: Future<Output = Result<ProcessId, ClientError>> + Send + 'static,
+    {
+        let token = self.next_token.fetch_add(1, atomic::Ordering::Relaxed);
+        self.spawns.insert(token, None);
+        let spawns = self.spawns.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            let result = spawn.await;
+            match spawns.get_mut(&token) {
+                Some(mut pending) => *pending = Some(result),
+                // Already forgotten, nobody is interested in the result.
+                None => return,
+            }
+            tokio::time::sleep(ttl).await;
+            if spawns.remove(&token).is_some() {
+                log::debug!("Dropping result of spawn {token} that was never polled");
+            }
+        });
+        token
+    }
+
+    /// Returns the state of the spawn, or `None` if the token is unknown or expired.
+    ///
+    /// A ready result is returned only once, afterwards the token is unknown.
+    pub fn poll(&self, token: u64) -> Option<SpawnPoll> {
+        match self.spawns.remove_if(&token, |_, result| result.is_some()) {
+            Some((_, result)) => result.map(SpawnPoll::Ready),
+            None if self.spawns.contains_key(&token) => Some(SpawnPoll::Pending),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{PendingSpawns, SpawnPoll};
+    use crate::{distributed::message::ClientError, ProcessId};
+
+    #[tokio::test]
+    async fn spawn_can_be_polled_to_completion() {
+        let spawns = PendingSpawns::new(Duration::from_secs(60));
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let token = spawns.start(async move { Ok(ProcessId(rx.await.unwrap())) });
+
+        // Do other work while the spawn is in progress.
+        let work: u64 = (1..=100).sum();
+        assert_eq!(work, 5050);
+        assert!(matches!(spawns.poll(token), Some(SpawnPoll::Pending)));
+
+        tx.send(42).unwrap();
+        let result = loop {
+            match spawns.poll(token) {
+                Some(SpawnPoll::Pending) => tokio::task::yield_now().await,
+                Some(SpawnPoll::Ready(result)) => break result,
+                None => panic!("spawn token expired"),
+            }
+        };
+        assert_eq!(result.unwrap(), ProcessId(42));
+        assert!(spawns.poll(token).is_none());
+    }
+
+    #[tokio::test]
+    async fn unpolled_results_expire() {
+        let spawns = PendingSpawns::new(Duration::from_millis(10));
+        let token = spawns.start(async { Err(ClientError::NodeNotFound) });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(spawns.poll(token).is_none());
+        assert!(spawns.poll(1234).is_none());
+    }
+}
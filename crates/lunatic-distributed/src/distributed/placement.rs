@@ -0,0 +1,57 @@
+use std::fmt::Debug;
+
+use lunatic_process::lifecycle::Lifecycle;
+
+use crate::NodeId;
+
+/// Picks the node a process is spawned on when the runtime gets to choose, e.g. when
+/// `spawn_with_fallback` has to move a spawn away from a node that left the cluster.
+pub trait Placement: Debug + Send + Sync {
+    /// Returns the node among `candidates` that a process with `lifecycle` should be spawned on,
+    /// or `None` if none of them fits. Candidates are ordered as listed by the control server.
+    fn place(&self, lifecycle: Lifecycle, candidates: &[NodeId]) -> Option<NodeId>;
+}
+
+/// Places persistent processes on the candidate that is part of the cluster the longest, and all
+/// other processes on the first candidate.
+///
+/// The control server hands out node ids in ascending order, so the node with the lowest id is
+/// the one that registered first.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StablePlacement;
+
+impl Placement for StablePlacement {
+    fn place(&self, lifecycle: Lifecycle, candidates: &[NodeId]) -> Option<NodeId> {
+        match lifecycle {
+            Lifecycle::Persistent => candidates.iter().min().copied(),
+            Lifecycle::Transient | Lifecycle::Unspecified => candidates.first().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lunatic_process::lifecycle::Lifecycle;
+
+    use super::{Placement, StablePlacement};
+    use crate::NodeId;
+
+    #[test]
+    fn persistent_processes_are_placed_on_oldest_node() {
+        let candidates = [NodeId(5), NodeId(3), NodeId(4)];
+        let placement = StablePlacement;
+        assert_eq!(
+            placement.place(Lifecycle::Persistent, &candidates),
+            Some(NodeId(3))
+        );
+        assert_eq!(
+            placement.place(Lifecycle::Transient, &candidates),
+            Some(NodeId(5))
+        );
+        assert_eq!(
+            placement.place(Lifecycle::Unspecified, &candidates),
+            Some(NodeId(5))
+        );
+        assert_eq!(placement.place(Lifecycle::Persistent, &[]), None);
+    }
+}
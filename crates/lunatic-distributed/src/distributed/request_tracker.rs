@@ -0,0 +1,126 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use dashmap::DashMap;
+use tokio::sync::oneshot;
+
+use super::message::{ClientError, Response};
+
+/// Correlates responses from other nodes with the requests they answer.
+///
+/// Every request gets a unique `msg_id` that the node sends back with the response. Many requests
+/// can be in flight on the same connection and nodes respond in the order the requests finish,
+/// not in the order they arrived, so a response is matched to its request only by the id.
+///
+/// A request is registered before it's sent, so that a response can't arrive before anyone waits
+/// for it. Responses to requests nobody waits for anymore, or to unknown ids, are dropped.
+#[derive(Default)]
+pub struct RequestTracker {
+    next_id: AtomicU64,
+    pending: Arc<DashMap<u64, oneshot::Sender<Response>>>,
+}
+
+/// A registered request waiting for its response.
+///
+/// Dropping it stops waiting, the response is dropped when it arrives.
+pub struct PendingRequest {
+    msg_id: u64,
+    response: oneshot::Receiver<Response>,
+    pending: Arc<DashMap<u64, oneshot::Sender<Response>>>,
+}
+
+impl RequestTracker {
+    /// Returns a new unique message id, for requests that don't wait for a response.
+    pub fn next_id(&self) -> u64 {
+        // Ids start at 1
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Allocates an id for a new request and starts waiting for its response.
+    pub fn register(&self) -> PendingRequest {
+        let msg_id = self.next_id();
+        let (sender, response) = oneshot::channel();
+        self.pending.insert(msg_id, sender);
+        PendingRequest {
+            msg_id,
+            response,
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// Hands the response to the request with `msg_id`. Returns false if no request is waiting
+    /// for it, e.g. if it was already answered.
+    pub fn complete(&self, msg_id: u64, response: Response) -> bool {
+        match self.pending.remove(&msg_id) {
+            Some((_, sender)) => sender.send(response).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Returns the number of requests waiting for a response.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl PendingRequest {
+    pub fn msg_id(&self) -> u64 {
+        self.msg_id
+    }
+
+    /// Waits for the response.
+    pub async fn response(mut self) -> Result<Response, ClientError> {
+        (&mut self.response)
+            .await
+            .map_err(|_| ClientError::Unexpected("Request tracker dropped".to_string()))
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.pending.remove(&self.msg_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::RequestTracker;
+    use crate::{distributed::message::Response, ProcessId};
+
+    #[tokio::test]
+    async fn responses_are_matched_by_id_in_any_order() {
+        let tracker = RequestTracker::default();
+        let requests: Vec<_> = (0..100).map(|_| tracker.register()).collect();
+        let ids: HashSet<u64> = requests.iter().map(|request| request.msg_id()).collect();
+        assert_eq!(ids.len(), 100);
+        assert_eq!(tracker.pending(), 100);
+
+        // Answer in reverse order, each response carries the id of its request
+        for msg_id in requests.iter().rev().map(|request| request.msg_id()) {
+            assert!(tracker.complete(msg_id, Response::Spawned(ProcessId(msg_id))));
+        }
+        for request in requests {
+            let msg_id = request.msg_id();
+            match request.response().await.unwrap() {
+                Response::Spawned(ProcessId(id)) => assert_eq!(id, msg_id),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+        assert_eq!(tracker.pending(), 0);
+    }
+
+    #[test]
+    fn unknown_and_abandoned_responses_are_dropped() {
+        let tracker = RequestTracker::default();
+        let request = tracker.register();
+        let msg_id = request.msg_id();
+        assert!(!tracker.complete(msg_id + 1, Response::Sent));
+        drop(request);
+        assert_eq!(tracker.pending(), 0);
+        assert!(!tracker.complete(msg_id, Response::Sent));
+    }
+}
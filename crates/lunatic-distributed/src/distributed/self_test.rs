@@ -0,0 +1,149 @@
+/*!
+Self-test of the distributed stack of a node, for operators to confirm that the node is fully
+functional beyond a liveness probe.
+
+All checks share the deadline of the whole test, so the test finishes within its timeout even if
+a node doesn't respond. Checks don't change the state of the cluster, the process spawned by the
+round trip is killed again right away, also if the deadline passed in the meantime.
+*/
+
+use std::time::Duration;
+
+/// A check of the self-test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestCheck {
+    /// Lists the nodes of the cluster on the control server.
+    Control,
+    /// Pings each node known to this node, with one result per node.
+    Peers,
+    /// Spawns a process on one of the nodes that answered the ping, sends it a message and kills
+    /// it again. The nodes are also pinged if only this check is enabled.
+    RoundTrip,
+}
+
+impl SelfTestCheck {
+    /// All checks, ordered by their bit.
+    pub const ALL: [SelfTestCheck; 3] = [
+        SelfTestCheck::Control,
+        SelfTestCheck::Peers,
+        SelfTestCheck::RoundTrip,
+    ];
+
+    /// Bit of the check in the bitset of checks to run.
+    pub fn bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check didn't finish before the deadline.
+    TimedOut,
+    /// The check couldn't run, e.g. the round trip without any other node.
+    Skipped,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTestConfig {
+    // Bitset of `SelfTestCheck::bit` of the checks to run
+    pub checks: u32,
+    // Maximum duration of the whole test
+    pub timeout: Duration,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self {
+            checks: SelfTestCheck::ALL
+                .iter()
+                .fold(0, |bits, check| bits | check.bit()),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl SelfTestConfig {
+    pub fn runs(&self, check: SelfTestCheck) -> bool {
+        self.checks & check.bit() != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    pub check: SelfTestCheck,
+    // Node the check ran against, 0 for the control server
+    pub node_id: u64,
+    pub status: CheckStatus,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    /// Version of the encoding, increased when its layout changes.
+    pub const VERSION: u8 = 1;
+
+    pub fn push(&mut self, check: SelfTestCheck, node_id: u64, status: CheckStatus) {
+        self.results.push(CheckResult {
+            check,
+            node_id,
+            status,
+        });
+    }
+
+    /// Returns the number of checks that failed or timed out.
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result.status, CheckStatus::Failed | CheckStatus::TimedOut))
+            .count()
+    }
+
+    // Encodes the report as little endian values:
+    // [version: u8][count: u32]([check: u8][node_id: u64][status: u8]){count}
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![Self::VERSION];
+        data.extend((self.results.len() as u32).to_le_bytes());
+        for result in self.results.iter() {
+            data.push(result.check as u8);
+            data.extend(result.node_id.to_le_bytes());
+            data.push(result.status as u8);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CheckStatus, SelfTestCheck, SelfTestConfig, SelfTestReport};
+
+    #[test]
+    fn report_encodes_results_in_order() {
+        let config = SelfTestConfig {
+            checks: SelfTestCheck::Peers.bit(),
+            ..Default::default()
+        };
+        assert!(config.runs(SelfTestCheck::Peers));
+        assert!(!config.runs(SelfTestCheck::Control));
+        assert_eq!(SelfTestConfig::default().checks, 0b111);
+
+        let mut report = SelfTestReport::default();
+        report.push(SelfTestCheck::Control, 0, CheckStatus::Passed);
+        report.push(SelfTestCheck::Peers, 7, CheckStatus::TimedOut);
+        assert_eq!(report.failures(), 1);
+
+        let data = report.encode();
+        assert_eq!(data[0], SelfTestReport::VERSION);
+        assert_eq!(&data[1..5], &2u32.to_le_bytes());
+        assert_eq!(data[5], 0);
+        assert_eq!(data[14], 0);
+        assert_eq!(data[15], 1);
+        assert_eq!(&data[16..24], &7u64.to_le_bytes());
+        assert_eq!(data[24], 2);
+        assert_eq!(data.len(), 25);
+    }
+}
@@ -1,30 +1,68 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
+use dashmap::DashMap;
 
 use lunatic_process::{
+    cancel,
     env::{Environment, Environments},
-    message::{DataMessage, Message},
-    runtimes::{wasmtime::WasmtimeRuntime, Modules, RawWasm},
+    message::{DataMessage, Message, MessageSender, Priority},
+    runtimes::{
+        wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
+        Modules, RawWasm,
+    },
     state::ProcessState,
-    Signal,
+    topics, DeathReason, Process, Signal,
 };
 use rcgen::*;
+use tokio::sync::Mutex;
 use wasmtime::ResourceLimiter;
 
 use crate::{
     distributed::message::{Request, Response},
+    join_token::{self, JoinToken},
     quic::{self, SendStream},
-    DistributedCtx, DistributedProcessState,
+    DistributedCtx, DistributedProcessState, EnvironmentId, ModuleId, NodeId, ProcessId,
 };
 
-use super::message::{ClientError, Spawn};
+use super::{
+    allowlist::ModuleAllowlist,
+    connection_limit::ConnectionLimit,
+    dedup::DedupWindow,
+    fair_queue::FairQueue,
+    links::RemoteLink,
+    message::{ClientError, ReplyCapability, Spawn, Val, VersionedModule},
+    monitors::MonitorWatcher,
+    node_stats::NodeStats,
+    ordering::{ConnectionOrdering, SequentialRequests},
+    signature::ModuleVerifier,
+    spawn_config::SpawnConfigs,
+    spawn_queue::SpawnQueue,
+    transaction::StagedTransactions,
+};
 
 pub struct ServerCtx<T, E: Environment> {
     pub envs: Arc<dyn Environments<Env = E>>,
     pub modules: Modules<T>,
     pub distributed: DistributedProcessState,
     pub runtime: WasmtimeRuntime,
+    pub module_allowlist: ModuleAllowlist,
+    pub module_verifier: ModuleVerifier,
+    pub compile_failures: CompileFailures,
+    pub preload: ModulePreload,
+    pub spawn_configs: SpawnConfigs,
+    pub spawn_queue: SpawnQueue,
+    pub transactions: StagedTransactions,
+    pub connection_config: quic::ConnectionConfig,
+    pub fair_queue: FairQueue,
+    pub dedup: DedupWindow,
+    pub connection_ordering: ConnectionOrdering,
+    pub connection_limit: ConnectionLimit,
 }
 
 impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
@@ -34,7 +72,129 @@ impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
             modules: self.modules.clone(),
             distributed: self.distributed.clone(),
             runtime: self.runtime.clone(),
+            module_allowlist: self.module_allowlist.clone(),
+            module_verifier: self.module_verifier.clone(),
+            compile_failures: self.compile_failures.clone(),
+            preload: self.preload.clone(),
+            spawn_configs: self.spawn_configs.clone(),
+            spawn_queue: self.spawn_queue.clone(),
+            transactions: self.transactions.clone(),
+            connection_config: self.connection_config,
+            fair_queue: self.fair_queue.clone(),
+            dedup: self.dedup.clone(),
+            connection_ordering: self.connection_ordering,
+            connection_limit: self.connection_limit.clone(),
+        }
+    }
+}
+
+/// Remembers modules that failed to compile, so that repeated spawns of the same module fail fast
+/// instead of recompiling it, until the failure expires.
+///
+/// Concurrent spawns of a module that is not compiled yet wait for a single compilation and share
+/// its result, so a burst of spawns of an invalid module results in one attempt and one log line.
+#[derive(Clone)]
+pub struct CompileFailures {
+    ttl: Duration,
+    failures: Arc<DashMap<u64, (Instant, String)>>,
+    // Module ID -> lock held while the module is fetched and compiled
+    compiling: Arc<DashMap<u64, Arc<Mutex<()>>>>,
+}
+
+impl CompileFailures {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            failures: Arc::new(DashMap::new()),
+            compiling: Arc::new(DashMap::new()),
+        }
+    }
+
+    fn get(&self, module_id: u64) -> Option<String> {
+        self.failures.remove_if(&module_id, |_, (failed_at, _)| {
+            failed_at.elapsed() >= self.ttl
+        });
+        self.failures
+            .get(&module_id)
+            .map(|failure| failure.1.clone())
+    }
+
+    fn insert(&self, module_id: u64, error: String) {
+        self.failures.insert(module_id, (Instant::now(), error));
+    }
+
+    /// Runs `compile` unless the module recently failed to compile or another caller is already
+    /// compiling it. In the latter case the result is taken from `cached` or from the recorded
+    /// failure once the other compilation finished.
+    async fn compile_once<M, F>(
+        &self,
+        module_id: u64,
+        cached: impl Fn() -> Option<M>,
+        compile: F,
+    ) -> Result<Result<M, ClientError>>
+    where
+        F: Future<Output = Result<Result<M, ClientError>>>,
+    {
+        if let Some(error) = self.get(module_id) {
+            return Ok(Err(ClientError::ModuleCompilation(error)));
+        }
+        let lock = self.compiling.entry(module_id).or_default().clone();
+        let _compiling = lock.lock().await;
+        if let Some(module) = cached() {
+            return Ok(Ok(module));
         }
+        if let Some(error) = self.get(module_id) {
+            return Ok(Err(ClientError::ModuleCompilation(error)));
+        }
+
+        let result = compile.await;
+        if let Ok(Err(ClientError::ModuleCompilation(error))) = &result {
+            log::warn!("Module {module_id} failed to compile: {error}");
+            self.insert(module_id, error.clone());
+        }
+        self.compiling.remove(&module_id);
+        result
+    }
+}
+
+impl Default for CompileFailures {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+/// Modules that are fetched and compiled when the node server starts, before it accepts
+/// connections, so that the first spawns of them don't pay for compilation.
+#[derive(Clone, Debug, Default)]
+pub struct ModulePreload {
+    pub module_ids: Vec<ModuleId>,
+    // If `true` the node server doesn't start if a module can't be preloaded, otherwise the
+    // failure is only logged.
+    pub fail_on_error: bool,
+}
+
+impl ModulePreload {
+    // Loads the modules one after another with `load`, which puts them into the module cache.
+    async fn run<M, F, Fut>(&self, load: F) -> Result<()>
+    where
+        F: Fn(ModuleId) -> Fut,
+        Fut: Future<Output = Result<Result<M, ClientError>>>,
+    {
+        for &module_id in self.module_ids.iter() {
+            let error = match load(module_id).await {
+                Ok(Ok(_)) => {
+                    log::debug!("Preloaded module {module_id}");
+                    continue;
+                }
+                Ok(Err(error)) => format!("{error:?}"),
+                Err(error) => error.to_string(),
+            };
+            if self.fail_on_error {
+                return Err(anyhow!("Failed to preload module {module_id}: {error}"));
+            }
+            log::warn!("Failed to preload module {module_id}: {error}");
+        }
+        Ok(())
     }
 }
 
@@ -59,86 +219,506 @@ pub fn gen_node_cert(node_name: &str) -> Result<Certificate> {
         .map_err(|_| anyhow!("Error while generating node certificate."))
 }
 
+/// Accepts requests from other nodes on `socket`. If `control_socket` is set, a second listener
+/// is bound to it for control-plane requests, which other nodes then send there instead.
+///
+/// If `client_ca` is set, both listeners only accept nodes that present a client certificate
+/// signed by it.
 pub async fn node_server<T, E>(
     ctx: ServerCtx<T, E>,
     socket: SocketAddr,
+    control_socket: Option<SocketAddr>,
     cert: String,
     key: String,
+    client_ca: Option<String>,
 ) -> Result<()>
 where
     T: ProcessState + ResourceLimiter + DistributedCtx<E> + Send + 'static,
     E: Environment + 'static,
 {
-    let mut quic_server = quic::new_quic_server(socket, &cert, &key)?;
+    preload_modules(ctx.clone()).await?;
+    let listen = |socket| match &client_ca {
+        Some(client_ca) => quic::new_mtls_quic_server(socket, &cert, &key, client_ca),
+        None => quic::new_quic_server(socket, &cert, &key),
+    };
+    let mut quic_server = listen(socket)?;
+    if let Some(control_socket) = control_socket {
+        let mut control_server = listen(control_socket)?;
+        let ctx = ctx.clone();
+        tokio::spawn(async move { quic::handle_node_server(&mut control_server, ctx).await });
+    }
     quic::handle_node_server(&mut quic_server, ctx.clone()).await?;
     Ok(())
 }
 
+async fn preload_modules<T, E>(ctx: ServerCtx<T, E>) -> Result<()>
+where
+    T: ProcessState + ResourceLimiter + DistributedCtx<E> + Send + 'static,
+    E: Environment + 'static,
+{
+    ctx.preload
+        .run(|module_id| get_module(ctx.clone(), module_id))
+        .await
+}
+
+/// Handles the request and sends the response on `send`. If `sequential` is set, the request
+/// waits for the requests queued there before it.
+///
+/// Requests are only handled if `join_token` is accepted by the token this node is configured
+/// with, otherwise any node could spawn processes or mark members of the cluster as dead.
 pub async fn handle_message<T, E>(
     ctx: ServerCtx<T, E>,
     send: &mut SendStream,
+    connection_id: u64,
     msg_id: u64,
     msg: Request,
+    join_token: Option<JoinToken>,
+    sequential: Option<&SequentialRequests>,
 ) where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
     E: Environment + 'static,
 {
-    if let Err(e) = handle_message_err(ctx, send, msg_id, msg).await {
+    let expected_token = ctx.distributed.node_client.config().join_token.as_ref();
+    if !join_token::accepts(expected_token, join_token.as_ref()) {
+        let response = Response::Error(ClientError::InvalidJoinToken);
+        let mut data = super::message::pack_response(msg_id, response);
+        if let Err(e) = send.send(&mut data).await {
+            log::error!("Error handling message: {e}");
+        }
+        return;
+    }
+    let in_flight = ctx.distributed.in_flight.clone();
+    let fair_queue = ctx.fair_queue.clone();
+    let environment_id = msg.environment_id();
+    let kind = msg.kind();
+    let kind_code = msg.kind_code();
+    let handler = async move {
+        in_flight
+            .run(
+                connection_id,
+                msg_id,
+                environment_id.into(),
+                kind,
+                kind_code,
+                async move {
+                    // Wait for the turn of the environment, so busy environments can't starve
+                    // others.
+                    let _slot = fair_queue.admit(environment_id.into()).await;
+                    handle_request(ctx, msg).await
+                },
+            )
+            .await
+    };
+    let response = match sequential {
+        Some(sequential) => sequential.run(handler).await,
+        None => handler.await,
+    };
+    let mut data = super::message::pack_response(msg_id, response);
+    if let Err(e) = send.send(&mut data).await {
         log::error!("Error handling message: {e}");
     }
 }
 
-async fn handle_message_err<T, E>(
-    ctx: ServerCtx<T, E>,
-    send: &mut SendStream,
-    msg_id: u64,
-    msg: Request,
-) -> Result<()>
+async fn handle_request<T, E>(ctx: ServerCtx<T, E>, msg: Request) -> Response
 where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
     E: Environment + 'static,
 {
     match msg {
         Request::Spawn(spawn) => {
-            match handle_spawn(ctx, spawn).await {
-                Ok(Ok(id)) => {
-                    let mut data = super::message::pack_response(msg_id, Response::Spawned(id));
-                    send.send(&mut data).await?;
-                }
-                Ok(Err(client_error)) => {
-                    let mut data =
-                        super::message::pack_response(msg_id, Response::Error(client_error));
-                    send.send(&mut data).await?;
+            let dedup = ctx.dedup.clone();
+            dedup
+                .run(spawn.idempotency_key, async move {
+                    // Hold the worker until the process is spawned.
+                    let _slot = match ctx.spawn_queue.admit().await {
+                        Ok(slot) => slot,
+                        Err(error) => return Response::Error(error),
+                    };
+                    match handle_spawn(ctx, spawn, None).await {
+                        Ok(Ok(ids)) => Response::Spawned(ids[0]),
+                        Ok(Err(client_error)) => Response::Error(client_error),
+                        Err(error) => Response::Error(ClientError::SpawnFailed(error.to_string())),
+                    }
+                })
+                .await
+        }
+        Request::SpawnReplicated { spawn, count } => {
+            let dedup = ctx.dedup.clone();
+            dedup
+                .run(spawn.idempotency_key, async move {
+                    // All replicas are spawned with one worker, like a single spawn.
+                    let _slot = match ctx.spawn_queue.admit().await {
+                        Ok(slot) => slot,
+                        Err(error) => return Response::Error(error),
+                    };
+                    match handle_spawn(ctx, spawn, Some(count)).await {
+                        Ok(Ok(ids)) => Response::SpawnedMany(ids),
+                        Ok(Err(client_error)) => Response::Error(client_error),
+                        Err(error) => Response::Error(ClientError::SpawnFailed(error.to_string())),
+                    }
+                })
+                .await
+        }
+        Request::Message {
+            environment_id,
+            process_id,
+            tag,
+            priority,
+            data,
+            reply_cap,
+            sender,
+            delivery_id,
+        } => match data.into_data() {
+            Ok(data) => {
+                let node_client = ctx.distributed.node_client.clone();
+                let mut message = incoming_message(tag, priority, data, reply_cap, sender);
+                // Messages sent with at-least-once delivery carry a receipt the receiver acks, and
+                // their id in the delivery log to drop duplicates.
+                if let (Some(delivery_id), Some((node_id, _))) = (delivery_id, sender) {
+                    message.receipt = Some(node_client.issue_receipt(
+                        node_id,
+                        delivery_id,
+                        (environment_id, process_id),
+                    ));
+                    message.unique_id = Some(delivery_id);
                 }
-                Err(error) => {
-                    let mut data = super::message::pack_response(
-                        msg_id,
-                        Response::Error(ClientError::Unexpected(error.to_string())),
-                    );
-                    send.send(&mut data).await?
+                let receipt = message.receipt;
+                match handle_process_message(ctx, environment_id, process_id, message).await {
+                    Ok(_) => Response::Sent,
+                    Err(error) => {
+                        // The sender drops undeliverable messages, nobody can ack them.
+                        if let Some(receipt) = receipt {
+                            node_client.remove_receipt(receipt);
+                        }
+                        Response::Error(error)
+                    }
                 }
+            }
+            Err(error) => Response::Error(error),
+        },
+        Request::Ack { delivery_id, .. } => {
+            // Duplicate acks are ignored.
+            ctx.distributed.node_client.ack_delivery(delivery_id);
+            Response::Sent
+        }
+        Request::AckMany { delivery_ids, .. } => {
+            ctx.distributed.node_client.ack_deliveries(&delivery_ids);
+            Response::Sent
+        }
+        Request::Ping { .. } => Response::Sent,
+        Request::Reply {
+            token, tag, data, ..
+        } => match handle_reply(ctx, token, tag, data).await {
+            Ok(_) => Response::Sent,
+            Err(error) => Response::Error(error),
+        },
+        Request::Publish {
+            environment_id,
+            topic,
+            tag,
+            priority,
+            data,
+        } => {
+            // Without an environment there can't be any subscribers.
+            let delivered = match ctx.envs.get(environment_id.into()) {
+                Some(env) => topics::publish(env.as_ref(), &topic, tag, priority, &data),
+                None => 0,
             };
+            Response::Published(delivered as u64)
         }
-        Request::Message {
+        Request::Kill {
             environment_id,
             process_id,
+        } => match ctx
+            .envs
+            .get(environment_id.into())
+            .and_then(|env| env.get_process(process_id.into()))
+        {
+            Some(proc) => {
+                proc.send(Signal::Kill);
+                Response::Sent
+            }
+            None => Response::Error(ClientError::ProcessNotFound),
+        },
+        Request::Stage {
+            environment_id,
+            transaction_id,
+            process_ids,
             tag,
+            priority,
             data,
-        } => match handle_process_message(ctx, environment_id, process_id, tag, data).await {
-            Ok(_) => {
-                let mut data = super::message::pack_response(msg_id, Response::Sent);
-                send.send(&mut data).await?;
+            sender,
+        } => {
+            let sender = sender.map(|(node_id, process_id)| MessageSender {
+                node_id: node_id.into(),
+                process_id: process_id.into(),
+            });
+            let process_ids: Vec<u64> = process_ids.into_iter().map(Into::into).collect();
+            let staged = match ctx.envs.get(environment_id.into()) {
+                Some(env) => stage_messages(
+                    env.as_ref(),
+                    &ctx.transactions,
+                    transaction_id,
+                    &process_ids,
+                    tag,
+                    priority,
+                    &data,
+                    sender,
+                ),
+                None => Err(ClientError::ProcessNotFound),
+            };
+            match staged {
+                Ok(_) => Response::Sent,
+                Err(error) => Response::Error(error),
             }
-            Err(error) => {
-                let mut data = super::message::pack_response(msg_id, Response::Error(error));
-                send.send(&mut data).await?;
+        }
+        Request::Commit {
+            environment_id,
+            transaction_id,
+        } => {
+            let committed = match ctx.envs.get(environment_id.into()) {
+                Some(env) => commit_transaction(env.as_ref(), &ctx.transactions, transaction_id),
+                None => {
+                    ctx.transactions.rollback(transaction_id);
+                    Err(ClientError::ProcessNotFound)
+                }
+            };
+            match committed {
+                Ok(_) => Response::Sent,
+                Err(error) => Response::Error(error),
+            }
+        }
+        Request::Rollback { transaction_id, .. } => {
+            ctx.transactions.rollback(transaction_id);
+            Response::Sent
+        }
+        Request::Cancel {
+            environment_id,
+            token,
+        } => {
+            let killed = match ctx.envs.get(environment_id.into()) {
+                Some(env) => cancel::cancel(env.as_ref(), token),
+                None => 0,
+            };
+            Response::Cancelled(killed as u64)
+        }
+        Request::NotifyOnExit {
+            environment_id,
+            process_id,
+            tag,
+            watcher: (node_id, watcher_id),
+        } => {
+            let watcher = Arc::new(RemoteWatcher {
+                node_client: ctx.distributed.node_client.clone(),
+                node_id,
+                environment_id,
+                process_id: watcher_id,
+            });
+            match ctx.envs.get(environment_id.into()) {
+                Some(env) => {
+                    lunatic_process::notify_on_exit(env.as_ref(), process_id.into(), tag, watcher)
+                }
+                // Without an environment the process can't exist.
+                None => watcher.send(Signal::Message(Message::Data(
+                    lunatic_process::exit_notification(
+                        tag,
+                        process_id.into(),
+                        &DeathReason::NoProcess,
+                    ),
+                ))),
             }
+            Response::Sent
+        }
+        Request::Link {
+            environment_id,
+            process_id,
+            tag,
+            linked: (node_id, linked_id),
+        } => match ctx
+            .envs
+            .get(environment_id.into())
+            .and_then(|env| env.get_process(process_id.into()))
+        {
+            Some(proc) => {
+                let node_client = ctx.distributed.node_client.clone();
+                // If the other node leaves, the process is told here that its link died
+                node_client
+                    .remote_links()
+                    .add(node_id, linked_id, tag, proc.clone());
+                proc.send(Signal::Link(
+                    tag,
+                    Arc::new(RemoteLink {
+                        node_client,
+                        node_id,
+                        environment_id,
+                        process_id: linked_id,
+                    }),
+                ));
+                Response::Linked
+            }
+            None => Response::Error(ClientError::ProcessNotFound),
         },
-    };
-    Ok(())
+        Request::LinkDied {
+            environment_id,
+            process_id,
+            linked: (node_id, linked_id),
+            tag,
+            failed,
+        } => {
+            ctx.distributed
+                .node_client
+                .remote_links()
+                .remove(node_id, linked_id, process_id);
+            match ctx
+                .envs
+                .get(environment_id.into())
+                .and_then(|env| env.get_process(process_id.into()))
+            {
+                Some(proc) => {
+                    let reason = if failed {
+                        DeathReason::Failure
+                    } else {
+                        DeathReason::Normal
+                    };
+                    proc.send(Signal::LinkDied(linked_id.into(), tag, reason));
+                    Response::Sent
+                }
+                None => Response::Error(ClientError::ProcessNotFound),
+            }
+        }
+        Request::Monitor {
+            environment_id,
+            process_id,
+            monitor_id,
+            node_id,
+        } => {
+            let node_client = ctx.distributed.node_client.clone();
+            node_client.monitors().watch(node_id, monitor_id);
+            let watcher = Arc::new(MonitorWatcher {
+                node_client,
+                monitor_id,
+                node_id,
+                environment_id,
+            });
+            match ctx.envs.get(environment_id.into()) {
+                Some(env) => {
+                    lunatic_process::notify_on_exit(env.as_ref(), process_id.into(), 0, watcher)
+                }
+                // Without an environment the process can't exist.
+                None => watcher.send(Signal::Message(Message::Data(
+                    lunatic_process::exit_notification(
+                        0,
+                        process_id.into(),
+                        &DeathReason::NoProcess,
+                    ),
+                ))),
+            }
+            Response::Sent
+        }
+        Request::Demonitor {
+            monitor_id,
+            node_id,
+            ..
+        } => {
+            // The process still notifies the watcher when it exits, which then drops it
+            ctx.distributed
+                .node_client
+                .monitors()
+                .unwatch(node_id, monitor_id);
+            Response::Sent
+        }
+        Request::Gossip {
+            updates, target, ..
+        } => {
+            let control = &ctx.distributed.control;
+            let membership = match control.membership() {
+                Some(membership) => membership,
+                None => {
+                    return Response::Error(ClientError::Unexpected(
+                        "Node doesn't gossip the membership".to_string(),
+                    ))
+                }
+            };
+            control.apply_gossip(updates);
+            if let Some(target) = target {
+                let probe = ctx
+                    .distributed
+                    .node_client
+                    .gossip(target, membership.updates(), None);
+                match tokio::time::timeout(membership.config().probe_timeout, probe).await {
+                    Ok(Ok(updates)) => control.apply_gossip(updates),
+                    _ => {
+                        return Response::Error(ClientError::Connection(format!(
+                            "Node {target} didn't answer the probe"
+                        )))
+                    }
+                }
+            }
+            Response::Gossip(membership.updates())
+        }
+        Request::ProcessDown {
+            monitor_id, data, ..
+        } => {
+            ctx.distributed
+                .node_client
+                .monitors()
+                .deliver(monitor_id, data);
+            Response::Sent
+        }
+        Request::NodeInfo { .. } => {
+            Response::NodeInfo(NodeStats::collect(ctx.envs.as_ref(), ctx.modules.len()))
+        }
+    }
+}
+
+// Forwards exit notifications to a process watching from another node.
+struct RemoteWatcher {
+    node_client: super::Client,
+    node_id: NodeId,
+    environment_id: EnvironmentId,
+    process_id: ProcessId,
+}
+
+impl Process for RemoteWatcher {
+    fn id(&self) -> u64 {
+        self.process_id.into()
+    }
+
+    fn send(&self, signal: Signal) {
+        if let Signal::Message(Message::Data(message)) = signal {
+            let node_client = self.node_client.clone();
+            let (node_id, environment_id, process_id) =
+                (self.node_id, self.environment_id, self.process_id);
+            tokio::spawn(async move {
+                if let Err(error) = node_client
+                    .message_process(
+                        node_id,
+                        environment_id,
+                        process_id,
+                        message.tag,
+                        message.priority,
+                        message.buffer,
+                        None,
+                        None,
+                    )
+                    .await
+                {
+                    log::debug!(
+                        "Failed to notify process {process_id} on node {node_id}: {error:?}"
+                    );
+                }
+            });
+        }
+    }
 }
 
-async fn handle_spawn<T, E>(ctx: ServerCtx<T, E>, spawn: Spawn) -> Result<Result<u64, ClientError>>
+// Spawns one process, or `replicas` processes that each get their index appended to the params.
+// If one of the replicas fails to spawn, the ones already spawned are killed.
+async fn handle_spawn<T, E>(
+    ctx: ServerCtx<T, E>,
+    spawn: Spawn,
+    replicas: Option<u32>,
+) -> Result<Result<Vec<ProcessId>, ClientError>>
 where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
     E: Environment + 'static,
@@ -146,51 +726,296 @@ where
     let Spawn {
         environment_id,
         module_id,
+        module_version,
         function,
         params,
         config,
+        idempotency_key: _,
+        cancel_token,
+        lifecycle: _,
     } = spawn;
 
+    if ctx.distributed.control.is_draining() {
+        return Ok(Err(ClientError::NodeDraining));
+    }
+    if !ctx.distributed.control.accepts_spawns() {
+        return Ok(Err(ClientError::ControlUnavailable));
+    }
+    let config = match ctx.spawn_configs.resolve(config) {
+        Some(config) => config,
+        None => return Ok(Err(ClientError::UnknownConfig)),
+    };
     let config: T::Config = bincode::deserialize(&config[..])?;
     let config = Arc::new(config);
 
+    // Processes spawned from earlier versions keep running, only new spawns use the resolved one.
+    let module_id = match module_version {
+        Some(VersionedModule { name, version }) => {
+            match ctx.distributed.control.resolve_module(&name, version).await {
+                Ok(module_id) => ModuleId(module_id),
+                Err(error) => {
+                    log::debug!("Cannot resolve module {name} version {version:?}: {error}");
+                    return Ok(Err(ClientError::ModuleNotFound));
+                }
+            }
+        }
+        None => module_id,
+    };
+    let module = match get_module(ctx.clone(), module_id).await? {
+        Ok(module) => module,
+        Err(error) => return Ok(Err(error)),
+    };
+    // Otherwise the process would only fail after the spawn succeeded
+    let exported = module
+        .exports()
+        .any(|export| export.name() == function && export.ty().func().is_some());
+    if !exported {
+        return Ok(Err(ClientError::FunctionNotFound));
+    }
+
+    // Concurrent spawns into a new environment must end up in the same environment, otherwise
+    // processes could be registered in an environment that is replaced right after.
+    let env = ctx.envs.get_or_create(environment_id.into());
+    let mut procs = Vec::new();
+    for params in replica_params(params, replicas) {
+        let distributed = ctx.distributed.clone();
+        let runtime = ctx.runtime.clone();
+        let params: Vec<wasmtime::Val> = params.into_iter().map(Into::into).collect();
+        // `spawn_wasm` adds the process to the environment before returning, so messages sent
+        // right after `Response::Spawned` is received will find it.
+        let spawned = match T::new_dist_state(
+            env.clone(),
+            distributed,
+            runtime,
+            module.clone(),
+            config.clone(),
+        ) {
+            Ok(state) => {
+                let process_id = state.id();
+                if let Some(token) = cancel_token {
+                    env.cancel_tokens().attach(token, process_id);
+                }
+                let spawned = lunatic_process::wasm::spawn_wasm(
+                    env.clone(),
+                    ctx.runtime.clone(),
+                    &module,
+                    state,
+                    &function,
+                    params,
+                    None,
+                )
+                .await;
+                if spawned.is_err() {
+                    env.cancel_tokens().detach(process_id);
+                }
+                spawned
+            }
+            Err(error) => Err(error),
+        };
+        match spawned {
+            Ok((_handle, proc)) => procs.push(proc),
+            Err(error) => {
+                for proc in procs {
+                    proc.send(Signal::Kill);
+                }
+                return Err(error);
+            }
+        }
+    }
+    Ok(Ok(procs.iter().map(|proc| ProcessId(proc.id())).collect()))
+}
+
+// Returns the params of each process to spawn. Replicas get their index appended as an i32.
+fn replica_params(params: Vec<Val>, replicas: Option<u32>) -> Vec<Vec<Val>> {
+    match replicas {
+        None => vec![params],
+        Some(count) => (0..count)
+            .map(|index| {
+                let mut params = params.clone();
+                params.push(Val::I32(index as i32));
+                params
+            })
+            .collect(),
+    }
+}
+
+// Returns the compiled module from the cache, or fetches it from the control server and compiles
+// it first.
+async fn get_module<T, E>(
+    ctx: ServerCtx<T, E>,
+    module_id: ModuleId,
+) -> Result<Result<Arc<WasmtimeCompiledModule<T>>, ClientError>>
+where
+    T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
+    E: Environment + 'static,
+{
+    let module_id = u64::from(module_id);
     let module = match ctx.modules.get(module_id) {
-        Some(module) => module,
+        Some(module) => {
+            if !ctx
+                .module_allowlist
+                .is_allowed(module_id, module.source().as_slice())
+            {
+                return Ok(Err(ClientError::PermissionDenied));
+            }
+            module
+        }
         None => {
-            if let Some(bytes) = ctx.distributed.control.get_module(module_id).await {
-                let wasm = RawWasm::new(Some(module_id), bytes);
-                ctx.modules.compile(ctx.runtime.clone(), wasm).await??
-            } else {
-                return Ok(Err(ClientError::ModuleNotFound));
+            let modules = ctx.modules.clone();
+            let compile = fetch_and_compile(ctx.clone(), ModuleId(module_id));
+            match ctx
+                .compile_failures
+                .compile_once(module_id, move || modules.get(module_id), compile)
+                .await?
+            {
+                Ok(module) => module,
+                Err(error) => return Ok(Err(error)),
             }
         }
     };
+    Ok(Ok(module))
+}
 
-    let env = ctx
-        .envs
-        .get(environment_id)
-        .unwrap_or_else(|| ctx.envs.create(environment_id));
-    let distributed = ctx.distributed.clone();
-    let runtime = ctx.runtime.clone();
-    let state = T::new_dist_state(env.clone(), distributed, runtime, module.clone(), config)?;
-    let params: Vec<wasmtime::Val> = params.into_iter().map(Into::into).collect();
-    let (_handle, proc) = lunatic_process::wasm::spawn_wasm(
-        env,
-        ctx.runtime,
-        &module,
-        state,
-        &function,
-        params,
-        None,
-    )
-    .await?;
-    Ok(Ok(proc.id()))
+async fn fetch_and_compile<T, E>(
+    ctx: ServerCtx<T, E>,
+    module_id: ModuleId,
+) -> Result<Result<Arc<WasmtimeCompiledModule<T>>, ClientError>>
+where
+    T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
+    E: Environment + 'static,
+{
+    let module_id = u64::from(module_id);
+    if let Some(module) = ctx.distributed.control.get_module(module_id).await {
+        if !ctx.module_allowlist.is_allowed(module_id, &module.bytes) {
+            return Ok(Err(ClientError::PermissionDenied));
+        }
+        // Don't trust the control server, it could have replaced the module bytes.
+        if !ctx
+            .module_verifier
+            .verify(&module.bytes, module.signature.as_deref())
+        {
+            log::warn!("Rejecting module {module_id} without a valid signature");
+            return Ok(Err(ClientError::UntrustedModule));
+        }
+        let wasm = RawWasm::new(Some(module_id), module.bytes);
+        let module = ctx.modules.compile(ctx.runtime.clone(), wasm).await?;
+        Ok(module.map_err(compile_error))
+    } else {
+        Ok(Err(ClientError::ModuleNotFound))
+    }
+}
+
+// Keeps the whole chain of causes, it contains the failed validation.
+fn compile_error(error: anyhow::Error) -> ClientError {
+    ClientError::ModuleCompilation(format!("{error:#}"))
 }
 
 async fn handle_process_message<T, E>(
     ctx: ServerCtx<T, E>,
-    environment_id: u64,
+    environment_id: EnvironmentId,
+    process_id: ProcessId,
+    message: DataMessage,
+) -> std::result::Result<(), ClientError>
+where
+    T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
+    E: Environment,
+{
+    let env = ctx.envs.get(environment_id.into());
+    if let Some(env) = env {
+        return deliver_message(env.as_ref(), process_id.into(), message);
+    }
+    Err(ClientError::ProcessNotFound)
+}
+
+// Rebuilds a message that was sent by a process on another node.
+fn incoming_message(
+    tag: Option<i64>,
+    priority: Priority,
+    data: Vec<u8>,
+    reply_cap: Option<ReplyCapability>,
+    sender: Option<(NodeId, ProcessId)>,
+) -> DataMessage {
+    let mut message = DataMessage::new_from_vec(tag, data).with_priority(priority);
+    // Messages from other nodes don't carry resources, so the capability always ends up
+    // at index 0.
+    if let Some(reply_cap) = reply_cap {
+        message.add_resource(Arc::new(reply_cap));
+    }
+    message.sender = sender.map(|(node_id, process_id)| MessageSender {
+        node_id: node_id.into(),
+        process_id: process_id.into(),
+    });
+    message
+}
+
+fn deliver_message<E: Environment + ?Sized>(
+    env: &E,
     process_id: u64,
+    message: DataMessage,
+) -> std::result::Result<(), ClientError> {
+    match env.get_process(process_id) {
+        Some(proc) => {
+            // A message dropped by an interceptor still counts as delivered for the sender.
+            if let Some(message) = env.interceptors().apply(message) {
+                proc.send(Signal::Message(Message::Data(message)));
+            }
+            Ok(())
+        }
+        None => Err(ClientError::ProcessNotFound),
+    }
+}
+
+// Stages a copy of the message for each process, or nothing if one of them doesn't exist.
+#[allow(clippy::too_many_arguments)]
+fn stage_messages<E: Environment + ?Sized>(
+    env: &E,
+    transactions: &StagedTransactions,
+    transaction_id: u128,
+    process_ids: &[u64],
+    tag: Option<i64>,
+    priority: Priority,
+    data: &[u8],
+    sender: Option<MessageSender>,
+) -> std::result::Result<(), ClientError> {
+    if process_ids
+        .iter()
+        .any(|&process_id| env.get_process(process_id).is_none())
+    {
+        return Err(ClientError::ProcessNotFound);
+    }
+    let messages = process_ids
+        .iter()
+        .map(|&process_id| {
+            let mut message = DataMessage::new_from_vec(tag, data.to_vec()).with_priority(priority);
+            message.sender = sender;
+            (process_id, message)
+        })
+        .collect();
+    transactions.stage(transaction_id, messages);
+    Ok(())
+}
+
+// Delivers the staged messages of the transaction. Processes that died after their message was
+// staged don't receive it, but the commit still succeeds.
+fn commit_transaction<E: Environment + ?Sized>(
+    env: &E,
+    transactions: &StagedTransactions,
+    transaction_id: u128,
+) -> std::result::Result<(), ClientError> {
+    let messages = transactions
+        .commit(transaction_id)
+        .ok_or(ClientError::TransactionExpired)?;
+    for (process_id, message) in messages {
+        if deliver_message(env, process_id, message).is_err() {
+            log::debug!("Process {process_id} died before transaction {transaction_id} committed");
+        }
+    }
+    Ok(())
+}
+
+async fn handle_reply<T, E>(
+    ctx: ServerCtx<T, E>,
+    token: u128,
     tag: Option<i64>,
     data: Vec<u8>,
 ) -> std::result::Result<(), ClientError>
@@ -198,15 +1023,396 @@ where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
     E: Environment,
 {
-    let env = ctx.envs.get(environment_id);
-    if let Some(env) = env {
-        if let Some(proc) = env.get_process(process_id) {
-            proc.send(Signal::Message(Message::Data(DataMessage::new_from_vec(
-                tag, data,
-            ))));
-        } else {
-            return Err(ClientError::ProcessNotFound);
+    // Replies to calls are handed to the waiting call instead of the mailbox
+    let data = match ctx.distributed.node_client.complete_call(token, data) {
+        Ok(()) => return Ok(()),
+        Err(data) => data,
+    };
+    match ctx.distributed.node_client.redeem_reply_capability(token) {
+        Some((environment_id, process_id)) => {
+            let message = DataMessage::new_from_vec(tag, data);
+            handle_process_message(ctx, environment_id, process_id, message).await
         }
+        None => Err(ClientError::InvalidCapability),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use dashmap::DashMap;
+    use lunatic_process::{
+        env::{Environment, Environments, LunaticEnvironment, LunaticEnvironments},
+        message::{DataMessage, Message, MessageSender, Priority},
+        Process, Signal,
+    };
+
+    use super::{
+        commit_transaction, compile_error, deliver_message, incoming_message, replica_params,
+        stage_messages, CompileFailures, ModulePreload,
+    };
+    use crate::{
+        distributed::{
+            message::{ClientError, Payload, Request, Val},
+            transaction::StagedTransactions,
+        },
+        EnvironmentId, ModuleId, NodeId, ProcessId,
+    };
+
+    #[derive(Default)]
+    struct Receiver {
+        senders: std::sync::Mutex<Vec<Option<MessageSender>>>,
+        priorities: std::sync::Mutex<Vec<Priority>>,
+    }
+
+    impl Process for Receiver {
+        fn id(&self) -> u64 {
+            1
+        }
+
+        fn send(&self, signal: Signal) {
+            if let Signal::Message(Message::Data(message)) = signal {
+                self.senders.lock().unwrap().push(message.sender);
+                self.priorities.lock().unwrap().push(message.priority);
+            }
+        }
+    }
+
+    #[test]
+    fn delivered_message_carries_sender() {
+        let env = LunaticEnvironment::new(1);
+        let receiver = Arc::new(Receiver::default());
+        env.add_process(1, receiver.clone());
+
+        let sender = MessageSender {
+            node_id: 3,
+            process_id: 7,
+        };
+        let mut message = DataMessage::new_from_vec(None, vec![1]);
+        message.sender = Some(sender);
+        deliver_message(&env, 1, message).unwrap();
+        // Senders that don't provide their ids are still delivered
+        deliver_message(&env, 1, DataMessage::new_from_vec(None, vec![2])).unwrap();
+        assert!(matches!(
+            deliver_message(&env, 2, DataMessage::new_from_vec(None, vec![3])),
+            Err(ClientError::ProcessNotFound)
+        ));
+
+        assert_eq!(*receiver.senders.lock().unwrap(), vec![Some(sender), None]);
+    }
+
+    #[tokio::test]
+    async fn spawned_processes_receive_messages_sent_right_away() {
+        let envs = LunaticEnvironments::default();
+        let spawns: Vec<_> = (0..50)
+            .map(|_| {
+                let envs = envs.clone();
+                tokio::spawn(async move {
+                    // Spawn the way `handle_spawn` does, the process is added before returning
+                    let env = envs.get_or_create(1);
+                    let (received, receive) = tokio::sync::oneshot::channel();
+                    let (_, process) =
+                        lunatic_process::spawn(env.clone(), |_this, mailbox| async move {
+                            let message = mailbox.pop(None).await;
+                            let _ = received.send(message.tag());
+                            Ok::<_, anyhow::Error>(())
+                        });
+                    assert!(env.add_process(process.id(), Arc::new(process.clone())));
+
+                    // The spawner messages the process as soon as it learns its id
+                    let env = envs.get(1).unwrap();
+                    let message = DataMessage::new_from_vec(Some(7), vec![]);
+                    deliver_message(env.as_ref(), process.id(), message).unwrap();
+                    tokio::time::timeout(Duration::from_secs(5), receive).await
+                })
+            })
+            .collect();
+        for spawn in spawns {
+            let tag = spawn.await.unwrap().expect("message lost").unwrap();
+            assert_eq!(tag, Some(7));
+        }
+    }
+
+    #[test]
+    fn priority_is_kept_across_nodes() {
+        let env = LunaticEnvironment::new(1);
+        let receiver = Arc::new(Receiver::default());
+        env.add_process(1, receiver.clone());
+
+        for priority in [Priority::High, Priority::Low] {
+            let request = Request::Message {
+                environment_id: EnvironmentId(1),
+                process_id: ProcessId(1),
+                tag: None,
+                priority,
+                data: Payload::new(vec![1], None),
+                reply_cap: None,
+                sender: Some((NodeId(3), ProcessId(7))),
+                delivery_id: None,
+            };
+            let request: Request =
+                bincode::deserialize(&bincode::serialize(&request).unwrap()).unwrap();
+            let message = match request {
+                Request::Message {
+                    tag,
+                    priority,
+                    data,
+                    reply_cap,
+                    sender,
+                    ..
+                } => incoming_message(tag, priority, data.into_data().unwrap(), reply_cap, sender),
+                request => panic!("unexpected request {request:?}"),
+            };
+            deliver_message(&env, 1, message).unwrap();
+        }
+        assert_eq!(
+            *receiver.priorities.lock().unwrap(),
+            vec![Priority::High, Priority::Low]
+        );
+    }
+
+    #[test]
+    fn replicas_receive_unique_indexes() {
+        let params = replica_params(vec![Val::I64(7)], Some(50));
+        assert_eq!(params.len(), 50);
+        for (index, params) in params.iter().enumerate() {
+            match params.as_slice() {
+                [Val::I64(7), Val::I32(i)] => assert_eq!(*i as usize, index),
+                params => panic!("unexpected params {params:?}"),
+            }
+        }
+        // A single spawn keeps its params
+        assert!(matches!(
+            replica_params(vec![Val::I64(7)], None).as_slice(),
+            [params] if matches!(params.as_slice(), [Val::I64(7)])
+        ));
+    }
+
+    // Two nodes, each with a receiving process and its own staging state
+    fn nodes() -> Vec<(LunaticEnvironment, Arc<Receiver>, StagedTransactions)> {
+        (0..2)
+            .map(|_| {
+                let env = LunaticEnvironment::new(1);
+                let receiver = Arc::new(Receiver::default());
+                env.add_process(1, receiver.clone());
+                (env, receiver, StagedTransactions::default())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_stage_delivers_no_messages() {
+        let nodes = nodes();
+        let (env, _, transactions) = &nodes[0];
+        stage_messages(
+            env,
+            transactions,
+            1,
+            &[1],
+            None,
+            Priority::Normal,
+            b"update",
+            None,
+        )
+        .unwrap();
+        // The second node doesn't have process 2, so nothing is staged there.
+        let (env, _, transactions) = &nodes[1];
+        assert!(matches!(
+            stage_messages(
+                env,
+                transactions,
+                1,
+                &[1, 2],
+                None,
+                Priority::Normal,
+                b"update",
+                None
+            ),
+            Err(ClientError::ProcessNotFound)
+        ));
+        // The sender rolls back the stages that succeeded.
+        assert!(nodes[0].2.rollback(1));
+        assert!(!nodes[1].2.rollback(1));
+
+        for (env, receiver, transactions) in &nodes {
+            assert!(matches!(
+                commit_transaction(env, transactions, 1),
+                Err(ClientError::TransactionExpired)
+            ));
+            assert!(receiver.senders.lock().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn committed_messages_are_delivered_to_all_processes() {
+        let nodes = nodes();
+        for (env, receiver, transactions) in &nodes {
+            stage_messages(
+                env,
+                transactions,
+                1,
+                &[1],
+                None,
+                Priority::High,
+                b"update",
+                None,
+            )
+            .unwrap();
+            // Staged messages are not visible yet.
+            assert!(receiver.senders.lock().unwrap().is_empty());
+        }
+        for (env, receiver, transactions) in &nodes {
+            commit_transaction(env, transactions, 1).unwrap();
+            assert_eq!(*receiver.priorities.lock().unwrap(), vec![Priority::High]);
+        }
+    }
+
+    #[tokio::test]
+    async fn staged_messages_are_rolled_back_after_timeout() {
+        let env = LunaticEnvironment::new(1);
+        let receiver = Arc::new(Receiver::default());
+        env.add_process(1, receiver.clone());
+        let transactions = StagedTransactions::new(Duration::from_millis(10));
+
+        stage_messages(
+            &env,
+            &transactions,
+            1,
+            &[1],
+            None,
+            Priority::Normal,
+            b"update",
+            None,
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            commit_transaction(&env, &transactions, 1),
+            Err(ClientError::TransactionExpired)
+        ));
+        assert!(receiver.senders.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_spawns_of_invalid_module_compile_once() {
+        let failures = CompileFailures::default();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let spawns: Vec<_> = (0..16)
+            .map(|_| {
+                let failures = failures.clone();
+                let attempts = attempts.clone();
+                tokio::spawn(async move {
+                    failures
+                        .compile_once(1, || None::<()>, async {
+                            attempts.fetch_add(1, Ordering::SeqCst);
+                            tokio::task::yield_now().await;
+                            Ok(Err(ClientError::ModuleCompilation("invalid".to_string())))
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for spawn in spawns {
+            let result = spawn.await.unwrap();
+            assert!(matches!(result, Err(ClientError::ModuleCompilation(e)) if e == "invalid"));
+        }
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn preloaded_module_is_not_compiled_on_first_spawn() {
+        let failures = CompileFailures::default();
+        let cache: Arc<DashMap<u64, ()>> = Arc::default();
+        let compiles = Arc::new(AtomicUsize::new(0));
+        // Loads modules like `get_module` does, compiling them only if they are not cached
+        let load = |module_id: ModuleId| {
+            let (cache, compiles) = (cache.clone(), compiles.clone());
+            let module_id = u64::from(module_id);
+            let cached = {
+                let cache = cache.clone();
+                move || cache.get(&module_id).map(|_| ())
+            };
+            let compile = async move {
+                compiles.fetch_add(1, Ordering::SeqCst);
+                cache.insert(module_id, ());
+                Ok(Ok(()))
+            };
+            let failures = failures.clone();
+            async move {
+                match cached() {
+                    Some(module) => Ok(Ok(module)),
+                    None => failures.compile_once(module_id, cached, compile).await,
+                }
+            }
+        };
+
+        let preload = ModulePreload {
+            module_ids: vec![ModuleId(1), ModuleId(2)],
+            fail_on_error: true,
+        };
+        preload.run(load).await.unwrap();
+        assert_eq!(compiles.load(Ordering::SeqCst), 2);
+
+        // The first spawn finds the module in the cache
+        load(ModuleId(1)).await.unwrap().unwrap();
+        assert_eq!(compiles.load(Ordering::SeqCst), 2);
+        load(ModuleId(3)).await.unwrap().unwrap();
+        assert_eq!(compiles.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn preload_failures_are_fatal_only_if_configured() {
+        let load = |_| async { Ok(Err::<(), _>(ClientError::ModuleNotFound)) };
+        let mut preload = ModulePreload {
+            module_ids: vec![ModuleId(1)],
+            fail_on_error: false,
+        };
+        assert!(preload.run(load).await.is_ok());
+        preload.fail_on_error = true;
+        assert!(preload.run(load).await.is_err());
+    }
+
+    #[test]
+    fn invalid_module_reports_failed_validation() {
+        // The header is valid, but the type section ends right after its id
+        let bytes = b"\0asm\x01\0\0\0\x01";
+        let error = wasmtime::Module::new(&wasmtime::Engine::default(), bytes)
+            .err()
+            .unwrap();
+        let (message, cause) = (error.to_string(), error.root_cause().to_string());
+        match compile_error(error) {
+            ClientError::ModuleCompilation(detail) => {
+                assert!(detail.starts_with(&message));
+                assert!(detail.ends_with(&cause));
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn failure_expires_after_ttl() {
+        let failures = CompileFailures::new(Duration::ZERO);
+        for _ in 0..2 {
+            let result = failures
+                .compile_once(1, || None::<()>, async {
+                    Ok(Err(ClientError::ModuleCompilation("invalid".to_string())))
+                })
+                .await
+                .unwrap();
+            assert!(result.is_err());
+        }
+        let result = failures
+            .compile_once(1, || None, async { Ok(Ok(())) })
+            .await
+            .unwrap();
+        assert!(result.is_ok());
     }
-    Ok(())
 }
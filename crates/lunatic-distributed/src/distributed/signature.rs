@@ -0,0 +1,80 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+/// Verifies that modules were signed by a trusted publisher before they are compiled.
+///
+/// Signatures are Ed25519 signatures of the module bytes. Without a trusted key all modules are
+/// accepted, including unsigned ones.
+#[derive(Clone, Default)]
+pub struct ModuleVerifier {
+    trusted_key: Option<Arc<Vec<u8>>>,
+}
+
+impl ModuleVerifier {
+    pub fn new(trusted_key: Vec<u8>) -> Self {
+        Self {
+            trusted_key: Some(Arc::new(trusted_key)),
+        }
+    }
+
+    /// Loads the trusted key from a file containing the raw 32 byte Ed25519 public key.
+    pub fn load(path: &Path) -> Result<Self> {
+        let key = std::fs::read(path)?;
+        if key.len() != 32 {
+            return Err(anyhow!(
+                "Trusted module key must be a raw 32 byte Ed25519 public key, got {} bytes",
+                key.len()
+            ));
+        }
+        Ok(Self::new(key))
+    }
+
+    /// Returns true if the module can be compiled on this node.
+    pub fn verify(&self, bytes: &[u8], signature: Option<&[u8]>) -> bool {
+        match (&self.trusted_key, signature) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(key), Some(signature)) => UnparsedPublicKey::new(&ED25519, key.as_slice())
+                .verify(bytes, signature)
+                .is_ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::ModuleVerifier;
+
+    fn publisher() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn accepts_signed_and_rejects_tampered_module() {
+        let key = publisher();
+        let verifier = ModuleVerifier::new(key.public_key().as_ref().to_vec());
+        let signature = key.sign(b"module");
+
+        assert!(verifier.verify(b"module", Some(signature.as_ref())));
+        assert!(!verifier.verify(b"tampered", Some(signature.as_ref())));
+        assert!(!verifier.verify(b"module", None));
+
+        let other = publisher();
+        let signature = other.sign(b"module");
+        assert!(!verifier.verify(b"module", Some(signature.as_ref())));
+    }
+
+    #[test]
+    fn without_trusted_key_allows_unsigned() {
+        let verifier = ModuleVerifier::default();
+        assert!(verifier.verify(b"module", None));
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The serialized process config of a [`Spawn`](super::message::Spawn) request.
+///
+/// Configs are usually shared by many spawns, so after a node has seen a config once, it can be
+/// referenced by its handle instead of being sent again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SpawnConfig {
+    Inline(Vec<u8>),
+    Reference(u64),
+}
+
+/// Returns the handle under which a serialized config is known to other nodes.
+pub fn config_handle(config: &[u8]) -> u64 {
+    let hash = Sha256::digest(config);
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Maximum number of configs a node remembers, inline configs are not stored after that.
+const MAX_SPAWN_CONFIGS: usize = 1024;
+
+/// Configs that were sent inline to this node, so that later spawns can reference them.
+#[derive(Clone, Default)]
+pub struct SpawnConfigs {
+    configs: Arc<DashMap<u64, Vec<u8>>>,
+}
+
+impl SpawnConfigs {
+    /// Returns the serialized config, or `None` if it's a reference to an unknown config.
+    pub fn resolve(&self, config: SpawnConfig) -> Option<Vec<u8>> {
+        match config {
+            SpawnConfig::Inline(config) => {
+                if self.configs.len() < MAX_SPAWN_CONFIGS {
+                    self.configs.insert(config_handle(&config), config.clone());
+                }
+                Some(config)
+            }
+            SpawnConfig::Reference(handle) => self.configs.get(&handle).map(|c| c.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config_handle, SpawnConfig, SpawnConfigs};
+
+    #[test]
+    fn spawns_reference_registered_config() {
+        let configs = SpawnConfigs::default();
+        let config = vec![1, 2, 3];
+        let handle = config_handle(&config);
+
+        // Unknown until it was sent inline once
+        assert_eq!(configs.resolve(SpawnConfig::Reference(handle)), None);
+        assert_eq!(
+            configs.resolve(SpawnConfig::Inline(config.clone())),
+            Some(config.clone())
+        );
+        for _ in 0..3 {
+            assert_eq!(
+                configs.resolve(SpawnConfig::Reference(handle)),
+                Some(config.clone())
+            );
+        }
+        assert_eq!(configs.resolve(SpawnConfig::Reference(handle + 1)), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::message::ClientError;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpawnQueueConfig {
+    // Number of spawns that are handled at the same time
+    pub workers: usize,
+    // Maximum number of spawns waiting for a worker, further spawns are rejected
+    pub max_depth: usize,
+}
+
+/// Limits the number of spawns the node server handles at the same time.
+///
+/// Spawns over the limit wait for a free worker in the order they arrived. A spawn that is
+/// cancelled while waiting leaves the queue. Without a config spawns are never queued.
+#[derive(Clone, Default)]
+pub struct SpawnQueue {
+    inner: Option<Arc<InnerSpawnQueue>>,
+}
+
+struct InnerSpawnQueue {
+    max_depth: usize,
+    workers: Arc<Semaphore>,
+    depth: AtomicUsize,
+    enqueued: AtomicU64,
+    rejected: AtomicU64,
+    // Spawns that waited for a worker and the sum of their waiting times in microseconds
+    admitted: AtomicU64,
+    total_wait: AtomicU64,
+}
+
+/// A snapshot of the spawn queue counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpawnQueueStats {
+    pub depth: usize,
+    pub enqueued: u64,
+    pub rejected: u64,
+    /// Average time queued spawns waited for a worker.
+    pub average_wait: Duration,
+}
+
+/// A worker slot held while a spawn is handled.
+pub struct SpawnSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// Removes a spawn from the queue depth when it leaves the queue, even if it's cancelled.
+struct Queued<'a>(&'a InnerSpawnQueue);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.depth.fetch_sub(1, Ordering::SeqCst);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(
+            "lunatic.distributed.spawn_queue.depth",
+            self.0.depth.load(Ordering::SeqCst) as f64
+        );
+    }
+}
+
+impl SpawnQueue {
+    pub fn new(config: SpawnQueueConfig) -> Self {
+        Self {
+            inner: Some(Arc::new(InnerSpawnQueue {
+                max_depth: config.max_depth,
+                workers: Arc::new(Semaphore::new(config.workers)),
+                depth: AtomicUsize::new(0),
+                enqueued: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+                admitted: AtomicU64::new(0),
+                total_wait: AtomicU64::new(0),
+            })),
+        }
+    }
+
+    /// Waits for a free worker, or returns `ClientError::SpawnQueueFull` if too many spawns are
+    /// already waiting.
+    pub async fn admit(&self) -> Result<SpawnSlot, ClientError> {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return Ok(SpawnSlot { _permit: None }),
+        };
+        // Permits are handed to waiting spawns first, so this can't overtake the queue.
+        if let Ok(permit) = inner.workers.clone().try_acquire_owned() {
+            return Ok(SpawnSlot {
+                _permit: Some(permit),
+            });
+        }
+
+        if inner.depth.fetch_add(1, Ordering::SeqCst) >= inner.max_depth {
+            inner.depth.fetch_sub(1, Ordering::SeqCst);
+            inner.rejected.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            metrics::increment_counter!("lunatic.distributed.spawn_queue.rejected");
+            return Err(ClientError::SpawnQueueFull);
+        }
+        let queued = Queued(inner);
+        inner.enqueued.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        {
+            metrics::increment_counter!("lunatic.distributed.spawn_queue.enqueued");
+            metrics::gauge!(
+                "lunatic.distributed.spawn_queue.depth",
+                inner.depth.load(Ordering::SeqCst) as f64
+            );
+        }
+
+        let queued_at = Instant::now();
+        let permit = inner
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| ClientError::Unexpected(e.to_string()))?;
+        drop(queued);
+
+        let wait = queued_at.elapsed();
+        inner.admitted.fetch_add(1, Ordering::Relaxed);
+        inner
+            .total_wait
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("lunatic.distributed.spawn_queue.wait", wait);
+        Ok(SpawnSlot {
+            _permit: Some(permit),
+        })
+    }
+
+    pub fn stats(&self) -> SpawnQueueStats {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return SpawnQueueStats::default(),
+        };
+        let admitted = inner.admitted.load(Ordering::Relaxed);
+        let average_wait = match admitted {
+            0 => Duration::ZERO,
+            admitted => Duration::from_micros(inner.total_wait.load(Ordering::Relaxed) / admitted),
+        };
+        SpawnQueueStats {
+            depth: inner.depth.load(Ordering::SeqCst),
+            enqueued: inner.enqueued.load(Ordering::Relaxed),
+            rejected: inner.rejected.load(Ordering::Relaxed),
+            average_wait,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+    describe_gauge!(
+        "lunatic.distributed.spawn_queue.depth",
+        Unit::Count,
+        "Number of spawns waiting for a worker"
+    );
+    describe_counter!(
+        "lunatic.distributed.spawn_queue.enqueued",
+        Unit::Count,
+        "Number of spawns that had to wait for a worker since startup"
+    );
+    describe_counter!(
+        "lunatic.distributed.spawn_queue.rejected",
+        Unit::Count,
+        "Number of spawns rejected because the queue was full since startup"
+    );
+    describe_histogram!(
+        "lunatic.distributed.spawn_queue.wait",
+        Unit::Seconds,
+        "Time spawns waited for a worker"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::{SpawnQueue, SpawnQueueConfig};
+    use crate::distributed::message::ClientError;
+
+    async fn wait_for_depth(queue: &SpawnQueue, depth: usize) {
+        while queue.stats().depth != depth {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn spawns_queue_and_drain_in_order() {
+        let queue = SpawnQueue::new(SpawnQueueConfig {
+            workers: 1,
+            max_depth: 2,
+        });
+        let running = queue.admit().await.unwrap();
+        assert_eq!(queue.stats().depth, 0);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for (i, spawn) in [1, 2].into_iter().enumerate() {
+            let waiting = queue.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _slot = waiting.admit().await.unwrap();
+                tx.send(spawn).unwrap();
+            });
+            wait_for_depth(&queue, i + 1).await;
+        }
+        assert!(matches!(
+            queue.admit().await,
+            Err(ClientError::SpawnQueueFull)
+        ));
+
+        drop(running);
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+        let stats = queue.stats();
+        assert_eq!(stats.depth, 0);
+        assert_eq!(stats.enqueued, 2);
+        assert_eq!(stats.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_spawn_leaves_queue() {
+        let queue = SpawnQueue::new(SpawnQueueConfig {
+            workers: 1,
+            max_depth: 1,
+        });
+        let _running = queue.admit().await.unwrap();
+        let waiting = queue.clone();
+        let queued = tokio::spawn(async move { waiting.admit().await.map(|_| ()) });
+        wait_for_depth(&queue, 1).await;
+
+        queued.abort();
+        assert!(queued.await.unwrap_err().is_cancelled());
+        assert_eq!(queue.stats().depth, 0);
+    }
+}
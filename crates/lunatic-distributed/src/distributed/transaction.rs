@@ -0,0 +1,61 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use lunatic_process::message::DataMessage;
+
+/// Messages of two-phase sends that are staged on this node, but not yet visible to the receiving
+/// processes.
+///
+/// Staged messages are delivered on commit, or dropped on rollback. A transaction that is not
+/// committed within `timeout` after its first stage is rolled back.
+#[derive(Clone)]
+pub struct StagedTransactions {
+    timeout: Duration,
+    // Transaction ID -> `(process_id, message)` pairs
+    staged: Arc<DashMap<u128, Vec<(u64, DataMessage)>>>,
+}
+
+impl StagedTransactions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            staged: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn stage(&self, transaction_id: u128, messages: Vec<(u64, DataMessage)>) {
+        match self.staged.entry(transaction_id) {
+            Entry::Occupied(mut staged) => staged.get_mut().extend(messages),
+            Entry::Vacant(staged) => {
+                staged.insert(messages);
+                let transactions = self.staged.clone();
+                let timeout = self.timeout;
+                tokio::spawn(async move {
+                    tokio::time::sleep(timeout).await;
+                    if transactions.remove(&transaction_id).is_some() {
+                        log::debug!("Rolled back transaction {transaction_id} after timeout");
+                    }
+                });
+            }
+        }
+    }
+
+    /// Returns the staged messages to be delivered, or `None` if the transaction is unknown or
+    /// was already rolled back.
+    pub fn commit(&self, transaction_id: u128) -> Option<Vec<(u64, DataMessage)>> {
+        self.staged
+            .remove(&transaction_id)
+            .map(|(_, messages)| messages)
+    }
+
+    /// Drops the staged messages, returns false if there were none.
+    pub fn rollback(&self, transaction_id: u128) -> bool {
+        self.staged.remove(&transaction_id).is_some()
+    }
+}
+
+impl Default for StagedTransactions {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10))
+    }
+}
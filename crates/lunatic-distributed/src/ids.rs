@@ -0,0 +1,99 @@
+/*!
+Typed ids of the entities nodes talk about.
+
+All of them are a `u64` on the wire and at the host function boundary, but mixing them up in the
+runtime is a compile time error:
+
+```compile_fail
+use lunatic_distributed::{NodeId, ProcessId};
+
+fn kill(node_id: NodeId, process_id: ProcessId) {}
+
+let (node_id, process_id) = (NodeId(1), ProcessId(2));
+kill(process_id, node_id);
+```
+*/
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+macro_rules! id_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(
+            Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+        )]
+        #[serde(transparent)]
+        pub struct $name(pub u64);
+
+        impl From<u64> for $name {
+            fn from(id: u64) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+id_type!(
+    /// Id of a node, assigned by the control server. 0 is reserved for processes that are not
+    /// running in a cluster.
+    NodeId
+);
+id_type!(
+    /// Id of a process, unique inside of its environment on a node.
+    ProcessId
+);
+id_type!(
+    /// Id of a module added to the control server. 0 is reserved for modules that were not
+    /// added to it.
+    ModuleId
+);
+id_type!(
+    /// Id of an environment, processes can only talk to processes in the same environment.
+    EnvironmentId
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{EnvironmentId, ModuleId, NodeId, ProcessId};
+
+    #[test]
+    fn conversions_are_lossless() {
+        for raw in [0, 1, u64::MAX / 2, u64::MAX] {
+            assert_eq!(u64::from(NodeId::from(raw)), raw);
+            assert_eq!(u64::from(ProcessId::from(raw)), raw);
+            assert_eq!(u64::from(ModuleId::from(raw)), raw);
+            assert_eq!(u64::from(EnvironmentId::from(raw)), raw);
+            assert_eq!(NodeId(raw).to_string(), raw.to_string());
+        }
+    }
+
+    #[test]
+    fn wire_format_is_unchanged() {
+        let raw = 0x0102_0304_0506_0708u64;
+        let encoded = bincode::serialize(&raw).unwrap();
+        assert_eq!(bincode::serialize(&NodeId(raw)).unwrap(), encoded);
+        assert_eq!(bincode::serialize(&ProcessId(raw)).unwrap(), encoded);
+        assert_eq!(bincode::serialize(&ModuleId(raw)).unwrap(), encoded);
+        assert_eq!(bincode::serialize(&EnvironmentId(raw)).unwrap(), encoded);
+
+        let ids: (NodeId, ProcessId) = bincode::deserialize(
+            &bincode::serialize(&(3u64, 7u64)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(ids, (NodeId(3), ProcessId(7)));
+    }
+}
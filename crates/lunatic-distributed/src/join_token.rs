@@ -0,0 +1,74 @@
+/*!
+Join tokens, a secret shared by all nodes of a cluster.
+
+If the control server is configured with a token, nodes can only register if they present the same
+token. Nodes configured with a token attach it to every request they send to other nodes, and
+refuse all requests from nodes that don't present it. The token is sent in plain text inside of
+the QUIC connections, which are always encrypted.
+*/
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct JoinToken(String);
+
+impl JoinToken {
+    pub fn new(token: String) -> Self {
+        Self(token)
+    }
+
+    /// Returns true if `presented` is the same token. Takes the same time for all tokens of the
+    /// same length, so that the token can't be guessed byte by byte.
+    pub fn verify(&self, presented: Option<&JoinToken>) -> bool {
+        let presented = match presented {
+            Some(presented) => presented.0.as_bytes(),
+            None => return false,
+        };
+        let expected = self.0.as_bytes();
+        expected.len() == presented.len()
+            && expected
+                .iter()
+                .zip(presented)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Returns true if `presented` is accepted by a node or control server configured with
+/// `expected`. Without a configured token everything is accepted.
+pub fn accepts(expected: Option<&JoinToken>, presented: Option<&JoinToken>) -> bool {
+    expected.is_none_or(|expected| expected.verify(presented))
+}
+
+// The token is never logged
+impl fmt::Debug for JoinToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JoinToken(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accepts, JoinToken};
+
+    #[test]
+    fn only_the_same_token_is_accepted() {
+        let token = JoinToken::new("secret".to_string());
+        assert!(accepts(Some(&token), Some(&token.clone())));
+        assert!(!accepts(
+            Some(&token),
+            Some(&JoinToken::new("secreT".to_string()))
+        ));
+        assert!(!accepts(
+            Some(&token),
+            Some(&JoinToken::new("secret2".to_string()))
+        ));
+        assert!(!accepts(Some(&token), None));
+        // Without a configured token everything is accepted
+        assert!(accepts(None, None));
+        assert!(accepts(None, Some(&token)));
+        assert_eq!(format!("{token:?}"), "JoinToken(..)");
+    }
+}
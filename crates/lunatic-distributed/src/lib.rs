@@ -1,15 +1,34 @@
+pub mod capabilities;
 pub mod control;
+pub mod discovery;
 pub mod distributed;
+pub mod ids;
+pub mod join_token;
 pub mod quic;
+pub mod timestamp;
+#[cfg(test)]
+mod test_node;
 
 use anyhow::Result;
+use distributed::{in_flight::InFlightRequests, message::ReplyCapability};
+pub use ids::{EnvironmentId, ModuleId, NodeId, ProcessId};
+use hash_map_id::HashMapId;
 use lunatic_process::{
     env::Environment,
+    kv::KvCheckpoints,
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
     state::ProcessState,
 };
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+pub type ReplyCapabilityResources = HashMapId<ReplyCapability>;
 
 pub trait DistributedCtx<E: Environment>: ProcessState + Sized {
     fn new_dist_state(
@@ -23,7 +42,12 @@ pub trait DistributedCtx<E: Environment>: ProcessState + Sized {
     fn distributed_mut(&mut self) -> Result<&mut DistributedProcessState>;
     fn module_id(&self) -> u64;
     fn environment_id(&self) -> u64;
+    // Cancellation token held by the process, passed on to processes it spawns on other nodes
+    fn cancel_token(&self) -> Option<u64>;
     fn can_spawn(&self) -> bool;
+    fn can_drain_node(&self) -> bool;
+    fn reply_capability_resources(&self) -> &ReplyCapabilityResources;
+    fn reply_capability_resources_mut(&mut self) -> &mut ReplyCapabilityResources;
 }
 
 #[derive(Clone)]
@@ -31,6 +55,11 @@ pub struct DistributedProcessState {
     node_id: u64,
     pub control: control::Client,
     pub node_client: distributed::Client,
+    // Requests from other nodes that are currently handled by this node
+    pub in_flight: InFlightRequests,
+    pub sequence: NodeSequence,
+    // Checkpoints of the kv stores of processes on this node, inherited by spawned processes
+    kv_checkpoints: Option<Arc<KvCheckpoints>>,
 }
 
 impl DistributedProcessState {
@@ -43,12 +72,38 @@ impl DistributedProcessState {
             node_id,
             control: control_client,
             node_client,
+            in_flight: InFlightRequests::default(),
+            sequence: NodeSequence::default(),
+            kv_checkpoints: None,
         })
     }
 
+    /// Processes spawned on this node by other nodes checkpoint their kv store to `checkpoints`.
+    pub fn with_kv_checkpoints(mut self, checkpoints: Arc<KvCheckpoints>) -> Self {
+        self.kv_checkpoints = Some(checkpoints);
+        self
+    }
+
     pub fn node_id(&self) -> u64 {
         self.node_id
     }
+
+    pub fn kv_checkpoints(&self) -> Option<&Arc<KvCheckpoints>> {
+        self.kv_checkpoints.as_ref()
+    }
+}
+
+/// Monotonically increasing sequence shared by all processes on a node.
+///
+/// Each value is returned only once on the node, combined with the node id it's unique in the
+/// cluster.
+#[derive(Clone, Default)]
+pub struct NodeSequence(Arc<AtomicU64>);
+
+impl NodeSequence {
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,4 +111,35 @@ pub struct NodeInfo {
     pub id: u64,
     pub address: SocketAddr,
     pub name: String,
+    // Address of a separate listener for control-plane requests, if the node has one
+    pub control_address: Option<SocketAddr>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::NodeSequence;
+
+    #[test]
+    fn concurrent_sequences_are_unique() {
+        let sequence = NodeSequence::default();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let sequence = sequence.clone();
+                std::thread::spawn(move || (0..10_000).map(|_| sequence.next()).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for handle in handles {
+            let values = handle.join().unwrap();
+            // Each process sees its values increasing
+            assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+            for value in values {
+                assert!(seen.insert(value), "duplicate sequence {value}");
+            }
+        }
+        assert_eq!(seen.len(), 80_000);
+        assert_eq!(sequence.next(), 80_000);
+    }
 }
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+/// When the keys of a QUIC connection are rotated.
+///
+/// Rotation uses the TLS 1.3 key update of QUIC. The connection and its streams stay open, so
+/// in-flight requests are not affected. Packets carry a key phase bit, and the peer keeps the
+/// previous keys until all packets protected with them are acknowledged, so a packet is never
+/// decrypted with the wrong key during the transition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyRotation {
+    // Rotate after the keys were used for this long
+    pub interval: Option<Duration>,
+    // Rotate after this many bytes were sent and received with the keys
+    pub max_bytes: Option<u64>,
+}
+
+impl KeyRotation {
+    pub fn is_enabled(&self) -> bool {
+        self.interval.is_some() || self.max_bytes.is_some()
+    }
+
+    fn is_due(&self, since_rotation: Duration, bytes_since_rotation: u64) -> bool {
+        matches!(self.interval, Some(interval) if since_rotation >= interval)
+            || matches!(self.max_bytes, Some(max_bytes) if bytes_since_rotation >= max_bytes)
+    }
+}
+
+// How often a connection is checked for a due rotation
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Rotates the keys of the connection according to `rotation` until it's closed.
+pub(crate) async fn rotate_keys(conn: quinn::Connection, rotation: KeyRotation) {
+    let check_interval = rotation
+        .interval
+        .map_or(CHECK_INTERVAL, |interval| interval.min(CHECK_INTERVAL));
+    let mut rotated_at = Instant::now();
+    let mut rotated_bytes = transferred(&conn);
+    loop {
+        tokio::time::sleep(check_interval).await;
+        if conn.close_reason().is_some() {
+            break;
+        }
+        let bytes = transferred(&conn);
+        if rotation.is_due(rotated_at.elapsed(), bytes - rotated_bytes) {
+            log::debug!("Rotating keys of connection {}", conn.stable_id());
+            conn.force_key_update();
+            rotated_at = Instant::now();
+            rotated_bytes = bytes;
+        }
+    }
+}
+
+fn transferred(conn: &quinn::Connection) -> u64 {
+    let stats = conn.stats();
+    stats.udp_tx.bytes + stats.udp_rx.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::KeyRotation;
+    use crate::quic::{new_quic_client, new_quic_server, quin::quic_stream, ConnectionConfig};
+
+    #[test]
+    fn rotation_is_due_by_time_or_bytes() {
+        let rotation = KeyRotation {
+            interval: Some(Duration::from_secs(60)),
+            max_bytes: Some(1024),
+        };
+        assert!(!rotation.is_due(Duration::from_secs(1), 10));
+        assert!(rotation.is_due(Duration::from_secs(60), 10));
+        assert!(rotation.is_due(Duration::from_secs(1), 1024));
+        assert!(!KeyRotation::default().is_enabled());
+        assert!(!KeyRotation::default().is_due(Duration::MAX, u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn traffic_continues_after_rekey() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let server = new_quic_server(
+            "127.0.0.1:0".parse().unwrap(),
+            &cert_pem,
+            &cert.serialize_private_key_pem(),
+        )
+        .unwrap();
+        let address = server.local_addr().unwrap();
+
+        let frames = 20u32;
+        let node = tokio::spawn(async move {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let (send, recv) = conn.accept_bi().await.unwrap();
+            let (_send, mut recv) = quic_stream(send, recv, ConnectionConfig::default());
+            let mut received = Vec::new();
+            for _ in 0..frames {
+                let bytes = recv.receive().await.unwrap();
+                received.push(u32::from_le_bytes(bytes[..].try_into().unwrap()));
+            }
+            received
+        });
+
+        let client = new_quic_client(&cert_pem).unwrap();
+        let connection = client.open_connection(address, "localhost").await.unwrap();
+        let (mut send, _recv) = connection.open_stream().await.unwrap();
+        for frame in 0..frames {
+            // Rekey mid-session, while the first half of the frames may still be in flight.
+            if frame == frames / 2 {
+                connection.rotate_keys();
+            }
+            let size = Bytes::copy_from_slice(&4u32.to_le_bytes());
+            let data = Bytes::copy_from_slice(&frame.to_le_bytes());
+            send.send(&mut [size, data]).await.unwrap();
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(10), node)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, (0..frames).collect::<Vec<_>>());
+    }
+}
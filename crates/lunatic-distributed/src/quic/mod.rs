@@ -1,7 +1,9 @@
+mod key_rotation;
 mod quin;
 
 use std::{net::SocketAddr, time::Duration};
 
+pub use key_rotation::KeyRotation;
 pub use quin::*;
 
 pub async fn try_connect_forever(
@@ -18,3 +20,18 @@ pub async fn try_connect_forever(
         tokio::time::sleep(Duration::from_secs(2)).await;
     }
 }
+
+pub async fn try_open_connection_forever(
+    quic_client: &self::Client,
+    addr: SocketAddr,
+    name: &str,
+) -> self::Connection {
+    loop {
+        log::info!("Connecting to node {addr} - {name}");
+        if let Ok(connection) = quic_client.open_connection(addr, name).await {
+            return connection;
+        }
+        log::warn!("Failed to connect to node {addr} - {name}, retrying...");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
@@ -2,103 +2,430 @@ use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use dashmap::DashMap;
 use lunatic_process::{env::Environment, state::ProcessState};
 use quinn::{ClientConfig, Connecting, ConnectionError, Endpoint, ServerConfig};
 use rustls_pemfile::Item;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
 use wasmtime::ResourceLimiter;
 
-use crate::{control, distributed, DistributedCtx};
+use super::key_rotation::{rotate_keys, KeyRotation};
+use crate::{
+    control,
+    distributed::{
+        self,
+        ordering::{ConnectionOrdering, SequentialRequests},
+    },
+    join_token::JoinToken,
+    DistributedCtx,
+};
+
+/// Timeouts of frame operations on node connections, and the transport the connections use.
+///
+/// Reading or writing a frame fails if it doesn't make progress within the timeout, and the
+/// stream is dropped. Every chunk of the frame that is read or written resets the timeout, so large
+/// transfers over slow links are not interrupted. Waiting for the next frame to start is not
+/// limited, idle streams are closed by the idle timeout of the client instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub transport: Transport,
+}
+
+/// How a [`Client`] reaches other nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// QUIC connections to the address of the node.
+    #[default]
+    Quic,
+    /// Connections that never leave the process, to nodes served with [`Client::serve_in_memory`].
+    /// A whole cluster can run in one process this way, without opening any sockets.
+    InMemory,
+}
 
 pub struct SendStream {
-    pub stream: quinn::SendStream,
+    stream: SendStreamKind,
+    write_timeout: Option<Duration>,
+}
+
+enum SendStreamKind {
+    Quic(quinn::SendStream),
+    InMemory(WriteHalf<DuplexStream>),
+}
+
+impl SendStreamKind {
+    async fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        let written = match self {
+            SendStreamKind::Quic(stream) => stream.write(buffer).await?,
+            SendStreamKind::InMemory(stream) => stream.write(buffer).await?,
+        };
+        if written == 0 {
+            return Err(anyhow!("Stream closed"));
+        }
+        Ok(written)
+    }
 }
 
 impl SendStream {
     pub async fn send(&mut self, data: &mut [Bytes]) -> Result<()> {
-        self.stream.write_all_chunks(data).await?;
+        match (&mut self.stream, self.write_timeout) {
+            (SendStreamKind::Quic(stream), None) => stream.write_all_chunks(data).await?,
+            (stream, write_timeout) => {
+                for chunk in data.iter() {
+                    let mut written = 0;
+                    while written < chunk.len() {
+                        written += progress(write_timeout, stream.write(&chunk[written..])).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish(&mut self) -> Result<()> {
+        match &mut self.stream {
+            SendStreamKind::Quic(stream) => stream.finish().await?,
+            SendStreamKind::InMemory(stream) => stream.shutdown().await?,
+        }
         Ok(())
     }
 }
 
 pub struct RecvStream {
-    pub stream: quinn::RecvStream,
+    stream: RecvStreamKind,
+    read_timeout: Option<Duration>,
+}
+
+enum RecvStreamKind {
+    Quic(quinn::RecvStream),
+    InMemory(ReadHalf<DuplexStream>),
+}
+
+impl RecvStreamKind {
+    async fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let read = match self {
+            RecvStreamKind::Quic(stream) => stream.read(buffer).await?.unwrap_or(0),
+            RecvStreamKind::InMemory(stream) => stream.read(buffer).await?,
+        };
+        if read == 0 {
+            return Err(anyhow!("Stream closed"));
+        }
+        Ok(read)
+    }
 }
 
 impl RecvStream {
     pub async fn receive(&mut self) -> Result<Bytes> {
         let mut size = [0u8; 4];
-        self.stream.read_exact(&mut size).await?;
+        // Waiting for the next frame to start is not limited by the read timeout.
+        let read = self.stream.read(&mut size).await?;
+        self.read_exact(&mut size[read..]).await?;
         let size = u32::from_le_bytes(size);
         let mut buffer = vec![0u8; size as usize];
-        self.stream.read_exact(&mut buffer).await?;
+        self.read_exact(&mut buffer).await?;
         Ok(buffer.into())
     }
 
-    pub fn id(&self) -> quinn::StreamId {
-        self.stream.id()
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            filled += progress(self.read_timeout, self.stream.read(&mut buffer[filled..])).await?;
+        }
+        Ok(())
+    }
+
+    // In-memory streams don't have a QUIC stream id.
+    pub fn id(&self) -> Option<quinn::StreamId> {
+        match &self.stream {
+            RecvStreamKind::Quic(stream) => Some(stream.id()),
+            RecvStreamKind::InMemory(_) => None,
+        }
     }
 }
 
+// Fails if the read or write doesn't finish within the timeout.
+async fn progress<F>(timeout: Option<Duration>, operation: F) -> Result<usize>
+where
+    F: std::future::Future<Output = Result<usize>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, operation)
+            .await
+            .map_err(|_| anyhow!("Stream made no progress for {timeout:?}"))?,
+        None => operation.await,
+    }
+}
+
+pub(crate) fn quic_stream(
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    config: ConnectionConfig,
+) -> (SendStream, RecvStream) {
+    (
+        SendStream {
+            stream: SendStreamKind::Quic(send),
+            write_timeout: config.write_timeout,
+        },
+        RecvStream {
+            stream: RecvStreamKind::Quic(recv),
+            read_timeout: config.read_timeout,
+        },
+    )
+}
+
+// Size of the in-memory buffer in each direction, writers wait if it's full.
+const IN_MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Application error code of connections closed because the node reached its connection limit
+const CONNECTION_LIMIT_REACHED: u32 = 1;
+
+fn in_memory_stream_pair(
+    config: ConnectionConfig,
+) -> ((SendStream, RecvStream), (SendStream, RecvStream)) {
+    let (a, b) = tokio::io::duplex(IN_MEMORY_BUFFER_SIZE);
+    let into_stream = |stream: DuplexStream| {
+        let (recv, send) = tokio::io::split(stream);
+        (
+            SendStream {
+                stream: SendStreamKind::InMemory(send),
+                write_timeout: config.write_timeout,
+            },
+            RecvStream {
+                stream: RecvStreamKind::InMemory(recv),
+                read_timeout: config.read_timeout,
+            },
+        )
+    };
+    (into_stream(a), into_stream(b))
+}
+
 #[derive(Clone)]
 pub struct Client {
-    inner: Endpoint,
+    // Not bound for clients that only open in-memory connections
+    inner: Option<Endpoint>,
+    key_rotation: KeyRotation,
+    connection_config: ConnectionConfig,
+    // Address -> connection to the node served in memory at the address
+    in_memory_nodes: Arc<DashMap<SocketAddr, Connection>>,
 }
 
 impl Client {
+    /// Creates a client that opens all connections in memory, to nodes served with
+    /// [`Client::serve_in_memory`]. It never opens a socket.
+    pub fn in_memory(connection_config: ConnectionConfig) -> Client {
+        Client {
+            inner: None,
+            key_rotation: KeyRotation::default(),
+            connection_config: ConnectionConfig {
+                transport: Transport::InMemory,
+                ..connection_config
+            },
+            in_memory_nodes: Arc::default(),
+        }
+    }
+
+    /// Serves a node at `addr` to in-memory connections opened by this client and its clones.
+    /// Their streams are accepted from the returned acceptor, e.g. by
+    /// [`handle_in_memory_node_connection`].
+    pub fn serve_in_memory(&self, addr: SocketAddr) -> InMemoryAcceptor {
+        let (connection, acceptor) = Connection::in_memory(self.connection_config);
+        self.in_memory_nodes.insert(addr, connection);
+        acceptor
+    }
+
+    fn endpoint(&self) -> Result<&Endpoint> {
+        self.inner
+            .as_ref()
+            .ok_or_else(|| anyhow!("Client can only open in-memory connections"))
+    }
+
+    /// Rotates the keys of all connections opened by this client according to `key_rotation`.
+    pub fn with_key_rotation(mut self, key_rotation: KeyRotation) -> Self {
+        self.key_rotation = key_rotation;
+        self
+    }
+
+    /// Applies `connection_config` to all streams opened by this client.
+    pub fn with_connection_config(mut self, connection_config: ConnectionConfig) -> Self {
+        self.connection_config = connection_config;
+        self
+    }
+
+    // Connections are rekeyed by the node that opened them.
+    fn start_key_rotation(&self, conn: &quinn::Connection) {
+        if self.key_rotation.is_enabled() {
+            tokio::spawn(rotate_keys(conn.clone(), self.key_rotation));
+        }
+    }
+
     pub async fn connect(
         &self,
         addr: SocketAddr,
         name: &str,
         retry: u32,
     ) -> Result<(SendStream, RecvStream)> {
+        if self.connection_config.transport == Transport::InMemory {
+            return self.open_connection(addr, name).await?.open_stream().await;
+        }
         for _ in 0..retry {
-            let conn = self.inner.connect(addr, name)?.await?;
+            let conn = self.endpoint()?.connect(addr, name)?.await?;
+            self.start_key_rotation(&conn);
             if let Ok((send, recv)) = conn.open_bi().await {
-                return Ok((SendStream { stream: send }, RecvStream { stream: recv }));
+                return Ok(quic_stream(send, recv, self.connection_config));
             }
             tokio::time::sleep(Duration::from_secs(2)).await;
         }
         Err(anyhow!("Failed to connect to {addr}"))
     }
+
+    pub async fn open_connection(&self, addr: SocketAddr, name: &str) -> Result<Connection> {
+        if self.connection_config.transport == Transport::InMemory {
+            return self
+                .in_memory_nodes
+                .get(&addr)
+                .map(|node| node.clone())
+                .filter(|node| !node.is_closed())
+                .ok_or_else(|| anyhow!("No node is served in memory at {addr}"));
+        }
+        let conn = self.endpoint()?.connect(addr, name)?.await?;
+        self.start_key_rotation(&conn);
+        Ok(Connection {
+            inner: ConnectionKind::Quic(conn),
+            config: self.connection_config,
+        })
+    }
+}
+
+/// A connection to another node that can be shared by multiple streams.
+#[derive(Clone)]
+pub struct Connection {
+    inner: ConnectionKind,
+    config: ConnectionConfig,
+}
+
+#[derive(Clone)]
+enum ConnectionKind {
+    Quic(quinn::Connection),
+    InMemory(UnboundedSender<(SendStream, RecvStream)>),
+}
+
+impl Connection {
+    /// Creates a connection that never leaves the process. Streams opened on it can be accepted
+    /// from the returned [`InMemoryAcceptor`] and use the same framing as QUIC streams.
+    pub fn in_memory(config: ConnectionConfig) -> (Connection, InMemoryAcceptor) {
+        let (sender, receiver) = unbounded_channel();
+        let connection = Connection {
+            inner: ConnectionKind::InMemory(sender),
+            config,
+        };
+        (connection, InMemoryAcceptor { receiver })
+    }
+
+    pub async fn open_stream(&self) -> Result<(SendStream, RecvStream)> {
+        match &self.inner {
+            ConnectionKind::Quic(conn) => {
+                let (send, recv) = conn.open_bi().await?;
+                Ok(quic_stream(send, recv, self.config))
+            }
+            ConnectionKind::InMemory(sender) => {
+                let (local, remote) = in_memory_stream_pair(self.config);
+                sender
+                    .send(remote)
+                    .map_err(|_| anyhow!("In-memory connection closed"))?;
+                Ok(local)
+            }
+        }
+    }
+
+    /// Starts a key update of the connection right away. In-memory connections are not
+    /// encrypted and ignore it.
+    pub fn rotate_keys(&self) {
+        if let ConnectionKind::Quic(conn) = &self.inner {
+            conn.force_key_update();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match &self.inner {
+            ConnectionKind::Quic(conn) => conn.close_reason().is_some(),
+            ConnectionKind::InMemory(sender) => sender.is_closed(),
+        }
+    }
+}
+
+/// The accepting side of an in-memory [`Connection`].
+pub struct InMemoryAcceptor {
+    receiver: UnboundedReceiver<(SendStream, RecvStream)>,
+}
+
+impl InMemoryAcceptor {
+    /// Returns the next stream opened on the connection, or `None` once all handles to the
+    /// connection are dropped.
+    pub async fn accept(&mut self) -> Option<(SendStream, RecvStream)> {
+        self.receiver.recv().await
+    }
 }
 
 pub fn new_quic_client(ca_cert: &str) -> Result<Client> {
-    let mut cert = ca_cert.as_bytes();
-    let cert = rustls_pemfile::read_one(&mut cert)?.unwrap();
-    let cert = match cert {
-        Item::X509Certificate(cert) => Ok(rustls::Certificate(cert)),
-        _ => Err(anyhow!("Not a valid certificate.")),
-    }?;
-    let mut certs = rustls::RootCertStore::empty();
-    certs.add(&cert)?;
     let client_crypto = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(certs)
+        .with_root_certificates(root_store(ca_cert)?)
         .with_no_client_auth();
+    quic_client(client_crypto)
+}
+
+/// Same as [`new_quic_client`], but the client presents the certificate `cert` with the private
+/// key `key` to the servers it connects to. Servers that require client certificates only accept
+/// it if it's signed by their CA.
+pub fn new_mtls_quic_client(ca_cert: &str, cert: &str, key: &str) -> Result<Client> {
+    let client_crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store(ca_cert)?)
+        .with_single_cert(vec![parse_cert(cert)?], parse_key(key)?)?;
+    quic_client(client_crypto)
+}
+
+fn quic_client(client_crypto: rustls::ClientConfig) -> Result<Client> {
     let client_config = ClientConfig::new(Arc::new(client_crypto));
     let mut endpoint = Endpoint::client("[::]:0".parse().unwrap())?;
     endpoint.set_default_client_config(client_config);
-    Ok(Client { inner: endpoint })
+    Ok(Client {
+        inner: Some(endpoint),
+        key_rotation: KeyRotation::default(),
+        connection_config: ConnectionConfig::default(),
+        in_memory_nodes: Arc::default(),
+    })
 }
 
 pub fn new_quic_server(addr: SocketAddr, cert: &str, key: &str) -> Result<Endpoint> {
-    let mut cert = cert.as_bytes();
-    let mut key = key.as_bytes();
-    let pk = rustls_pemfile::read_one(&mut key)?.unwrap();
-    let pk = match pk {
-        Item::PKCS8Key(key) => Ok(rustls::PrivateKey(key)),
-        _ => Err(anyhow!("Not a valid private key.")),
-    }?;
-    let cert = rustls_pemfile::read_one(&mut cert)?.unwrap();
-    let cert = match cert {
-        Item::X509Certificate(cert) => Ok(rustls::Certificate(cert)),
-        _ => Err(anyhow!("Not a valid certificate")),
-    }?;
-    let cert = vec![cert];
     let server_crypto = rustls::ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
-        .with_single_cert(cert, pk)?;
+        .with_single_cert(vec![parse_cert(cert)?], parse_key(key)?)?;
+    quic_server(addr, server_crypto)
+}
+
+/// Same as [`new_quic_server`], but the handshake of clients that don't present a certificate
+/// signed by `client_ca` fails, so they can't send any requests.
+pub fn new_mtls_quic_server(
+    addr: SocketAddr,
+    cert: &str,
+    key: &str,
+    client_ca: &str,
+) -> Result<Endpoint> {
+    let verifier = rustls::server::AllowAnyAuthenticatedClient::new(root_store(client_ca)?);
+    let server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![parse_cert(cert)?], parse_key(key)?)?;
+    quic_server(addr, server_crypto)
+}
+
+fn quic_server(addr: SocketAddr, server_crypto: rustls::ServerConfig) -> Result<Endpoint> {
     let mut server_config = ServerConfig::with_crypto(Arc::new(server_crypto));
     Arc::get_mut(&mut server_config.transport)
         .unwrap()
@@ -107,6 +434,28 @@ pub fn new_quic_server(addr: SocketAddr, cert: &str, key: &str) -> Result<Endpoi
     Ok(quinn::Endpoint::server(server_config, addr)?)
 }
 
+fn root_store(ca_cert: &str) -> Result<rustls::RootCertStore> {
+    let mut certs = rustls::RootCertStore::empty();
+    certs.add(&parse_cert(ca_cert)?)?;
+    Ok(certs)
+}
+
+fn parse_cert(cert: &str) -> Result<rustls::Certificate> {
+    let mut cert = cert.as_bytes();
+    match rustls_pemfile::read_one(&mut cert)? {
+        Some(Item::X509Certificate(cert)) => Ok(rustls::Certificate(cert)),
+        _ => Err(anyhow!("Not a valid certificate.")),
+    }
+}
+
+fn parse_key(key: &str) -> Result<rustls::PrivateKey> {
+    let mut key = key.as_bytes();
+    match rustls_pemfile::read_one(&mut key)? {
+        Some(Item::PKCS8Key(key)) => Ok(rustls::PrivateKey(key)),
+        _ => Err(anyhow!("Not a valid private key.")),
+    }
+}
+
 pub async fn handle_accept_control(
     quic_server: &mut Endpoint,
     control_server: control::server::Server,
@@ -126,8 +475,7 @@ async fn handle_quic_stream(
         let stream = conn.accept_bi().await;
         match stream {
             Ok((s, r)) => {
-                let send = SendStream { stream: s };
-                let recv = RecvStream { stream: r };
+                let (send, recv) = quic_stream(s, r, ConnectionConfig::default());
                 tokio::spawn(handle_quic_connection(send, recv, control_server.clone()));
             }
             Err(ConnectionError::LocallyClosed) => {
@@ -164,11 +512,36 @@ where
     E: Environment + 'static,
 {
     while let Some(conn) = quic_server.accept().await {
-        tokio::spawn(handle_quic_connection_node(ctx.clone(), conn));
+        // Waiting for a slot holds up further accepts
+        match ctx.connection_limit.admit().await {
+            Some(slot) => {
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    let result = handle_quic_connection_node(ctx, conn).await;
+                    drop(slot);
+                    result
+                });
+            }
+            None => {
+                tokio::spawn(reject_connection(conn));
+            }
+        }
     }
     Ok(())
 }
 
+// Closes a connection over the limit once it's established, so that the other node gets an error
+// instead of a timeout.
+async fn reject_connection(conn: Connecting) {
+    if let Ok(conn) = conn.await {
+        log::debug!(
+            "Rejected connection from {}, limit reached",
+            conn.remote_address()
+        );
+        conn.close(CONNECTION_LIMIT_REACHED.into(), b"connection limit reached");
+    }
+}
+
 async fn handle_quic_connection_node<T, E>(
     ctx: distributed::server::ServerCtx<T, E>,
     conn: Connecting,
@@ -178,36 +551,354 @@ where
     E: Environment + 'static,
 {
     let conn = conn.await?;
+    let sequential = sequential_requests(&ctx);
     loop {
         let stream = conn.accept_bi().await;
         match stream {
             Ok((s, r)) => {
-                let send = SendStream { stream: s };
-                let recv = RecvStream { stream: r };
-                tokio::spawn(handle_quic_stream_node(ctx.clone(), send, recv));
+                let (send, recv) = quic_stream(s, r, ctx.connection_config);
+                tokio::spawn(handle_quic_stream_node(
+                    ctx.clone(),
+                    conn.stable_id() as u64,
+                    send,
+                    recv,
+                    sequential.clone(),
+                ));
             }
             Err(ConnectionError::LocallyClosed) => break,
-            Err(_) => {}
+            // The connection can't be used anymore once it's closed or lost.
+            Err(e) => {
+                log::debug!("Node connection closed: {e}");
+                break;
+            }
         }
     }
+    // Nobody is waiting on the responses anymore, e.g. spawns still queued can be dropped.
+    ctx.distributed
+        .in_flight
+        .cancel_connection(conn.stable_id() as u64);
     Ok(())
 }
 
+/// Handles all streams of an in-memory connection the same way as streams of a QUIC connection
+/// from another node. `connection_id` identifies the connection in the in-flight requests.
+pub async fn handle_in_memory_node_connection<T, E>(
+    ctx: distributed::server::ServerCtx<T, E>,
+    connection_id: u64,
+    mut acceptor: InMemoryAcceptor,
+) where
+    T: ProcessState + ResourceLimiter + DistributedCtx<E> + Send + 'static,
+    E: Environment + 'static,
+{
+    let sequential = sequential_requests(&ctx);
+    while let Some((send, recv)) = acceptor.accept().await {
+        tokio::spawn(handle_quic_stream_node(
+            ctx.clone(),
+            connection_id,
+            send,
+            recv,
+            sequential.clone(),
+        ));
+    }
+    ctx.distributed.in_flight.cancel_connection(connection_id);
+}
+
+// Returns the queue that all requests of a new connection go through, if they are handled
+// sequentially.
+fn sequential_requests<T, E: Environment>(
+    ctx: &distributed::server::ServerCtx<T, E>,
+) -> Option<SequentialRequests> {
+    match ctx.connection_ordering {
+        ConnectionOrdering::Concurrent => None,
+        ConnectionOrdering::Sequential => Some(SequentialRequests::new()),
+    }
+}
+
 async fn handle_quic_stream_node<T, E>(
     ctx: distributed::server::ServerCtx<T, E>,
+    connection_id: u64,
     mut send: SendStream,
     mut recv: RecvStream,
+    sequential: Option<SequentialRequests>,
 ) where
     T: ProcessState + ResourceLimiter + DistributedCtx<E> + Send + 'static,
     E: Environment + 'static,
 {
     while let Ok(bytes) = recv.receive().await {
-        if let Ok((msg_id, request)) =
-            bincode::deserialize::<(u64, distributed::message::Request)>(&bytes)
+        if let Ok((msg_id, request, join_token)) =
+            bincode::deserialize::<(u64, distributed::message::Request, Option<JoinToken>)>(&bytes)
         {
-            distributed::server::handle_message(ctx.clone(), &mut send, msg_id, request).await;
+            distributed::server::handle_message(
+                ctx.clone(),
+                &mut send,
+                connection_id,
+                msg_id,
+                request,
+                join_token,
+                sequential.as_ref(),
+            )
+            .await;
         } else {
             log::debug!("Error deserializing request");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use bytes::Bytes;
+    use lunatic_process::lifecycle::Lifecycle;
+
+    use super::{
+        handle_in_memory_node_connection, in_memory_stream_pair, new_mtls_quic_client,
+        new_mtls_quic_server, new_quic_client, Client, Connection, ConnectionConfig,
+    };
+    use crate::{
+        control,
+        distributed::{
+            self,
+            client::ClientConfig,
+            message::{ClientError, Request, Response, Spawn},
+            spawn_config::SpawnConfig,
+        },
+        join_token::JoinToken,
+        test_node::{test_node, TestConfig},
+        EnvironmentId, ModuleId,
+    };
+
+    fn spawn(function: &str) -> Request {
+        Request::Spawn(Spawn {
+            environment_id: EnvironmentId(1),
+            module_id: ModuleId(1),
+            module_version: None,
+            function: function.to_string(),
+            params: vec![],
+            config: SpawnConfig::Inline(bincode::serialize(&TestConfig::default()).unwrap()),
+            idempotency_key: None,
+            cancel_token: None,
+            lifecycle: Lifecycle::Unspecified,
+        })
+    }
+
+    fn frame(msg_id: u64, request: Request, join_token: Option<&str>) -> [Bytes; 2] {
+        let join_token = join_token.map(|token| JoinToken::new(token.to_string()));
+        let data = bincode::serialize(&(msg_id, request, join_token)).unwrap();
+        let size = Bytes::copy_from_slice(&(data.len() as u32).to_le_bytes());
+        [size, data.into()]
+    }
+
+    #[tokio::test]
+    async fn spawn_round_trip_over_in_memory_connection() {
+        let client = Client::in_memory(ConnectionConfig::default());
+        // Never bound, the node is only reachable in memory
+        let address: SocketAddr = "127.0.0.1:3030".parse().unwrap();
+        let node = test_node(client.clone(), ClientConfig::default()).await;
+        let envs = node.envs.clone();
+        tokio::spawn(handle_in_memory_node_connection(
+            node,
+            1,
+            client.serve_in_memory(address),
+        ));
+
+        let connection = client.open_connection(address, "node").await.unwrap();
+        let (mut send, mut recv) = connection.open_stream().await.unwrap();
+        send.send(&mut frame(7, spawn("hello"), None))
+            .await
+            .unwrap();
+        let bytes = recv.receive().await.unwrap();
+        let (msg_id, response): (u64, Response) = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(msg_id, 7);
+        assert!(matches!(response, Response::Spawned(_)));
+        assert!(envs.get(1).is_some());
+
+        // The request went through the real spawn handler
+        send.send(&mut frame(8, spawn("missing"), None))
+            .await
+            .unwrap();
+        let bytes = recv.receive().await.unwrap();
+        let (msg_id, response): (u64, Response) = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(msg_id, 8);
+        assert!(matches!(
+            response,
+            Response::Error(ClientError::FunctionNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn requests_without_join_token_are_rejected() {
+        let client = Client::in_memory(ConnectionConfig::default());
+        let address: SocketAddr = "127.0.0.1:3031".parse().unwrap();
+        let config = ClientConfig {
+            join_token: Some(JoinToken::new("secret".to_string())),
+            ..Default::default()
+        };
+        let node = test_node(client.clone(), config).await;
+        let envs = node.envs.clone();
+        tokio::spawn(handle_in_memory_node_connection(
+            node,
+            1,
+            client.serve_in_memory(address),
+        ));
+
+        let connection = client.open_connection(address, "node").await.unwrap();
+        let (mut send, mut recv) = connection.open_stream().await.unwrap();
+        let gossip = || Request::Gossip {
+            environment_id: EnvironmentId(0),
+            updates: vec![],
+            target: None,
+        };
+        let requests = [
+            (1, spawn("hello"), None),
+            (2, spawn("hello"), Some("guess")),
+            (3, gossip(), None),
+        ];
+        for (msg_id, request, join_token) in requests {
+            send.send(&mut frame(msg_id, request, join_token))
+                .await
+                .unwrap();
+            let bytes = recv.receive().await.unwrap();
+            let (id, response): (u64, Response) = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(id, msg_id);
+            assert!(matches!(
+                response,
+                Response::Error(ClientError::InvalidJoinToken)
+            ));
+        }
+        assert!(envs.get(1).is_none());
+
+        send.send(&mut frame(4, spawn("hello"), Some("secret")))
+            .await
+            .unwrap();
+        let bytes = recv.receive().await.unwrap();
+        let (_, response): (u64, Response) = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(response, Response::Spawned(_)));
+    }
+
+    #[tokio::test]
+    async fn in_memory_client_only_reaches_served_nodes() {
+        let client = Client::in_memory(ConnectionConfig::default());
+        let address: SocketAddr = "127.0.0.1:3030".parse().unwrap();
+        assert!(client.open_connection(address, "node").await.is_err());
+        let acceptor = client.serve_in_memory(address);
+        assert!(client.open_connection(address, "node").await.is_ok());
+        drop(acceptor);
+        assert!(client.open_connection(address, "node").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_connection_closes_with_acceptor() {
+        let (connection, acceptor) = Connection::in_memory(ConnectionConfig::default());
+        assert!(!connection.is_closed());
+        drop(acceptor);
+        assert!(connection.is_closed());
+        assert!(connection.open_stream().await.is_err());
+    }
+
+    fn timeouts() -> ConnectionConfig {
+        ConnectionConfig {
+            read_timeout: Some(Duration::from_millis(50)),
+            write_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_stalling_mid_frame_is_dropped() {
+        let ((mut send, _), (_, mut recv)) = in_memory_stream_pair(timeouts());
+        // Announce 8 bytes, but only send 2 of them and keep the stream open.
+        let size = Bytes::copy_from_slice(&8u32.to_le_bytes());
+        send.send(&mut [size, Bytes::from_static(b"ab")])
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        assert!(recv.receive().await.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        drop(send);
+    }
+
+    #[tokio::test]
+    async fn slow_transfer_with_progress_is_not_dropped() {
+        let ((mut send, _), (_, mut recv)) = in_memory_stream_pair(timeouts());
+        let sender = tokio::spawn(async move {
+            // Waiting for the next frame is not limited.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            send.send(&mut [Bytes::copy_from_slice(&10u32.to_le_bytes())])
+                .await
+                .unwrap();
+            // The whole frame takes longer than the timeout, but every byte makes progress.
+            for byte in 0..10u8 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                send.send(&mut [Bytes::copy_from_slice(&[byte])])
+                    .await
+                    .unwrap();
+            }
+            send
+        });
+
+        let frame = recv.receive().await.unwrap();
+        assert_eq!(&frame[..], &(0..10).collect::<Vec<u8>>()[..]);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mtls_server_rejects_clients_without_signed_certificate() {
+        let ca = control::server::root_cert(true, None, None).unwrap();
+        let ca_pem = distributed::server::root_cert(true, None).unwrap();
+        let signed = |name: &str| {
+            let cert = distributed::server::gen_node_cert(name).unwrap();
+            (
+                cert.serialize_pem_with_signer(&ca).unwrap(),
+                cert.serialize_private_key_pem(),
+            )
+        };
+        let (server_cert, server_key) = signed("localhost");
+        let server = new_mtls_quic_server(
+            "127.0.0.1:0".parse().unwrap(),
+            &server_cert,
+            &server_key,
+            &ca_pem,
+        )
+        .unwrap();
+        let address = server.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            for _ in 0..3 {
+                let conn = server.accept().await.unwrap().await;
+                accepted.push(conn.is_ok());
+            }
+            accepted
+        });
+
+        let (client_cert, client_key) = signed("client");
+        let untrusted = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let clients = [
+            new_mtls_quic_client(&ca_pem, &client_cert, &client_key).unwrap(),
+            new_quic_client(&ca_pem).unwrap(),
+            new_mtls_quic_client(
+                &ca_pem,
+                &untrusted.serialize_pem().unwrap(),
+                &untrusted.serialize_private_key_pem(),
+            )
+            .unwrap(),
+        ];
+        for client in clients {
+            // The client may only learn about the rejection after its side of the handshake
+            let connect = client.open_connection(address, "localhost");
+            tokio::time::timeout(Duration::from_secs(5), connect)
+                .await
+                .ok();
+        }
+
+        let accepted = tokio::time::timeout(Duration::from_secs(10), accepted)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(accepted, vec![true, false, false]);
+    }
+}
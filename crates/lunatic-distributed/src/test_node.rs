@@ -0,0 +1,335 @@
+// A node that handles requests with the real request handlers, for tests that need a whole node
+// without the host functions of the runtime.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use lunatic_process::{
+    config::ProcessConfig,
+    env::{Environment, Environments, LunaticEnvironment, LunaticEnvironments},
+    kv::{KvCheckpoints, KvStore},
+    latency::SchedulingLatency,
+    lifecycle::Lifecycle,
+    limits::ResourceLimitExceeded,
+    mailbox::MessageMailbox,
+    message::PriorityBoost,
+    restart::RestartPolicy,
+    runtimes::{
+        wasmtime::{default_config, WasmtimeCompiledModule, WasmtimeRuntime},
+        Modules, RawWasm,
+    },
+    state::{ConfigResources, ProcessState, SignalReceiver, SignalSender, StartTime},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::unbounded_channel, Mutex};
+use wasmtime::{Linker, ResourceLimiter};
+
+use crate::{
+    control,
+    distributed::{self, client::ClientConfig, ordering::ConnectionOrdering, server::ServerCtx},
+    quic::{Client, ConnectionConfig},
+    DistributedCtx, DistributedProcessState, NodeId, ReplyCapabilityResources,
+};
+
+// Process state of a node that can only run modules without imports
+pub(crate) struct TestState {
+    id: u64,
+    environment: Arc<LunaticEnvironment>,
+    distributed: Option<DistributedProcessState>,
+    runtime: Option<WasmtimeRuntime>,
+    module: Option<Arc<WasmtimeCompiledModule<Self>>>,
+    config: Arc<TestConfig>,
+    signal_mailbox: (SignalSender, SignalReceiver),
+    message_mailbox: MessageMailbox,
+    scheduling_latency: SchedulingLatency,
+    start_time: StartTime,
+    kv: KvStore,
+    priority_boost: PriorityBoost,
+    config_resources: ConfigResources<TestConfig>,
+    registry: Arc<DashMap<String, (u64, u64)>>,
+    reply_capabilities: ReplyCapabilityResources,
+    initialized: bool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TestConfig {
+    lifecycle: Lifecycle,
+}
+
+impl ProcessConfig for TestConfig {
+    fn set_max_fuel(&mut self, _max_fuel: Option<u64>) {}
+    fn get_max_fuel(&self) -> Option<u64> {
+        None
+    }
+    fn set_max_memory(&mut self, _max_memory: usize) {}
+    fn get_max_memory(&self) -> usize {
+        usize::MAX
+    }
+    fn set_restart_policy(&mut self, _restart_policy: Option<RestartPolicy>) {}
+    fn get_restart_policy(&self) -> Option<RestartPolicy> {
+        None
+    }
+    fn set_lifecycle(&mut self, lifecycle: Lifecycle) {
+        self.lifecycle = lifecycle;
+    }
+    fn get_lifecycle(&self) -> Lifecycle {
+        self.lifecycle
+    }
+}
+
+impl TestState {
+    fn new(
+        environment: Arc<LunaticEnvironment>,
+        distributed: Option<DistributedProcessState>,
+        module: Option<Arc<WasmtimeCompiledModule<Self>>>,
+        config: Arc<TestConfig>,
+    ) -> Self {
+        let signal_mailbox = unbounded_channel();
+        Self {
+            id: environment.get_next_process_id(),
+            environment,
+            distributed,
+            runtime: None,
+            module,
+            config,
+            signal_mailbox: (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1))),
+            message_mailbox: MessageMailbox::default(),
+            scheduling_latency: SchedulingLatency::default(),
+            start_time: StartTime::now(),
+            kv: KvStore::default(),
+            priority_boost: PriorityBoost::default(),
+            config_resources: ConfigResources::default(),
+            registry: Arc::default(),
+            reply_capabilities: ReplyCapabilityResources::default(),
+            initialized: false,
+        }
+    }
+}
+
+impl ProcessState for TestState {
+    type Config = TestConfig;
+
+    fn new_state(
+        &self,
+        module: Arc<WasmtimeCompiledModule<Self>>,
+        config: Arc<TestConfig>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            runtime: self.runtime.clone(),
+            ..Self::new(
+                self.environment.clone(),
+                self.distributed.clone(),
+                Some(module),
+                config,
+            )
+        })
+    }
+
+    fn state_for_instantiation() -> Self {
+        Self::new(
+            Arc::new(LunaticEnvironment::new(0)),
+            None,
+            None,
+            Arc::default(),
+        )
+    }
+
+    fn register(_linker: &mut Linker<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn initialize(&mut self) {
+        self.initialized = true;
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    fn runtime(&self) -> &WasmtimeRuntime {
+        self.runtime.as_ref().unwrap()
+    }
+
+    fn module(&self) -> &Arc<WasmtimeCompiledModule<Self>> {
+        self.module.as_ref().unwrap()
+    }
+
+    fn config(&self) -> &Arc<TestConfig> {
+        &self.config
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn signal_mailbox(&self) -> &(SignalSender, SignalReceiver) {
+        &self.signal_mailbox
+    }
+
+    fn message_mailbox(&self) -> &MessageMailbox {
+        &self.message_mailbox
+    }
+
+    fn scheduling_latency(&self) -> &SchedulingLatency {
+        &self.scheduling_latency
+    }
+
+    fn start_time(&self) -> &StartTime {
+        &self.start_time
+    }
+
+    fn set_start_time(&mut self, start_time: StartTime) {
+        self.start_time = start_time;
+    }
+
+    fn kv(&self) -> &KvStore {
+        &self.kv
+    }
+
+    fn kv_mut(&mut self) -> &mut KvStore {
+        &mut self.kv
+    }
+
+    fn kv_checkpoints(&self) -> Option<&Arc<KvCheckpoints>> {
+        None
+    }
+
+    fn priority_boost(&self) -> &PriorityBoost {
+        &self.priority_boost
+    }
+
+    fn priority_boost_mut(&mut self) -> &mut PriorityBoost {
+        &mut self.priority_boost
+    }
+
+    fn config_resources(&self) -> &ConfigResources<TestConfig> {
+        &self.config_resources
+    }
+
+    fn config_resources_mut(&mut self) -> &mut ConfigResources<TestConfig> {
+        &mut self.config_resources
+    }
+
+    fn registry(&self) -> &Arc<DashMap<String, (u64, u64)>> {
+        &self.registry
+    }
+
+    fn resource_limit_exceeded(&self) -> Option<&ResourceLimitExceeded> {
+        None
+    }
+}
+
+impl ResourceLimiter for TestState {
+    fn memory_growing(&mut self, _current: usize, _desired: usize, _max: Option<usize>) -> bool {
+        true
+    }
+
+    fn table_growing(&mut self, _current: u32, _desired: u32, _max: Option<u32>) -> bool {
+        true
+    }
+}
+
+impl DistributedCtx<LunaticEnvironment> for TestState {
+    fn new_dist_state(
+        environment: Arc<LunaticEnvironment>,
+        distributed: DistributedProcessState,
+        runtime: WasmtimeRuntime,
+        module: Arc<WasmtimeCompiledModule<Self>>,
+        config: Arc<TestConfig>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            runtime: Some(runtime),
+            ..Self::new(environment, Some(distributed), Some(module), config)
+        })
+    }
+
+    fn distributed(&self) -> anyhow::Result<&DistributedProcessState> {
+        Ok(self.distributed.as_ref().unwrap())
+    }
+
+    fn distributed_mut(&mut self) -> anyhow::Result<&mut DistributedProcessState> {
+        Ok(self.distributed.as_mut().unwrap())
+    }
+
+    fn module_id(&self) -> u64 {
+        self.module().source().id.unwrap_or(0)
+    }
+
+    fn environment_id(&self) -> u64 {
+        self.environment.id()
+    }
+
+    fn cancel_token(&self) -> Option<u64> {
+        None
+    }
+
+    fn can_spawn(&self) -> bool {
+        false
+    }
+
+    fn can_drain_node(&self) -> bool {
+        false
+    }
+
+    fn reply_capability_resources(&self) -> &ReplyCapabilityResources {
+        &self.reply_capabilities
+    }
+
+    fn reply_capability_resources_mut(&mut self) -> &mut ReplyCapabilityResources {
+        &mut self.reply_capabilities
+    }
+}
+
+// A module exporting the function `hello` that returns right away
+pub(crate) const HELLO_MODULE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type () -> ()
+    0x03, 0x02, 0x01, 0x00, // one function of type 0
+    0x07, 0x09, 0x01, 0x05, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, // export
+    0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // empty body
+];
+
+// Node server handling requests with the real request handlers, its client is configured with
+// `config`. `HELLO_MODULE` is compiled as module 1.
+pub(crate) async fn test_node(
+    client: Client,
+    config: ClientConfig,
+) -> ServerCtx<TestState, LunaticEnvironment> {
+    let node_client =
+        distributed::Client::new(NodeId(2), control::Client::detached(), client, config)
+            .await
+            .unwrap();
+    let distributed = DistributedProcessState::new(2, control::Client::detached(), node_client)
+        .await
+        .unwrap();
+    let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+    let modules = Modules::default();
+    modules
+        .compile(
+            runtime.clone(),
+            RawWasm::new(Some(1), HELLO_MODULE.to_vec()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    let envs: Arc<dyn Environments<Env = LunaticEnvironment>> =
+        Arc::new(LunaticEnvironments::default());
+    ServerCtx {
+        envs,
+        modules,
+        distributed,
+        runtime,
+        module_allowlist: Default::default(),
+        module_verifier: Default::default(),
+        compile_failures: Default::default(),
+        preload: Default::default(),
+        spawn_configs: Default::default(),
+        spawn_queue: Default::default(),
+        transactions: Default::default(),
+        connection_config: ConnectionConfig::default(),
+        fair_queue: Default::default(),
+        dedup: Default::default(),
+        connection_ordering: ConnectionOrdering::Concurrent,
+        connection_limit: Default::default(),
+    }
+}
@@ -0,0 +1,208 @@
+/*!
+Timestamps that are passed between nodes.
+
+Nodes don't share a clock. Their wall clocks can be skewed against each other and can jump when
+the system clock is adjusted, and a reading of a monotonic clock only means something on the
+machine it was taken on. A [`DistTimestamp`] therefore records which clock of which node it was
+read from, and a node's [`Clock`] converts timestamps of other nodes into its own time.
+
+Conversion rules:
+* Monotonic timestamps of this node are exact.
+* Wall clock timestamps of any node are shifted by the estimated offset of that node's wall clock
+  against the wall clock of this node, see [`Clock::set_offset`]. The result is only as accurate
+  as the estimate, nodes without an estimate are assumed to have no offset.
+* Monotonic timestamps of other nodes can't be converted. Before a monotonic timestamp leaves its
+  node, it's turned into a wall clock timestamp with [`Clock::export`].
+
+Timestamps have a precision of a microsecond. Points before the clock of this node started are
+converted to its start.
+*/
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::NodeId;
+
+/// A point in time read from a clock of the node `node_id`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DistTimestamp {
+    /// Microseconds since the unix epoch on the wall clock of the node.
+    WallClock { node_id: NodeId, micros: u64 },
+    /// Microseconds on the monotonic clock of the node, counted from when its [`Clock`] was
+    /// created.
+    Monotonic { node_id: NodeId, micros: u64 },
+}
+
+impl DistTimestamp {
+    pub fn node_id(&self) -> NodeId {
+        match self {
+            DistTimestamp::WallClock { node_id, .. } | DistTimestamp::Monotonic { node_id, .. } => {
+                *node_id
+            }
+        }
+    }
+}
+
+/// The clocks of a node, used to create timestamps and to convert timestamps of other nodes.
+///
+/// The wall clock is only read when the clock is created, later wall clock timestamps are derived
+/// from the monotonic clock. Adjusting the system clock afterwards doesn't make them jump.
+#[derive(Debug)]
+pub struct Clock {
+    node_id: NodeId,
+    start: Instant,
+    // Wall clock time at `start`, in microseconds since the unix epoch
+    start_micros: u64,
+    // Node ID -> estimated offset of its wall clock against the wall clock of this node in
+    // microseconds, positive if the node's clock is ahead
+    offsets: DashMap<NodeId, i64>,
+}
+
+impl Clock {
+    pub fn new(node_id: NodeId) -> Self {
+        let wall_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        Self::with_wall_clock(node_id, Instant::now(), wall_micros)
+    }
+
+    /// Creates a clock that starts at `start`, at which point the wall clock of the node showed
+    /// `wall_micros` microseconds since the unix epoch.
+    pub fn with_wall_clock(node_id: NodeId, start: Instant, wall_micros: u64) -> Self {
+        Self {
+            node_id,
+            start,
+            start_micros: wall_micros,
+            offsets: DashMap::new(),
+        }
+    }
+
+    /// Sets the estimated offset of the wall clock of `node_id` against the wall clock of this
+    /// node in microseconds, positive if the clock of `node_id` is ahead.
+    pub fn set_offset(&self, node_id: NodeId, offset_micros: i64) {
+        self.offsets.insert(node_id, offset_micros);
+    }
+
+    pub fn monotonic_now(&self) -> DistTimestamp {
+        self.timestamp(Instant::now())
+    }
+
+    pub fn wall_now(&self) -> DistTimestamp {
+        self.export(self.monotonic_now())
+    }
+
+    /// Returns the monotonic timestamp of `instant`.
+    pub fn timestamp(&self, instant: Instant) -> DistTimestamp {
+        DistTimestamp::Monotonic {
+            node_id: self.node_id,
+            micros: instant.saturating_duration_since(self.start).as_micros() as u64,
+        }
+    }
+
+    /// Turns a monotonic timestamp of this node into a wall clock timestamp, so that other nodes
+    /// can convert it. Other timestamps are returned as they are.
+    pub fn export(&self, timestamp: DistTimestamp) -> DistTimestamp {
+        match timestamp {
+            DistTimestamp::Monotonic { node_id, micros } if node_id == self.node_id => {
+                DistTimestamp::WallClock {
+                    node_id,
+                    micros: self.start_micros.saturating_add(micros),
+                }
+            }
+            timestamp => timestamp,
+        }
+    }
+
+    /// Converts the timestamp into an instant of this node. Returns `None` for monotonic
+    /// timestamps of other nodes.
+    pub fn to_instant(&self, timestamp: DistTimestamp) -> Option<Instant> {
+        let since_start = match timestamp {
+            DistTimestamp::Monotonic { node_id, micros } if node_id == self.node_id => {
+                micros as i128
+            }
+            DistTimestamp::Monotonic { .. } => return None,
+            DistTimestamp::WallClock { node_id, micros } => {
+                let offset = if node_id == self.node_id {
+                    0
+                } else {
+                    self.offsets
+                        .get(&node_id)
+                        .map(|offset| *offset)
+                        .unwrap_or(0)
+                };
+                micros as i128 - offset as i128 - self.start_micros as i128
+            }
+        };
+        let since_start = Duration::from_micros(since_start.clamp(0, u64::MAX as i128) as u64);
+        Some(self.start + since_start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Clock, DistTimestamp};
+    use crate::NodeId;
+
+    // Two nodes started at the same moment, the wall clock of node 2 is 2s ahead of node 1
+    fn nodes() -> (Instant, Clock, Clock) {
+        let start = Instant::now();
+        let node_1 = Clock::with_wall_clock(NodeId(1), start, 1_000_000_000);
+        let node_2 = Clock::with_wall_clock(NodeId(2), start, 1_002_000_000);
+        node_1.set_offset(NodeId(2), 2_000_000);
+        node_2.set_offset(NodeId(1), -2_000_000);
+        (start, node_1, node_2)
+    }
+
+    #[test]
+    fn deadlines_survive_skewed_clocks() {
+        let (start, node_1, node_2) = nodes();
+        let deadline = start + Duration::from_millis(1500);
+
+        // Node 2 sets a deadline and passes it to node 1
+        let sent = node_2.export(node_2.timestamp(deadline));
+        assert_eq!(
+            sent,
+            DistTimestamp::WallClock {
+                node_id: NodeId(2),
+                micros: 1_003_500_000
+            }
+        );
+        let received: DistTimestamp =
+            bincode::deserialize(&bincode::serialize(&sent).unwrap()).unwrap();
+        assert_eq!(node_1.to_instant(received), Some(deadline));
+        // Without the offset node 1 would expire it 2s late
+        let unaware = Clock::with_wall_clock(NodeId(1), start, 1_000_000_000);
+        assert_eq!(
+            unaware.to_instant(received),
+            Some(deadline + Duration::from_secs(2))
+        );
+
+        // And back, node 1 exports its own wall clock
+        let returned = node_1.export(node_1.timestamp(deadline));
+        assert_eq!(node_2.to_instant(returned), Some(deadline));
+        assert_eq!(
+            node_2.to_instant(node_2.timestamp(deadline)),
+            Some(deadline)
+        );
+    }
+
+    #[test]
+    fn foreign_monotonic_timestamps_are_not_converted() {
+        let (start, node_1, node_2) = nodes();
+        let timestamp = node_2.timestamp(start + Duration::from_secs(1));
+        assert_eq!(node_1.to_instant(timestamp), None);
+        assert_eq!(node_1.export(timestamp), timestamp);
+
+        // Points before the start of the clock end up at the start
+        let early = DistTimestamp::WallClock {
+            node_id: NodeId(2),
+            micros: 0,
+        };
+        assert_eq!(node_1.to_instant(early), Some(start));
+    }
+}
@@ -12,9 +12,12 @@ use tokio::time::{timeout, Duration};
 use wasmtime::{Caller, Linker};
 
 use lunatic_process::{
-    message::{DataMessage, Message},
+    env::Environment,
+    mailbox::ReceiveOrder,
+    message::{DataMessage, Message, Priority},
+    ring::RingOverflow,
     state::ProcessState,
-    Signal,
+    topics, Signal,
 };
 
 // Register the mailbox APIs to the linker
@@ -27,6 +30,8 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
     linker.func_wrap("lunatic::message", "seek_data", seek_data)?;
     linker.func_wrap("lunatic::message", "get_tag", get_tag)?;
     linker.func_wrap("lunatic::message", "data_size", data_size)?;
+    linker.func_wrap("lunatic::message", "set_priority", set_priority)?;
+    linker.func_wrap("lunatic::message", "get_priority", get_priority)?;
     linker.func_wrap("lunatic::message", "push_module", push_module)?;
     linker.func_wrap("lunatic::message", "take_module", take_module)?;
     linker.func_wrap("lunatic::message", "push_tcp_stream", push_tcp_stream)?;
@@ -40,8 +45,18 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
         send_receive_skip_search,
     )?;
     linker.func_wrap3_async("lunatic::message", "receive", receive)?;
+    linker.func_wrap3_async("lunatic::message", "receive_fifo", receive_fifo)?;
     linker.func_wrap("lunatic::message", "push_udp_socket", push_udp_socket)?;
     linker.func_wrap("lunatic::message", "take_udp_socket", take_udp_socket)?;
+    linker.func_wrap("lunatic::message", "subscribe", subscribe)?;
+    linker.func_wrap("lunatic::message", "unsubscribe", unsubscribe)?;
+    linker.func_wrap("lunatic::message", "publish", publish)?;
+    linker.func_wrap("lunatic::message", "mailbox_digest", mailbox_digest)?;
+    linker.func_wrap("lunatic::message", "register_ring", register_ring)?;
+    linker.func_wrap("lunatic::message", "unregister_ring", unregister_ring)?;
+    linker.func_wrap("lunatic::message", "drain_ring", drain_ring)?;
+    linker.func_wrap("lunatic::message", "set_dedup_window", set_dedup_window)?;
+    linker.func_wrap("lunatic::message", "duplicates_dropped", duplicates_dropped)?;
 
     Ok(())
 }
@@ -259,6 +274,56 @@ fn data_size<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u
     Ok(bytes as u64)
 }
 
+// Sets the priority of the message in the scratch area. Messages with a higher priority are
+// received before the ones with a lower priority that are already in the mailbox, messages with
+// the same priority are received in the order they were sent.
+//
+// Priorities:
+// * 0 - Low
+// * 1 - Normal, the priority of new messages
+// * 2 - High
+//
+// Traps:
+// * If the priority is not one of the above.
+// * If it's called without a data message being inside of the scratch area.
+fn set_priority<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    priority: u32,
+) -> Result<()> {
+    let priority = Priority::try_from(priority)
+        .ok()
+        .or_trap("lunatic::message::set_priority::invalid_priority")?;
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::set_priority")?;
+    let message = match message {
+        Message::Data(data) => data.with_priority(priority),
+        Message::LinkDied(_) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+    };
+    caller
+        .data_mut()
+        .message_scratch_area()
+        .replace(Message::Data(message));
+    Ok(())
+}
+
+// Returns the priority of the message in the scratch area, see `set_priority`.
+//
+// Traps:
+// * If it's called without a message being inside of the scratch area.
+fn get_priority<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u32> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::get_priority")?;
+    Ok(message.priority().into())
+}
+
 // Adds a module resource to the message that is currently in the scratch area and returns
 // the new location of it.
 //
@@ -439,14 +504,26 @@ fn send<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, process_id: u64)
         .message_scratch_area()
         .take()
         .or_trap("lunatic::message::send::no_message")?;
+    let message = caller.data().priority_boost().apply(message);
 
-    if let Some(process) = caller.data_mut().environment().get_process(process_id) {
-        process.send(Signal::Message(message));
+    let environment = caller.data_mut().environment();
+    if let Some(message) = intercept(environment.as_ref(), message) {
+        if let Some(process) = environment.get_process(process_id) {
+            process.send(Signal::Message(message));
+        }
     }
 
     Ok(0)
 }
 
+// Runs data messages through the node's interceptors, returns `None` if the message was dropped.
+fn intercept(environment: &dyn Environment, message: Message) -> Option<Message> {
+    match message {
+        Message::Data(message) => environment.interceptors().apply(message).map(Message::Data),
+        message => Some(message),
+    }
+}
+
 // Sends the message to a process and waits for a reply, but doesn't look through existing
 // messages in the mailbox queue while waiting. This is an optimization that only makes sense
 // with tagged messages. In a request/reply scenario we can tag the request message with an
@@ -476,6 +553,7 @@ fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
             .message_scratch_area()
             .take()
             .or_trap("lunatic::message::send_receive_skip_search")?;
+        let message = caller.data().priority_boost().apply(message);
         let mut _tags = [0; 1];
         let tags = if let Some(tag) = message.tag() {
             _tags = [tag];
@@ -484,8 +562,11 @@ fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
             None
         };
 
-        if let Some(process) = caller.data_mut().environment().get_process(process_id) {
-            process.send(Signal::Message(message));
+        let environment = caller.data_mut().environment();
+        if let Some(message) = intercept(environment.as_ref(), message) {
+            if let Some(process) = environment.get_process(process_id) {
+                process.send(Signal::Message(message));
+            }
         }
 
         let pop_skip_search_tag = caller.data_mut().mailbox().pop_skip_search(tags);
@@ -525,47 +606,94 @@ fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
 // Traps:
 // * If **tag_ptr + (ciovec_array_len * 8) is outside the memory
 fn receive<T: ProcessState + ProcessCtx<T> + Send>(
-    mut caller: Caller<T>,
+    caller: Caller<T>,
     tag_ptr: u32,
     tag_len: u32,
     timeout_duration: u64,
 ) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
-    Box::new(async move {
-        let tags = if tag_len > 0 {
-            let memory = get_memory(&mut caller)?;
-            let buffer = memory
-                .data(&caller)
-                .get(tag_ptr as usize..(tag_ptr + tag_len * 8) as usize)
-                .or_trap("lunatic::message::receive")?;
-
-            // Gether all tags
-            let tags: Vec<i64> = buffer
-                .chunks_exact(8)
-                .map(|chunk| i64::from_le_bytes(chunk.try_into().expect("works")))
-                .collect();
-            Some(tags)
-        } else {
-            None
-        };
+    Box::new(receive_in_order(
+        caller,
+        tag_ptr,
+        tag_len,
+        timeout_duration,
+        ReceiveOrder::Priority,
+        "lunatic::message::receive",
+    ))
+}
 
-        let pop = caller.data_mut().mailbox().pop(tags.as_deref());
-        if let Ok(message) = match timeout_duration {
-            // Without timeout
-            u64::MAX => Ok(pop.await),
-            // With timeout
-            t => timeout(Duration::from_millis(t), pop).await,
-        } {
-            let result = match message {
-                Message::Data(_) => 0,
-                Message::LinkDied(_) => 1,
-            };
-            // Put the message into the scratch area
-            caller.data_mut().message_scratch_area().replace(message);
-            Ok(result)
-        } else {
-            Ok(9027)
-        }
-    })
+// Same as `receive`, but takes the oldest message out of the queue regardless of its priority.
+//
+// This can be used to temporarily receive all messages in the order they arrived. Messages that
+// are still queued afterwards are received by `receive` in priority order again.
+//
+// Returns:
+// * 0    if it's a data message.
+// * 1    if it's a signal turned into a message.
+// * 9027 if call timed out.
+//
+// Traps:
+// * If **tag_ptr + (ciovec_array_len * 8) is outside the memory
+fn receive_fifo<T: ProcessState + ProcessCtx<T> + Send>(
+    caller: Caller<T>,
+    tag_ptr: u32,
+    tag_len: u32,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(receive_in_order(
+        caller,
+        tag_ptr,
+        tag_len,
+        timeout_duration,
+        ReceiveOrder::Arrival,
+        "lunatic::message::receive_fifo",
+    ))
+}
+
+async fn receive_in_order<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<'_, T>,
+    tag_ptr: u32,
+    tag_len: u32,
+    timeout_duration: u64,
+    order: ReceiveOrder,
+    function: &'static str,
+) -> Result<u32> {
+    let tags = if tag_len > 0 {
+        let memory = get_memory(&mut caller)?;
+        let buffer = memory
+            .data(&caller)
+            .get(tag_ptr as usize..(tag_ptr + tag_len * 8) as usize)
+            .or_trap(function)?;
+
+        // Gether all tags
+        let tags: Vec<i64> = buffer
+            .chunks_exact(8)
+            .map(|chunk| i64::from_le_bytes(chunk.try_into().expect("works")))
+            .collect();
+        Some(tags)
+    } else {
+        None
+    };
+
+    let pop = caller
+        .data_mut()
+        .mailbox()
+        .pop_in_order(tags.as_deref(), order);
+    if let Ok(message) = match timeout_duration {
+        // Without timeout
+        u64::MAX => Ok(pop.await),
+        // With timeout
+        t => timeout(Duration::from_millis(t), pop).await,
+    } {
+        let result = match message {
+            Message::Data(_) => 0,
+            Message::LinkDied(_) => 1,
+        };
+        // Put the message into the scratch area
+        caller.data_mut().message_scratch_area().replace(message);
+        Ok(result)
+    } else {
+        Ok(9027)
+    }
 }
 
 // Adds a udp socket resource to the message that is currently in the scratch area and returns
@@ -621,3 +749,239 @@ fn take_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
     };
     Ok(caller.data_mut().udp_resources_mut().add(udp_socket))
 }
+
+// Subscribes the process to the topic `topic_ptr, topic_len` inside of its environment. All
+// subscriptions are removed when the process finishes.
+//
+// Traps:
+// * If the topic is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn subscribe<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+) -> Result<()> {
+    let topic = read_topic(&mut caller, topic_ptr, topic_len)?;
+    let id = caller.data().id();
+    caller.data().environment().topics().subscribe(&topic, id);
+    Ok(())
+}
+
+// Unsubscribes the process from the topic `topic_ptr, topic_len`.
+//
+// Returns:
+// * 0 If the process was unsubscribed.
+// * 1 If the process was not subscribed to the topic.
+//
+// Traps:
+// * If the topic is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn unsubscribe<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+) -> Result<u32> {
+    let topic = read_topic(&mut caller, topic_ptr, topic_len)?;
+    let id = caller.data().id();
+    if caller.data().environment().topics().unsubscribe(&topic, id) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+// Sends a copy of the data message in the scratch area to all processes subscribed to the topic
+// `topic_ptr, topic_len` in the same environment. Use `lunatic::distributed::publish` to also
+// publish to subscribers on other nodes.
+//
+// Returns the number of processes the message was delivered to.
+//
+// Traps:
+// * If the topic is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+// * If it's called before creating the next message.
+// * If the message contains resources.
+fn publish<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    topic_ptr: u32,
+    topic_len: u32,
+) -> Result<u32> {
+    let topic = read_topic(&mut caller, topic_ptr, topic_len)?;
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::publish::no_message")?;
+    let message = caller.data().priority_boost().apply(message);
+    match message {
+        Message::Data(message) => {
+            if !message.resources.is_empty() {
+                return Err(anyhow!("Cannot publish messages containing resources."));
+            }
+            let environment = caller.data().environment();
+            let delivered = topics::publish(
+                environment.as_ref(),
+                &topic,
+                message.tag,
+                message.priority,
+                &message.buffer,
+            );
+            Ok(delivered as u32)
+        }
+        Message::LinkDied(_) => Err(anyhow!("Only data messages can be published.")),
+    }
+}
+
+// Writes a summary of the messages waiting in the mailbox of the process to `digest_ptr`, without
+// receiving any of them. Useful to find out why a process is not making progress, e.g. if it's
+// waiting on a tag that never arrives.
+//
+// The digest starts with 4 little endian u64 values:
+// [number of messages, total size of data buffers in bytes, age of the oldest message in ms,
+//  number of distinct tags]
+// followed by `(tag as i64, number of messages as u64)` pairs ordered by tag, for at most
+// `max_tags` tags. Untagged messages are listed under tag 0. The age is 0 if the mailbox is empty.
+//
+// Returns the number of tag pairs written.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn mailbox_digest<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    digest_ptr: u32,
+    max_tags: u32,
+) -> Result<u32> {
+    let digest = caller.data_mut().mailbox().digest();
+    let tags = &digest.tags[..digest.tags.len().min(max_tags as usize)];
+    let oldest_age = digest.oldest_age.unwrap_or_default().as_millis() as u64;
+    let mut data = Vec::with_capacity((4 + tags.len() * 2) * std::mem::size_of::<u64>());
+    data.extend_from_slice(&(digest.count as u64).to_le_bytes());
+    data.extend_from_slice(&(digest.total_bytes as u64).to_le_bytes());
+    data.extend_from_slice(&oldest_age.to_le_bytes());
+    data.extend_from_slice(&(digest.tags.len() as u64).to_le_bytes());
+    for (tag, count) in tags {
+        data.extend_from_slice(&tag.unwrap_or(0).to_le_bytes());
+        data.extend_from_slice(&(*count as u64).to_le_bytes());
+    }
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, digest_ptr as usize, &data)
+        .or_trap("lunatic::message::mailbox_digest")?;
+    Ok(tags.len() as u32)
+}
+
+// Registers a receive ring of `capacity` bytes for the process. This is a performance mode for
+// processes receiving large volumes of small messages.
+//
+// Data messages without resources and with at most `max_message_size` bytes are copied into the
+// ring instead of the mailbox queue, and can be drained in batches with
+// `lunatic::message::drain_ring`. Messages that a `receive` without tags is already waiting on are
+// still delivered to it, and the order between ring entries and queued messages is not preserved.
+// Messages from other nodes always go into the queue.
+//
+// If the ring is full and `drop_oldest` is 1, the oldest entries are overwritten. Otherwise the
+// new message is put into the regular mailbox queue instead.
+//
+// Registering a new ring moves the entries of the previous one into the queue.
+//
+// Returns:
+// * 0 on success.
+// * 1 if `max_message_size` plus the 12 byte entry header doesn't fit into `capacity`.
+fn register_ring<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    capacity: u32,
+    max_message_size: u32,
+    drop_oldest: u32,
+) -> u32 {
+    let overflow = match drop_oldest {
+        1 => RingOverflow::DropOldest,
+        _ => RingOverflow::Reject,
+    };
+    match caller.data_mut().mailbox().register_ring(
+        capacity as usize,
+        max_message_size as usize,
+        overflow,
+    ) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+// Removes the receive ring of the process and moves its entries to the end of the mailbox queue.
+fn unregister_ring<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) {
+    caller.data_mut().mailbox().unregister_ring();
+}
+
+// Moves as many whole entries from the receive ring into `buffer_ptr, buffer_len` as fit, oldest
+// first. Each entry is encoded as `[tag: i64 LE][size: u32 LE][data]`, untagged messages have the
+// tag 0. The number of entries lost since the last drain because the ring was full is written as
+// a little endian u64 to `dropped_ptr`.
+//
+// The buffer should be at least `max_message_size + 12` bytes large, otherwise no entry may fit.
+//
+// Returns the number of entries written.
+//
+// Traps:
+// * If no ring is registered.
+// * If any memory outside the guest heap space is referenced.
+fn drain_ring<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    buffer_ptr: u32,
+    buffer_len: u32,
+    dropped_ptr: u32,
+) -> Result<u32> {
+    let mailbox = caller.data_mut().mailbox().clone();
+    let memory = get_memory(&mut caller)?;
+    let buffer = memory
+        .data_mut(&mut caller)
+        .get_mut(buffer_ptr as usize..(buffer_ptr as usize + buffer_len as usize))
+        .or_trap("lunatic::message::drain_ring")?;
+    let drain = mailbox
+        .drain_ring(buffer)
+        .or_trap("lunatic::message::drain_ring::no_ring")?;
+    memory
+        .write(
+            &mut caller,
+            dropped_ptr as usize,
+            &drain.dropped.to_le_bytes(),
+        )
+        .or_trap("lunatic::message::drain_ring")?;
+    Ok(drain.messages as u32)
+}
+
+fn read_topic<T>(caller: &mut Caller<T>, topic_ptr: u32, topic_len: u32) -> Result<String> {
+    let memory = get_memory(caller)?;
+    let topic = memory
+        .data(&*caller)
+        .get(topic_ptr as usize..(topic_ptr as usize + topic_len as usize))
+        .or_trap("lunatic::message::topic")?;
+    let topic = std::str::from_utf8(topic).or_trap("lunatic::message::topic_utf8")?;
+    Ok(topic.to_string())
+}
+
+// Drops messages that the process receives more than once within `window_ms` milliseconds.
+//
+// Messages sent from other nodes with at-least-once delivery carry an id assigned by the sending
+// node, and can arrive again if the acknowledgement is late. With deduplication the duplicates
+// never reach the mailbox, so `receive` returns each of these messages only once. Other messages
+// are not affected.
+//
+// At most `max_entries` ids are remembered, if more messages arrive within the window the oldest
+// ids are forgotten early. A `window_ms` of 0 turns the deduplication off. Setting a new window
+// forgets the ids remembered so far.
+fn set_dedup_window<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    window_ms: u64,
+    max_entries: u32,
+) {
+    let mailbox = caller.data_mut().mailbox();
+    match window_ms {
+        0 => mailbox.unset_dedup(),
+        window_ms => mailbox.set_dedup(Duration::from_millis(window_ms), max_entries as usize),
+    }
+}
+
+// Returns the number of duplicates dropped since the deduplication window was set.
+fn duplicates_dropped<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u64 {
+    caller.data_mut().mailbox().duplicates_dropped()
+}
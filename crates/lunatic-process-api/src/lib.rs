@@ -10,13 +10,16 @@ use hash_map_id::HashMapId;
 use lunatic_common_api::{get_memory, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
-    config::ProcessConfig,
+    config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
     env::Environment,
+    kv::CheckpointError,
+    lifecycle::Lifecycle,
     mailbox::MessageMailbox,
     message::Message,
+    restart::{RestartPolicy, RestartType},
     runtimes::{wasmtime::WasmtimeCompiledModule, RawWasm},
     state::ProcessState,
-    DeathReason, Process, Signal, WasmProcess,
+    DeathReason, ExitReason, Process, Signal, WasmProcess,
 };
 use lunatic_wasi_api::LunaticWasiCtx;
 use wasmtime::{Caller, Linker, ResourceLimiter, Val};
@@ -31,6 +34,79 @@ pub trait ProcessConfigCtx {
     fn set_can_create_configs(&mut self, can: bool);
     fn can_spawn_processes(&self) -> bool;
     fn set_can_spawn_processes(&mut self, can: bool);
+    fn can_add_fuel(&self) -> bool;
+    fn set_can_add_fuel(&mut self, can: bool);
+    fn can_drain_node(&self) -> bool;
+    fn set_can_drain_node(&mut self, can: bool);
+}
+
+/// Effective configuration of a process, as seen by the process itself through `current_config`.
+///
+/// Environment variables and command line arguments can carry secrets and are left out, only
+/// limits and permissions are exposed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfigSnapshot {
+    pub max_memory: u64,
+    pub max_fuel: Option<u64>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub lifecycle: Lifecycle,
+    pub can_compile_modules: bool,
+    pub can_create_configs: bool,
+    pub can_spawn_processes: bool,
+    pub can_add_fuel: bool,
+    pub can_drain_node: bool,
+}
+
+impl ConfigSnapshot {
+    /// Version of the encoding, increased when fields are added.
+    pub const VERSION: u8 = 3;
+
+    pub fn of<C: ProcessConfig + ProcessConfigCtx>(config: &C) -> Self {
+        Self {
+            max_memory: config.get_max_memory() as u64,
+            max_fuel: config.get_max_fuel(),
+            restart_policy: config.get_restart_policy(),
+            lifecycle: config.get_lifecycle(),
+            can_compile_modules: config.can_compile_modules(),
+            can_create_configs: config.can_create_configs(),
+            can_spawn_processes: config.can_spawn_processes(),
+            can_add_fuel: config.can_add_fuel(),
+            can_drain_node: config.can_drain_node(),
+        }
+    }
+
+    // Encodes the snapshot as little endian values:
+    // [version: u8][max_memory: u64][max_fuel: u64][max_restarts: u32][window_ms: u64][flags: u8]
+    // [restart_type: u8][lifecycle: u8]
+    // A `max_fuel` or `max_restarts` of 0 means there is no limit or no restarts. The flags have
+    // bit 0 set if the process can compile modules, bit 1 if it can create configs, bit 2 if it
+    // can spawn processes, bit 3 if it can add fuel and bit 4 if it can drain the node. The restart
+    // type uses the codes of `config_set_restart_type` and the lifecycle the codes of
+    // `config_set_lifecycle`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![Self::VERSION];
+        data.extend(self.max_memory.to_le_bytes());
+        data.extend(self.max_fuel.unwrap_or(0).to_le_bytes());
+        let (max_restarts, window_ms) = match self.restart_policy {
+            Some(policy) => (policy.max_restarts, policy.window.as_millis() as u64),
+            None => (0, 0),
+        };
+        data.extend(max_restarts.to_le_bytes());
+        data.extend(window_ms.to_le_bytes());
+        let flags = self.can_compile_modules as u8
+            | (self.can_create_configs as u8) << 1
+            | (self.can_spawn_processes as u8) << 2
+            | (self.can_add_fuel as u8) << 3
+            | (self.can_drain_node as u8) << 4;
+        data.push(flags);
+        let restart_type = self
+            .restart_policy
+            .map(|policy| policy.restart)
+            .unwrap_or_default();
+        data.push(restart_type.code() as u8);
+        data.push(self.lifecycle.code() as u8);
+        data
+    }
 }
 
 pub trait ProcessCtx<S: ProcessState> {
@@ -39,6 +115,8 @@ pub trait ProcessCtx<S: ProcessState> {
     fn module_resources(&self) -> &ModuleResources<S>;
     fn module_resources_mut(&mut self) -> &mut ModuleResources<S>;
     fn environment(&self) -> Arc<dyn Environment>;
+    // Fuel the process added to its budget with `fuel_add`
+    fn fuel_added(&mut self) -> &mut u64;
 }
 
 // Register the process APIs to the linker
@@ -125,6 +203,26 @@ where
         "config_get_max_fuel",
         config_get_max_fuel,
     )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_restart_policy",
+        config_set_restart_policy,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_restart_type",
+        config_set_restart_type,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_lifecycle",
+        config_set_lifecycle,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_lifecycle",
+        config_get_lifecycle,
+    )?;
     linker.func_wrap(
         "lunatic::process",
         "config_can_compile_modules",
@@ -155,6 +253,26 @@ where
         "config_set_can_spawn_processes",
         config_set_can_spawn_processes,
     )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_can_add_fuel",
+        config_can_add_fuel,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_can_add_fuel",
+        config_set_can_add_fuel,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_can_drain_node",
+        config_can_drain_node,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_can_drain_node",
+        config_set_can_drain_node,
+    )?;
 
     linker.func_wrap8_async("lunatic::process", "spawn", spawn)?;
 
@@ -163,10 +281,25 @@ where
 
     linker.func_wrap("lunatic::process", "process_id", process_id)?;
     linker.func_wrap("lunatic::process", "environment_id", environment_id)?;
+    linker.func_wrap("lunatic::process", "node_memory_usage", node_memory_usage)?;
+    linker.func_wrap("lunatic::process", "scheduling_latency", scheduling_latency)?;
+    linker.func_wrap("lunatic::process", "current_config", current_config)?;
+    linker.func_wrap("lunatic::process", "process_uptime_ms", process_uptime_ms)?;
+    linker.func_wrap("lunatic::process", "process_start_time", process_start_time)?;
+    linker.func_wrap("lunatic::process", "fuel_remaining", fuel_remaining)?;
+    linker.func_wrap("lunatic::process", "fuel_add", fuel_add)?;
+    linker.func_wrap("lunatic::process", "kv_put", kv_put)?;
+    linker.func_wrap("lunatic::process", "kv_get", kv_get)?;
+    linker.func_wrap("lunatic::process", "kv_delete", kv_delete)?;
+    linker.func_wrap("lunatic::process", "kv_checkpoint", kv_checkpoint)?;
+    linker.func_wrap("lunatic::process", "boost_priority", boost_priority)?;
+    linker.func_wrap("lunatic::process", "restore_priority", restore_priority)?;
     linker.func_wrap("lunatic::process", "link", link)?;
     linker.func_wrap("lunatic::process", "unlink", unlink)?;
     linker.func_wrap("lunatic::process", "kill", kill)?;
+    linker.func_wrap("lunatic::process", "shutdown", shutdown)?;
     linker.func_wrap("lunatic::process", "exists", exists)?;
+    linker.func_wrap("lunatic::process", "exit", exit)?;
     Ok(())
 }
 
@@ -359,6 +492,119 @@ fn config_set_max_fuel<T: ProcessState + ProcessCtx<T>>(
     Ok(())
 }
 
+// Sets how often processes spawned with the configuration are restarted if they trap. A process
+// that trapped is restarted by calling its entry function again, keeping its id, mailbox and
+// resources. Once it trapped more than `max_restarts` times within `window_ms` milliseconds it's
+// not restarted anymore and fails.
+//
+// A `max_restarts` value of 0 indicates that processes are never restarted. The restart type set
+// with `config_set_restart_type` is kept.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_restart_policy<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    max_restarts: u32,
+    window_ms: u64,
+) -> Result<()> {
+    let config = caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_restart_policy: Config ID doesn't exist")?;
+    let restart = config
+        .get_restart_policy()
+        .map(|policy| policy.restart)
+        .unwrap_or_default();
+    let restart_policy = match max_restarts {
+        0 => None,
+        max_restarts => Some(RestartPolicy {
+            max_restarts,
+            window: Duration::from_millis(window_ms),
+            restart,
+        }),
+    };
+    config.set_restart_policy(restart_policy);
+    Ok(())
+}
+
+// Sets which exits of processes spawned with the configuration lead to a restart, following the
+// child restart types of OTP:
+// * 0 (permanent) Processes are restarted after every exit, including a normal one.
+// * 1 (transient) Processes are only restarted if they trapped. This is the default.
+// * 2 (temporary) Processes are never restarted.
+//
+// Killed processes are never restarted. How often processes are restarted is limited by the
+// policy set with `config_set_restart_policy`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the configuration has no restart policy.
+// * If the restart type is unknown.
+fn config_set_restart_type<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    restart_type: u32,
+) -> Result<()> {
+    let restart = RestartType::from_code(restart_type)
+        .or_trap("lunatic::process::config_set_restart_type: Unknown restart type")?;
+    let config = caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_restart_type: Config ID doesn't exist")?;
+    let policy = config
+        .get_restart_policy()
+        .or_trap("lunatic::process::config_set_restart_type: Config has no restart policy")?;
+    config.set_restart_policy(Some(RestartPolicy { restart, ..policy }));
+    Ok(())
+}
+
+// Declares how long processes spawned with the configuration are expected to run:
+// * 0 (unspecified) Nothing is known about the processes. This is the default.
+// * 1 (transient) Short lived processes, e.g. one-shot tasks.
+// * 2 (persistent) Long running processes, e.g. services.
+//
+// The hint doesn't change how the processes run, it's used to pick the node they are spawned on
+// and is visible through `current_config`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the lifecycle is unknown.
+fn config_set_lifecycle<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    lifecycle: u32,
+) -> Result<()> {
+    let lifecycle = Lifecycle::from_code(lifecycle)
+        .or_trap("lunatic::process::config_set_lifecycle: Unknown lifecycle")?;
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_lifecycle: Config ID doesn't exist")?
+        .set_lifecycle(lifecycle);
+    Ok(())
+}
+
+// Returns the lifecycle of a configuration, using the codes of `config_set_lifecycle`.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_lifecycle<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u32> {
+    let lifecycle = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_lifecycle: Config ID doesn't exist")?
+        .get_lifecycle();
+    Ok(lifecycle.code())
+}
+
 // Returns the fuel limit of a configuration.
 //
 // A value of 0 indicates no fuel limit.
@@ -493,6 +739,82 @@ where
     Ok(())
 }
 
+// Returns 1 if processes spawned from this configuration can add fuel to their own budget with
+// `fuel_add`, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_add_fuel<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_add_fuel: Config ID doesn't exist")?
+        .can_add_fuel();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to add fuel
+// to their own budget with `fuel_add`. New configurations can't add fuel.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_add_fuel<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_add_fuel: Config ID doesn't exist")?
+        .set_can_add_fuel(can != 0);
+    Ok(())
+}
+
+// Returns 1 if processes spawned from this configuration can drain the node they are running on
+// with `lunatic::distributed::drain_node`, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_can_drain_node<T>(caller: Caller<T>, config_id: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let can = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_can_drain_node: Config ID doesn't exist")?
+        .can_drain_node();
+    Ok(can as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will be able to drain
+// the node they are running on. New configurations can't drain the node.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_can_drain_node<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_can_drain_node: Config ID doesn't exist")?
+        .set_can_drain_node(can != 0);
+    Ok(())
+}
+
 // Spawns a new process using the passed in function inside a module as the entry point.
 //
 // If **link** is not 0, it will link the child and parent processes. The value of the **link**
@@ -638,13 +960,25 @@ where
 
         // set state instead of config TODO
         let env = caller.data().environment();
+        // The child holds the cancellation token of its parent, if it has one
+        let child_id = state.id();
+        env.cancel_tokens().inherit(caller.data().id(), child_id);
         let (proc_or_error_id, result) = match lunatic_process::wasm::spawn_wasm(
-            env, runtime, &module, state, function, params, link,
+            env.clone(),
+            runtime,
+            &module,
+            state,
+            function,
+            params,
+            link,
         )
         .await
         {
             Ok((_, process)) => (process.id(), 0),
-            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+            Err(error) => {
+                env.cancel_tokens().detach(child_id);
+                (caller.data_mut().error_resources_mut().add(error), 1)
+            }
         };
 
         memory
@@ -696,6 +1030,239 @@ fn environment_id<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
     caller.data().environment().id()
 }
 
+// Returns the number of bytes of memory currently used by all processes on the node
+fn node_memory_usage<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().environment().memory().used() as u64
+}
+
+// Returns the number of milliseconds since the process was spawned, measured with a monotonic
+// clock.
+fn process_uptime_ms<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().start_time().uptime().as_millis() as u64
+}
+
+// Returns the time the process was spawned at, in milliseconds since the unix epoch.
+fn process_start_time<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().start_time().unix_millis()
+}
+
+// Returns the fuel the process has left before it traps, in the units of `config_set_max_fuel`
+// rounded down. The budget is the fuel limit of the process plus the fuel it added with
+// `fuel_add`, minus the fuel it consumed so far.
+//
+// Returns u64::MAX if the process has no fuel limit.
+fn fuel_remaining<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u64 {
+    let max_fuel = match caller.data().config().get_max_fuel() {
+        Some(max_fuel) => max_fuel,
+        None => return u64::MAX,
+    };
+    let budget = max_fuel
+        .saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
+        .saturating_add(*caller.data_mut().fuel_added());
+    let consumed = caller.fuel_consumed().unwrap_or(0);
+    budget.saturating_sub(consumed) / UNIT_OF_COMPUTE_IN_INSTRUCTIONS
+}
+
+// Adds `amount` fuel, in the units of `config_set_max_fuel`, to the budget of the process. Only
+// processes spawned from a configuration that allows it can add fuel, see
+// `config_set_can_add_fuel`.
+//
+// Returns:
+// * 0 If the fuel was added, or the process has no fuel limit.
+// * 1 If the process is not allowed to add fuel.
+fn fuel_add<T>(mut caller: Caller<T>, amount: u64) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let config = caller.data().config();
+    if !config.can_add_fuel() {
+        return Ok(1);
+    }
+    if config.get_max_fuel().is_none() {
+        return Ok(0);
+    }
+    let fuel = amount.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+    caller
+        .add_fuel(fuel)
+        .or_trap("lunatic::process::fuel_add")?;
+    let added = caller.data_mut().fuel_added();
+    *added = added.saturating_add(fuel);
+    Ok(0)
+}
+
+// Writes how long the process waited to be polled by the runtime after it became ready to
+// continue, as 2 little endian u64 values in microseconds to `latency_ptr`:
+// [recent average, highest latency since the last call]
+//
+// A high latency means that the node is overloaded or that another process is blocking the
+// thread this one is scheduled on.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn scheduling_latency<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    latency_ptr: u32,
+) -> Result<()> {
+    let latency = caller.data().scheduling_latency();
+    let average = latency.average().as_micros() as u64;
+    let max = latency.take_max().as_micros() as u64;
+    let mut data = [0; 16];
+    data[..8].copy_from_slice(&average.to_le_bytes());
+    data[8..].copy_from_slice(&max.to_le_bytes());
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, latency_ptr as usize, &data)
+        .or_trap("lunatic::process::scheduling_latency")?;
+    Ok(())
+}
+
+// Stores `value` under `key` in the process-local key-value store, replacing the previous value.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn kv_put<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr + key_len) as usize)
+        .or_trap("lunatic::process::kv_put")?;
+    let value = memory_slice
+        .get(value_ptr as usize..(value_ptr + value_len) as usize)
+        .or_trap("lunatic::process::kv_put")?;
+    state.kv_mut().put(key.to_vec(), value.to_vec());
+    Ok(())
+}
+
+// Writes the value stored under `key` in the process-local key-value store to `value_ptr`, up
+// to `value_len` bytes.
+//
+// Returns:
+// * The length of the value, if it's larger than `value_len` only a part of it was written.
+// * u64::MAX if there is no value under `key`.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn kv_get<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<u64> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr + key_len) as usize)
+        .or_trap("lunatic::process::kv_get")?;
+    let value = match state.kv().get(key) {
+        Some(value) => value.to_vec(),
+        None => return Ok(u64::MAX),
+    };
+    let written = value.len().min(value_len as usize);
+    memory
+        .write(&mut caller, value_ptr as usize, &value[..written])
+        .or_trap("lunatic::process::kv_get")?;
+    Ok(value.len() as u64)
+}
+
+// Removes `key` from the process-local key-value store.
+//
+// Returns:
+// * 0 if the key was removed.
+// * 1 if there was no value under the key.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn kv_delete<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key = memory_slice
+        .get(key_ptr as usize..(key_ptr + key_len) as usize)
+        .or_trap("lunatic::process::kv_delete")?;
+    Ok(if state.kv_mut().delete(key) { 0 } else { 1 })
+}
+
+// Persists the process-local key-value store under the name the process registered itself with.
+// A process registering itself under the same name later, e.g. after the node restarted, starts
+// with the checkpointed store.
+//
+// Returns:
+// * 0 on success.
+// * 1 if the process didn't register itself under a name.
+// * 2 if the store is larger than the maximum checkpoint size.
+// * 3 if the node doesn't have a checkpoint store.
+// * 4 if writing the checkpoint failed.
+fn kv_checkpoint<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u32 {
+    let state = caller.data();
+    let checkpoints = match state.kv_checkpoints() {
+        Some(checkpoints) => checkpoints,
+        None => return 3,
+    };
+    match checkpoints.save(state.environment().id(), state.kv()) {
+        Ok(()) => 0,
+        Err(CheckpointError::Unnamed) => 1,
+        Err(CheckpointError::TooLarge(_)) => 2,
+        Err(CheckpointError::Io(_)) => 4,
+    }
+}
+
+// Raises the priority of all messages the process sends by one level, until `restore_priority`
+// is called. Boosts nest, and are capped at 2 levels (a low priority message becomes a high
+// priority one). Boosts end when the process exits or is restarted.
+//
+// Returns the number of levels the priority is raised by after the boost.
+fn boost_priority<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u32 {
+    caller.data_mut().priority_boost_mut().boost()
+}
+
+// Undoes the last `boost_priority` call.
+//
+// Returns the number of levels the priority is still raised by, 0 once all boosts are undone.
+//
+// Traps:
+// * If the priority isn't boosted.
+fn restore_priority<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u32> {
+    caller
+        .data_mut()
+        .priority_boost_mut()
+        .restore()
+        .or_trap("lunatic::process::restore_priority")
+}
+
+// Writes the effective configuration of the current process to `config_ptr`, up to `config_len`
+// bytes. The encoding is versioned and described on `ConfigSnapshot::encode`, secrets like
+// environment variables are never included.
+//
+// Returns the length of the encoded configuration, if it's larger than `config_len` only a part
+// of it was written.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn current_config<T>(mut caller: Caller<T>, config_ptr: u32, config_len: u32) -> Result<u32>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let config = ConfigSnapshot::of(caller.data().config().as_ref()).encode();
+    let written = config.len().min(config_len as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, config_ptr as usize, &config[..written])
+        .or_trap("lunatic::process::current_config")?;
+    Ok(config.len() as u32)
+}
+
 // Link current process to **process_id**. This is not an atomic operation, any of the 2 processes
 // could fail before processing the `Link` signal and may not notify the other.
 //
@@ -781,6 +1348,19 @@ fn kill<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) ->
     Ok(())
 }
 
+// Asks **process_id** to shut down. The process receives an empty message tagged with
+// `i64::MIN` and is killed if it doesn't finish within **grace_period_ms** milliseconds.
+fn shutdown<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    process_id: u64,
+    grace_period_ms: u64,
+) -> Result<()> {
+    if let Some(process) = caller.data().environment().get_process(process_id) {
+        process.send(Signal::Shutdown(Duration::from_millis(grace_period_ms)));
+    }
+    Ok(())
+}
+
 // Checks to see if a process exists
 fn exists<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> i32 {
     caller
@@ -789,3 +1369,139 @@ fn exists<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -
         .get_process(process_id)
         .is_some() as i32
 }
+
+// Finishes the current process cleanly with the reason `code` and the detail
+// `detail_ptr, detail_len`, instead of returning from the entry function.
+//
+// The process finishes like after a return: it's removed from its environment and its resources
+// are released, linked processes don't die, and a restart policy treats it as a normal finish.
+// Processes waiting for its exit get the reason code 3, followed by `code` as a little endian u32
+// and the detail.
+//
+// Traps:
+// * Always, to stop the process.
+// * If any memory outside the guest heap space is referenced.
+fn exit<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    code: u32,
+    detail_ptr: u32,
+    detail_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let detail = memory
+        .data(&caller)
+        .get(detail_ptr as usize..(detail_ptr as usize + detail_len as usize))
+        .or_trap("lunatic::process::exit")?
+        .to_vec();
+    Err(ExitReason { code, detail }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lunatic_process::{
+        config::ProcessConfig,
+        lifecycle::Lifecycle,
+        restart::{RestartPolicy, RestartType},
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{ConfigSnapshot, ProcessConfigCtx};
+
+    #[derive(Clone, Default, Serialize, Deserialize)]
+    struct Config {
+        max_memory: usize,
+        max_fuel: Option<u64>,
+        restart_policy: Option<RestartPolicy>,
+        lifecycle: Lifecycle,
+        can_spawn_processes: bool,
+        can_add_fuel: bool,
+        environment_variables: Vec<(String, String)>,
+    }
+
+    impl ProcessConfig for Config {
+        fn set_max_fuel(&mut self, max_fuel: Option<u64>) {
+            self.max_fuel = max_fuel;
+        }
+        fn get_max_fuel(&self) -> Option<u64> {
+            self.max_fuel
+        }
+        fn set_max_memory(&mut self, max_memory: usize) {
+            self.max_memory = max_memory;
+        }
+        fn get_max_memory(&self) -> usize {
+            self.max_memory
+        }
+        fn set_restart_policy(&mut self, restart_policy: Option<RestartPolicy>) {
+            self.restart_policy = restart_policy;
+        }
+        fn get_restart_policy(&self) -> Option<RestartPolicy> {
+            self.restart_policy
+        }
+        fn set_lifecycle(&mut self, lifecycle: Lifecycle) {
+            self.lifecycle = lifecycle;
+        }
+        fn get_lifecycle(&self) -> Lifecycle {
+            self.lifecycle
+        }
+    }
+
+    impl ProcessConfigCtx for Config {
+        fn can_compile_modules(&self) -> bool {
+            false
+        }
+        fn set_can_compile_modules(&mut self, _can: bool) {}
+        fn can_create_configs(&self) -> bool {
+            false
+        }
+        fn set_can_create_configs(&mut self, _can: bool) {}
+        fn can_spawn_processes(&self) -> bool {
+            self.can_spawn_processes
+        }
+        fn set_can_spawn_processes(&mut self, can: bool) {
+            self.can_spawn_processes = can;
+        }
+        fn can_add_fuel(&self) -> bool {
+            self.can_add_fuel
+        }
+        fn set_can_add_fuel(&mut self, can: bool) {
+            self.can_add_fuel = can;
+        }
+        fn can_drain_node(&self) -> bool {
+            false
+        }
+        fn set_can_drain_node(&mut self, _can: bool) {}
+    }
+
+    #[test]
+    fn current_config_exposes_limits_and_permissions() {
+        let mut config = Config::default();
+        config.set_max_memory(1024 * 1024);
+        config.set_max_fuel(Some(7));
+        config.set_restart_policy(Some(RestartPolicy {
+            max_restarts: 3,
+            window: Duration::from_secs(2),
+            restart: RestartType::Permanent,
+        }));
+        config.set_can_spawn_processes(true);
+        config.set_can_add_fuel(true);
+        config.set_lifecycle(Lifecycle::Persistent);
+        config
+            .environment_variables
+            .push(("TOKEN".to_string(), "secret".to_string()));
+
+        let data = ConfigSnapshot::of(&config).encode();
+        assert_eq!(data[0], ConfigSnapshot::VERSION);
+        assert_eq!(&data[1..9], &(1024 * 1024u64).to_le_bytes());
+        assert_eq!(&data[9..17], &7u64.to_le_bytes());
+        assert_eq!(&data[17..21], &3u32.to_le_bytes());
+        assert_eq!(&data[21..29], &2000u64.to_le_bytes());
+        assert_eq!(data[29], 0b1100);
+        assert_eq!(data[30], 0);
+        assert_eq!(data[31], 2);
+        assert_eq!(data.len(), 32);
+        // Secrets never end up in guest memory
+        assert!(!data.windows(6).any(|window| window == b"secret"));
+    }
+}
@@ -0,0 +1,150 @@
+/*!
+Cancellation tokens shared by a tree of processes.
+
+A process opts in by creating a token. Processes it spawns afterwards, locally or on other nodes,
+hold the same token, and so do the processes they spawn. Cancelling the token kills all holders.
+Each environment only knows the holders running in it, so a cancellation has to reach every node
+the tree spread to. A process stops holding its token when it's removed from the environment.
+*/
+
+use std::collections::HashSet;
+
+use dashmap::DashMap;
+
+use crate::{env::Environment, Signal};
+
+#[derive(Debug, Default)]
+pub struct CancelTokens {
+    // Token -> IDs of processes holding it
+    holders: DashMap<u64, HashSet<u64>>,
+    // Process ID -> token
+    tokens: DashMap<u64, u64>,
+}
+
+impl CancelTokens {
+    /// Makes the process a holder of `token`, replacing its previous token.
+    pub fn attach(&self, token: u64, process_id: u64) {
+        self.detach(process_id);
+        self.tokens.insert(process_id, token);
+        self.holders.entry(token).or_default().insert(process_id);
+    }
+
+    /// Makes the child hold the token of its parent, if the parent holds one.
+    pub fn inherit(&self, parent_id: u64, child_id: u64) {
+        if let Some(token) = self.token(parent_id) {
+            self.attach(token, child_id);
+        }
+    }
+
+    pub fn detach(&self, process_id: u64) {
+        if let Some((_, token)) = self.tokens.remove(&process_id) {
+            if let Some(mut holders) = self.holders.get_mut(&token) {
+                holders.remove(&process_id);
+            }
+            self.holders.remove_if(&token, |_, holders| holders.is_empty());
+        }
+    }
+
+    pub fn token(&self, process_id: u64) -> Option<u64> {
+        self.tokens.get(&process_id).map(|token| *token)
+    }
+
+    pub fn holders(&self, token: u64) -> Vec<u64> {
+        self.holders
+            .get(&token)
+            .map(|holders| holders.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Kills every process in the environment that holds `token` and returns how many were killed.
+pub fn cancel(env: &dyn Environment, token: u64) -> usize {
+    let mut killed = 0;
+    for process_id in env.cancel_tokens().holders(token) {
+        if let Some(process) = env.get_process(process_id) {
+            process.send(Signal::Kill);
+            killed += 1;
+        }
+    }
+    killed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::cancel;
+    use crate::{
+        env::{Environment, LunaticEnvironment},
+        Process, Signal,
+    };
+
+    #[derive(Default)]
+    struct Killable {
+        id: u64,
+        killed: AtomicBool,
+    }
+
+    impl Process for Killable {
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn send(&self, signal: Signal) {
+            if let Signal::Kill = signal {
+                self.killed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn killable(env: &LunaticEnvironment, id: u64) -> Arc<Killable> {
+        let process = Arc::new(Killable {
+            id,
+            ..Default::default()
+        });
+        env.add_process(id, process.clone());
+        process
+    }
+
+    #[test]
+    fn cancelling_token_kills_tree_on_all_nodes() {
+        // The same environment on two nodes
+        let first_node = LunaticEnvironment::new(1);
+        let second_node = LunaticEnvironment::new(1);
+        let root = killable(&first_node, 1);
+        let child = killable(&first_node, 2);
+        let remote_child = killable(&second_node, 1);
+        let grandchild = killable(&second_node, 2);
+        let unrelated = killable(&first_node, 3);
+
+        first_node.cancel_tokens().attach(42, 1);
+        first_node.cancel_tokens().inherit(1, 2);
+        // Spawns on other nodes carry the token of the parent
+        let token = first_node.cancel_tokens().token(2).unwrap();
+        second_node.cancel_tokens().attach(token, 1);
+        second_node.cancel_tokens().inherit(1, 2);
+        // Processes without a token don't pass one on
+        first_node.cancel_tokens().inherit(3, 4);
+
+        assert_eq!(cancel(&first_node, 42), 2);
+        assert_eq!(cancel(&second_node, 42), 2);
+        for process in [&root, &child, &remote_child, &grandchild] {
+            assert!(process.killed.load(Ordering::SeqCst));
+        }
+        assert!(!unrelated.killed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn removed_processes_stop_holding_token() {
+        let env = LunaticEnvironment::new(1);
+        killable(&env, 1);
+        env.cancel_tokens().attach(42, 1);
+        env.remove_process(1);
+        assert!(env.cancel_tokens().holders(42).is_empty());
+        assert_eq!(env.cancel_tokens().token(1), None);
+        assert_eq!(cancel(&env, 42), 0);
+    }
+}
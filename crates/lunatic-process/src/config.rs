@@ -1,5 +1,7 @@
 use serde::{de::DeserializeOwned, Serialize};
 
+use crate::{lifecycle::Lifecycle, restart::RestartPolicy};
+
 // One unit of fuel represents around 100k instructions.
 pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 
@@ -12,8 +14,8 @@ pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 /// the process. This host functions are the ones that consider specific configuration while
 /// performing operations.
 ///
-/// However, two properties of a process are enforced by the runtime (maximum memory and maximum
-/// fuel usage). This two properties need to be part of every configuration.
+/// However, some properties of a process are enforced by the runtime (maximum memory, maximum
+/// fuel usage and restarts after traps). These properties need to be part of every configuration.
 ///
 /// `ProcessConfig` must be serializable in case it is used to spawn processes on other nodes.
 pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
@@ -21,4 +23,8 @@ pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
     fn get_max_fuel(&self) -> Option<u64>;
     fn set_max_memory(&mut self, max_memory: usize);
     fn get_max_memory(&self) -> usize;
+    fn set_restart_policy(&mut self, restart_policy: Option<RestartPolicy>);
+    fn get_restart_policy(&self) -> Option<RestartPolicy>;
+    fn set_lifecycle(&mut self, lifecycle: Lifecycle);
+    fn get_lifecycle(&self) -> Lifecycle;
 }
@@ -0,0 +1,143 @@
+/*!
+Receiver side deduplication of messages, an opt-in of each process.
+
+Messages sent with at-least-once delivery are sent again until the receiver acknowledges them, so
+a receiver can get the same message more than once. Such messages carry an id assigned by the
+sending node. A [`MessageDedup`] remembers the ids it has seen within its window and drops
+messages that arrive again with the same sending node and id, before they reach the queue of the
+mailbox.
+
+Memory is bounded by the number of remembered ids. If more ids are seen within the window, the
+oldest ones are forgotten early and a late duplicate of them is not detected anymore. Messages
+without an id are never dropped.
+*/
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::message::Message;
+
+// Node id of the sender and the id it assigned to the message
+type DedupKey = (u64, u64);
+
+pub struct MessageDedup {
+    window: Duration,
+    max_entries: usize,
+    // Keys in the order they were seen
+    seen: VecDeque<(Instant, DedupKey)>,
+    keys: HashSet<DedupKey>,
+    dropped: u64,
+}
+
+impl MessageDedup {
+    /// Creates a deduplication that remembers ids for `window`, but at most `max_entries` of
+    /// them.
+    pub fn new(window: Duration, max_entries: usize) -> Self {
+        Self {
+            window,
+            max_entries,
+            seen: VecDeque::new(),
+            keys: HashSet::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns false if the message is a duplicate of a message seen within the window.
+    pub fn admit(&mut self, message: &Message) -> bool {
+        let key = match message {
+            Message::Data(message) => match (&message.sender, message.unique_id) {
+                (Some(sender), Some(unique_id)) => (sender.node_id, unique_id),
+                _ => return true,
+            },
+            Message::LinkDied(_) => return true,
+        };
+        let now = Instant::now();
+        self.forget(now);
+        if self.keys.contains(&key) {
+            self.dropped += 1;
+            return false;
+        }
+        if self.max_entries == 0 {
+            return true;
+        }
+        if self.seen.len() == self.max_entries {
+            if let Some((_, oldest)) = self.seen.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.seen.push_back((now, key));
+        self.keys.insert(key);
+        true
+    }
+
+    /// Returns the number of duplicates dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Returns the number of remembered ids.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    // Forgets the ids that were seen before the window
+    fn forget(&mut self, now: Instant) {
+        while let Some((seen_at, key)) = self.seen.front() {
+            if now.duration_since(*seen_at) < self.window {
+                break;
+            }
+            self.keys.remove(key);
+            self.seen.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::MessageDedup;
+    use crate::message::{DataMessage, Message, MessageSender};
+
+    fn message(node_id: u64, unique_id: Option<u64>) -> Message {
+        let mut message = DataMessage::new_from_vec(None, vec![1]);
+        message.sender = Some(MessageSender {
+            node_id,
+            process_id: 1,
+        });
+        message.unique_id = unique_id;
+        Message::Data(message)
+    }
+
+    #[test]
+    fn remembered_ids_are_bounded() {
+        let mut dedup = MessageDedup::new(Duration::from_secs(60), 2);
+        assert!(dedup.admit(&message(1, Some(1))));
+        assert!(!dedup.admit(&message(1, Some(1))));
+        // The same id from another node is a different message
+        assert!(dedup.admit(&message(2, Some(1))));
+        // Messages without an id are never dropped
+        assert!(dedup.admit(&message(1, None)));
+        assert!(dedup.admit(&message(1, None)));
+
+        // The first id is forgotten to make room
+        assert!(dedup.admit(&message(1, Some(2))));
+        assert_eq!(dedup.len(), 2);
+        assert!(dedup.admit(&message(1, Some(1))));
+        assert_eq!(dedup.dropped(), 1);
+    }
+
+    #[test]
+    fn ids_are_forgotten_after_the_window() {
+        let mut dedup = MessageDedup::new(Duration::ZERO, 10);
+        assert!(dedup.admit(&message(1, Some(1))));
+        assert!(dedup.admit(&message(1, Some(1))));
+        assert_eq!(dedup.len(), 1);
+    }
+}
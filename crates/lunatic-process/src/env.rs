@@ -1,25 +1,65 @@
-use dashmap::DashMap;
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 
-use crate::{Process, Signal};
+use crate::{
+    cancel::CancelTokens, interceptor::Interceptors, labels::ProcessLabels, memory::NodeMemory,
+    topics::Topics, Process, Signal,
+};
 
 pub trait Environment: Send + Sync {
     fn id(&self) -> u64;
     fn get_next_process_id(&self) -> u64;
     fn get_process(&self, id: u64) -> Option<Arc<dyn Process>>;
-    fn add_process(&self, id: u64, proc: Arc<dyn Process>);
+    // Adds the process to the environment and returns `true`, unless the environment was
+    // destroyed. A process that was added is killed if the environment is destroyed later.
+    fn add_process(&self, id: u64, proc: Arc<dyn Process>) -> bool;
     fn remove_process(&self, id: u64);
     fn process_count(&self) -> usize;
     fn send(&self, id: u64, signal: Signal);
+    // Memory used by all processes on the node
+    fn memory(&self) -> &Arc<NodeMemory>;
+    // Interceptors applied to every data message delivered to processes on the node
+    fn interceptors(&self) -> &Interceptors;
+    // Topics that processes in the environment can subscribe to
+    fn topics(&self) -> &Topics;
+    // Labels of processes in the environment
+    fn labels(&self) -> &ProcessLabels;
+    // Cancellation tokens held by processes in the environment
+    fn cancel_tokens(&self) -> &CancelTokens;
+    // Number of queued messages past which processes warn about their mailbox growing
+    fn mailbox_high_water_mark(&self) -> Option<usize>;
+    // Kills all processes in the environment and rejects processes added afterwards
+    fn destroy(&self);
+    fn is_destroyed(&self) -> bool;
+    // Asks all processes in the environment to finish, they are killed if they are still running
+    // after `grace_period`
+    fn shutdown(&self, grace_period: Duration);
 }
 
 pub trait Environments: Send + Sync {
     type Env: Environment;
     fn create(&self, id: u64) -> Arc<Self::Env>;
     fn get(&self, id: u64) -> Option<Arc<Self::Env>>;
+    // Returns the environment with `id`, creating it if it doesn't exist yet. Concurrent calls
+    // with the same `id` are guaranteed to return the same environment.
+    //
+    // If the environment with `id` was destroyed, the returned environment is destroyed too.
+    fn get_or_create(&self, id: u64) -> Arc<Self::Env>;
+    // Destroys the environment with `id`. Destroyed environments are never created again, so
+    // spawns racing with the destruction either end up killed or fail.
+    fn destroy(&self, id: u64);
+    // Asks all processes in all environments to finish within `grace_period`
+    fn shutdown(&self, grace_period: Duration);
+    // Number of processes in all environments
+    fn process_count(&self) -> usize;
+    // Memory used by all processes on the node
+    fn memory(&self) -> &Arc<NodeMemory>;
 }
 
 #[derive(Clone)]
@@ -27,14 +67,35 @@ pub struct LunaticEnvironment {
     environment_id: u64,
     next_process_id: Arc<AtomicU64>,
     processes: Arc<DashMap<u64, Arc<dyn Process>>>,
+    memory: Arc<NodeMemory>,
+    interceptors: Interceptors,
+    topics: Arc<Topics>,
+    labels: Arc<ProcessLabels>,
+    cancel_tokens: Arc<CancelTokens>,
+    mailbox_high_water_mark: Option<usize>,
+    // Held for writing while the environment is destroyed, so that no process can be added in
+    // the middle of it
+    destroyed: Arc<RwLock<bool>>,
 }
 
 impl LunaticEnvironment {
     pub fn new(id: u64) -> Self {
+        Self::with_memory(id, Arc::new(NodeMemory::default()))
+    }
+
+    /// Creates an environment that shares the memory accounting with other environments.
+    pub fn with_memory(id: u64, memory: Arc<NodeMemory>) -> Self {
         Self {
             environment_id: id,
             processes: Arc::new(DashMap::new()),
             next_process_id: Arc::new(AtomicU64::new(1)),
+            memory,
+            interceptors: Interceptors::default(),
+            topics: Arc::new(Topics::default()),
+            labels: Arc::new(ProcessLabels::default()),
+            cancel_tokens: Arc::new(CancelTokens::default()),
+            mailbox_high_water_mark: None,
+            destroyed: Arc::new(RwLock::new(false)),
         }
     }
 }
@@ -44,8 +105,13 @@ impl Environment for LunaticEnvironment {
         self.processes.get(&id).map(|x| x.clone())
     }
 
-    fn add_process(&self, id: u64, proc: Arc<dyn Process>) {
+    fn add_process(&self, id: u64, proc: Arc<dyn Process>) -> bool {
+        let destroyed = self.destroyed.read().unwrap();
+        if *destroyed {
+            return false;
+        }
         self.processes.insert(id, proc);
+        drop(destroyed);
         #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
         let labels: [(String, String); 0] = [];
         #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
@@ -56,10 +122,14 @@ impl Environment for LunaticEnvironment {
             self.processes.len() as f64,
             &labels
         );
+        true
     }
 
     fn remove_process(&self, id: u64) {
         self.processes.remove(&id);
+        self.topics.unsubscribe_all(id);
+        self.labels.remove(id);
+        self.cancel_tokens.detach(id);
         #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
         let labels: [(String, String); 0] = [];
         #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
@@ -89,17 +159,102 @@ impl Environment for LunaticEnvironment {
     fn id(&self) -> u64 {
         self.environment_id
     }
+
+    fn memory(&self) -> &Arc<NodeMemory> {
+        &self.memory
+    }
+
+    fn interceptors(&self) -> &Interceptors {
+        &self.interceptors
+    }
+
+    fn topics(&self) -> &Topics {
+        &self.topics
+    }
+
+    fn labels(&self) -> &ProcessLabels {
+        &self.labels
+    }
+
+    fn cancel_tokens(&self) -> &CancelTokens {
+        &self.cancel_tokens
+    }
+
+    fn mailbox_high_water_mark(&self) -> Option<usize> {
+        self.mailbox_high_water_mark
+    }
+
+    fn destroy(&self) {
+        let mut destroyed = self.destroyed.write().unwrap();
+        if *destroyed {
+            return;
+        }
+        *destroyed = true;
+        drop(destroyed);
+        // Killed processes remove themselves once they finish
+        for process in self.processes.iter() {
+            process.send(Signal::Kill);
+        }
+    }
+
+    fn is_destroyed(&self) -> bool {
+        *self.destroyed.read().unwrap()
+    }
+
+    fn shutdown(&self, grace_period: Duration) {
+        for process in self.processes.iter() {
+            process.send(Signal::Shutdown(grace_period));
+        }
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct LunaticEnvironments {
     envs: Arc<DashMap<u64, Arc<LunaticEnvironment>>>,
+    // IDs of destroyed environments
+    destroyed: Arc<DashSet<u64>>,
+    memory: Arc<NodeMemory>,
+    interceptors: Interceptors,
+    mailbox_high_water_mark: Option<usize>,
+}
+
+impl LunaticEnvironments {
+    /// All environments created from here will share the same memory limit.
+    pub fn with_memory_limit(limit: usize) -> Self {
+        Self {
+            envs: Default::default(),
+            destroyed: Default::default(),
+            memory: Arc::new(NodeMemory::new(Some(limit))),
+            interceptors: Interceptors::default(),
+            mailbox_high_water_mark: None,
+        }
+    }
+
+    /// Applies `interceptors` to all messages delivered inside of environments created from here.
+    pub fn with_interceptors(mut self, interceptors: Interceptors) -> Self {
+        self.interceptors = interceptors;
+        self
+    }
+
+    /// Processes in environments created from here warn when more than `mark` messages are
+    /// queued in their mailbox.
+    pub fn with_mailbox_high_water_mark(mut self, mark: usize) -> Self {
+        self.mailbox_high_water_mark = Some(mark);
+        self
+    }
+
+    fn new_env(&self, id: u64) -> LunaticEnvironment {
+        let mut env = LunaticEnvironment::with_memory(id, self.memory.clone());
+        env.interceptors = self.interceptors.clone();
+        env.mailbox_high_water_mark = self.mailbox_high_water_mark;
+        env
+    }
 }
 
 impl Environments for LunaticEnvironments {
     type Env = LunaticEnvironment;
     fn create(&self, id: u64) -> Arc<Self::Env> {
-        let env = Arc::new(LunaticEnvironment::new(id));
+        let env = Arc::new(self.new_env(id));
         self.envs.insert(id, env.clone());
         #[cfg(feature = "metrics")]
         metrics::gauge!("lunatic.process.environment.count", self.envs.len() as f64);
@@ -108,4 +263,140 @@ impl Environments for LunaticEnvironments {
     fn get(&self, id: u64) -> Option<Arc<Self::Env>> {
         self.envs.get(&id).map(|e| e.clone())
     }
+    fn get_or_create(&self, id: u64) -> Arc<Self::Env> {
+        // `destroy` marks the ID as destroyed while holding the entry, so it can't be destroyed
+        // between the check and the insert.
+        let env = match self.envs.entry(id) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                let env = Arc::new(self.new_env(id));
+                if self.destroyed.contains(&id) {
+                    env.destroy();
+                    return env;
+                }
+                entry.insert(env.clone());
+                env
+            }
+        };
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("lunatic.process.environment.count", self.envs.len() as f64);
+        env
+    }
+    fn destroy(&self, id: u64) {
+        let env = match self.envs.entry(id) {
+            Entry::Occupied(entry) => {
+                self.destroyed.insert(id);
+                Some(entry.remove())
+            }
+            Entry::Vacant(_) => {
+                self.destroyed.insert(id);
+                None
+            }
+        };
+        if let Some(env) = env {
+            env.destroy();
+        }
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("lunatic.process.environment.count", self.envs.len() as f64);
+    }
+    fn shutdown(&self, grace_period: Duration) {
+        for env in self.envs.iter() {
+            env.shutdown(grace_period);
+        }
+    }
+    fn process_count(&self) -> usize {
+        self.envs.iter().map(|env| env.process_count()).sum()
+    }
+    fn memory(&self) -> &Arc<NodeMemory> {
+        &self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Environment, Environments, LunaticEnvironments};
+    use crate::{spawn, Process, SHUTDOWN_TAG};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn get_or_create_returns_existing_environment() {
+        let envs = LunaticEnvironments::default();
+        let first = envs.get_or_create(1);
+        let second = envs.get_or_create(1);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&first, &envs.get(1).unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn spawns_racing_with_destroy_are_killed_or_rejected() {
+        let envs = LunaticEnvironments::default();
+        let spawns: Vec<_> = (0..200)
+            .map(|_| {
+                let envs = envs.clone();
+                tokio::spawn(async move {
+                    let env = envs.get_or_create(1);
+                    let (handle, process) = spawn(env.clone(), |_this, mailbox| async move {
+                        // Runs until killed
+                        mailbox.pop(None).await;
+                        Ok::<_, anyhow::Error>(())
+                    });
+                    if !env.add_process(process.id(), Arc::new(process.clone())) {
+                        // A failed spawn doesn't leave anything running
+                        process.send(crate::Signal::Kill);
+                    }
+                    (env, handle)
+                })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        envs.destroy(1);
+
+        let mut spawned_into = Vec::new();
+        for spawned in spawns {
+            let (env, handle) = spawned.await.unwrap();
+            assert!(env.is_destroyed());
+            // Every process was either killed by the destroy or never added
+            tokio::time::timeout(Duration::from_secs(5), handle)
+                .await
+                .expect("process leaked")
+                .unwrap()
+                .unwrap_err();
+            spawned_into.push(env);
+        }
+        assert!(spawned_into.iter().all(|env| env.process_count() == 0));
+        // The environment is not recreated
+        assert!(envs.get(1).is_none());
+        let env = envs.get_or_create(1);
+        assert!(env.is_destroyed());
+        let (_, process) = spawn(env.clone(), |_this, _mailbox| async move {
+            Ok::<_, anyhow::Error>(())
+        });
+        assert!(!env.add_process(process.id(), Arc::new(process)));
+        assert!(envs.get(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_reaches_processes_in_all_environments() {
+        let envs = LunaticEnvironments::default();
+        let mut handles = Vec::new();
+        for id in [1, 2] {
+            let env = envs.create(id);
+            let (handle, process) = spawn(env.clone(), |_this, mailbox| async move {
+                mailbox.pop(Some(&[SHUTDOWN_TAG])).await;
+                Ok::<_, anyhow::Error>(())
+            });
+            env.add_process(process.id(), Arc::new(process));
+            handles.push(handle);
+        }
+
+        envs.shutdown(Duration::from_secs(60));
+        for handle in handles {
+            // Finished on its own, instead of being killed
+            tokio::time::timeout(Duration::from_secs(5), handle)
+                .await
+                .expect("process didn't shut down")
+                .unwrap()
+                .unwrap();
+        }
+    }
 }
@@ -0,0 +1,137 @@
+/*!
+Interceptors observe or modify [`DataMessage`]s before they are delivered to a process.
+
+A chain of interceptors is configured once per node (see
+[`LunaticEnvironments::with_interceptors`](crate::env::LunaticEnvironments::with_interceptors))
+and is applied to messages sent between local processes and to messages arriving from other
+nodes. Each message passes through the chain exactly once, on the node that delivers it.
+*/
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::message::DataMessage;
+
+/// What should happen with a message after an interceptor has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intercepted {
+    /// Pass the (possibly modified) message on to the next interceptor.
+    Pass,
+    /// Drop the message, it will not be delivered.
+    Drop,
+}
+
+pub trait MessageInterceptor: Send + Sync {
+    fn intercept(&self, message: &mut DataMessage) -> Result<Intercepted>;
+}
+
+/// An ordered chain of [`MessageInterceptor`]s.
+#[derive(Clone, Default)]
+pub struct Interceptors {
+    chain: Arc<Vec<Arc<dyn MessageInterceptor>>>,
+}
+
+impl Interceptors {
+    pub fn new(chain: Vec<Arc<dyn MessageInterceptor>>) -> Self {
+        Self {
+            chain: Arc::new(chain),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    /// Runs the message through all interceptors and returns it, or `None` if it was dropped.
+    ///
+    /// A failing interceptor doesn't stop the delivery, the error is logged and the message is
+    /// passed on to the next interceptor as the failing one left it.
+    pub fn apply(&self, mut message: DataMessage) -> Option<DataMessage> {
+        for interceptor in self.chain.iter() {
+            match interceptor.intercept(&mut message) {
+                Ok(Intercepted::Pass) => {}
+                Ok(Intercepted::Drop) => return None,
+                Err(error) => warn!("Message interceptor failed: {error:?}"),
+            }
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, sync::Arc};
+
+    use anyhow::{anyhow, Result};
+
+    use super::{Intercepted, Interceptors, MessageInterceptor};
+    use crate::message::DataMessage;
+
+    struct Tag(i64);
+
+    impl MessageInterceptor for Tag {
+        fn intercept(&self, message: &mut DataMessage) -> Result<Intercepted> {
+            message.tag = Some(self.0);
+            Ok(Intercepted::Pass)
+        }
+    }
+
+    struct DropIf(fn(&DataMessage) -> bool);
+
+    impl MessageInterceptor for DropIf {
+        fn intercept(&self, message: &mut DataMessage) -> Result<Intercepted> {
+            if (self.0)(message) {
+                Ok(Intercepted::Drop)
+            } else {
+                Ok(Intercepted::Pass)
+            }
+        }
+    }
+
+    struct Fail;
+
+    impl MessageInterceptor for Fail {
+        fn intercept(&self, _message: &mut DataMessage) -> Result<Intercepted> {
+            Err(anyhow!("failed"))
+        }
+    }
+
+    fn message(data: &[u8]) -> DataMessage {
+        DataMessage::new_from_vec(None, data.to_vec())
+    }
+
+    #[test]
+    fn tags_every_message() {
+        let chain: Vec<Arc<dyn MessageInterceptor>> = vec![Arc::new(Tag(42))];
+        let interceptors = Interceptors::new(chain);
+        for data in [&b"a"[..], b"b"] {
+            let message = interceptors.apply(message(data)).unwrap();
+            assert_eq!(message.tag, Some(42));
+        }
+    }
+
+    #[test]
+    fn drops_matching_messages() {
+        let chain: Vec<Arc<dyn MessageInterceptor>> = vec![
+            Arc::new(DropIf(|message| message.buffer == b"drop")),
+            Arc::new(Tag(1)),
+        ];
+        let interceptors = Interceptors::new(chain);
+        assert!(interceptors.apply(message(b"drop")).is_none());
+        let mut kept = interceptors.apply(message(b"keep")).unwrap();
+        assert_eq!(kept.tag, Some(1));
+        let mut data = Vec::new();
+        kept.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"keep");
+    }
+
+    #[test]
+    fn failing_interceptor_passes_message_on() {
+        let chain: Vec<Arc<dyn MessageInterceptor>> = vec![Arc::new(Fail), Arc::new(Tag(7))];
+        let interceptors = Interceptors::new(chain);
+        let message = interceptors.apply(message(b"a")).unwrap();
+        assert_eq!(message.tag, Some(7));
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Key-value map that is local to a process.
+///
+/// The map lives as long as the process and survives restarts. It can be checkpointed to
+/// [`KvCheckpoints`] under the name the process registered itself with, and is restored from the
+/// checkpoint when a process with the same name registers again.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KvStore {
+    // Name the process registered itself under, used as the checkpoint key
+    name: Option<String>,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvStore {
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(|value| value.as_slice())
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.entries.insert(key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = Some(name);
+    }
+
+    // Every key and value is prefixed with its length as a little endian u64.
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for (key, value) in self.entries.iter() {
+            buffer.extend((key.len() as u64).to_le_bytes());
+            buffer.extend(key);
+            buffer.extend((value.len() as u64).to_le_bytes());
+            buffer.extend(value);
+        }
+        buffer
+    }
+
+    fn decode(mut buffer: &[u8]) -> Option<BTreeMap<Vec<u8>, Vec<u8>>> {
+        fn take(buffer: &mut &[u8]) -> Option<Vec<u8>> {
+            let len = u64::from_le_bytes(buffer.get(..8)?.try_into().ok()?) as usize;
+            let data = buffer.get(8..8usize.checked_add(len)?)?.to_vec();
+            *buffer = &buffer[8 + len..];
+            Some(data)
+        }
+
+        let mut entries = BTreeMap::new();
+        while !buffer.is_empty() {
+            let key = take(&mut buffer)?;
+            let value = take(&mut buffer)?;
+            entries.insert(key, value);
+        }
+        Some(entries)
+    }
+}
+
+/// Durable store for [`KvStore`] checkpoints.
+///
+/// Each checkpoint is a file in `dir/<environment id>` named after the hex encoded process name,
+/// so processes registered under the same name in different environments don't share a
+/// checkpoint. Checkpoints are written to a temporary file first and then renamed, so a crash never leaves a partially
+/// written checkpoint behind.
+#[derive(Debug, Clone)]
+pub struct KvCheckpoints {
+    dir: PathBuf,
+    max_size: usize,
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    /// The process didn't register itself under a name.
+    Unnamed,
+    /// The encoded store is larger than the maximum checkpoint size.
+    TooLarge(usize),
+    Io(io::Error),
+}
+
+impl Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckpointError::Unnamed => write!(f, "Process is not registered under a name"),
+            CheckpointError::TooLarge(size) => {
+                write!(f, "Checkpoint of {size} bytes exceeds the maximum size")
+            }
+            CheckpointError::Io(err) => write!(f, "Failed to write checkpoint: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl KvCheckpoints {
+    /// Default maximum size of a single checkpoint (1 MiB).
+    pub const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+    pub fn new(dir: PathBuf, max_size: usize) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_size })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Persists `store` under its name in the environment, replacing the previous checkpoint.
+    pub fn save(&self, environment_id: u64, store: &KvStore) -> Result<(), CheckpointError> {
+        let name = store.name().ok_or(CheckpointError::Unnamed)?;
+        let data = store.encode();
+        if data.len() > self.max_size {
+            return Err(CheckpointError::TooLarge(data.len()));
+        }
+        let path = self.path(environment_id, name);
+        fs::create_dir_all(path.parent().unwrap_or(&self.dir)).map_err(CheckpointError::Io)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data).map_err(CheckpointError::Io)?;
+        fs::rename(&tmp, &path).map_err(CheckpointError::Io)
+    }
+
+    /// Replaces the entries of `store` with the checkpoint saved under `name` in the environment
+    /// and names the store.
+    ///
+    /// If there is no checkpoint, or it can't be read, the store is left empty.
+    pub fn restore(&self, environment_id: u64, name: &str, store: &mut KvStore) {
+        let entries = match fs::read(self.path(environment_id, name)) {
+            Ok(data) => KvStore::decode(&data).unwrap_or_else(|| {
+                log::warn!("Ignoring corrupted kv checkpoint of process {name}");
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                log::warn!("Failed to read kv checkpoint of process {name}: {err}");
+                BTreeMap::new()
+            }
+        };
+        store.name = Some(name.to_owned());
+        store.entries = entries;
+    }
+
+    fn path(&self, environment_id: u64, name: &str) -> PathBuf {
+        let file: String = name.bytes().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(environment_id.to_string()).join(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CheckpointError, KvCheckpoints, KvStore};
+
+    fn checkpoints(test: &str, max_size: usize) -> KvCheckpoints {
+        let dir = std::env::temp_dir().join(format!("lunatic-kv-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        KvCheckpoints::new(dir, max_size).unwrap()
+    }
+
+    #[test]
+    fn checkpoint_is_restored_after_restart() {
+        let checkpoints = checkpoints("restore", KvCheckpoints::DEFAULT_MAX_SIZE);
+        let mut store = KvStore::default();
+        store.set_name("counter".to_string());
+        store.put(b"count".to_vec(), b"41".to_vec());
+        store.put(b"empty".to_vec(), Vec::new());
+        checkpoints.save(1, &store).unwrap();
+        // Changes after the checkpoint are lost
+        store.put(b"count".to_vec(), b"42".to_vec());
+        drop(store);
+
+        // A new process registering under the same name gets the checkpoint
+        let mut restarted = KvStore::default();
+        checkpoints.restore(1, "counter", &mut restarted);
+        assert_eq!(restarted.name(), Some("counter"));
+        assert_eq!(restarted.len(), 2);
+        assert_eq!(restarted.get(b"count"), Some(&b"41"[..]));
+        assert_eq!(restarted.get(b"empty"), Some(&b""[..]));
+
+        // Other names start empty
+        let mut other = KvStore::default();
+        checkpoints.restore(1, "other", &mut other);
+        assert!(other.is_empty());
+
+        // The same name in another environment starts empty
+        let mut other_env = KvStore::default();
+        checkpoints.restore(2, "counter", &mut other_env);
+        assert!(other_env.is_empty());
+        std::fs::remove_dir_all(checkpoints.dir()).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_are_bounded() {
+        let checkpoints = checkpoints("bounded", 48);
+        let mut store = KvStore::default();
+        store.put(b"key".to_vec(), vec![0; 16]);
+        assert!(matches!(
+            checkpoints.save(1, &store),
+            Err(CheckpointError::Unnamed)
+        ));
+        store.set_name("big".to_string());
+        checkpoints.save(1, &store).unwrap();
+        store.put(b"other".to_vec(), vec![0; 16]);
+        assert!(matches!(
+            checkpoints.save(1, &store),
+            Err(CheckpointError::TooLarge(_))
+        ));
+
+        // The previous checkpoint is kept
+        let mut restored = KvStore::default();
+        checkpoints.restore(1, "big", &mut restored);
+        assert_eq!(restored.len(), 1);
+        std::fs::remove_dir_all(checkpoints.dir()).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+/*!
+Labels attached to processes, so that they can be found without knowing their IDs.
+
+Unlike names in the registry, a label can be used by many processes at the same time. Each
+process has at most one label, and it's removed when the process is removed from the environment.
+*/
+
+use std::collections::BTreeSet;
+
+use dashmap::DashMap;
+
+#[derive(Debug, Default)]
+pub struct ProcessLabels {
+    // Process ID -> label
+    labels: DashMap<u64, String>,
+    // Label -> IDs of processes with the label
+    processes: DashMap<String, BTreeSet<u64>>,
+}
+
+impl ProcessLabels {
+    /// Sets the label of a process, replacing the previous one.
+    pub fn set(&self, process_id: u64, label: &str) {
+        self.remove(process_id);
+        self.labels.insert(process_id, label.to_string());
+        self.processes
+            .entry(label.to_string())
+            .or_default()
+            .insert(process_id);
+    }
+
+    /// Removes the label of a process.
+    pub fn remove(&self, process_id: u64) {
+        if let Some((_, label)) = self.labels.remove(&process_id) {
+            if let Some(mut processes) = self.processes.get_mut(&label) {
+                processes.remove(&process_id);
+            }
+            self.processes
+                .remove_if(&label, |_, processes| processes.is_empty());
+        }
+    }
+
+    pub fn get(&self, process_id: u64) -> Option<String> {
+        self.labels.get(&process_id).map(|label| label.clone())
+    }
+
+    /// Returns the IDs of all processes with `label` in ascending order, or an empty list if
+    /// there are none.
+    pub fn find(&self, label: &str) -> Vec<u64> {
+        self.processes
+            .get(label)
+            .map(|processes| processes.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProcessLabels;
+
+    #[test]
+    fn find_processes_by_label() {
+        let labels = ProcessLabels::default();
+        labels.set(1, "worker");
+        labels.set(2, "worker");
+        labels.set(3, "supervisor");
+
+        assert_eq!(labels.find("worker"), vec![1, 2]);
+        assert_eq!(labels.find("supervisor"), vec![3]);
+        assert!(labels.find("missing").is_empty());
+        assert_eq!(labels.get(3).as_deref(), Some("supervisor"));
+    }
+
+    #[test]
+    fn relabel_and_remove() {
+        let labels = ProcessLabels::default();
+        labels.set(1, "worker");
+        labels.set(2, "worker");
+        labels.set(1, "supervisor");
+        assert_eq!(labels.find("worker"), vec![2]);
+        assert_eq!(labels.find("supervisor"), vec![1]);
+
+        labels.remove(2);
+        assert!(labels.find("worker").is_empty());
+        assert_eq!(labels.get(2), None);
+    }
+}
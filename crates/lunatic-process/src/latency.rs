@@ -0,0 +1,186 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+    time::{Duration, Instant},
+};
+
+/// Measures how long a process waits between being woken up and being polled by the runtime.
+///
+/// High scheduling latency means that the runtime is overloaded or that another task is blocking
+/// the thread the process is scheduled on. Only wake ups are measured, time spent waiting on a
+/// message or a timer doesn't count.
+#[derive(Clone)]
+pub struct SchedulingLatency {
+    inner: Arc<InnerLatency>,
+}
+
+struct InnerLatency {
+    // Wake up times are stored as nanoseconds since `start`, 0 means the task is not woken up
+    start: Instant,
+    woken_at: AtomicU64,
+    // Exponentially weighted moving average in nanoseconds
+    average: AtomicU64,
+    // Highest latency in nanoseconds since the last `take_max`
+    max: AtomicU64,
+}
+
+// Weight of the latest sample in the moving average is 1/2^AVERAGE_SHIFT
+const AVERAGE_SHIFT: u32 = 3;
+
+impl SchedulingLatency {
+    /// Recent average latency.
+    pub fn average(&self) -> Duration {
+        Duration::from_nanos(self.inner.average.load(Ordering::Relaxed))
+    }
+
+    /// Highest latency since the last call.
+    pub fn take_max(&self) -> Duration {
+        Duration::from_nanos(self.inner.max.swap(0, Ordering::Relaxed))
+    }
+
+    /// Wraps the future of a process so that its polls are measured.
+    pub fn measure<F: Future>(&self, fut: F) -> Measured<F> {
+        Measured {
+            fut: Box::pin(fut),
+            latency: self.clone(),
+            waker: None,
+        }
+    }
+
+    fn woken(&self) {
+        let now = self.inner.now();
+        // Keep the first wake up, the task is ready from then on
+        self.inner
+            .woken_at
+            .compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed)
+            .ok();
+    }
+
+    fn polled(&self) {
+        let woken_at = self.inner.woken_at.swap(0, Ordering::Relaxed);
+        if woken_at == 0 {
+            return;
+        }
+        let latency = self.inner.now().saturating_sub(woken_at);
+        // Only polls of one task update the average, so load and store don't race
+        let average = self.inner.average.load(Ordering::Relaxed);
+        let average = average - (average >> AVERAGE_SHIFT) + (latency >> AVERAGE_SHIFT);
+        self.inner.average.store(average, Ordering::Relaxed);
+        self.inner.max.fetch_max(latency, Ordering::Relaxed);
+    }
+}
+
+impl Default for SchedulingLatency {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(InnerLatency {
+                start: Instant::now(),
+                woken_at: AtomicU64::new(0),
+                average: AtomicU64::new(0),
+                max: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl InnerLatency {
+    fn now(&self) -> u64 {
+        // Never 0, so it can't be confused with not being woken up
+        (self.start.elapsed().as_nanos() as u64).max(1)
+    }
+}
+
+/// Future returned by [`SchedulingLatency::measure`].
+pub struct Measured<F> {
+    fut: Pin<Box<F>>,
+    latency: SchedulingLatency,
+    // Reused as long as the runtime polls with the same waker
+    waker: Option<(Waker, Waker)>,
+}
+
+struct LatencyWaker {
+    latency: SchedulingLatency,
+    waker: Waker,
+}
+
+impl Wake for LatencyWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.latency.woken();
+        self.waker.wake_by_ref();
+    }
+}
+
+impl<F: Future> Future for Measured<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.latency.polled();
+        let waker = match &this.waker {
+            Some((runtime_waker, waker)) if runtime_waker.will_wake(cx.waker()) => waker.clone(),
+            _ => {
+                let waker = Waker::from(Arc::new(LatencyWaker {
+                    latency: this.latency.clone(),
+                    waker: cx.waker().clone(),
+                }));
+                this.waker = Some((cx.waker().clone(), waker.clone()));
+                waker
+            }
+        };
+        this.fut.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::SchedulingLatency;
+
+    // Receives `count` messages that another thread sends every millisecond
+    async fn receive(count: usize) {
+        let (sender, mut receiver) = unbounded_channel();
+        std::thread::spawn(move || {
+            for _ in 0..count {
+                std::thread::sleep(Duration::from_millis(1));
+                sender.send(()).unwrap();
+            }
+        });
+        for _ in 0..count {
+            receiver.recv().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn latency_rises_under_load() {
+        let latency = SchedulingLatency::default();
+        tokio::spawn(latency.measure(receive(10))).await.unwrap();
+        let idle = latency.average();
+        assert!(latency.take_max() < Duration::from_millis(5));
+
+        // A neighbor blocking the only thread of the runtime between its yields
+        let neighbor = tokio::spawn(async {
+            for _ in 0..20 {
+                std::thread::sleep(Duration::from_millis(5));
+                tokio::task::yield_now().await;
+            }
+        });
+        tokio::spawn(latency.measure(receive(10))).await.unwrap();
+        neighbor.await.unwrap();
+        assert!(latency.average() > idle);
+        assert!(latency.take_max() >= Duration::from_millis(1));
+        // The maximum is reset once it's read
+        assert_eq!(latency.take_max(), Duration::ZERO);
+    }
+}
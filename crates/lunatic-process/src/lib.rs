@@ -1,12 +1,26 @@
+pub mod cancel;
 pub mod config;
+pub mod dedup;
 pub mod env;
+pub mod interceptor;
+pub mod kv;
+pub mod labels;
+pub mod latency;
+pub mod lifecycle;
+pub mod limits;
 pub mod mailbox;
+pub mod memory;
 pub mod message;
+pub mod restart;
+pub mod ring;
 pub mod runtimes;
 pub mod state;
+pub mod topics;
 pub mod wasm;
 
-use std::{collections::HashMap, fmt::Debug, future::Future, hash::Hash, sync::Arc};
+use std::{
+    collections::HashMap, fmt::Debug, future::Future, hash::Hash, sync::Arc, time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use env::Environment;
@@ -18,9 +32,17 @@ use tokio::{
         Mutex,
     },
     task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    limits::ResourceLimitExceeded,
+    mailbox::MessageMailbox,
+    message::{DataMessage, Message},
 };
 
-use crate::{mailbox::MessageMailbox, message::Message};
+/// Tag of the message a process receives when it's asked to shut down with [`Signal::Shutdown`].
+pub const SHUTDOWN_TAG: i64 = i64::MIN;
 
 #[cfg(feature = "metrics")]
 pub fn describe_metrics() {
@@ -91,6 +113,24 @@ pub fn describe_metrics() {
         Unit::Count,
         "Number of currently active environments"
     );
+
+    describe_gauge!(
+        "lunatic.process.modules.cache.size",
+        Unit::Bytes,
+        "Memory used by cached modules, if the module cache has a memory budget"
+    );
+
+    describe_counter!(
+        "lunatic.process.modules.evictions",
+        Unit::Count,
+        "Number of modules evicted from the module cache since startup"
+    );
+
+    describe_counter!(
+        "lunatic.process.mailbox.high_water",
+        Unit::Count,
+        "Number of times the mailbox of a process grew past the high-water mark"
+    );
 }
 
 /// The `Process` is the main abstraction in lunatic.
@@ -124,6 +164,9 @@ pub enum Signal {
     Message(Message),
     // When received, the process should stop immediately.
     Kill,
+    // When received, the process gets an empty message tagged with `SHUTDOWN_TAG` and should clean
+    // up and finish. If it's still running after the grace period, it's killed.
+    Shutdown(Duration),
     // Change behaviour of what happens if a linked process dies.
     DieWhenLinkDies(bool),
     // Sent from a process that wants to be linked. In case of a death the tag will be returned
@@ -136,6 +179,9 @@ pub enum Signal {
     // the death reason, the receiving process will turn this signal into a message or the
     // process will immediately die as well.
     LinkDied(u64, Option<i64>, DeathReason),
+    // Sent from a process that wants to receive a message tagged with the tag when this process
+    // exits, see [`exit_notification`].
+    NotifyOnExit(i64, Arc<dyn Process>),
 }
 
 impl Debug for Signal {
@@ -143,21 +189,87 @@ impl Debug for Signal {
         match self {
             Self::Message(_) => write!(f, "Message"),
             Self::Kill => write!(f, "Kill"),
+            Self::Shutdown(grace_period) => write!(f, "Shutdown {:?}", grace_period),
             Self::DieWhenLinkDies(_) => write!(f, "DieWhenLinkDies"),
             Self::Link(_, p) => write!(f, "Link {}", p.id()),
             Self::UnLink { process_id } => write!(f, "UnLink {process_id}"),
             Self::LinkDied(_, _, reason) => write!(f, "LinkDied {:?}", reason),
+            Self::NotifyOnExit(tag, p) => write!(f, "NotifyOnExit {} {tag}", p.id()),
         }
     }
 }
 
 // The reason of a process' death
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum DeathReason {
     // Process finished normaly.
     Normal,
     Failure,
     NoProcess,
+    // Process finished cleanly by calling `exit` with a reason of its own.
+    Exit(ExitReason),
+}
+
+impl DeathReason {
+    // Code of the reason in exit notifications
+    fn code(&self) -> u8 {
+        match self {
+            DeathReason::Normal => 0,
+            DeathReason::Failure => 1,
+            DeathReason::NoProcess => 2,
+            DeathReason::Exit(_) => 3,
+        }
+    }
+}
+
+/// Reason a process gave for finishing when it exited on its own.
+///
+/// The code and detail are chosen by the guest and passed on to the processes watching it
+/// unchanged. Returning it as the error of a host function finishes the process cleanly, it
+/// doesn't count as a failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExitReason {
+    pub code: u32,
+    pub detail: Vec<u8>,
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process exited with code {}", self.code)
+    }
+}
+
+impl std::error::Error for ExitReason {}
+
+/// Returns the message a process registered with [`Signal::NotifyOnExit`] receives when the
+/// process `process_id` exits.
+///
+/// The message is tagged with `tag` and contains `[process_id: u64 LE][reason: u8]`. The reason
+/// is 0 if the process finished normally, 1 if it failed or was killed, 2 if it didn't exist
+/// when the registration arrived and 3 if it exited with an [`ExitReason`]. The exit reason
+/// follows as `[code: u32 LE][detail]`.
+pub fn exit_notification(tag: i64, process_id: u64, reason: &DeathReason) -> DataMessage {
+    let mut data = Vec::with_capacity(9);
+    data.extend_from_slice(&process_id.to_le_bytes());
+    data.push(reason.code());
+    if let DeathReason::Exit(reason) = reason {
+        data.extend_from_slice(&reason.code.to_le_bytes());
+        data.extend_from_slice(&reason.detail);
+    }
+    DataMessage::new_from_vec(Some(tag), data)
+}
+
+/// Asks the process `process_id` in the environment to notify `watcher` when it exits. If the
+/// process doesn't exist the notification is sent right away.
+pub fn notify_on_exit(env: &dyn Environment, process_id: u64, tag: i64, watcher: Arc<dyn Process>) {
+    match env.get_process(process_id) {
+        Some(process) => process.send(Signal::NotifyOnExit(tag, watcher)),
+        None => watcher.send(Signal::Message(Message::Data(exit_notification(
+            tag,
+            process_id,
+            &DeathReason::NoProcess,
+        )))),
+    }
 }
 
 /// The reason of a process finishing
@@ -240,6 +352,9 @@ where
 {
     trace!("Process {} spawned", id);
     tokio::pin!(fut);
+    if let Some(mark) = env.mailbox_high_water_mark() {
+        message_mailbox.set_high_water_mark(id, mark);
+    }
 
     // Defines what happens if one of the linked processes dies.
     // If the value is set to false, instead of dying too the process will receive a message about
@@ -247,11 +362,15 @@ where
     let mut die_when_link_dies = true;
     // Process linked to this one
     let mut links = HashMap::new();
+    // Processes that want a message when this one exits
+    let mut exit_watchers = Vec::new();
     // TODO: Maybe wrapping this in some kind of `std::panic::catch_unwind` wold be a good idea,
     //       to protect against panics in host function calls that unwind through Wasm code.
     //       Currently a panic would just kill the task, but not notify linked processes.
     let mut signal_mailbox = signal_mailbox.lock().await;
     let mut has_sender = true;
+    // Set once a `Shutdown` signal is received, the process is killed if it doesn't finish by then.
+    let mut shutdown_deadline: Option<Instant> = None;
     #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
     let labels: [(String, String); 0] = [];
     #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
@@ -296,6 +415,22 @@ where
                     }
                     // Exit loop and don't poll anymore the future if Signal::Kill received.
                     Ok(Signal::Kill) => break Finished::KillSignal,
+                    // Let the process know it should finish, but keep running it until the grace
+                    // period expires. Repeated signals can only shorten the grace period.
+                    Ok(Signal::Shutdown(grace_period)) => {
+                        let deadline = Instant::now() + grace_period;
+                        if shutdown_deadline.is_none() {
+                            message_mailbox.push(Message::Data(DataMessage::new_from_vec(
+                                Some(SHUTDOWN_TAG),
+                                Vec::new(),
+                            )));
+                        }
+                        shutdown_deadline = Some(match shutdown_deadline {
+                            Some(current) => current.min(deadline),
+                            None => deadline,
+                        });
+                    }
+                    Ok(Signal::NotifyOnExit(tag, proc)) => exit_watchers.push((tag, proc)),
                     // Depending if `die_when_link_dies` is set, process will die or turn the
                     // signal into a message
                     Ok(Signal::LinkDied(id, tag, reason)) => {
@@ -321,7 +456,7 @@ where
                                 }
                             },
                             // In case a linked process finishes normally, don't do anything.
-                            DeathReason::Normal => {},
+                            DeathReason::Normal | DeathReason::Exit(_) => {},
                         }
                     },
                     Err(_) => {
@@ -330,12 +465,31 @@ where
                     }
                 }
             }
+            // Kill the process if it didn't finish within the shutdown grace period
+            _ = tokio::time::sleep_until(shutdown_deadline.unwrap_or_else(Instant::now)),
+                if shutdown_deadline.is_some() => {
+                warn!("Process {} didn't shut down within the grace period", id);
+                break Finished::KillSignal;
+            }
             // Run process
             output = &mut fut => { break Finished::Normal(output); }
         }
     };
 
     env.remove_process(id);
+    // Watchers registered while the process was finishing are still notified. Later registrations
+    // can't find the process anymore and are notified right away.
+    while let Ok(signal) = signal_mailbox.try_recv() {
+        if let Signal::NotifyOnExit(tag, proc) = signal {
+            exit_watchers.push((tag, proc));
+        }
+    }
+    let notify_exit_watchers = |reason: DeathReason| {
+        for (tag, proc) in exit_watchers.iter() {
+            let message = exit_notification(*tag, id, &reason);
+            proc.send(Signal::Message(Message::Data(message)));
+        }
+    };
 
     match result {
         Finished::Normal(result) => {
@@ -357,12 +511,18 @@ where
                 links.iter().for_each(|(_, (proc, tag))| {
                     proc.send(Signal::LinkDied(id, *tag, DeathReason::Failure));
                 });
-                Err(anyhow!(failure.to_string()))
+                notify_exit_watchers(DeathReason::Failure);
+                Err(anyhow!(failure))
             } else {
+                let reason = match result.exit_reason() {
+                    Some(reason) => DeathReason::Exit(reason.clone()),
+                    None => DeathReason::Normal,
+                };
                 // Notify all links that we finished normally
                 links.iter().for_each(|(_, (proc, tag))| {
-                    proc.send(Signal::LinkDied(id, *tag, DeathReason::Normal));
+                    proc.send(Signal::LinkDied(id, *tag, reason.clone()));
                 });
+                notify_exit_watchers(reason);
                 Ok(result.state())
             }
         }
@@ -376,6 +536,7 @@ where
             links.iter().for_each(|(_, (proc, tag))| {
                 proc.send(Signal::LinkDied(id, *tag, DeathReason::Failure));
             });
+            notify_exit_watchers(DeathReason::Failure);
             Err(anyhow!("Process received Kill signal"))
         }
     }
@@ -402,7 +563,6 @@ pub struct NativeProcess {
 ///     Ok(())
 /// });
 /// ```
-
 pub fn spawn<T, F, K, R>(
     env: Arc<dyn Environment>,
     func: F,
@@ -460,10 +620,27 @@ pub struct ExecutionResult<T> {
 
 impl<T> ExecutionResult<T> {
     // Returns the failure as `String` if the process failed.
-    pub fn failure(&self) -> Option<&str> {
+    pub fn failure(&self) -> Option<String> {
+        match self.result {
+            ResultValue::Failed(ref failure) => Some(failure.clone()),
+            ResultValue::SpawnError(ref failure) => Some(failure.clone()),
+            ResultValue::ResourceLimitExceeded(ref exceeded) => Some(exceeded.to_string()),
+            ResultValue::Ok | ResultValue::Exited(_) => None,
+        }
+    }
+
+    // Returns the reason the process gave if it exited on its own.
+    pub fn exit_reason(&self) -> Option<&ExitReason> {
+        match self.result {
+            ResultValue::Exited(ref reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    // Returns the exceeded limit if the process failed because of it.
+    pub fn resource_limit_exceeded(&self) -> Option<&ResourceLimitExceeded> {
         match self.result {
-            ResultValue::Failed(ref failure) => Some(failure),
-            ResultValue::SpawnError(ref failure) => Some(failure),
+            ResultValue::ResourceLimitExceeded(ref exceeded) => Some(exceeded),
             _ => None,
         }
     }
@@ -485,9 +662,15 @@ where
                 state: t,
                 result: ResultValue::Ok,
             },
-            Err(e) => ExecutionResult {
-                state: T::default(),
-                result: ResultValue::Failed(e.to_string()),
+            Err(e) => match e.downcast::<ExitReason>() {
+                Ok(reason) => ExecutionResult {
+                    state: T::default(),
+                    result: ResultValue::Exited(reason),
+                },
+                Err(e) => ExecutionResult {
+                    state: T::default(),
+                    result: ResultValue::Failed(e.to_string()),
+                },
             },
         }
     }
@@ -498,4 +681,96 @@ pub enum ResultValue {
     Ok,
     Failed(String),
     SpawnError(String),
+    ResourceLimitExceeded(ResourceLimitExceeded),
+    Exited(ExitReason),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use tokio::task::JoinHandle;
+
+    use crate::{
+        env::{Environment, LunaticEnvironment},
+        message::Message,
+        notify_on_exit, spawn, NativeProcess, Process, Signal, SHUTDOWN_TAG,
+    };
+
+    // Spawns a process that waits to be shut down and marks `cleaned_up` before finishing.
+    fn spawn_with_cleanup(cleaned_up: Arc<AtomicBool>) -> (JoinHandle<Result<()>>, NativeProcess) {
+        let env = Arc::new(LunaticEnvironment::new(1));
+        spawn(env, move |_this, mailbox| async move {
+            mailbox.pop(Some(&[SHUTDOWN_TAG])).await;
+            cleaned_up.store(true, Ordering::SeqCst);
+            Ok::<_, anyhow::Error>(())
+        })
+    }
+
+    #[tokio::test]
+    async fn shutdown_runs_cleanup_but_kill_does_not() {
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+        let (handle, process) = spawn_with_cleanup(cleaned_up.clone());
+        process.send(Signal::Shutdown(Duration::from_secs(60)));
+        assert!(handle.await.unwrap().is_ok());
+        assert!(cleaned_up.load(Ordering::SeqCst));
+
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+        let (handle, process) = spawn_with_cleanup(cleaned_up.clone());
+        process.send(Signal::Kill);
+        assert!(handle.await.unwrap().is_err());
+        assert!(!cleaned_up.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn shutdown_kills_process_after_grace_period() {
+        let env = Arc::new(LunaticEnvironment::new(1));
+        let (handle, process) = spawn(env, |_this, mailbox| async move {
+            // Ignore the shutdown message and keep waiting
+            mailbox.pop(Some(&[1])).await;
+            Ok::<_, anyhow::Error>(())
+        });
+        process.send(Signal::Shutdown(Duration::from_millis(10)));
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn exit_watchers_are_notified() {
+        let env = Arc::new(LunaticEnvironment::new(1));
+        let (notifications, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let (_, watcher) = spawn(env.clone(), |_this, mailbox| async move {
+            for tag in [7, 8] {
+                notifications.send(mailbox.pop(Some(&[tag])).await).unwrap();
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        let (handle, process) = spawn(env.clone(), |_this, mailbox| async move {
+            mailbox.pop(Some(&[1])).await;
+            Ok::<_, anyhow::Error>(())
+        });
+        env.add_process(process.id(), Arc::new(process.clone()));
+
+        notify_on_exit(env.as_ref(), process.id(), 7, Arc::new(watcher.clone()));
+        process.send(Signal::Kill);
+        assert!(handle.await.unwrap().is_err());
+        // The process is gone, so the registration fires right away.
+        notify_on_exit(env.as_ref(), process.id(), 8, Arc::new(watcher));
+
+        let mut expected = process.id().to_le_bytes().to_vec();
+        for reason in [1, 2] {
+            expected.push(reason);
+            match received.recv().await {
+                Some(Message::Data(message)) => assert_eq!(message.buffer, expected),
+                _ => panic!("expected an exit notification"),
+            }
+            expected.pop();
+        }
+    }
 }
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// How long a process is expected to run, as declared by whoever spawns it.
+///
+/// The hint doesn't change how the process runs. It's used to pick the node a process is spawned
+/// on, e.g. to keep long running services on nodes that are part of the cluster for a while, and
+/// it shows up in diagnostics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Lifecycle {
+    #[default]
+    Unspecified,
+    /// A short lived process, e.g. a one-shot task. It can run on any node.
+    Transient,
+    /// A long running process, e.g. a service.
+    Persistent,
+}
+
+impl Lifecycle {
+    /// Code of the hint used by guests.
+    pub fn code(&self) -> u32 {
+        match self {
+            Lifecycle::Unspecified => 0,
+            Lifecycle::Transient => 1,
+            Lifecycle::Persistent => 2,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Lifecycle::Unspecified),
+            1 => Some(Lifecycle::Transient),
+            2 => Some(Lifecycle::Persistent),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::fmt::Display;
+
+/// A resource whose growth the runtime limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// Memory of the process, limited by the process configuration.
+    Memory,
+    /// Memory of all processes on the node together.
+    NodeMemory,
+    /// Elements of the process' table.
+    Table,
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resource::Memory => write!(f, "memory"),
+            Resource::NodeMemory => write!(f, "node memory"),
+            Resource::Table => write!(f, "table"),
+        }
+    }
+}
+
+/// A rejected attempt of a process to grow a resource past its limit.
+///
+/// The guest only sees the failed growth (e.g. `memory.grow` returning -1), most guests trap
+/// right after. If the process then fails, it finishes with this as its reason instead of the
+/// generic trap, so that supervisors can tell it apart from other failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceLimitExceeded {
+    pub resource: Resource,
+    // Limit in bytes for memory and in elements for tables
+    pub limit: u64,
+    // Size the process tried to grow the resource to
+    pub attempted: u64,
+}
+
+impl Display for ResourceLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "resource limit exceeded: {} (limit {}, attempted {})",
+            self.resource, self.limit, self.attempted
+        )
+    }
+}
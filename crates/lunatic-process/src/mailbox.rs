@@ -1,15 +1,21 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
+use anyhow::Result;
+
+use crate::dedup::MessageDedup;
 use crate::message::Message;
+use crate::ring::{MessageRing, RingDrain, RingOverflow};
 
 /// The `MessageMailbox` is a data structure holding all messages of a process.
 ///
 /// If a `Signal` of type `Message` is received it will be taken from the Signal queue and put into
-/// this structure. The order of messages is preserved. This struct also implements the [`Future`]
+/// this structure. Messages are ordered by [`Priority`](crate::message::Priority), the order of
+/// messages with the same priority is preserved. This struct also implements the [`Future`]
 /// trait and `pop()` operations can be awaited on if the queue is empty.
 ///
 /// ## Safety
@@ -26,17 +32,157 @@ struct InnerMessageMailbox {
     waker: Option<Waker>,
     tags: Option<Vec<i64>>,
     found: Option<Message>,
-    messages: VecDeque<Message>,
+    // Messages in the queue together with the time and the order they were put into it
+    messages: VecDeque<(Instant, u64, Message)>,
+    next_arrival: u64,
+    // Number of queued messages per tag, untagged messages are counted under `None`
+    tag_counts: BTreeMap<Option<i64>, usize>,
+    // Sum of the data buffer sizes of all queued messages
+    total_bytes: usize,
+    // Small data messages are written here instead of the queue if registered
+    ring: Option<MessageRing>,
+    // Warns about the queue growing too long if set
+    high_water_mark: Option<HighWaterMark>,
+    // Drops duplicates of messages sent with at-least-once delivery if set
+    dedup: Option<MessageDedup>,
+}
+
+// The mailbox warns once when the queue grows past `mark` messages, and only again after it
+// drained to half of the mark, so that a queue staying long doesn't warn for every message.
+struct HighWaterMark {
+    process_id: u64,
+    mark: usize,
+    armed: bool,
+    warnings: u64,
+}
+
+impl HighWaterMark {
+    fn grown(&mut self, len: usize) {
+        if self.armed && len > self.mark {
+            self.armed = false;
+            self.warnings += 1;
+            log::warn!(
+                "Mailbox of process {} grew to {} messages, past the high-water mark of {}",
+                self.process_id,
+                len,
+                self.mark
+            );
+            #[cfg(feature = "metrics")]
+            metrics::increment_counter!(
+                "lunatic.process.mailbox.high_water",
+                "process_id" => self.process_id.to_string()
+            );
+        }
+    }
+
+    fn shrunk(&mut self, len: usize) {
+        if len <= self.mark / 2 {
+            self.armed = true;
+        }
+    }
+}
+
+impl InnerMessageMailbox {
+    // Keeps the queue ordered by priority. Messages of the same priority stay in arrival order, so
+    // with only the default priority this is a push to the back.
+    fn enqueue(&mut self, message: Message) {
+        *self.tag_counts.entry(message.tag()).or_default() += 1;
+        self.total_bytes += message_size(&message);
+        let priority = message.priority();
+        let index = self
+            .messages
+            .iter()
+            .rposition(|(_, _, queued)| queued.priority() >= priority)
+            .map_or(0, |index| index + 1);
+        let arrival = self.next_arrival;
+        self.next_arrival += 1;
+        self.messages
+            .insert(index, (Instant::now(), arrival, message));
+    }
+
+    // Returns the index of the next message matching `tags` to receive in `order`.
+    fn next(&self, tags: Option<&[i64]>, order: ReceiveOrder) -> Option<usize> {
+        // Only consider messages that also have a tag when looking for specific tags
+        let matches = |message: &Message| match (tags, message.tag()) {
+            (None, _) => true,
+            (Some(tags), Some(tag)) => tags.contains(&tag),
+            (Some(_), None) => false,
+        };
+        let mut candidates = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, _, message))| matches(message));
+        match order {
+            ReceiveOrder::Priority => candidates.next().map(|(index, _)| index),
+            ReceiveOrder::Arrival => candidates
+                .min_by_key(|(_, (_, arrival, _))| *arrival)
+                .map(|(index, _)| index),
+        }
+    }
+
+    fn dequeue(&mut self, index: usize) -> Option<Message> {
+        let (_, _, message) = self.messages.remove(index)?;
+        if let Some(count) = self.tag_counts.get_mut(&message.tag()) {
+            *count -= 1;
+            if *count == 0 {
+                self.tag_counts.remove(&message.tag());
+            }
+        }
+        self.total_bytes -= message_size(&message);
+        let len = self.messages.len();
+        if let Some(high_water_mark) = self.high_water_mark.as_mut() {
+            high_water_mark.shrunk(len);
+        }
+        Some(message)
+    }
+}
+
+fn message_size(message: &Message) -> usize {
+    match message {
+        Message::Data(message) => message.size(),
+        Message::LinkDied(_) => 0,
+    }
+}
+
+/// The order in which [`MessageMailbox::pop_in_order`] receives messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReceiveOrder {
+    /// Messages with a higher priority first, messages of the same priority in arrival order.
+    #[default]
+    Priority,
+    /// Messages in arrival order, regardless of their priority.
+    Arrival,
+}
+
+/// A summary of the messages waiting in a [`MessageMailbox`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailboxDigest {
+    pub count: usize,
+    /// Sum of the data buffer sizes of all messages.
+    pub total_bytes: usize,
+    /// How long the oldest message is waiting, `None` if the mailbox is empty.
+    pub oldest_age: Option<Duration>,
+    /// Number of messages per tag ordered by tag, untagged messages are counted under `None`.
+    pub tags: Vec<(Option<i64>, usize)>,
 }
 
 impl MessageMailbox {
-    /// Return message in FIFO order from mailbox.
+    /// Return message in priority and then FIFO order from mailbox.
     ///
     /// If function is called with a `tags` value different from None, it will only return the first
     /// message matching any of the tags.
     ///
     /// If no message exist, blocks until a message is received.
     pub async fn pop(&self, tags: Option<&[i64]>) -> Message {
+        self.pop_in_order(tags, ReceiveOrder::Priority).await
+    }
+
+    /// Like `pop`, but returns the messages in the given `order`.
+    ///
+    /// The order only affects which queued message is received, the queue itself stays ordered by
+    /// priority. Receiving in different orders one after another never loses or skips messages.
+    pub async fn pop_in_order(&self, tags: Option<&[i64]>, order: ReceiveOrder) -> Message {
         // Mailbox lock must be released before .await
         {
             let mut mailbox = self.inner.lock().expect("only accessed by one process");
@@ -44,28 +190,12 @@ impl MessageMailbox {
             // If a found message exists here, it means that the previous `.await` was canceled
             // after a `wake()` call. To not lose this message it should be put into the queue.
             if let Some(found) = mailbox.found.take() {
-                mailbox.messages.push_back(found);
+                mailbox.enqueue(found);
             }
 
-            // When looking for specific tags, loop through all messages to check for it
-            if let Some(tags) = tags {
-                let index = mailbox.messages.iter().position(|x| {
-                    // Only consider messages that also have a tag.
-                    if let Some(tag) = x.tag() {
-                        tags.contains(&tag)
-                    } else {
-                        false
-                    }
-                });
-                // If message matching tags is found, remove it.
-                if let Some(index) = index {
-                    return mailbox.messages.remove(index).expect("must exist");
-                }
-            } else {
-                // If not looking for a specific tags try to pop the first message available.
-                if let Some(message) = mailbox.messages.pop_front() {
-                    return message;
-                }
+            // If a message matching the tags is found, remove it.
+            if let Some(index) = mailbox.next(tags, order) {
+                return mailbox.dequeue(index).expect("must exist");
             }
             // Mark the tags to wait on.
             mailbox.tags = tags.map(|tags| tags.into());
@@ -100,7 +230,7 @@ impl MessageMailbox {
             // If a found message exists here, it means that the previous `.await` was canceled
             // after a `wake()` call. To not lose this message it should be put into the queue.
             if let Some(found) = mailbox.found.take() {
-                mailbox.messages.push_back(found);
+                mailbox.enqueue(found);
             }
 
             // Mark the tags to wait on.
@@ -115,6 +245,11 @@ impl MessageMailbox {
     /// ready, otherwise it will push it at the end of the queue.
     pub fn push(&self, message: Message) {
         let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        if let Some(dedup) = mailbox.dedup.as_mut() {
+            if !dedup.admit(&message) {
+                return;
+            }
+        }
         // If waiting on a new message notify executor that it arrived.
         if let Some(waker) = mailbox.waker.take() {
             // If waiting on specific tags only notify if tags are matched, otherwise forward every message.
@@ -135,8 +270,138 @@ impl MessageMailbox {
                 mailbox.waker = Some(waker);
             }
         }
+        // Small messages go into the ring if the process registered one
+        let message = match (message, mailbox.ring.as_mut()) {
+            (Message::Data(data), Some(ring)) if ring.accepts(&data) => match ring.push(data) {
+                Ok(()) => return,
+                Err(data) => Message::Data(*data),
+            },
+            (message, _) => message,
+        };
         // Otherwise put message into queue
-        mailbox.messages.push_back(message);
+        mailbox.enqueue(message);
+        let len = mailbox.messages.len();
+        if let Some(high_water_mark) = mailbox.high_water_mark.as_mut() {
+            high_water_mark.grown(len);
+        }
+    }
+
+    /// Puts a message that was taken out of the mailbox back into the queue, e.g. because it
+    /// couldn't be handed over to another process. Unlike a pushed message it's not checked for
+    /// duplicates again and doesn't go into the ring.
+    ///
+    /// Only the process owning the mailbox requeues messages, it's never waiting on the mailbox
+    /// at the same time.
+    pub fn requeue(&self, message: Message) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.enqueue(message);
+        let len = mailbox.messages.len();
+        if let Some(high_water_mark) = mailbox.high_water_mark.as_mut() {
+            high_water_mark.grown(len);
+        }
+    }
+
+    /// Logs a warning and increments the `lunatic.process.mailbox.high_water` metric when the
+    /// number of queued messages grows past `mark`.
+    ///
+    /// To not warn for every message while the queue stays long, the next warning is only given
+    /// after the queue drained to half of the mark.
+    pub fn set_high_water_mark(&self, process_id: u64, mark: usize) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.high_water_mark = Some(HighWaterMark {
+            process_id,
+            mark,
+            armed: true,
+            warnings: 0,
+        });
+    }
+
+    /// Returns how often the mailbox warned about crossing the high-water mark.
+    pub fn high_water_warnings(&self) -> u64 {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox
+            .high_water_mark
+            .as_ref()
+            .map_or(0, |high_water_mark| high_water_mark.warnings)
+    }
+
+    /// Drops messages that arrive again with the same id from the same node within `window`,
+    /// remembering at most `max_entries` ids. See [`MessageDedup`] for which messages carry an
+    /// id. Replaces a previous deduplication, forgetting the ids it remembered.
+    pub fn set_dedup(&self, window: Duration, max_entries: usize) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.dedup = Some(MessageDedup::new(window, max_entries));
+    }
+
+    /// Stops dropping duplicates.
+    pub fn unset_dedup(&self) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.dedup = None;
+    }
+
+    /// Returns the number of duplicates dropped by the current deduplication.
+    pub fn duplicates_dropped(&self) -> u64 {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.dedup.as_ref().map_or(0, |dedup| dedup.dropped())
+    }
+
+    /// Registers a [`MessageRing`] of `capacity` bytes for data messages of up to
+    /// `max_message_size` bytes.
+    ///
+    /// From now on small messages are written into the ring, unless someone is waiting for them
+    /// with `pop`. Messages in the ring are not ordered relative to messages in the queue. If a
+    /// ring was already registered, its entries are moved to the end of the queue.
+    pub fn register_ring(
+        &self,
+        capacity: usize,
+        max_message_size: usize,
+        overflow: RingOverflow,
+    ) -> Result<()> {
+        let ring = MessageRing::new(capacity, max_message_size, overflow)?;
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        if let Some(previous) = mailbox.ring.replace(ring) {
+            for message in previous.into_messages() {
+                mailbox.enqueue(Message::Data(message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the ring and moves its entries to the end of the queue.
+    pub fn unregister_ring(&self) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        if let Some(ring) = mailbox.ring.take() {
+            for message in ring.into_messages() {
+                mailbox.enqueue(Message::Data(message));
+            }
+        }
+    }
+
+    /// Moves as many whole ring entries as fit into `buffer`, returns `None` if no ring is
+    /// registered.
+    pub fn drain_ring(&self, buffer: &mut [u8]) -> Option<RingDrain> {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.ring.as_mut().map(|ring| ring.drain(buffer))
+    }
+
+    /// Removes all messages from the mailbox and returns them in the order they would be received.
+    pub fn drain(&self) -> Vec<Message> {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        let mut messages: Vec<Message> = mailbox
+            .messages
+            .drain(..)
+            .map(|(_, _, message)| message)
+            .collect();
+        mailbox.tag_counts.clear();
+        mailbox.total_bytes = 0;
+        if let Some(high_water_mark) = mailbox.high_water_mark.as_mut() {
+            high_water_mark.shrunk(0);
+        }
+        // A found message was received after all messages still in the queue.
+        if let Some(found) = mailbox.found.take() {
+            messages.push(found);
+        }
+        messages
     }
 
     /// Returns the number of messages currently available
@@ -152,6 +417,31 @@ impl MessageMailbox {
 
         mailbox.messages.is_empty()
     }
+
+    /// Returns a summary of the available messages without removing any of them.
+    ///
+    /// The summary is taken while holding the mailbox lock, so it's consistent with messages
+    /// arriving concurrently.
+    pub fn digest(&self) -> MailboxDigest {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+
+        MailboxDigest {
+            count: mailbox.messages.len(),
+            total_bytes: mailbox.total_bytes,
+            // With priorities the front of the queue is not necessarily the oldest message
+            oldest_age: mailbox
+                .messages
+                .iter()
+                .map(|(received_at, _, _)| *received_at)
+                .min()
+                .map(|received_at| received_at.elapsed()),
+            tags: mailbox
+                .tag_counts
+                .iter()
+                .map(|(tag, count)| (*tag, *count))
+                .collect(),
+        }
+    }
 }
 
 impl Future for &MessageMailbox {
@@ -174,9 +464,11 @@ mod tests {
         future::Future,
         sync::{Arc, Mutex},
         task::{Context, Poll, Wake},
+        time::Duration,
     };
 
-    use super::{Message, MessageMailbox};
+    use super::{Message, MessageMailbox, ReceiveOrder};
+    use crate::message::{DataMessage, MessageSender, Priority, PriorityBoost};
 
     #[tokio::test]
     async fn no_tags_signal_message() {
@@ -252,6 +544,256 @@ mod tests {
         assert_eq!(message.tag(), Some(tag5));
     }
 
+    #[tokio::test]
+    async fn drain_returns_messages_in_order() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(Message::LinkDied(Some(1)));
+        mailbox.push(Message::LinkDied(Some(2)));
+        mailbox.push(Message::LinkDied(Some(3)));
+        let tags: Vec<_> = mailbox.drain().iter().map(|m| m.tag()).collect();
+        assert_eq!(tags, vec![Some(1), Some(2), Some(3)]);
+        assert!(mailbox.is_empty());
+        assert!(mailbox.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn higher_priority_messages_are_received_first() {
+        let mailbox = MessageMailbox::default();
+        let message = |tag, priority| {
+            Message::Data(DataMessage::new_from_vec(Some(tag), vec![]).with_priority(priority))
+        };
+        mailbox.push(message(1, Priority::Normal));
+        mailbox.push(message(2, Priority::Low));
+        mailbox.push(message(3, Priority::High));
+        mailbox.push(message(4, Priority::Normal));
+        mailbox.push(message(5, Priority::High));
+        mailbox.push(Message::LinkDied(Some(6)));
+
+        let mut tags = Vec::new();
+        while !mailbox.is_empty() {
+            tags.push(mailbox.pop(None).await.tag().unwrap());
+        }
+        assert_eq!(tags, vec![3, 5, 1, 4, 6, 2]);
+    }
+
+    #[tokio::test]
+    async fn arrival_order_ignores_priorities() {
+        let mailbox = MessageMailbox::default();
+        let message = |tag, priority| {
+            Message::Data(DataMessage::new_from_vec(Some(tag), vec![]).with_priority(priority))
+        };
+        mailbox.push(message(1, Priority::Low));
+        mailbox.push(message(2, Priority::High));
+        mailbox.push(message(3, Priority::Normal));
+        mailbox.push(message(4, Priority::High));
+        mailbox.push(message(5, Priority::Low));
+
+        let arrival = ReceiveOrder::Arrival;
+        assert_eq!(mailbox.pop_in_order(None, arrival).await.tag(), Some(1));
+        let tags = [4, 5];
+        assert_eq!(
+            mailbox.pop_in_order(Some(&tags), arrival).await.tag(),
+            Some(4)
+        );
+        // Switching back to priority order keeps the remaining messages
+        assert_eq!(mailbox.pop(None).await.tag(), Some(2));
+        assert_eq!(mailbox.pop_in_order(None, arrival).await.tag(), Some(3));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(5));
+        assert!(mailbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn boosted_messages_are_received_first_during_boost() {
+        let mailbox = MessageMailbox::default();
+        let mut boost = PriorityBoost::default();
+        let message = |tag| Message::Data(DataMessage::new_from_vec(Some(tag), vec![]));
+        mailbox.push(message(1));
+        assert_eq!(boost.boost(), 1);
+        mailbox.push(boost.apply(message(2)));
+        // Nested boosts past the maximum don't raise the priority further
+        assert_eq!(boost.boost(), 2);
+        assert_eq!(boost.boost(), 2);
+        assert_eq!(boost.restore(), Some(2));
+        mailbox.push(boost.apply(message(3)));
+        assert_eq!(boost.restore(), Some(1));
+        assert_eq!(boost.restore(), Some(0));
+        assert_eq!(boost.restore(), None);
+        mailbox.push(boost.apply(message(4)));
+
+        let mut tags = Vec::new();
+        while !mailbox.is_empty() {
+            let message = mailbox.pop(None).await;
+            tags.push((message.tag().unwrap(), message.priority()));
+        }
+        assert_eq!(
+            tags,
+            vec![
+                (2, Priority::High),
+                (3, Priority::High),
+                (1, Priority::Normal),
+                (4, Priority::Normal)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn growing_past_high_water_mark_warns_once() {
+        let mailbox = MessageMailbox::default();
+        mailbox.set_high_water_mark(1, 5);
+        let message = || Message::Data(DataMessage::new_from_vec(None, vec![]));
+        for _ in 0..20 {
+            mailbox.push(message());
+        }
+        assert_eq!(mailbox.high_water_warnings(), 1);
+
+        // Draining below the mark isn't enough to warn again
+        for _ in 0..16 {
+            mailbox.pop(None).await;
+        }
+        for _ in 0..20 {
+            mailbox.push(message());
+        }
+        assert_eq!(mailbox.high_water_warnings(), 1);
+
+        // Draining to half of the mark is
+        for _ in 0..22 {
+            mailbox.pop(None).await;
+        }
+        for _ in 0..20 {
+            mailbox.push(message());
+        }
+        assert_eq!(mailbox.high_water_warnings(), 2);
+    }
+
+    #[tokio::test]
+    async fn replayed_messages_are_received_once() {
+        let mailbox = MessageMailbox::default();
+        mailbox.set_dedup(Duration::from_secs(60), 100);
+        let message = |unique_id: u64, data: u8| {
+            let mut message = DataMessage::new_from_vec(None, vec![data]);
+            message.sender = Some(MessageSender {
+                node_id: 2,
+                process_id: 1,
+            });
+            message.unique_id = Some(unique_id);
+            Message::Data(message)
+        };
+        mailbox.push(message(1, 1));
+        // Replayed before the first delivery was acknowledged
+        mailbox.push(message(1, 1));
+        mailbox.push(message(2, 2));
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            if let Message::Data(message) = mailbox.pop(None).await {
+                received.push(message.buffer[0]);
+            }
+        }
+        assert_eq!(received, vec![1, 2]);
+        assert!(mailbox.is_empty());
+
+        // Replayed after it was received
+        mailbox.push(message(2, 2));
+        mailbox.push(message(3, 3));
+        match mailbox.pop(None).await {
+            Message::Data(message) => assert_eq!(message.buffer, vec![3]),
+            message => panic!("unexpected message {message:?}"),
+        }
+        assert_eq!(mailbox.duplicates_dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn requeued_messages_are_not_duplicates() {
+        let mailbox = MessageMailbox::default();
+        mailbox.set_dedup(Duration::from_secs(60), 100);
+        for unique_id in 1..=2 {
+            let mut message = DataMessage::new_from_vec(None, vec![unique_id as u8]);
+            message.sender = Some(MessageSender {
+                node_id: 2,
+                process_id: 1,
+            });
+            message.unique_id = Some(unique_id);
+            mailbox.push(Message::Data(message));
+        }
+
+        for message in mailbox.drain() {
+            mailbox.requeue(message);
+        }
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            if let Message::Data(message) = mailbox.pop(None).await {
+                received.push(message.buffer[0]);
+            }
+        }
+        assert_eq!(received, vec![1, 2]);
+        assert_eq!(mailbox.duplicates_dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn digest_summarizes_queued_messages() {
+        let mailbox = MessageMailbox::default();
+        let digest = mailbox.digest();
+        assert_eq!(digest.count, 0);
+        assert_eq!(digest.oldest_age, None);
+
+        let data = |tag, size| Message::Data(DataMessage::new_from_vec(tag, vec![0; size]));
+        mailbox.push(data(Some(1), 10));
+        mailbox.push(data(Some(2), 20));
+        mailbox.push(data(Some(1), 30));
+        mailbox.push(data(None, 5));
+        mailbox.push(Message::LinkDied(Some(3)));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let digest = mailbox.digest();
+        assert_eq!(digest.count, 5);
+        assert_eq!(digest.total_bytes, 65);
+        assert!(digest.oldest_age.unwrap() >= std::time::Duration::from_millis(5));
+        assert_eq!(
+            digest.tags,
+            vec![(None, 1), (Some(1), 2), (Some(2), 1), (Some(3), 1)]
+        );
+        // Taking the digest doesn't consume anything
+        assert_eq!(mailbox.len(), 5);
+
+        mailbox.pop(Some(&[1])).await;
+        mailbox.pop(Some(&[2])).await;
+        let digest = mailbox.digest();
+        assert_eq!(digest.count, 3);
+        assert_eq!(digest.total_bytes, 35);
+        assert_eq!(digest.tags, vec![(None, 1), (Some(1), 1), (Some(3), 1)]);
+
+        mailbox.drain();
+        assert_eq!(mailbox.digest(), Default::default());
+    }
+
+    #[test]
+    fn digest_is_consistent_with_concurrent_pushes() {
+        let mailbox = MessageMailbox::default();
+        let senders: Vec<_> = (0..4)
+            .map(|tag| {
+                let mailbox = mailbox.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        mailbox.push(Message::Data(DataMessage::new_from_vec(
+                            Some(tag),
+                            vec![0; 2],
+                        )));
+                    }
+                })
+            })
+            .collect();
+        while senders.iter().any(|sender| !sender.is_finished()) {
+            let digest = mailbox.digest();
+            let tagged: usize = digest.tags.iter().map(|(_, count)| count).sum();
+            assert_eq!(tagged, digest.count);
+            assert_eq!(digest.total_bytes, digest.count * 2);
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert_eq!(mailbox.digest().count, 4000);
+    }
+
     #[derive(Clone)]
     struct FlagWaker(Arc<Mutex<bool>>);
     impl Wake for FlagWaker {
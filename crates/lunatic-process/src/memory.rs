@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks the memory used by all processes on a node and enforces an optional limit on it.
+///
+/// Each process limits its own memory through the process configuration, but together they
+/// could still exhaust the host. Processes reserve memory here before growing and release it
+/// once they exit.
+#[derive(Debug, Default)]
+pub struct NodeMemory {
+    used: AtomicUsize,
+    limit: Option<usize>,
+}
+
+impl NodeMemory {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+            limit,
+        }
+    }
+
+    /// Returns the number of bytes currently used by all processes.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Reserves `bytes` of memory, returns false if this would exceed the limit.
+    pub fn reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                let used = used.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if used > limit => None,
+                    _ => Some(used),
+                }
+            })
+            .is_ok()
+    }
+
+    /// Releases `bytes` of previously reserved memory.
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeMemory;
+
+    #[test]
+    fn reserve_up_to_limit() {
+        let memory = NodeMemory::new(Some(100));
+        assert!(memory.reserve(60));
+        assert!(memory.reserve(40));
+        assert!(!memory.reserve(1));
+        assert_eq!(memory.used(), 100);
+    }
+
+    #[test]
+    fn release_frees_memory() {
+        let memory = NodeMemory::new(Some(100));
+        assert!(memory.reserve(100));
+        memory.release(50);
+        assert_eq!(memory.used(), 50);
+        assert!(memory.reserve(50));
+    }
+
+    #[test]
+    fn no_limit() {
+        let memory = NodeMemory::default();
+        assert!(memory.reserve(usize::MAX));
+        assert!(!memory.reserve(1));
+    }
+}
@@ -12,6 +12,7 @@ use std::{
 };
 
 use lunatic_networking_api::{TcpConnection, TlsConnection};
+use serde::{Deserialize, Serialize};
 use tokio::net::UdpSocket;
 
 use crate::runtimes::wasmtime::WasmtimeCompiledModule;
@@ -39,6 +40,13 @@ impl Message {
         }
     }
 
+    pub fn priority(&self) -> Priority {
+        match self {
+            Message::Data(message) => message.priority,
+            Message::LinkDied(_) => Priority::Normal,
+        }
+    }
+
     #[cfg(feature = "metrics")]
     pub fn write_metrics(&self) {
         match self {
@@ -50,6 +58,96 @@ impl Message {
     }
 }
 
+/// The node and process a [`DataMessage`] was sent from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageSender {
+    pub node_id: u64,
+    pub process_id: u64,
+}
+
+/// Delivery priority of a [`DataMessage`].
+///
+/// A message is received before all waiting messages of a lower priority, messages of the same
+/// priority are received in the order they arrived. The priority is kept when the message is sent
+/// to another node.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl TryFrom<u32> for Priority {
+    type Error = u32;
+
+    fn try_from(priority: u32) -> Result<Self, Self::Error> {
+        match priority {
+            0 => Ok(Priority::Low),
+            1 => Ok(Priority::Normal),
+            2 => Ok(Priority::High),
+            priority => Err(priority),
+        }
+    }
+}
+
+impl From<Priority> for u32 {
+    fn from(priority: Priority) -> Self {
+        priority as u32
+    }
+}
+
+/// Temporarily raises the [`Priority`] of all messages a process sends.
+///
+/// Each boost raises the priority by one level, up to [`PriorityBoost::MAX_LEVEL`]. Boosts nest,
+/// a boost is only undone by its matching restore, boosts past the maximum are counted but don't
+/// raise the priority further. The boost belongs to the process and ends when it exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PriorityBoost {
+    depth: u32,
+}
+
+impl PriorityBoost {
+    /// Maximum number of levels a priority is raised by.
+    pub const MAX_LEVEL: u32 = 2;
+
+    /// Raises the priority and returns the new boost level.
+    pub fn boost(&mut self) -> u32 {
+        self.depth = self.depth.saturating_add(1);
+        self.level()
+    }
+
+    /// Undoes the last boost and returns the new boost level, or `None` if there is no boost.
+    pub fn restore(&mut self) -> Option<u32> {
+        self.depth = self.depth.checked_sub(1)?;
+        Some(self.level())
+    }
+
+    /// Number of levels the priority of sent messages is currently raised by.
+    pub fn level(&self) -> u32 {
+        self.depth.min(Self::MAX_LEVEL)
+    }
+
+    /// Raises `priority` by the current boost level.
+    pub fn raise(&self, priority: Priority) -> Priority {
+        // Priorities past the highest one are capped
+        Priority::try_from(u32::from(priority) + self.level()).unwrap_or(Priority::High)
+    }
+
+    /// Raises the priority of a data message by the current boost level.
+    pub fn apply(&self, message: Message) -> Message {
+        match message {
+            Message::Data(message) if self.depth > 0 => {
+                let priority = self.raise(message.priority);
+                Message::Data(message.with_priority(priority))
+            }
+            message => message,
+        }
+    }
+}
+
 /// A variant of a [`Message`] that has a buffer of data and resources attached to it.
 ///
 /// It implements the [`Read`](std::io::Read) and [`Write`](std::io::Write) traits.
@@ -60,6 +158,15 @@ pub struct DataMessage {
     pub read_ptr: usize,
     pub buffer: Vec<u8>,
     pub resources: Vec<Option<Arc<Resource>>>,
+    // Only set for messages that arrived from other nodes
+    pub sender: Option<MessageSender>,
+    pub priority: Priority,
+    // Receipt the message is acknowledged with, only set for messages that arrived from other
+    // nodes with at-least-once delivery
+    pub receipt: Option<u64>,
+    // Id the sending node assigned to the message, unique among the messages it sends. Only set
+    // for messages that arrived from other nodes with at-least-once delivery.
+    pub unique_id: Option<u64>,
 }
 
 impl DataMessage {
@@ -70,6 +177,10 @@ impl DataMessage {
             read_ptr: 0,
             buffer: Vec::with_capacity(buffer_capacity),
             resources: Vec::new(),
+            sender: None,
+            priority: Priority::Normal,
+            receipt: None,
+            unique_id: None,
         }
     }
 
@@ -80,9 +191,18 @@ impl DataMessage {
             read_ptr: 0,
             buffer,
             resources: Vec::new(),
+            sender: None,
+            priority: Priority::Normal,
+            receipt: None,
+            unique_id: None,
         }
     }
 
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     /// Adds a resource to the message and returns the index of it inside of the message.
     ///
     /// The resource is `Any` and is downcasted when accessing later.
@@ -145,7 +265,11 @@ impl DataMessage {
         metrics::histogram!("lunatic.process.messages.data.size", self.size() as f64);
     }
 
-    fn take_downcast<T: Send + Sync + 'static>(&mut self, index: usize) -> Option<Arc<T>> {
+    /// Takes a resource of type `T` from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not of type `T` the function will return
+    /// None.
+    pub fn take_downcast<T: Send + Sync + 'static>(&mut self, index: usize) -> Option<Arc<T>> {
         let resource = self.resources.get_mut(index);
         match resource {
             Some(resource_ref) => {
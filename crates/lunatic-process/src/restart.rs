@@ -0,0 +1,260 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{ExecutionResult, ResultValue};
+
+/// Restarts a process by calling its entry function again, depending on how it finished.
+///
+/// The restarted process keeps its id, mailbox and resources, only the wasm instance is created
+/// again. If the process was restarted `max_restarts` times within `window`, it's not restarted
+/// anymore and finishes with the last result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,
+    pub window: Duration,
+    pub restart: RestartType,
+}
+
+/// Which exits of a process lead to a restart, following the child restart types of OTP.
+///
+/// A process that is killed is never restarted, independent of the type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartType {
+    /// Restarted after every exit, including a normal one.
+    Permanent,
+    /// Restarted only if it trapped.
+    #[default]
+    Transient,
+    /// Never restarted.
+    Temporary,
+}
+
+impl RestartType {
+    /// Code of the type used by guests.
+    pub fn code(&self) -> u32 {
+        match self {
+            RestartType::Permanent => 0,
+            RestartType::Transient => 1,
+            RestartType::Temporary => 2,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(RestartType::Permanent),
+            1 => Some(RestartType::Transient),
+            2 => Some(RestartType::Temporary),
+            _ => None,
+        }
+    }
+
+    // Processes that couldn't be instantiated again are not restarted, it won't help
+    fn restarts(&self, result: &ResultValue) -> bool {
+        matches!(
+            (self, result),
+            (
+                RestartType::Permanent | RestartType::Transient,
+                ResultValue::Failed(_) | ResultValue::ResourceLimitExceeded(_)
+            ) | (
+                RestartType::Permanent,
+                ResultValue::Ok | ResultValue::Exited(_)
+            )
+        )
+    }
+}
+
+struct Restarts {
+    policy: RestartPolicy,
+    // Times of the restarts within the window
+    restarts: VecDeque<Instant>,
+}
+
+impl Restarts {
+    fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    // Records a restart if the policy allows another one.
+    fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while let Some(&oldest) = self.restarts.front() {
+            if now.duration_since(oldest) < self.policy.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.policy.max_restarts as usize {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+// Runs `restart` with the state of the finished process for as long as the restart type of the
+// policy restarts it and the policy allows it, and returns the result of the last run. Killed
+// processes never get here.
+pub(crate) async fn restart_on_failure<S, F, Fut>(
+    id: u64,
+    policy: Option<RestartPolicy>,
+    result: ExecutionResult<S>,
+    mut restart: F,
+) -> ExecutionResult<S>
+where
+    F: FnMut(S) -> Fut,
+    Fut: Future<Output = ExecutionResult<S>>,
+{
+    let mut restarts = match policy {
+        Some(policy) => Restarts::new(policy),
+        None => return result,
+    };
+    let mut result = result;
+    while restarts.policy.restart.restarts(&result.result) {
+        if !restarts.allow() {
+            warn!("Process {id} was restarted too often, not restarting it anymore");
+            break;
+        }
+        match result.failure() {
+            Some(failure) => warn!("Process {id} failed, restarting it: {failure}"),
+            None => warn!("Process {id} finished, restarting it"),
+        }
+        result = restart(result.state).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{restart_on_failure, RestartPolicy, RestartType};
+    use crate::{ExecutionResult, ResultValue};
+
+    // A process that traps the first `traps` times it runs, the state counts the runs
+    fn run(runs: u32, traps: u32) -> ExecutionResult<u32> {
+        let result = if runs <= traps {
+            ResultValue::Failed("trap".to_string())
+        } else {
+            ResultValue::Ok
+        };
+        ExecutionResult {
+            state: runs,
+            result,
+        }
+    }
+
+    #[tokio::test]
+    async fn trapping_process_is_restarted() {
+        let policy = RestartPolicy {
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            restart: RestartType::Transient,
+        };
+        let result = restart_on_failure(1, Some(policy), run(1, 2), |runs| async move {
+            run(runs + 1, 2)
+        })
+        .await;
+        assert!(result.failure().is_none());
+        assert_eq!(result.state(), 3);
+    }
+
+    #[tokio::test]
+    async fn restarts_are_limited_within_window() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_secs(60),
+            restart: RestartType::Transient,
+        };
+        let result = restart_on_failure(1, Some(policy), run(1, 2), |runs| async move {
+            run(runs + 1, 2)
+        })
+        .await;
+        assert_eq!(result.failure().as_deref(), Some("trap"));
+        assert_eq!(result.state(), 2);
+
+        // Without a policy the process is never restarted
+        let result =
+            restart_on_failure(1, None, run(1, 2), |runs| async move { run(runs + 1, 2) }).await;
+        assert_eq!(result.state(), 1);
+    }
+
+    #[tokio::test]
+    async fn restarts_outside_of_window_are_forgotten() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_millis(10),
+            restart: RestartType::Transient,
+        };
+        let result = restart_on_failure(1, Some(policy), run(1, 2), |runs| async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            run(runs + 1, 2)
+        })
+        .await;
+        assert!(result.failure().is_none());
+        assert_eq!(result.state(), 3);
+    }
+
+    fn policy(restart: RestartType) -> Option<RestartPolicy> {
+        Some(RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+            restart,
+        })
+    }
+
+    #[tokio::test]
+    async fn transient_process_is_only_restarted_after_trap() {
+        // Finishes normally the first time
+        let result = restart_on_failure(
+            1,
+            policy(RestartType::Transient),
+            run(1, 0),
+            |runs| async move { run(runs + 1, 0) },
+        )
+        .await;
+        assert_eq!(result.state(), 1);
+
+        let result = restart_on_failure(
+            1,
+            policy(RestartType::Transient),
+            run(1, 1),
+            |runs| async move { run(runs + 1, 1) },
+        )
+        .await;
+        assert!(result.failure().is_none());
+        assert_eq!(result.state(), 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_and_temporary_processes() {
+        // Permanent processes are restarted after normal exits too, until the policy gives up
+        let result = restart_on_failure(
+            1,
+            policy(RestartType::Permanent),
+            run(1, 0),
+            |runs| async move { run(runs + 1, 0) },
+        )
+        .await;
+        assert!(result.failure().is_none());
+        assert_eq!(result.state(), 6);
+
+        let result = restart_on_failure(
+            1,
+            policy(RestartType::Temporary),
+            run(1, 1),
+            |runs| async move { run(runs + 1, 1) },
+        )
+        .await;
+        assert_eq!(result.failure().as_deref(), Some("trap"));
+        assert_eq!(result.state(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+/*!
+A [`MessageRing`] is an optional receive buffer for processes that get large volumes of small
+messages.
+
+Instead of keeping each message in the mailbox queue, small data messages are copied into one
+fixed size buffer and the original message is dropped right away. The guest can then drain many
+messages with a single call. Entries are stored as `[tag: i64 LE][size: u32 LE][data]`, untagged
+messages use the tag 0.
+*/
+
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+
+use crate::message::{DataMessage, Priority};
+
+/// Size of the header in front of each entry.
+pub const RING_HEADER_SIZE: usize = 12;
+
+/// What happens to a message that doesn't fit into a full ring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RingOverflow {
+    /// Remove the oldest entries until the new message fits, they are lost.
+    DropOldest,
+    /// Keep the ring untouched and put the new message into the regular mailbox queue.
+    Reject,
+}
+
+/// Result of a [`MessageRing::drain`] call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RingDrain {
+    /// Number of entries copied into the buffer.
+    pub messages: usize,
+    /// Number of bytes written to the buffer.
+    pub bytes: usize,
+    /// Number of entries lost to [`RingOverflow::DropOldest`] since the previous drain.
+    pub dropped: u64,
+}
+
+pub struct MessageRing {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    max_message_size: usize,
+    overflow: RingOverflow,
+    entries: usize,
+    dropped: u64,
+}
+
+impl MessageRing {
+    /// Allocates a ring of `capacity` bytes that accepts messages with up to `max_message_size`
+    /// bytes of data.
+    pub fn new(capacity: usize, max_message_size: usize, overflow: RingOverflow) -> Result<Self> {
+        if max_message_size + RING_HEADER_SIZE > capacity || max_message_size > u32::MAX as usize {
+            return Err(anyhow!(
+                "Ring of {capacity} bytes can't hold messages of {max_message_size} bytes"
+            ));
+        }
+        Ok(Self {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            max_message_size,
+            overflow,
+            entries: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Returns true if the message should be written into the ring instead of the mailbox queue.
+    ///
+    /// Messages carrying resources, coming from other nodes or with a priority other than the
+    /// default are never accepted.
+    pub fn accepts(&self, message: &DataMessage) -> bool {
+        message.resources.is_empty()
+            && message.sender.is_none()
+            && message.priority == Priority::Normal
+            && message.buffer.len() <= self.max_message_size
+    }
+
+    /// Copies the message into the ring, or returns it back if the ring is full and the overflow
+    /// behaviour is [`RingOverflow::Reject`].
+    ///
+    /// The backing buffer is allocated once when the ring is created and never grows.
+    pub fn push(&mut self, message: DataMessage) -> Result<(), Box<DataMessage>> {
+        let entry_size = RING_HEADER_SIZE + message.buffer.len();
+        if self.buffer.len() + entry_size > self.capacity {
+            match self.overflow {
+                RingOverflow::Reject => return Err(Box::new(message)),
+                RingOverflow::DropOldest => {
+                    while self.buffer.len() + entry_size > self.capacity {
+                        self.pop_front();
+                        self.dropped += 1;
+                    }
+                }
+            }
+        }
+        self.buffer
+            .extend(message.tag.unwrap_or(0).to_le_bytes().iter());
+        self.buffer
+            .extend((message.buffer.len() as u32).to_le_bytes().iter());
+        self.buffer.extend(message.buffer.iter());
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Moves as many whole entries as fit into `buffer`, oldest first.
+    pub fn drain(&mut self, buffer: &mut [u8]) -> RingDrain {
+        let mut drain = RingDrain {
+            dropped: std::mem::take(&mut self.dropped),
+            ..Default::default()
+        };
+        while let Some(entry_size) = self.front_entry_size() {
+            if drain.bytes + entry_size > buffer.len() {
+                break;
+            }
+            for (to, from) in buffer[drain.bytes..drain.bytes + entry_size]
+                .iter_mut()
+                .zip(self.buffer.drain(..entry_size))
+            {
+                *to = from;
+            }
+            self.entries -= 1;
+            drain.messages += 1;
+            drain.bytes += entry_size;
+        }
+        drain
+    }
+
+    /// Turns all entries back into data messages, oldest first.
+    pub fn into_messages(mut self) -> Vec<DataMessage> {
+        let mut messages = Vec::with_capacity(self.entries);
+        while let Some(entry_size) = self.front_entry_size() {
+            let (tag, _) = self.front_header();
+            let data = self
+                .buffer
+                .drain(..entry_size)
+                .skip(RING_HEADER_SIZE)
+                .collect();
+            let tag = match tag {
+                0 => None,
+                tag => Some(tag),
+            };
+            messages.push(DataMessage::new_from_vec(tag, data));
+        }
+        messages
+    }
+
+    /// Number of entries in the ring.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(entry_size) = self.front_entry_size() {
+            self.buffer.drain(..entry_size);
+            self.entries -= 1;
+        }
+    }
+
+    fn front_entry_size(&self) -> Option<usize> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let (_, size) = self.front_header();
+        Some(RING_HEADER_SIZE + size as usize)
+    }
+
+    fn front_header(&self) -> (i64, u32) {
+        let mut header = [0; RING_HEADER_SIZE];
+        for (to, from) in header.iter_mut().zip(self.buffer.iter()) {
+            *to = *from;
+        }
+        let tag = i64::from_le_bytes(header[..8].try_into().expect("8 bytes"));
+        let size = u32::from_le_bytes(header[8..].try_into().expect("4 bytes"));
+        (tag, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use super::{MessageRing, RingDrain, RingOverflow, RING_HEADER_SIZE};
+    use crate::{
+        mailbox::MessageMailbox,
+        message::{DataMessage, Message},
+    };
+
+    // Counts allocations per thread, so that tests running in parallel don't interfere.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    fn data(tag: i64, data: &[u8]) -> DataMessage {
+        DataMessage::new_from_vec(Some(tag), data.to_vec())
+    }
+
+    fn entries(buffer: &[u8]) -> Vec<(i64, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut buffer = buffer;
+        while !buffer.is_empty() {
+            let tag = i64::from_le_bytes(buffer[..8].try_into().unwrap());
+            let size = u32::from_le_bytes(buffer[8..12].try_into().unwrap()) as usize;
+            entries.push((tag, buffer[12..12 + size].to_vec()));
+            buffer = &buffer[12 + size..];
+        }
+        entries
+    }
+
+    #[test]
+    fn drop_oldest_overwrites_entries() {
+        let mut ring =
+            MessageRing::new(3 * (RING_HEADER_SIZE + 4), 4, RingOverflow::DropOldest).unwrap();
+        for tag in 1..=5 {
+            ring.push(data(tag, &[tag as u8; 4])).unwrap();
+        }
+        assert_eq!(ring.len(), 3);
+
+        let mut buffer = [0; 128];
+        let drain = ring.drain(&mut buffer);
+        assert_eq!(drain.messages, 3);
+        assert_eq!(drain.dropped, 2);
+        let tags: Vec<_> = entries(&buffer[..drain.bytes])
+            .into_iter()
+            .map(|(tag, _)| tag)
+            .collect();
+        assert_eq!(tags, vec![3, 4, 5]);
+        assert_eq!(ring.drain(&mut buffer), RingDrain::default());
+    }
+
+    #[test]
+    fn reject_returns_message() {
+        let mut ring =
+            MessageRing::new(2 * (RING_HEADER_SIZE + 4), 4, RingOverflow::Reject).unwrap();
+        ring.push(data(1, b"abcd")).unwrap();
+        ring.push(data(2, b"efgh")).unwrap();
+        let rejected = ring.push(data(3, b"ijkl")).unwrap_err();
+        assert_eq!(rejected.tag, Some(3));
+
+        // Only whole entries are drained
+        let mut buffer = [0; RING_HEADER_SIZE + 6];
+        let drain = ring.drain(&mut buffer);
+        assert_eq!(drain.messages, 1);
+        assert_eq!(entries(&buffer[..drain.bytes]), vec![(1, b"abcd".to_vec())]);
+
+        let messages = ring.into_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].tag, Some(2));
+        assert_eq!(messages[0].buffer, b"efgh");
+    }
+
+    #[test]
+    fn rejects_too_large_messages_on_creation() {
+        assert!(MessageRing::new(16, 8, RingOverflow::Reject).is_err());
+        assert!(MessageRing::new(20, 8, RingOverflow::Reject).is_ok());
+    }
+
+    #[test]
+    fn flooded_mailbox_drains_in_batches_without_allocating() {
+        const MESSAGES: usize = 10_000;
+        let flood = || -> Vec<Message> {
+            (0..MESSAGES)
+                .map(|i| Message::Data(data(i as i64 + 1, &(i as u32).to_le_bytes())))
+                .collect()
+        };
+
+        // Regular mailbox, every message is queued and received on its own.
+        let mailbox = MessageMailbox::default();
+        let messages = flood();
+        let before = allocations();
+        for message in messages {
+            mailbox.push(message);
+        }
+        let received = mailbox.drain();
+        let queue_allocations = allocations() - before;
+        assert_eq!(received.len(), MESSAGES);
+        drop(received);
+
+        // Ring large enough for all messages, drained into a reused 4 KiB buffer.
+        let mailbox = MessageMailbox::default();
+        mailbox
+            .register_ring(MESSAGES * (RING_HEADER_SIZE + 4), 4, RingOverflow::Reject)
+            .unwrap();
+        let messages = flood();
+        let mut buffer = vec![0; 4096];
+        let before = allocations();
+        for message in messages {
+            mailbox.push(message);
+        }
+        let mut batches = 0;
+        let mut drained = 0;
+        let mut next_tag = 1;
+        loop {
+            let drain = mailbox.drain_ring(&mut buffer).unwrap();
+            if drain.messages == 0 {
+                break;
+            }
+            batches += 1;
+            drained += drain.messages;
+            // Check order without allocating
+            for entry in buffer[..drain.bytes].chunks_exact(RING_HEADER_SIZE + 4) {
+                assert_eq!(i64::from_le_bytes(entry[..8].try_into().unwrap()), next_tag);
+                next_tag += 1;
+            }
+        }
+        let ring_allocations = allocations() - before;
+
+        assert_eq!(drained, MESSAGES);
+        assert!(mailbox.is_empty());
+        assert!(batches <= MESSAGES / 100);
+        assert_eq!(ring_allocations, 0);
+        assert!(queue_allocations > ring_allocations);
+    }
+}
@@ -6,7 +6,10 @@
 //! NOTE: This traits are not used at all. Until rust supports async-traits all functions working
 //!       with a runtime will directly take `wasmtime::WasmtimeRuntime` instead of a generic.
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 use dashmap::DashMap;
@@ -73,14 +76,23 @@ pub trait WasmInstance {
     /* async fn call(&mut self, function: &str, params: Vec<Self::Param>) -> Result<()>; */
 }
 
+/// Cache of compiled modules.
+///
+/// By default modules are kept forever. With a memory budget the least recently used modules are
+/// evicted once the cached modules use more memory than the budget. Modules that still have
+/// running processes are never evicted, so the cache can temporarily exceed the budget. Evicted
+/// modules need to be compiled again the next time they are used.
 pub struct Modules<T> {
     modules: Arc<DashMap<u64, Arc<WasmtimeCompiledModule<T>>>>,
+    // Only tracked if the cache has a memory budget
+    usage: Option<Arc<Mutex<ModuleUsage>>>,
 }
 
 impl<T> Clone for Modules<T> {
     fn clone(&self) -> Self {
         Self {
             modules: self.modules.clone(),
+            usage: self.usage.clone(),
         }
     }
 }
@@ -89,13 +101,47 @@ impl<T> Default for Modules<T> {
     fn default() -> Self {
         Self {
             modules: Arc::new(DashMap::new()),
+            usage: None,
         }
     }
 }
 
+impl<T> Modules<T> {
+    /// Creates a cache that keeps the memory used by modules without running processes below
+    /// `budget` bytes.
+    pub fn with_memory_budget(budget: usize) -> Self {
+        Self {
+            modules: Arc::new(DashMap::new()),
+            usage: Some(Arc::new(Mutex::new(ModuleUsage::new(budget)))),
+        }
+    }
+
+    /// Memory used by the cached modules and the number of evicted modules, if the cache has a
+    /// memory budget.
+    pub fn cache_usage(&self) -> Option<(usize, u64)> {
+        self.usage.as_ref().map(|usage| {
+            let usage = usage.lock().unwrap();
+            (usage.size, usage.evictions)
+        })
+    }
+
+    /// Number of compiled modules in the cache.
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
+
 impl<T: ProcessState + 'static> Modules<T> {
     pub fn get(&self, module_id: u64) -> Option<Arc<WasmtimeCompiledModule<T>>> {
-        self.modules.get(&module_id).map(|m| m.clone())
+        let module = self.modules.get(&module_id).map(|m| m.clone());
+        if let (Some(_), Some(usage)) = (&module, &self.usage) {
+            usage.lock().unwrap().touch(module_id);
+        }
+        module
     }
 
     pub fn compile(
@@ -104,6 +150,7 @@ impl<T: ProcessState + 'static> Modules<T> {
         wasm: RawWasm,
     ) -> JoinHandle<Result<Arc<WasmtimeCompiledModule<T>>>> {
         let modules = self.modules.clone();
+        let usage = self.usage.clone();
         tokio::task::spawn_blocking(move || {
             let id = wasm.id;
             match runtime.compile_module(wasm) {
@@ -111,6 +158,18 @@ impl<T: ProcessState + 'static> Modules<T> {
                     let module = Arc::new(m);
                     if let Some(id) = id {
                         modules.insert(id, Arc::clone(&module));
+                        if let Some(usage) = usage {
+                            let mut usage = usage.lock().unwrap();
+                            usage.insert(id, module.memory_size());
+                            // The new module is held here, so it's never evicted right away
+                            usage.evict(|id| {
+                                modules
+                                    .remove_if(&id, |_, module| {
+                                        Arc::strong_count(module) == 1 && !module.is_shared()
+                                    })
+                                    .is_some()
+                            });
+                        }
                     }
                     Ok(module)
                 }
@@ -119,3 +178,110 @@ impl<T: ProcessState + 'static> Modules<T> {
         })
     }
 }
+
+// Memory used by cached modules and when they were last used
+struct ModuleUsage {
+    budget: usize,
+    size: usize,
+    // Module ID -> (memory size, last use)
+    modules: HashMap<u64, (usize, u64)>,
+    // Incremented on every use
+    clock: u64,
+    evictions: u64,
+}
+
+impl ModuleUsage {
+    fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            size: 0,
+            modules: HashMap::new(),
+            clock: 0,
+            evictions: 0,
+        }
+    }
+
+    fn touch(&mut self, id: u64) {
+        self.clock += 1;
+        if let Some((_, last_use)) = self.modules.get_mut(&id) {
+            *last_use = self.clock;
+        }
+    }
+
+    fn insert(&mut self, id: u64, size: usize) {
+        self.clock += 1;
+        if let Some((previous, _)) = self.modules.insert(id, (size, self.clock)) {
+            self.size -= previous;
+        }
+        self.size += size;
+    }
+
+    // Evicts the least recently used modules until the cache is within the budget. `evict`
+    // removes the module from the cache and returns `false` if the module is still in use.
+    fn evict(&mut self, mut evict: impl FnMut(u64) -> bool) {
+        if self.size > self.budget {
+            let mut lru: Vec<_> = self
+                .modules
+                .iter()
+                .map(|(id, (_, last_use))| (*last_use, *id))
+                .collect();
+            lru.sort_unstable();
+            for (_, id) in lru {
+                if self.size <= self.budget {
+                    break;
+                }
+                if evict(id) {
+                    if let Some((size, _)) = self.modules.remove(&id) {
+                        self.size -= size;
+                    }
+                    self.evictions += 1;
+                    #[cfg(feature = "metrics")]
+                    metrics::increment_counter!("lunatic.process.modules.evictions");
+                }
+            }
+        }
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("lunatic.process.modules.cache.size", self.size as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::ModuleUsage;
+
+    #[test]
+    fn least_recently_used_modules_without_processes_are_evicted() {
+        let mut usage = ModuleUsage::new(300);
+        let mut cached = HashSet::new();
+        // Modules 1 and 4 have running processes
+        let in_use = [1, 4];
+        let mut compile = |usage: &mut ModuleUsage, id: u64| {
+            cached.insert(id);
+            usage.insert(id, 100);
+            usage.evict(|id| !in_use.contains(&id) && cached.remove(&id));
+            cached.clone()
+        };
+
+        for id in 1..=3 {
+            compile(&mut usage, id);
+        }
+        assert_eq!(usage.evictions, 0);
+        // Module 2 is used again, so 3 is the least recently used one without processes
+        usage.touch(2);
+        assert_eq!(compile(&mut usage, 4), HashSet::from([1, 2, 4]));
+        assert_eq!(compile(&mut usage, 5), HashSet::from([1, 4, 5]));
+        assert_eq!((usage.size, usage.evictions), (300, 2));
+
+        // Evicted modules are compiled again on the next spawn
+        assert_eq!(compile(&mut usage, 3), HashSet::from([1, 3, 4]));
+
+        // The budget is exceeded while the modules are in use
+        let mut in_use_only = ModuleUsage::new(100);
+        in_use_only.insert(1, 100);
+        in_use_only.insert(4, 100);
+        in_use_only.evict(|id| !in_use.contains(&id));
+        assert_eq!((in_use_only.size, in_use_only.evictions), (200, 0));
+    }
+}
@@ -6,7 +6,7 @@ use wasmtime::ResourceLimiter;
 use crate::{
     config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
     state::ProcessState,
-    ExecutionResult, ResultValue,
+    ExecutionResult, ExitReason, ResultValue,
 };
 
 use super::RawWasm;
@@ -111,6 +111,16 @@ impl<T> WasmtimeCompiledModule<T> {
     pub fn instantiator(&self) -> &wasmtime::InstancePre<T> {
         &self.inner.instance_pre
     }
+
+    /// Approximate memory used by the module, the source and the compiled code.
+    pub fn memory_size(&self) -> usize {
+        self.inner.source.bytes.len() + self.inner.module.image_range().len()
+    }
+
+    // Processes spawned from the module hold a clone of it
+    pub(crate) fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.inner) > 1
+    }
 }
 
 impl<T> Clone for WasmtimeCompiledModule<T> {
@@ -131,7 +141,7 @@ where
 
 impl<T> WasmtimeInstance<T>
 where
-    T: Send,
+    T: ProcessState + Send,
 {
     pub async fn call(mut self, function: &str, params: Vec<wasmtime::Val>) -> ExecutionResult<T> {
         let entry = self.instance.get_func(&mut self.store, function);
@@ -148,18 +158,25 @@ where
             .call_async(&mut self.store, &params, &mut [])
             .await;
 
+        let result = match result {
+            Ok(()) => ResultValue::Ok,
+            // The process called `exit` with a reason of its own
+            Err(err) => match err.downcast::<ExitReason>() {
+                Ok(reason) => ResultValue::Exited(reason),
+                // If the trap is a result of calling `proc_exit(0)`, treat it as an no-error finish.
+                Err(err) => match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                    Some(wasmtime_wasi::I32Exit(0)) => ResultValue::Ok,
+                    // A trap after a rejected growth is most likely caused by it
+                    _ => match self.store.data().resource_limit_exceeded() {
+                        Some(exceeded) => ResultValue::ResourceLimitExceeded(*exceeded),
+                        None => ResultValue::Failed(err.to_string()),
+                    },
+                },
+            },
+        };
         ExecutionResult {
             state: self.store.into_data(),
-            result: match result {
-                Ok(()) => ResultValue::Ok,
-                Err(err) => {
-                    // If the trap is a result of calling `proc_exit(0)`, treat it as an no-error finish.
-                    match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
-                        Some(wasmtime_wasi::I32Exit(0)) => ResultValue::Ok,
-                        _ => ResultValue::Failed(err.to_string()),
-                    }
-                }
-            },
+            result,
         }
     }
 }
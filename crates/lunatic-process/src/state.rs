@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
 use dashmap::DashMap;
@@ -11,7 +14,11 @@ use wasmtime::Linker;
 
 use crate::{
     config::ProcessConfig,
+    kv::{KvCheckpoints, KvStore},
+    latency::SchedulingLatency,
+    limits::ResourceLimitExceeded,
     mailbox::MessageMailbox,
+    message::PriorityBoost,
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
     Signal,
 };
@@ -59,6 +66,20 @@ pub trait ProcessState: Sized {
     fn signal_mailbox(&self) -> &(SignalSender, SignalReceiver);
     // Returns message mailbox
     fn message_mailbox(&self) -> &MessageMailbox;
+    // Returns the scheduling latency measured for the process
+    fn scheduling_latency(&self) -> &SchedulingLatency;
+    // Returns when the process was started
+    fn start_time(&self) -> &StartTime;
+    // Sets the start time, called when the process is spawned
+    fn set_start_time(&mut self, start_time: StartTime);
+    // Returns the process-local key-value store
+    fn kv(&self) -> &KvStore;
+    fn kv_mut(&mut self) -> &mut KvStore;
+    // Returns the durable store for kv checkpoints, if the node has one
+    fn kv_checkpoints(&self) -> Option<&Arc<KvCheckpoints>>;
+    // Returns the boost of the priority of messages the process sends
+    fn priority_boost(&self) -> &PriorityBoost;
+    fn priority_boost_mut(&mut self) -> &mut PriorityBoost;
 
     // Config resources
     fn config_resources(&self) -> &ConfigResources<Self::Config>;
@@ -66,4 +87,55 @@ pub trait ProcessState: Sized {
 
     // Registry
     fn registry(&self) -> &Arc<DashMap<String, (u64, u64)>>;
+
+    // Returns the last limit the process was stopped at while growing a resource
+    fn resource_limit_exceeded(&self) -> Option<&ResourceLimitExceeded>;
+}
+
+/// When a process was started.
+#[derive(Clone, Copy, Debug)]
+pub struct StartTime {
+    // Used for the uptime, so that it's not affected by changes of the system clock
+    instant: Instant,
+    system: SystemTime,
+}
+
+impl StartTime {
+    pub fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+        }
+    }
+
+    /// How long the process has been running.
+    pub fn uptime(&self) -> Duration {
+        self.instant.elapsed()
+    }
+
+    /// Wall clock time of the start, in milliseconds since the unix epoch.
+    pub fn unix_millis(&self) -> u64 {
+        self.system
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::StartTime;
+
+    #[test]
+    fn uptime_increases_and_start_time_is_stable() {
+        let start_time = StartTime::now();
+        let unix_millis = start_time.unix_millis();
+        let uptime = start_time.uptime();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(start_time.uptime() >= uptime + Duration::from_millis(10));
+        assert_eq!(start_time.unix_millis(), unix_millis);
+        assert!(unix_millis > 0);
+    }
 }
@@ -0,0 +1,178 @@
+/*!
+Named topics that processes inside of an environment can subscribe to.
+
+Publishing to a topic delivers a copy of the message to all processes that are subscribed at that
+moment. Subscriptions of a process are removed when it's removed from the environment.
+*/
+
+use std::collections::HashSet;
+
+use dashmap::DashMap;
+
+use crate::{
+    env::Environment,
+    message::{DataMessage, Message, Priority},
+    Signal,
+};
+
+#[derive(Debug, Default)]
+pub struct Topics {
+    // Topic name -> IDs of subscribed processes
+    subscribers: DashMap<String, HashSet<u64>>,
+    // Process ID -> topics the process is subscribed to
+    subscriptions: DashMap<u64, HashSet<String>>,
+}
+
+impl Topics {
+    pub fn subscribe(&self, topic: &str, process_id: u64) {
+        self.subscribers
+            .entry(topic.to_string())
+            .or_default()
+            .insert(process_id);
+        self.subscriptions
+            .entry(process_id)
+            .or_default()
+            .insert(topic.to_string());
+    }
+
+    /// Returns `false` if the process was not subscribed to the topic.
+    pub fn unsubscribe(&self, topic: &str, process_id: u64) -> bool {
+        let removed = self
+            .subscribers
+            .get_mut(topic)
+            .map(|mut subscribers| subscribers.remove(&process_id))
+            .unwrap_or(false);
+        self.subscribers
+            .remove_if(topic, |_, subscribers| subscribers.is_empty());
+        if let Some(mut topics) = self.subscriptions.get_mut(&process_id) {
+            topics.remove(topic);
+        }
+        self.subscriptions
+            .remove_if(&process_id, |_, topics| topics.is_empty());
+        removed
+    }
+
+    /// Removes all subscriptions of a process.
+    pub fn unsubscribe_all(&self, process_id: u64) {
+        if let Some((_, topics)) = self.subscriptions.remove(&process_id) {
+            for topic in topics {
+                if let Some(mut subscribers) = self.subscribers.get_mut(&topic) {
+                    subscribers.remove(&process_id);
+                }
+                self.subscribers
+                    .remove_if(&topic, |_, subscribers| subscribers.is_empty());
+            }
+        }
+    }
+
+    pub fn subscribers(&self, topic: &str) -> Vec<u64> {
+        self.subscribers
+            .get(topic)
+            .map(|subscribers| subscribers.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Sends a copy of the message to every subscriber of `topic` in the environment and returns the
+/// number of processes it was delivered to.
+pub fn publish(
+    env: &dyn Environment,
+    topic: &str,
+    tag: Option<i64>,
+    priority: Priority,
+    data: &[u8],
+) -> usize {
+    let mut delivered = 0;
+    for process_id in env.topics().subscribers(topic) {
+        if let Some(process) = env.get_process(process_id) {
+            let message = DataMessage::new_from_vec(tag, data.to_vec()).with_priority(priority);
+            if let Some(message) = env.interceptors().apply(message) {
+                process.send(Signal::Message(Message::Data(message)));
+                delivered += 1;
+            }
+        }
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::publish;
+    use crate::{
+        env::{Environment, LunaticEnvironment},
+        message::{Message, Priority},
+        Process, Signal,
+    };
+
+    #[derive(Default)]
+    struct Collector {
+        id: u64,
+        tags: Mutex<Vec<Option<i64>>>,
+    }
+
+    impl Process for Collector {
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn send(&self, signal: Signal) {
+            if let Signal::Message(Message::Data(message)) = signal {
+                self.tags.lock().unwrap().push(message.tag);
+            }
+        }
+    }
+
+    fn collector(env: &LunaticEnvironment, id: u64) -> Arc<Collector> {
+        let collector = Arc::new(Collector {
+            id,
+            ..Default::default()
+        });
+        env.add_process(id, collector.clone());
+        collector
+    }
+
+    #[test]
+    fn subscribe_publish_unsubscribe() {
+        let env = LunaticEnvironment::new(1);
+        let first = collector(&env, 1);
+        let second = collector(&env, 2);
+        env.topics().subscribe("news", 1);
+        env.topics().subscribe("news", 2);
+        env.topics().subscribe("sports", 2);
+
+        assert_eq!(
+            publish(&env, "news", Some(1), Priority::Normal, b"hello"),
+            2
+        );
+        assert_eq!(
+            publish(&env, "sports", Some(2), Priority::Normal, b"goal"),
+            1
+        );
+        assert_eq!(
+            publish(&env, "weather", Some(3), Priority::Normal, b"rain"),
+            0
+        );
+
+        assert!(env.topics().unsubscribe("news", 2));
+        assert!(!env.topics().unsubscribe("news", 2));
+        assert_eq!(publish(&env, "news", Some(4), Priority::Normal, b"bye"), 1);
+
+        assert_eq!(*first.tags.lock().unwrap(), vec![Some(1), Some(4)]);
+        assert_eq!(*second.tags.lock().unwrap(), vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn subscriptions_are_removed_with_process() {
+        let env = LunaticEnvironment::new(1);
+        collector(&env, 1);
+        env.topics().subscribe("news", 1);
+        env.topics().subscribe("sports", 1);
+
+        env.remove_process(1);
+        assert!(env.topics().subscribers("news").is_empty());
+        assert!(env.topics().subscribers("sports").is_empty());
+        assert_eq!(publish(&env, "news", None, Priority::Normal, b"hello"), 0);
+    }
+}
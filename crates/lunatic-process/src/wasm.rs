@@ -1,14 +1,17 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::trace;
 use tokio::task::JoinHandle;
 use wasmtime::{ResourceLimiter, Val};
 
+use crate::config::ProcessConfig;
 use crate::env::Environment;
+use crate::message::PriorityBoost;
+use crate::restart::restart_on_failure;
 use crate::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
-use crate::state::ProcessState;
-use crate::{Process, Signal, WasmProcess};
+use crate::state::{ProcessState, StartTime};
+use crate::{ExecutionResult, Process, ResultValue, Signal, WasmProcess};
 
 /// Spawns a new wasm process from a compiled module.
 ///
@@ -23,7 +26,7 @@ pub async fn spawn_wasm<S>(
     env: Arc<dyn Environment>,
     runtime: WasmtimeRuntime,
     module: &WasmtimeCompiledModule<S>,
-    state: S,
+    mut state: S,
     function: &str,
     params: Vec<Val>,
     link: Option<(Option<i64>, Arc<dyn Process>)>,
@@ -35,14 +38,40 @@ where
     trace!("Spawning process: {}", id);
     let signal_mailbox = state.signal_mailbox().clone();
     let message_mailbox = state.message_mailbox().clone();
+    let scheduling_latency = state.scheduling_latency().clone();
+    state.set_start_time(StartTime::now());
+
+    let restart_policy = state.config().get_restart_policy();
 
     let instance = runtime.instantiate(module, state).await?;
     let function = function.to_string();
-    let fut = async move { instance.call(&function, params).await };
+    let module = module.clone();
+    let fut = async move {
+        let result = instance.call(&function, params.clone()).await;
+        restart_on_failure(id, restart_policy, result, |mut state| {
+            let (runtime, module, function, params) = (&runtime, &module, &function, &params);
+            // A priority boost ends with the run it was started in
+            *state.priority_boost_mut() = PriorityBoost::default();
+            async move {
+                match runtime.instantiate(module, state).await {
+                    Ok(instance) => instance.call(function, params.clone()).await,
+                    // The state is lost with the failed instantiation, but it's only returned if
+                    // the process finished successfully.
+                    Err(error) => ExecutionResult {
+                        state: S::state_for_instantiation(),
+                        result: ResultValue::SpawnError(error.to_string()),
+                    },
+                }
+            }
+        })
+        .await
+    };
     let child_process = crate::new(fut, id, env.clone(), signal_mailbox.1, message_mailbox);
     let child_process_handle = Arc::new(WasmProcess::new(id, signal_mailbox.0.clone()));
 
-    env.add_process(id, child_process_handle.clone());
+    if !env.add_process(id, child_process_handle.clone()) {
+        return Err(anyhow!("Environment {} was destroyed", env.id()));
+    }
 
     // **Child link guarantees**:
     // The link signal is going to be put inside of the child's mailbox and is going to be
@@ -81,6 +110,6 @@ where
 
     // Spawn a background process
     trace!("Process size: {}", std::mem::size_of_val(&child_process));
-    let join = tokio::task::spawn(child_process);
+    let join = tokio::task::spawn(scheduling_latency.measure(child_process));
     Ok((join, child_process_handle))
 }
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use dashmap::mapref::entry::Entry;
 use lunatic_common_api::{get_memory, IntoTrap};
 use lunatic_process::state::ProcessState;
 use lunatic_process_api::ProcessCtx;
@@ -8,7 +9,11 @@ use wasmtime::{Caller, Linker};
 pub fn register<T: ProcessState + ProcessCtx<T> + 'static>(linker: &mut Linker<T>) -> Result<()> {
     linker.func_wrap("lunatic::registry", "put", put)?;
     linker.func_wrap("lunatic::registry", "get", get)?;
+    linker.func_wrap("lunatic::registry", "locate", locate)?;
     linker.func_wrap("lunatic::registry", "remove", remove)?;
+    linker.func_wrap("lunatic::registry", "takeover", takeover)?;
+    linker.func_wrap("lunatic::registry", "set_label", set_label)?;
+    linker.func_wrap("lunatic::registry", "find_by_label", find_by_label)?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -40,6 +45,11 @@ pub fn register<T: ProcessState + ProcessCtx<T> + 'static>(linker: &mut Linker<T
 
 // Registers process with ID under `name`.
 //
+// If the process registers itself on a node with a kv checkpoint store, its process-local kv store
+// is replaced with the checkpoint saved under `name` in the environment, or emptied if there is
+// none. A store that is already named and holds entries is left alone, so registering again under
+// another name doesn't discard the process' state.
+//
 // Traps:
 // * If the process ID doesn't exist.
 // * If any memory outside the guest heap space is referenced.
@@ -60,6 +70,17 @@ fn put<T: ProcessState + ProcessCtx<T>>(
     state
         .registry()
         .insert(name.to_owned(), (node_id, process_id));
+    // A process registering itself under a name continues with the kv store checkpointed under it
+    if process_id == state.id() && (state.kv().name().is_none() || state.kv().is_empty()) {
+        let name = name.to_owned();
+        match state.kv_checkpoints().cloned() {
+            Some(checkpoints) => {
+                let environment_id = state.environment().id();
+                checkpoints.restore(environment_id, &name, state.kv_mut())
+            }
+            None => state.kv_mut().set_name(name),
+        }
+    }
     #[cfg(feature = "metrics")]
     metrics::increment_counter!("lunatic.registry.write");
 
@@ -110,6 +131,39 @@ fn get<T: ProcessState + ProcessCtx<T>>(
     Ok(0)
 }
 
+// Looks up the node of the process under `name`, like `get` without the process ID. Returns 0 if
+// it was found and writes the node ID to `node_id_ptr`, or 1 if not found.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn locate<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    node_id_ptr: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let name = memory_slice
+        .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+        .or_trap("lunatic::registry::locate")?;
+    let name = std::str::from_utf8(name).or_trap("lunatic::registry::locate")?;
+
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("lunatic.registry.read");
+
+    let node_id = if let Some(process) = state.registry().get(name) {
+        process.0
+    } else {
+        return Ok(1);
+    };
+
+    memory
+        .write(&mut caller, node_id_ptr as usize, &node_id.to_le_bytes())
+        .or_trap("lunatic::registry::locate")?;
+    Ok(0)
+}
+
 // Removes process under `name` if it exists.
 //
 // Traps:
@@ -136,3 +190,120 @@ fn remove<T: ProcessState + ProcessCtx<T>>(
 
     Ok(())
 }
+
+// Atomically registers the calling process on the node `node_id` under `name`, taking the name
+// over from the process currently registered under it.
+//
+// If `force` is 0, the name is only taken over if the current holder is dead. Only holders in the
+// same environment on the same node can be checked, holders anywhere else count as alive. If
+// `force` is not 0, the name is taken over in any case.
+//
+// Returns:
+// * 0 if the calling process is now registered under `name`.
+// * 1 if the current holder is alive and the name was not taken over.
+//
+// Traps:
+// * If the name is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn takeover<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    node_id: u64,
+    force: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let name = memory_slice
+        .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+        .or_trap("lunatic::registry::takeover")?;
+    let name = std::str::from_utf8(name).or_trap("lunatic::registry::takeover")?;
+
+    let process_id = state.id();
+    let environment = state.environment();
+    // The entry is locked until the name is reassigned, so a concurrent takeover can't interleave
+    match state.registry().entry(name.to_owned()) {
+        Entry::Occupied(mut entry) => {
+            let (holder_node_id, holder_id) = *entry.get();
+            let is_caller = holder_node_id == node_id && holder_id == process_id;
+            let holder_dead =
+                holder_node_id == node_id && environment.get_process(holder_id).is_none();
+            if force == 0 && !is_caller && !holder_dead {
+                return Ok(1);
+            }
+            entry.insert((node_id, process_id));
+        }
+        Entry::Vacant(entry) => {
+            entry.insert((node_id, process_id));
+            #[cfg(feature = "metrics")]
+            metrics::increment_gauge!("lunatic.registry.registered", 1.0);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("lunatic.registry.write");
+
+    Ok(0)
+}
+
+// Labels the calling process with `label`, replacing its previous label. Many processes in the
+// same environment can have the same label. An empty label removes the label from the process.
+//
+// Traps:
+// * If the label is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn set_label<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    label_str_ptr: u32,
+    label_str_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let label = memory_slice
+        .get(label_str_ptr as usize..(label_str_ptr + label_str_len) as usize)
+        .or_trap("lunatic::registry::set_label")?;
+    let label = std::str::from_utf8(label).or_trap("lunatic::registry::set_label")?;
+
+    let id = state.id();
+    let environment = state.environment();
+    if label.is_empty() {
+        environment.labels().remove(id);
+    } else {
+        environment.labels().set(id, label);
+    }
+    Ok(())
+}
+
+// Copies the IDs of processes in the same environment labeled with `label` into the buffer at
+// `ids_ptr`, but not more than `ids_len` of them.
+//
+// Returns the number of processes with the label, 0 if there are none.
+//
+// Traps:
+// * If the label is not a valid UTF-8 string.
+// * If any memory outside the guest heap space is referenced.
+fn find_by_label<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    label_str_ptr: u32,
+    label_str_len: u32,
+    ids_ptr: u32,
+    ids_len: u32,
+) -> Result<u32> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let label = memory_slice
+        .get(label_str_ptr as usize..(label_str_ptr + label_str_len) as usize)
+        .or_trap("lunatic::registry::find_by_label")?;
+    let label = std::str::from_utf8(label).or_trap("lunatic::registry::find_by_label")?;
+
+    let ids = state.environment().labels().find(label);
+    let copy_len = ids.len().min(ids_len as usize);
+    let data: Vec<u8> = ids[..copy_len]
+        .iter()
+        .flat_map(|id| id.to_le_bytes())
+        .collect();
+    memory
+        .write(&mut caller, ids_ptr as usize, &data)
+        .or_trap("lunatic::registry::find_by_label")?;
+    Ok(ids.len() as u32)
+}
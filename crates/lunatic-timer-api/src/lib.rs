@@ -1,14 +1,20 @@
 use std::{
     cmp::Ordering,
-    collections::BinaryHeap,
+    collections::{BinaryHeap, HashSet},
     future::Future,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use hash_map_id::HashMapId;
 use lunatic_common_api::IntoTrap;
-use lunatic_process::{state::ProcessState, Signal};
+use lunatic_process::{
+    env::Environment,
+    message::{DataMessage, Message},
+    state::ProcessState,
+    Process, Signal,
+};
 use lunatic_process_api::ProcessCtx;
 use tokio::task::JoinHandle;
 use wasmtime::{Caller, Linker};
@@ -21,7 +27,7 @@ struct HeapValue {
 
 impl PartialOrd for HeapValue {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.instant.cmp(&other.instant).reverse())
+        Some(self.cmp(other))
     }
 }
 
@@ -43,6 +49,8 @@ impl Eq for HeapValue {}
 pub struct TimerResources {
     hash_map: HashMapId<JoinHandle<()>>,
     heap: BinaryHeap<HeapValue>,
+    // Interval timers never expire and are aborted when the resources are dropped.
+    intervals: HashSet<u64>,
 }
 
 impl TimerResources {
@@ -74,11 +82,32 @@ impl TimerResources {
         }
     }
 
+    pub fn add_interval(&mut self, handle: JoinHandle<()>) -> u64 {
+        self.cleanup_expired_timers();
+
+        let id = self.hash_map.add(handle);
+        self.intervals.insert(id);
+        id
+    }
+
     pub fn remove(&mut self, id: u64) -> Option<JoinHandle<()>> {
+        self.intervals.remove(&id);
         self.hash_map.remove(id)
     }
 }
 
+// Stop all pending timers when the process owning them exits.
+impl Drop for TimerResources {
+    fn drop(&mut self) {
+        let one_shots = self.heap.drain().map(|timer| timer.key);
+        for id in one_shots.chain(self.intervals.drain()) {
+            if let Some(handle) = self.hash_map.remove(id) {
+                handle.abort();
+            }
+        }
+    }
+}
+
 pub trait TimerCtx {
     fn timer_resources(&self) -> &TimerResources;
     fn timer_resources_mut(&mut self) -> &mut TimerResources;
@@ -88,6 +117,7 @@ pub fn register<T: ProcessState + ProcessCtx<T> + TimerCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
     linker.func_wrap("lunatic::timer", "send_after", send_after)?;
+    linker.func_wrap("lunatic::timer", "send_interval", send_interval)?;
     linker.func_wrap1_async("lunatic::timer", "cancel_timer", cancel_timer)?;
 
     #[cfg(feature = "metrics")]
@@ -139,23 +169,7 @@ fn send_after<T: ProcessState + ProcessCtx<T> + TimerCtx>(
     let process = caller.data_mut().environment().get_process(process_id);
 
     let target_time = Instant::now() + Duration::from_millis(delay);
-    let timer_handle = tokio::task::spawn(async move {
-        #[cfg(feature = "metrics")]
-        metrics::increment_counter!("lunatic.timers.started");
-        #[cfg(feature = "metrics")]
-        metrics::increment_gauge!("lunatic.timers.active", 1.0);
-        let duration_remaining = target_time - Instant::now();
-        if duration_remaining != Duration::ZERO {
-            tokio::time::sleep(duration_remaining).await;
-        }
-        if let Some(process) = process {
-            #[cfg(feature = "metrics")]
-            metrics::increment_counter!("lunatic.timers.completed");
-            #[cfg(feature = "metrics")]
-            metrics::decrement_gauge!("lunatic.timers.active", 1.0);
-            process.send(Signal::Message(message));
-        }
-    });
+    let timer_handle = tokio::task::spawn(send_after_task(process, message, target_time));
 
     let id = caller
         .data_mut()
@@ -164,6 +178,114 @@ fn send_after<T: ProcessState + ProcessCtx<T> + TimerCtx>(
     Ok(id)
 }
 
+// Sends the message to a process every `period` milliseconds, until the timer is canceled, the
+// receiving process exits or the process that created the timer exits.
+//
+// There are no guarantees that the messages will be received.
+//
+// Traps:
+// * If the period is 0.
+// * If it's called before creating the next message.
+// * If the message is not a data message or contains resources.
+fn send_interval<T: ProcessState + ProcessCtx<T> + TimerCtx>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    period: u64,
+) -> Result<u64> {
+    if period == 0 {
+        return Err(anyhow!("Interval period must be bigger than 0"));
+    }
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::send_interval")?;
+    // Each tick sends a copy of the message, so it can't contain resources.
+    let (tag, buffer) = match message {
+        Message::Data(DataMessage {
+            tag,
+            buffer,
+            resources,
+            ..
+        }) if resources.is_empty() => (tag, buffer),
+        _ => {
+            return Err(anyhow!(
+                "Only data messages without resources can be sent on an interval"
+            ))
+        }
+    };
+
+    let environment = caller.data_mut().environment();
+    let period = Duration::from_millis(period);
+    let timer_handle = tokio::task::spawn(send_interval_task(
+        environment,
+        process_id,
+        period,
+        tag,
+        buffer,
+    ));
+
+    let id = caller
+        .data_mut()
+        .timer_resources_mut()
+        .add_interval(timer_handle);
+    Ok(id)
+}
+
+// Sends the message to the process once `target_time` is reached.
+async fn send_after_task(
+    process: Option<Arc<dyn Process>>,
+    message: Message,
+    target_time: Instant,
+) {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("lunatic.timers.started");
+    #[cfg(feature = "metrics")]
+    metrics::increment_gauge!("lunatic.timers.active", 1.0);
+    let duration_remaining = target_time.saturating_duration_since(Instant::now());
+    if duration_remaining != Duration::ZERO {
+        tokio::time::sleep(duration_remaining).await;
+    }
+    if let Some(process) = process {
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!("lunatic.timers.completed");
+        #[cfg(feature = "metrics")]
+        metrics::decrement_gauge!("lunatic.timers.active", 1.0);
+        process.send(Signal::Message(message));
+    }
+}
+
+// Sends a copy of the message to the process every `period`, until the process doesn't exist
+// anymore.
+async fn send_interval_task(
+    environment: Arc<dyn Environment>,
+    process_id: u64,
+    period: Duration,
+    tag: Option<i64>,
+    buffer: Vec<u8>,
+) {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("lunatic.timers.started");
+    #[cfg(feature = "metrics")]
+    metrics::increment_gauge!("lunatic.timers.active", 1.0);
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match environment.get_process(process_id) {
+            Some(process) => process.send(Signal::Message(Message::Data(
+                DataMessage::new_from_vec(tag, buffer.clone()),
+            ))),
+            None => break,
+        }
+    }
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("lunatic.timers.completed");
+    #[cfg(feature = "metrics")]
+    metrics::decrement_gauge!("lunatic.timers.active", 1.0);
+}
+
 // Cancels the specified timer.
 //
 // Returns:
@@ -191,3 +313,132 @@ fn cancel_timer<T: ProcessState + TimerCtx + Send>(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use lunatic_process::{
+        env::{Environment, LunaticEnvironment},
+        message::{DataMessage, Message},
+        Process, Signal,
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use super::{send_after_task, send_interval_task, TimerResources};
+
+    // Forwards the tags of received messages
+    struct Receiver(UnboundedSender<Option<i64>>);
+
+    impl Process for Receiver {
+        fn id(&self) -> u64 {
+            1
+        }
+
+        fn send(&self, signal: Signal) {
+            if let Signal::Message(message) = signal {
+                self.0.send(message.tag()).ok();
+            }
+        }
+    }
+
+    fn receiver() -> (Arc<dyn Process>, UnboundedReceiver<Option<i64>>) {
+        let (sender, tags) = unbounded_channel();
+        (Arc::new(Receiver(sender)), tags)
+    }
+
+    fn message(tag: i64) -> Message {
+        Message::Data(DataMessage::new_from_vec(Some(tag), vec![]))
+    }
+
+    #[tokio::test]
+    async fn one_shot_timer_fires_once() {
+        let (process, mut tags) = receiver();
+        let target_time = Instant::now() + Duration::from_millis(20);
+        let mut timers = TimerResources::default();
+        let handle = tokio::task::spawn(send_after_task(Some(process), message(1), target_time));
+        timers.add(handle, target_time);
+
+        assert_eq!(tags.recv().await, Some(Some(1)));
+        assert!(Instant::now() >= target_time);
+        // The timer is done and dropped its process
+        assert_eq!(tags.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn interval_timer_fires_until_process_exits() {
+        let (process, mut tags) = receiver();
+        let environment = LunaticEnvironment::new(1);
+        environment.add_process(1, process);
+        let period = Duration::from_millis(10);
+        let handle = tokio::task::spawn(send_interval_task(
+            Arc::new(environment.clone()),
+            1,
+            period,
+            Some(2),
+            vec![],
+        ));
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(tags.recv().await, Some(Some(2)));
+        }
+        assert!(started.elapsed() >= period * 3);
+        environment.remove_process(1);
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("interval kept running")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn canceled_timer_does_not_fire() {
+        let (process, mut tags) = receiver();
+        let target_time = Instant::now() + Duration::from_millis(20);
+        let mut timers = TimerResources::default();
+        let handle = tokio::task::spawn(send_after_task(Some(process), message(3), target_time));
+        let id = timers.add(handle, target_time);
+        timers.remove(id).unwrap().abort();
+
+        // The aborted timer drops its process without sending anything
+        assert_eq!(tags.recv().await, None);
+        assert!(timers.remove(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn one_shot_timers_are_aborted_on_drop() {
+        let (process, mut tags) = receiver();
+        let target_time = Instant::now() + Duration::from_secs(60);
+        let mut timers = TimerResources::default();
+        let handle = tokio::task::spawn(send_after_task(Some(process), message(4), target_time));
+        timers.add(handle, target_time);
+        drop(timers);
+        assert_eq!(tags.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn interval_timers_are_aborted_on_drop() {
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::task::spawn(async move {
+            let _sender = sender;
+            std::future::pending::<()>().await
+        });
+        let mut timers = TimerResources::default();
+        timers.add_interval(handle);
+        drop(timers);
+        // The sender is dropped together with the aborted task
+        assert!(receiver.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn canceled_interval_is_not_aborted_again() {
+        let handle = tokio::task::spawn(std::future::pending::<()>());
+        let mut timers = TimerResources::default();
+        let id = timers.add_interval(handle);
+        assert!(timers.remove(id).is_some());
+        assert!(timers.remove(id).is_none());
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use lunatic_process::config::ProcessConfig;
+use lunatic_process::{config::ProcessConfig, lifecycle::Lifecycle, restart::RestartPolicy};
 use lunatic_process_api::ProcessConfigCtx;
 use lunatic_wasi_api::LunaticWasiConfigCtx;
 use serde::{Deserialize, Serialize};
@@ -11,12 +11,20 @@ pub struct DefaultProcessConfig {
     max_memory: usize,
     // Maximum amount of compute expressed in units of 100k instructions.
     max_fuel: Option<u64>,
+    // Restart the process if it traps
+    restart_policy: Option<RestartPolicy>,
+    // How long processes are expected to run, used to place them on nodes
+    lifecycle: Lifecycle,
     // Can this process compile new WebAssembly modules
     can_compile_modules: bool,
     // Can this process create new configurations
     can_create_configs: bool,
     // Can this process spawn sub-processes
     can_spawn_processes: bool,
+    // Can this process add fuel to its own budget
+    can_add_fuel: bool,
+    // Can this process drain the node it's running on
+    can_drain_node: bool,
     // WASI configs
     preopened_dirs: Vec<String>,
     command_line_arguments: Vec<String>,
@@ -28,6 +36,8 @@ impl Debug for DefaultProcessConfig {
         f.debug_struct("EnvConfig")
             .field("max_memory", &self.max_memory)
             .field("max_fuel", &self.max_fuel)
+            .field("restart_policy", &self.restart_policy)
+            .field("lifecycle", &self.lifecycle)
             .field("preopened_dirs", &self.preopened_dirs)
             .field("args", &self.command_line_arguments)
             .field("envs", &self.environment_variables)
@@ -51,6 +61,22 @@ impl ProcessConfig for DefaultProcessConfig {
     fn get_max_memory(&self) -> usize {
         self.max_memory
     }
+
+    fn set_restart_policy(&mut self, restart_policy: Option<RestartPolicy>) {
+        self.restart_policy = restart_policy;
+    }
+
+    fn get_restart_policy(&self) -> Option<RestartPolicy> {
+        self.restart_policy
+    }
+
+    fn set_lifecycle(&mut self, lifecycle: Lifecycle) {
+        self.lifecycle = lifecycle;
+    }
+
+    fn get_lifecycle(&self) -> Lifecycle {
+        self.lifecycle
+    }
 }
 
 impl LunaticWasiConfigCtx for DefaultProcessConfig {
@@ -118,6 +144,22 @@ impl ProcessConfigCtx for DefaultProcessConfig {
     fn set_can_spawn_processes(&mut self, can: bool) {
         self.can_spawn_processes = can
     }
+
+    fn can_add_fuel(&self) -> bool {
+        self.can_add_fuel
+    }
+
+    fn set_can_add_fuel(&mut self, can: bool) {
+        self.can_add_fuel = can
+    }
+
+    fn can_drain_node(&self) -> bool {
+        self.can_drain_node
+    }
+
+    fn set_can_drain_node(&mut self, can: bool) {
+        self.can_drain_node = can
+    }
 }
 
 impl Default for DefaultProcessConfig {
@@ -125,9 +167,13 @@ impl Default for DefaultProcessConfig {
         Self {
             max_memory: u32::MAX as usize, // = 4 GB
             max_fuel: None,
+            restart_policy: None,
+            lifecycle: Lifecycle::default(),
             can_compile_modules: false,
             can_create_configs: false,
             can_spawn_processes: false,
+            can_add_fuel: false,
+            can_drain_node: false,
             preopened_dirs: vec![],
             command_line_arguments: vec![],
             environment_variables: vec![],
@@ -1,14 +1,43 @@
-use std::{collections::HashMap, env, fs, path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Ok, Result};
 use clap::Parser;
+#[cfg(feature = "bench")]
+use lunatic_distributed::distributed::bench;
 use lunatic_distributed::{
-    control::{self, server::control_server, Scanner, TokenType},
-    distributed::{self, server::ServerCtx},
-    quic,
+    control::{
+        self,
+        gossip::GossipConfig,
+        server::{control_server, CounterRetention, DEFAULT_SINGLETON_GRACE},
+        status::OutageMode,
+        Scanner, TokenType,
+    },
+    discovery::{Discovery, DEFAULT_DISCOVERY_TIMEOUT},
+    distributed::{
+        self,
+        allowlist::{watch_allowlist, ModuleAllowlist},
+        connection_limit::{AtConnectionLimit, ConnectionLimit, ConnectionLimitConfig},
+        dedup::{DedupConfig, DedupWindow},
+        fair_queue::{FairQueue, FairQueueConfig},
+        ordering::ConnectionOrdering,
+        server::{CompileFailures, ModulePreload, ServerCtx},
+        signature::ModuleVerifier,
+        spawn_config::SpawnConfigs,
+        spawn_queue::{SpawnQueue, SpawnQueueConfig},
+        transaction::StagedTransactions,
+    },
+    join_token::JoinToken,
+    quic, ModuleId, NodeId,
 };
 use lunatic_process::{
     env::{Environments, LunaticEnvironments},
+    kv::KvCheckpoints,
     runtimes::{self, Modules, RawWasm},
     wasm::spawn_wasm,
 };
@@ -28,7 +57,14 @@ struct Args {
     #[arg(long, value_name = "NODE_ADDRESS", requires = "control")]
     node: Option<String>,
 
-    /// Address of a control node inside the cluster that will be used for bootstrapping
+    /// Binds a separate listener for control-plane requests from other nodes to the provided
+    /// address, so that they are not delayed by data traffic
+    #[arg(long, value_name = "NODE_CONTROL_ADDRESS", requires = "node")]
+    node_control: Option<String>,
+
+    /// Address of a control node inside the cluster that will be used for bootstrapping. Nodes
+    /// can also discover it with `dns-srv:<name>` from DNS-SRV records, like the ones of a
+    /// Kubernetes headless service, or with `mdns:<service>` on the local network
     #[arg(long, value_name = "CONTROL_ADDRESS")]
     control: Option<String>,
 
@@ -48,6 +84,246 @@ struct Args {
     #[arg(long, requires = "control_server", conflicts_with = "test_ca")]
     ca_key: Option<String>,
 
+    /// Client certificate presented by this node to the control server and other nodes, signed by
+    /// the Certificate Authority
+    #[arg(long, value_name = "PATH", requires_all = ["node", "node_key"])]
+    node_cert: Option<String>,
+
+    /// Private key of the client certificate of this node
+    #[arg(long, value_name = "PATH", requires = "node_cert")]
+    node_key: Option<String>,
+
+    /// Only accept connections to the control server and the listeners of this node from nodes
+    /// that present a client certificate signed by the Certificate Authority
+    #[arg(long, requires = "control")]
+    require_client_certs: bool,
+
+    /// File with the join token of the cluster. The control server only registers nodes and
+    /// nodes only accept spawns from nodes that present the same token
+    #[arg(long, value_name = "PATH", requires = "control")]
+    join_token_file: Option<String>,
+
+    /// Remove the contributions of a node to cluster wide counters when it leaves the cluster,
+    /// instead of keeping its last known contribution
+    #[arg(long, requires = "control_server")]
+    drop_counters_of_removed_nodes: bool,
+
+    /// Keep singleton roles claimed by processes on a node that lost the connection for the given
+    /// number of seconds, before releasing them to other processes (defaults to 5)
+    #[arg(long, value_name = "SECONDS", requires = "control_server")]
+    singleton_grace_period: Option<u64>,
+
+    /// Store modules added to the control server compressed, to reduce its memory usage
+    #[arg(long, requires = "control_server")]
+    compress_modules: bool,
+
+    /// Remove nodes from the cluster that didn't send a heartbeat for the given number of seconds
+    #[arg(long, value_name = "SECONDS", requires = "control_server")]
+    heartbeat_timeout: Option<u64>,
+
+    /// Send a heartbeat to the control server every given number of seconds (defaults to 2). Must
+    /// be shorter than the heartbeat timeout of the control server
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    heartbeat_interval: Option<u64>,
+
+    /// Gossip the cluster membership between nodes instead of taking the nodes listed by the
+    /// control server, so that nodes keep detecting each other while it's unreachable. Nodes
+    /// still register with the control server
+    #[arg(long, requires = "node")]
+    gossip: bool,
+
+    /// Refuse spawns from other nodes while the control server is unreachable, instead of
+    /// serving them with the last known nodes and already compiled modules
+    #[arg(long, requires = "node")]
+    strict_control_outage: bool,
+
+    /// Number of connections to the control server that requests are spread over
+    #[arg(long, value_name = "COUNT", requires = "node")]
+    control_connections: Option<usize>,
+
+    /// Close connections to other nodes after the given number of seconds without traffic
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    node_idle_timeout: Option<u64>,
+
+    /// Drop streams from and to other nodes if reading a started frame makes no progress for the
+    /// given number of seconds
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    node_read_timeout: Option<u64>,
+
+    /// Drop streams from and to other nodes if writing a frame makes no progress for the given
+    /// number of seconds
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    node_write_timeout: Option<u64>,
+
+    /// Rotate the keys of connections to other nodes after they were used for the given number of
+    /// seconds, without closing the connections
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    rekey_interval: Option<u64>,
+
+    /// Rotate the keys of connections to other nodes after the given number of bytes was
+    /// transferred with them
+    #[arg(long, value_name = "BYTES", requires = "node")]
+    rekey_bytes: Option<u64>,
+
+    /// Maximum size in bytes of the params array passed to remote spawns (17 bytes per param)
+    #[arg(long, value_name = "BYTES", requires = "node")]
+    max_spawn_params_size: Option<usize>,
+
+    /// Maximum length in bytes of strings passed to the distributed host functions, like function
+    /// names of remote spawns (defaults to 4096)
+    #[arg(long, value_name = "BYTES", requires = "node")]
+    max_string_arg_len: Option<usize>,
+
+    /// Maximum number of messages queued with `try_send` that were not sent to other nodes yet
+    /// (defaults to 1024)
+    #[arg(long, value_name = "COUNT", requires = "node")]
+    max_buffered_sends: Option<usize>,
+
+    /// LZ4 compress the data of messages sent to other nodes if it's larger than the given
+    /// number of bytes
+    #[arg(long, value_name = "BYTES", requires = "node")]
+    compress_messages_above: Option<usize>,
+
+    /// Compact the log of messages sent with at-least-once delivery every given number of
+    /// seconds (defaults to 60, 0 turns compaction off)
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    log_compaction_interval: Option<u64>,
+
+    /// Only compact the log of messages sent with at-least-once delivery once acknowledged
+    /// messages left room for the given number of messages unused (defaults to 1024)
+    #[arg(long, value_name = "COUNT", requires = "node")]
+    log_compaction_threshold: Option<usize>,
+
+    /// Always send the whole process config with remote spawns, instead of a reference to a
+    /// config the node already received
+    #[arg(long, requires = "node")]
+    inline_spawn_configs: bool,
+
+    /// Drop results of asynchronous spawns that were not polled for the given number of seconds
+    /// after the spawn finished (defaults to 60)
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    spawn_token_ttl: Option<u64>,
+
+    /// File listing module ids or SHA-256 hashes that are allowed to be spawned on this node
+    #[arg(long, value_name = "FILE", requires = "node")]
+    module_allowlist: Option<String>,
+
+    /// File containing the raw Ed25519 public key of the trusted module publisher. If set, only
+    /// modules signed with the matching private key are spawned on this node
+    #[arg(long, value_name = "FILE", requires = "node")]
+    trusted_module_key: Option<String>,
+
+    /// File containing the raw Ed25519 signature of the entry .wasm file, sent along with the
+    /// module to the control server
+    #[arg(long, value_name = "FILE", requires = "control")]
+    module_signature: Option<String>,
+
+    /// Register the entry .wasm file as the next version of the named module. Processes spawned
+    /// from earlier versions keep running, new spawns of the module use this version
+    #[arg(long, value_name = "NAME", requires = "control")]
+    module_name: Option<String>,
+
+    /// Fail spawns of a module that failed to compile for the given number of seconds, instead
+    /// of compiling it again (defaults to 30)
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    compile_failure_ttl: Option<u64>,
+
+    /// Evict the least recently used modules without running processes once the compiled
+    /// modules use more than the given number of bytes
+    #[arg(long, value_name = "BYTES", requires = "node")]
+    module_cache_budget: Option<usize>,
+
+    /// Handle at most the given number of spawns from other nodes at the same time, further
+    /// spawns wait in a queue
+    #[arg(long, value_name = "COUNT", requires = "node")]
+    spawn_workers: Option<usize>,
+
+    /// Maximum number of spawns waiting for a worker, further spawns are rejected (defaults to
+    /// 1024)
+    #[arg(long, value_name = "COUNT", requires = "spawn_workers")]
+    max_spawn_queue_depth: Option<usize>,
+
+    /// Remember spawns with an idempotency key for the given number of seconds, so that retries
+    /// of a spawn are not handled again
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    dedup_window: Option<u64>,
+
+    /// Maximum number of idempotency keys remembered at the same time, further keyed spawns are
+    /// rejected (defaults to 100000)
+    #[arg(long, value_name = "COUNT", requires = "dedup_window")]
+    dedup_max_entries: Option<usize>,
+
+    /// Log a warning when a request from another node is handled for longer than the given number
+    /// of seconds
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    slow_request_threshold: Option<u64>,
+
+    /// Handle at most the given number of requests from other nodes at the same time, further
+    /// requests are scheduled fairly across environments
+    #[arg(long, value_name = "COUNT", requires = "node")]
+    request_workers: Option<usize>,
+
+    /// Share of the request workers an environment gets, as ENVIRONMENT_ID=WEIGHT (environments
+    /// default to a weight of 1)
+    #[arg(
+        long,
+        value_name = "ENVIRONMENT_ID=WEIGHT",
+        requires = "request_workers",
+        value_parser = parse_environment_weight,
+        action = clap::ArgAction::Append
+    )]
+    environment_weight: Vec<(u64, u32)>,
+
+    /// Keep at most the given number of connections from other nodes open at the same time,
+    /// further connections are closed right away
+    #[arg(long, value_name = "COUNT", requires = "node")]
+    max_node_connections: Option<usize>,
+
+    /// Hold connections from other nodes over the limit until another connection is closed,
+    /// instead of closing them
+    #[arg(long, requires = "max_node_connections")]
+    wait_at_connection_limit: bool,
+
+    /// Handle the requests arriving on one connection from another node one at a time, in the
+    /// order they were received
+    #[arg(long, requires = "node")]
+    sequential_node_requests: bool,
+
+    /// Roll back messages staged by two-phase sends from other nodes if they are not committed
+    /// within the given number of seconds (defaults to 10)
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    transaction_timeout: Option<u64>,
+
+    /// Fetch and compile the module with the given id before accepting connections from other nodes
+    #[arg(long, value_name = "MODULE_ID", requires = "node", action = clap::ArgAction::Append)]
+    preload_module: Vec<u64>,
+
+    /// Don't start the node if one of the preloaded modules can't be fetched or compiled
+    #[arg(long, requires = "preload_module")]
+    fail_on_preload_error: bool,
+
+    /// Maximum amount of memory in bytes that all processes on this node can use together
+    #[arg(long, value_name = "BYTES")]
+    max_node_memory: Option<usize>,
+
+    /// Warn when more than this many messages are queued in the mailbox of a process
+    #[arg(long, value_name = "MESSAGES")]
+    mailbox_high_water_mark: Option<usize>,
+
+    /// When the node shuts down, give processes that are still running the given number of
+    /// seconds to finish before they are killed (defaults to 5)
+    #[arg(long, value_name = "SECONDS")]
+    shutdown_grace_period: Option<u64>,
+
+    /// Directory where processes checkpoint their process-local kv store, keyed by their
+    /// environment and the name they registered themselves under
+    #[arg(long, value_name = "DIRECTORY")]
+    kv_checkpoint_dir: Option<PathBuf>,
+
+    /// Maximum size in bytes of a single kv checkpoint (defaults to 1 MiB)
+    #[arg(long, value_name = "BYTES", requires = "kv_checkpoint_dir")]
+    kv_checkpoint_max_size: Option<usize>,
+
     /// Define key=value variable to store as node information
     #[arg(long, value_parser = parse_key_val, action = clap::ArgAction::Append)]
     tag: Vec<(String, String)>,
@@ -68,6 +344,32 @@ struct Args {
     #[arg(conflicts_with = "no_entry", index = 2)]
     wasm_args: Vec<String>,
 
+    /// Benchmark an operation of the distributed layer (spawn, send or send_receive) and print
+    /// the results, used together with `--no-entry`
+    #[cfg(feature = "bench")]
+    #[arg(long, value_name = "OPERATION", requires = "node")]
+    bench_distributed: Option<bench::Operation>,
+
+    /// Node to benchmark against, an in-memory node that answers right away if not set
+    #[cfg(feature = "bench")]
+    #[arg(long, value_name = "NODE_ID", requires = "bench_distributed")]
+    bench_node: Option<u64>,
+
+    /// Number of benchmark requests in flight at the same time (defaults to 16)
+    #[cfg(feature = "bench")]
+    #[arg(long, value_name = "COUNT", requires = "bench_distributed")]
+    bench_concurrency: Option<usize>,
+
+    /// Total number of benchmark requests (defaults to 10000)
+    #[cfg(feature = "bench")]
+    #[arg(long, value_name = "COUNT", requires = "bench_distributed")]
+    bench_requests: Option<usize>,
+
+    /// Size of the messages sent by the benchmark in bytes (defaults to 64)
+    #[cfg(feature = "bench")]
+    #[arg(long, value_name = "BYTES", requires = "bench_distributed")]
+    bench_payload_size: Option<usize>,
+
     /// Enables the prometheus metrics exporter
     #[cfg(feature = "prometheus")]
     #[arg(long)]
@@ -92,10 +394,33 @@ pub(crate) async fn execute() -> Result<()> {
     if args.test_ca {
         log::warn!("Do not use test Certificate Authority in production!")
     }
+    let join_token = args
+        .join_token_file
+        .as_deref()
+        .map(std::fs::read_to_string)
+        .transpose()?
+        .map(|token| JoinToken::new(token.trim().to_string()));
+    // Shared by the main process and all processes spawned on this node by other nodes
+    let kv_checkpoints = match args.kv_checkpoint_dir.clone() {
+        Some(dir) => {
+            let max_size = args
+                .kv_checkpoint_max_size
+                .unwrap_or(KvCheckpoints::DEFAULT_MAX_SIZE);
+            let checkpoints = KvCheckpoints::new(dir, max_size)
+                .context("Failed to create the kv checkpoint directory")?;
+            Some(Arc::new(checkpoints))
+        }
+        None => None,
+    };
 
     // Run control server
     if args.control_server {
         if let Some(control_address) = &args.control {
+            if Discovery::parse(control_address).is_some() {
+                return Err(anyhow!(
+                    "The control server needs an address to bind to instead of {control_address}"
+                ));
+            }
             // TODO unwrap, better message
             let ca_cert = lunatic_distributed::control::server::root_cert(
                 args.test_ca,
@@ -103,14 +428,45 @@ pub(crate) async fn execute() -> Result<()> {
                 args.ca_key.as_deref(),
             )
             .unwrap();
-            tokio::task::spawn(control_server(control_address.parse().unwrap(), ca_cert));
+            let client_ca = if args.require_client_certs {
+                Some(lunatic_distributed::distributed::server::root_cert(
+                    args.test_ca,
+                    args.ca_cert.as_deref(),
+                )?)
+            } else {
+                None
+            };
+            let counter_retention = if args.drop_counters_of_removed_nodes {
+                CounterRetention::Drop
+            } else {
+                CounterRetention::Keep
+            };
+            tokio::task::spawn(control_server(
+                control_address.parse().unwrap(),
+                ca_cert,
+                counter_retention,
+                args.singleton_grace_period
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_SINGLETON_GRACE),
+                args.compress_modules,
+                client_ca,
+                join_token.clone(),
+                args.heartbeat_timeout.map(Duration::from_secs),
+            ));
         }
     }
 
     // Create wasmtime runtime
     let wasmtime_config = runtimes::wasmtime::default_config();
     let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
-    let envs = Arc::new(LunaticEnvironments::default());
+    let envs = match args.max_node_memory {
+        Some(limit) => LunaticEnvironments::with_memory_limit(limit),
+        None => LunaticEnvironments::default(),
+    };
+    let envs = Arc::new(match args.mailbox_high_water_mark {
+        Some(mark) => envs.with_mailbox_high_water_mark(mark),
+        None => envs,
+    });
 
     let env = envs.create(1);
 
@@ -118,9 +474,16 @@ pub(crate) async fn execute() -> Result<()> {
         if let (Some(node_address), Some(control_address)) = (args.node, args.control) {
             // TODO unwrap, better message
             let node_address = node_address.parse().unwrap();
+            let node_control_address = args
+                .node_control
+                .map(|address| address.parse())
+                .transpose()?;
             let node_name = Uuid::new_v4().to_string();
             let node_attributes: HashMap<String, String> = args.tag.into_iter().collect();
-            let control_address = control_address.parse().unwrap();
+            let control_address = match Discovery::parse(&control_address) {
+                Some(discovery) => discovery.resolve(DEFAULT_DISCOVERY_TIMEOUT).await?[0],
+                None => control_address.parse().unwrap(),
+            };
             let ca_cert = lunatic_distributed::distributed::server::root_cert(
                 args.test_ca,
                 args.ca_cert.as_deref(),
@@ -129,39 +492,205 @@ pub(crate) async fn execute() -> Result<()> {
             let node_cert =
                 lunatic_distributed::distributed::server::gen_node_cert(&node_name).unwrap();
 
-            let quic_client = quic::new_quic_client(&ca_cert).unwrap();
+            let connection_config = quic::ConnectionConfig {
+                read_timeout: args.node_read_timeout.map(Duration::from_secs),
+                write_timeout: args.node_write_timeout.map(Duration::from_secs),
+                transport: quic::Transport::Quic,
+            };
+            let quic_client = match (&args.node_cert, &args.node_key) {
+                (Some(cert), Some(key)) => quic::new_mtls_quic_client(
+                    &ca_cert,
+                    &std::fs::read_to_string(cert)?,
+                    &std::fs::read_to_string(key)?,
+                ),
+                _ => quic::new_quic_client(&ca_cert),
+            }
+            .unwrap()
+            .with_key_rotation(quic::KeyRotation {
+                interval: args.rekey_interval.map(Duration::from_secs),
+                max_bytes: args.rekey_bytes,
+            })
+            .with_connection_config(connection_config);
 
             let (node_id, control_client, signed_cert_pem) = control::Client::register(
                 node_address,
+                node_control_address,
                 node_name.to_string(),
                 node_attributes,
                 control_address,
                 quic_client.clone(),
                 node_cert.serialize_request_pem().unwrap(),
+                join_token.clone(),
+                if args.strict_control_outage {
+                    OutageMode::Strict
+                } else {
+                    OutageMode::Degraded
+                },
+                args.control_connections
+                    .unwrap_or(control::client::DEFAULT_POOL_SIZE),
+                args.heartbeat_interval
+                    .map(Duration::from_secs)
+                    .unwrap_or(control::client::DEFAULT_HEARTBEAT_INTERVAL),
+                args.gossip.then(GossipConfig::default),
             )
             .await?;
 
-            let distributed_client =
-                distributed::Client::new(node_id, control_client.clone(), quic_client.clone())
-                    .await?;
+            let distributed_client = distributed::Client::new(
+                NodeId(node_id),
+                control_client.clone(),
+                quic_client.clone(),
+                distributed::ClientConfig {
+                    idle_timeout: args.node_idle_timeout.map(Duration::from_secs),
+                    max_spawn_params_size: args
+                        .max_spawn_params_size
+                        .unwrap_or(distributed::ClientConfig::default().max_spawn_params_size),
+                    reference_spawn_configs: !args.inline_spawn_configs,
+                    spawn_token_ttl: args
+                        .spawn_token_ttl
+                        .map(Duration::from_secs)
+                        .unwrap_or(distributed::ClientConfig::default().spawn_token_ttl),
+                    max_buffered_sends: args
+                        .max_buffered_sends
+                        .unwrap_or(distributed::ClientConfig::default().max_buffered_sends),
+                    compress_messages_above: args.compress_messages_above,
+                    max_string_arg_len: args
+                        .max_string_arg_len
+                        .unwrap_or(distributed::ClientConfig::default().max_string_arg_len),
+                    log_compaction_interval: match args.log_compaction_interval {
+                        Some(0) => None,
+                        Some(seconds) => Some(Duration::from_secs(seconds)),
+                        None => distributed::ClientConfig::default().log_compaction_interval,
+                    },
+                    log_compaction_threshold: args
+                        .log_compaction_threshold
+                        .unwrap_or(distributed::ClientConfig::default().log_compaction_threshold),
+                    join_token,
+                    ..Default::default()
+                },
+            )
+            .await?;
 
-            let dist = lunatic_distributed::DistributedProcessState::new(
+            let mut dist = lunatic_distributed::DistributedProcessState::new(
                 node_id,
                 control_client.clone(),
                 distributed_client,
             )
             .await?;
+            if let Some(checkpoints) = kv_checkpoints.clone() {
+                dist = dist.with_kv_checkpoints(checkpoints);
+            }
+
+            let module_allowlist = match args.module_allowlist {
+                Some(path) => {
+                    let path = PathBuf::from(path);
+                    let allowlist = ModuleAllowlist::load(&path)?;
+                    tokio::task::spawn(watch_allowlist(allowlist.clone(), path));
+                    allowlist
+                }
+                None => ModuleAllowlist::default(),
+            };
+
+            let spawn_queue = match args.spawn_workers {
+                Some(workers) => {
+                    #[cfg(feature = "metrics")]
+                    lunatic_distributed::distributed::spawn_queue::describe_metrics();
+                    SpawnQueue::new(SpawnQueueConfig {
+                        workers,
+                        max_depth: args.max_spawn_queue_depth.unwrap_or(1024),
+                    })
+                }
+                None => SpawnQueue::default(),
+            };
+
+            let dedup = match args.dedup_window {
+                Some(window) => {
+                    #[cfg(feature = "metrics")]
+                    lunatic_distributed::distributed::dedup::describe_metrics();
+                    DedupWindow::new(DedupConfig {
+                        window: Duration::from_secs(window),
+                        max_entries: args.dedup_max_entries.unwrap_or(100_000),
+                    })
+                }
+                None => DedupWindow::default(),
+            };
+
+            if let Some(threshold) = args.slow_request_threshold {
+                #[cfg(feature = "metrics")]
+                lunatic_distributed::distributed::in_flight::describe_metrics();
+                tokio::task::spawn(
+                    dist.in_flight
+                        .clone()
+                        .watch_slow(Duration::from_secs(threshold)),
+                );
+            }
+
+            #[cfg(feature = "metrics")]
+            lunatic_distributed::distributed::connection_limit::describe_metrics();
+            let connection_limit = match args.max_node_connections {
+                Some(max_connections) => ConnectionLimit::new(ConnectionLimitConfig {
+                    max_connections,
+                    at_limit: if args.wait_at_connection_limit {
+                        AtConnectionLimit::Wait
+                    } else {
+                        AtConnectionLimit::Reject
+                    },
+                }),
+                None => ConnectionLimit::default(),
+            };
+
+            let fair_queue = match args.request_workers {
+                Some(workers) => FairQueue::new(FairQueueConfig {
+                    workers,
+                    weights: args.environment_weight.into_iter().collect(),
+                }),
+                None => FairQueue::default(),
+            };
+
+            let module_verifier = match args.trusted_module_key {
+                Some(path) => ModuleVerifier::load(Path::new(&path))?,
+                None => ModuleVerifier::default(),
+            };
 
             tokio::task::spawn(lunatic_distributed::distributed::server::node_server(
                 ServerCtx {
-                    envs,
-                    modules: Modules::<DefaultProcessState>::default(),
+                    envs: envs.clone(),
+                    modules: args
+                        .module_cache_budget
+                        .map(Modules::<DefaultProcessState>::with_memory_budget)
+                        .unwrap_or_default(),
                     distributed: dist.clone(),
                     runtime: runtime.clone(),
+                    module_allowlist,
+                    module_verifier,
+                    compile_failures: args
+                        .compile_failure_ttl
+                        .map(|ttl| CompileFailures::new(Duration::from_secs(ttl)))
+                        .unwrap_or_default(),
+                    spawn_configs: SpawnConfigs::default(),
+                    spawn_queue,
+                    transactions: args
+                        .transaction_timeout
+                        .map(|timeout| StagedTransactions::new(Duration::from_secs(timeout)))
+                        .unwrap_or_default(),
+                    connection_config,
+                    fair_queue,
+                    dedup,
+                    connection_ordering: if args.sequential_node_requests {
+                        ConnectionOrdering::Sequential
+                    } else {
+                        ConnectionOrdering::Concurrent
+                    },
+                    connection_limit,
+                    preload: ModulePreload {
+                        module_ids: args.preload_module.into_iter().map(ModuleId).collect(),
+                        fail_on_error: args.fail_on_preload_error,
+                    },
                 },
                 node_address,
+                node_control_address,
                 signed_cert_pem,
                 node_cert.serialize_private_key_pem(),
+                args.require_client_certs.then(|| ca_cert.clone()),
             ));
 
             log::info!("Registration successful, node id {}", node_id);
@@ -190,10 +719,37 @@ pub(crate) async fn execute() -> Result<()> {
     }
 
     let mut config = DefaultProcessConfig::default();
-    // Allow initial process to compile modules, create configurations and spawn sub-processes
+    // Allow initial process to compile modules, create configurations, spawn sub-processes and
+    // drain the node
     config.set_can_compile_modules(true);
     config.set_can_create_configs(true);
     config.set_can_spawn_processes(true);
+    config.set_can_drain_node(true);
+
+    #[cfg(feature = "bench")]
+    if let (Some(operation), Some(dist)) = (args.bench_distributed, distributed_state.as_ref()) {
+        let defaults = bench::BenchConfig::default();
+        let config = bench::BenchConfig {
+            operation,
+            concurrency: args.bench_concurrency.unwrap_or(defaults.concurrency),
+            requests: args.bench_requests.unwrap_or(defaults.requests),
+            payload_size: args.bench_payload_size.unwrap_or(defaults.payload_size),
+            spawn_config: bincode::serialize(&config)?,
+            ..defaults
+        };
+        let target = match args.bench_node {
+            Some(node_id) => NodeId(node_id),
+            None => {
+                // Node ids are assigned by the control server starting at 1
+                let target = NodeId(u64::MAX);
+                bench::echo_node(&dist.node_client, target);
+                target
+            }
+        };
+        let result = bench::run(&dist.node_client, target, &config).await;
+        println!("{result}");
+        return Ok(());
+    }
 
     if args.no_entry {
         // Block forever
@@ -227,12 +783,23 @@ pub(crate) async fn execute() -> Result<()> {
     // Spawn main process
     let module = fs::read(path)?;
     let module: RawWasm = if let Some(dist) = distributed_state.as_ref() {
-        dist.control.add_module(module).await?
+        let signature = args.module_signature.map(fs::read).transpose()?;
+        match args.module_name {
+            Some(name) => {
+                let (module, version) = dist
+                    .control
+                    .add_module_version(&name, module, signature)
+                    .await?;
+                log::info!("Registered version {version} of module {name}");
+                module
+            }
+            None => dist.control.add_module(module, signature).await?,
+        }
     } else {
         module.into()
     };
     let module = Arc::new(runtime.compile_module::<DefaultProcessState>(module)?);
-    let state = DefaultProcessState::new(
+    let mut state = DefaultProcessState::new(
         env.clone(),
         distributed_state,
         runtime.clone(),
@@ -241,6 +808,9 @@ pub(crate) async fn execute() -> Result<()> {
         Default::default(),
     )
     .unwrap();
+    if let Some(checkpoints) = kv_checkpoints {
+        state = state.with_kv_checkpoints(checkpoints);
+    }
 
     let (task, _) = spawn_wasm(env, runtime, &module, state, "_start", Vec::new(), None)
         .await
@@ -251,6 +821,22 @@ pub(crate) async fn execute() -> Result<()> {
     // Wait on the main process to finish
     let result = task.await.map(|_| ()).map_err(|e| anyhow!(e.to_string()));
 
+    // Let the remaining processes finish, processes still running after the grace period are
+    // killed by the shutdown signal
+    let grace_period = Duration::from_secs(args.shutdown_grace_period.unwrap_or(5));
+    envs.shutdown(grace_period);
+    let shut_down = async {
+        while envs.process_count() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    if tokio::time::timeout(grace_period + Duration::from_secs(1), shut_down)
+        .await
+        .is_err()
+    {
+        log::warn!("Processes didn't finish within the shutdown grace period");
+    }
+
     // Until we refactor registration and reconnect authentication, send node id explicitly
     if let (Some(ctrl), Some(node_id)) = (control_client, node_id) {
         ctrl.deregister(node_id).await;
@@ -260,6 +846,17 @@ pub(crate) async fn execute() -> Result<()> {
 }
 
 /// Parse a single key-value pair
+fn parse_environment_weight(s: &str) -> Result<(u64, u32)> {
+    let (environment_id, weight) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("invalid ENVIRONMENT_ID=WEIGHT: no `=` found in `{}`", s))?;
+    let weight: u32 = weight.trim().parse()?;
+    if weight == 0 {
+        return Err(anyhow!("environment weight must be at least 1"));
+    }
+    Ok((environment_id.trim().parse()?, weight))
+}
+
 fn parse_key_val(s: &str) -> Result<(String, String)> {
     let scanner = Scanner::new(s.to_string());
     let tokens = scanner.scan()?;
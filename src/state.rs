@@ -4,18 +4,24 @@ use std::sync::Arc;
 use anyhow::Result;
 use dashmap::DashMap;
 use hash_map_id::HashMapId;
-use lunatic_distributed::{DistributedCtx, DistributedProcessState};
+use lunatic_distributed::{DistributedCtx, DistributedProcessState, ReplyCapabilityResources};
 use lunatic_error_api::{ErrorCtx, ErrorResource};
 use lunatic_networking_api::{DnsIterator, TlsConnection, TlsListener};
 use lunatic_networking_api::{NetworkingCtx, TcpConnection};
 use lunatic_process::env::{Environment, LunaticEnvironment};
+use lunatic_process::kv::{KvCheckpoints, KvStore};
+use lunatic_process::limits::{Resource, ResourceLimitExceeded};
 use lunatic_process::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
-use lunatic_process::state::{ConfigResources, ProcessState};
+use lunatic_process::state::{ConfigResources, ProcessState, StartTime};
 use lunatic_process::{
     config::ProcessConfig,
     state::{SignalReceiver, SignalSender},
 };
-use lunatic_process::{mailbox::MessageMailbox, message::Message};
+use lunatic_process::{
+    latency::SchedulingLatency,
+    mailbox::MessageMailbox,
+    message::{Message, PriorityBoost},
+};
 use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
 use lunatic_stdout_capture::StdoutCapture;
 use lunatic_timer_api::{TimerCtx, TimerResources};
@@ -49,6 +55,16 @@ pub struct DefaultProcessState {
     signal_mailbox: (SignalSender, SignalReceiver),
     // Messages sent to the process
     message_mailbox: MessageMailbox,
+    // Time the process waits to be polled after being woken up
+    scheduling_latency: SchedulingLatency,
+    // When the process was spawned
+    start_time: StartTime,
+    // Process-local key-value store
+    kv: KvStore,
+    // Durable store for kv checkpoints, shared by all processes of the node
+    kv_checkpoints: Option<Arc<KvCheckpoints>>,
+    // Boost of the priority of sent messages
+    priority_boost: PriorityBoost,
     // Resources
     resources: Resources,
     // WASI
@@ -61,6 +77,12 @@ pub struct DefaultProcessState {
     initialized: bool,
     // Shared process registry
     registry: Arc<DashMap<String, (u64, u64)>>,
+    // Memory reserved by this process from the node wide memory accounting
+    memory_usage: usize,
+    // Last limit the process was stopped at while growing a resource
+    resource_limit_exceeded: Option<ResourceLimitExceeded>,
+    // Fuel the process added to its budget
+    fuel_added: u64,
 }
 
 impl DefaultProcessState {
@@ -85,6 +107,11 @@ impl DefaultProcessState {
             message: None,
             signal_mailbox,
             message_mailbox,
+            scheduling_latency: SchedulingLatency::default(),
+            start_time: StartTime::now(),
+            kv: KvStore::default(),
+            kv_checkpoints: None,
+            priority_boost: PriorityBoost::default(),
             resources: Resources::default(),
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
@@ -95,9 +122,18 @@ impl DefaultProcessState {
             wasi_stderr: None,
             initialized: false,
             registry,
+            memory_usage: 0,
+            resource_limit_exceeded: None,
+            fuel_added: 0,
         };
         Ok(state)
     }
+
+    /// Checkpoints of the process-local kv store are written to and restored from `checkpoints`.
+    pub fn with_kv_checkpoints(mut self, checkpoints: Arc<KvCheckpoints>) -> Self {
+        self.kv_checkpoints = Some(checkpoints);
+        self
+    }
 }
 
 impl ProcessState for DefaultProcessState {
@@ -121,6 +157,11 @@ impl ProcessState for DefaultProcessState {
             message: None,
             signal_mailbox,
             message_mailbox,
+            scheduling_latency: SchedulingLatency::default(),
+            start_time: StartTime::now(),
+            kv: KvStore::default(),
+            kv_checkpoints: self.kv_checkpoints.clone(),
+            priority_boost: PriorityBoost::default(),
             resources: Resources::default(),
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
@@ -131,6 +172,9 @@ impl ProcessState for DefaultProcessState {
             wasi_stderr: None,
             initialized: false,
             registry: self.registry.clone(),
+            memory_usage: 0,
+            resource_limit_exceeded: None,
+            fuel_added: 0,
         };
         Ok(state)
     }
@@ -151,6 +195,11 @@ impl ProcessState for DefaultProcessState {
             message: None,
             signal_mailbox,
             message_mailbox,
+            scheduling_latency: SchedulingLatency::default(),
+            start_time: StartTime::now(),
+            kv: KvStore::default(),
+            kv_checkpoints: None,
+            priority_boost: PriorityBoost::default(),
             resources: Resources::default(),
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
@@ -161,6 +210,9 @@ impl ProcessState for DefaultProcessState {
             wasi_stdout: None,
             wasi_stderr: None,
             initialized: false,
+            memory_usage: 0,
+            resource_limit_exceeded: None,
+            fuel_added: 0,
         }
     }
 
@@ -211,6 +263,38 @@ impl ProcessState for DefaultProcessState {
         &self.message_mailbox
     }
 
+    fn scheduling_latency(&self) -> &SchedulingLatency {
+        &self.scheduling_latency
+    }
+
+    fn start_time(&self) -> &StartTime {
+        &self.start_time
+    }
+
+    fn set_start_time(&mut self, start_time: StartTime) {
+        self.start_time = start_time;
+    }
+
+    fn kv(&self) -> &KvStore {
+        &self.kv
+    }
+
+    fn kv_mut(&mut self) -> &mut KvStore {
+        &mut self.kv
+    }
+
+    fn kv_checkpoints(&self) -> Option<&Arc<KvCheckpoints>> {
+        self.kv_checkpoints.as_ref()
+    }
+
+    fn priority_boost(&self) -> &PriorityBoost {
+        &self.priority_boost
+    }
+
+    fn priority_boost_mut(&mut self) -> &mut PriorityBoost {
+        &mut self.priority_boost
+    }
+
     fn config_resources(&self) -> &ConfigResources<<DefaultProcessState as ProcessState>::Config> {
         &self.resources.configs
     }
@@ -224,6 +308,10 @@ impl ProcessState for DefaultProcessState {
     fn registry(&self) -> &Arc<DashMap<String, (u64, u64)>> {
         &self.registry
     }
+
+    fn resource_limit_exceeded(&self) -> Option<&ResourceLimitExceeded> {
+        self.resource_limit_exceeded.as_ref()
+    }
 }
 
 impl Debug for DefaultProcessState {
@@ -234,14 +322,53 @@ impl Debug for DefaultProcessState {
     }
 }
 
+// Maximum number of elements in the table of a process
+const MAX_TABLE_ELEMENTS: u32 = 99_999;
+
 // Limit the maximum memory of the process depending on the environment it was spawned in.
+// Rejected growths are remembered, so that a process failing because of them finishes with the
+// exceeded limit as its reason.
 impl ResourceLimiter for DefaultProcessState {
-    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
-        desired <= self.config().get_max_memory()
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        let max_memory = self.config().get_max_memory();
+        if desired > max_memory {
+            self.resource_limit_exceeded = Some(ResourceLimitExceeded {
+                resource: Resource::Memory,
+                limit: max_memory as u64,
+                attempted: desired as u64,
+            });
+            return false;
+        }
+        // Processes can't shrink their memory, it's only released when the process exits.
+        let growth = desired.saturating_sub(current);
+        if !self.environment.memory().reserve(growth) {
+            log::warn!(
+                "Process {} can't grow memory to {} bytes, node memory limit reached",
+                self.id,
+                desired
+            );
+            let node_limit = self.environment.memory().limit().unwrap_or(usize::MAX);
+            self.resource_limit_exceeded = Some(ResourceLimitExceeded {
+                resource: Resource::NodeMemory,
+                limit: node_limit as u64,
+                attempted: (self.environment.memory().used() + growth) as u64,
+            });
+            return false;
+        }
+        self.memory_usage += growth;
+        true
     }
 
     fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
-        desired < 100_000
+        if desired > MAX_TABLE_ELEMENTS {
+            self.resource_limit_exceeded = Some(ResourceLimitExceeded {
+                resource: Resource::Table,
+                limit: MAX_TABLE_ELEMENTS as u64,
+                attempted: desired as u64,
+            });
+            return false;
+        }
+        true
     }
 
     // Allow one instance per store
@@ -260,6 +387,12 @@ impl ResourceLimiter for DefaultProcessState {
     }
 }
 
+impl Drop for DefaultProcessState {
+    fn drop(&mut self) {
+        self.environment.memory().release(self.memory_usage);
+    }
+}
+
 impl ErrorCtx for DefaultProcessState {
     fn error_resources(&self) -> &ErrorResource {
         &self.resources.errors
@@ -292,6 +425,10 @@ impl ProcessCtx<DefaultProcessState> for DefaultProcessState {
     fn environment(&self) -> Arc<dyn Environment> {
         self.environment.clone()
     }
+
+    fn fuel_added(&mut self) -> &mut u64 {
+        &mut self.fuel_added
+    }
 }
 
 impl NetworkingCtx for DefaultProcessState {
@@ -396,6 +533,7 @@ pub(crate) struct Resources {
     pub(crate) tls_streams: HashMapId<Arc<TlsConnection>>,
     pub(crate) udp_sockets: HashMapId<Arc<UdpSocket>>,
     pub(crate) errors: HashMapId<anyhow::Error>,
+    pub(crate) reply_capabilities: ReplyCapabilityResources,
 }
 
 impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
@@ -424,10 +562,26 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
         self.environment.id()
     }
 
+    fn cancel_token(&self) -> Option<u64> {
+        self.environment.cancel_tokens().token(self.id)
+    }
+
     fn can_spawn(&self) -> bool {
         self.config().can_spawn_processes()
     }
 
+    fn can_drain_node(&self) -> bool {
+        self.config().can_drain_node()
+    }
+
+    fn reply_capability_resources(&self) -> &ReplyCapabilityResources {
+        &self.resources.reply_capabilities
+    }
+
+    fn reply_capability_resources_mut(&mut self) -> &mut ReplyCapabilityResources {
+        &mut self.resources.reply_capabilities
+    }
+
     fn new_dist_state(
         environment: Arc<LunaticEnvironment>,
         distributed: DistributedProcessState,
@@ -438,6 +592,7 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
         let message_mailbox = MessageMailbox::default();
+        let kv_checkpoints = distributed.kv_checkpoints().cloned();
         let state = Self {
             id: environment.get_next_process_id(),
             environment,
@@ -448,6 +603,11 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
             message: None,
             signal_mailbox,
             message_mailbox,
+            scheduling_latency: SchedulingLatency::default(),
+            start_time: StartTime::now(),
+            kv: KvStore::default(),
+            kv_checkpoints,
+            priority_boost: PriorityBoost::default(),
             resources: Resources::default(),
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
@@ -458,6 +618,9 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
             wasi_stderr: None,
             initialized: false,
             registry: Default::default(), // TODO move registry into env?
+            memory_usage: 0,
+            resource_limit_exceeded: None,
+            fuel_added: 0,
         };
         Ok(state)
     }
@@ -499,4 +662,411 @@ mod tests {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn guest_detects_non_distributed_mode() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Traps unless the guest sees that it's not distributed and gets the reserved ids.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::distributed" "is_distributed" (func $is_distributed (result i32)))
+                (import "lunatic::distributed" "node_id" (func $node_id (result i64)))
+                (import "lunatic::distributed" "module_id" (func $module_id (result i64)))
+                (func (export "check")
+                    call $is_distributed
+                    if unreachable end
+                    call $node_id
+                    i64.const 0
+                    i64.ne
+                    if unreachable end
+                    call $module_id
+                    i64.const 0
+                    i64.ne
+                    if unreachable end))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            Arc::new(dashmap::DashMap::new()),
+        )
+        .unwrap();
+
+        let (handle, _) = spawn_wasm(env, runtime, &module, state, "check", Vec::new(), None)
+            .await
+            .unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn exceeding_memory_limit_is_exit_reason() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process::limits::{Resource, ResourceLimitExceeded};
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use std::sync::Arc;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Grows the memory by one page and traps if that fails, like an allocator running out
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (memory 1)
+                (func (export "grow")
+                    i32.const 1
+                    memory.grow
+                    i32.const -1
+                    i32.eq
+                    if unreachable end))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let mut config = DefaultProcessConfig::default();
+        config.set_max_memory(65536);
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env,
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Arc::new(dashmap::DashMap::new()),
+        )
+        .unwrap();
+
+        let instance = runtime.instantiate(&module, state).await.unwrap();
+        let result = instance.call("grow", Vec::new()).await;
+        assert_eq!(
+            result.resource_limit_exceeded(),
+            Some(&ResourceLimitExceeded {
+                resource: Resource::Memory,
+                limit: 65536,
+                attempted: 131072,
+            })
+        );
+        assert_eq!(
+            result.failure().as_deref(),
+            Some("resource limit exceeded: memory (limit 65536, attempted 131072)")
+        );
+    }
+
+    #[tokio::test]
+    async fn adding_fuel_avoids_running_out() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Checks the remaining fuel, tops it up if allowed and burns about 1M instructions
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "fuel_remaining" (func $fuel_remaining (result i64)))
+                (import "lunatic::process" "fuel_add" (func $fuel_add (param i64) (result i32)))
+                (func (export "work")
+                    (local $i i32)
+                    call $fuel_remaining
+                    i64.const 2
+                    i64.gt_u
+                    if unreachable end
+                    i64.const 100
+                    call $fuel_add
+                    i32.eqz
+                    if
+                        call $fuel_remaining
+                        i64.const 100
+                        i64.lt_u
+                        if unreachable end
+                    end
+                    (loop $burn
+                        local.get $i
+                        i32.const 1
+                        i32.add
+                        local.tee $i
+                        i32.const 200000
+                        i32.lt_u
+                        br_if $burn)))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+
+        for can_add_fuel in [false, true] {
+            let mut config = DefaultProcessConfig::default();
+            config.set_max_fuel(Some(2));
+            config.set_can_add_fuel(can_add_fuel);
+            let state = DefaultProcessState::new(
+                Arc::new(lunatic_process::env::LunaticEnvironment::new(0)),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(config),
+                Arc::new(dashmap::DashMap::new()),
+            )
+            .unwrap();
+
+            let instance = runtime.instantiate(&module, state).await.unwrap();
+            let result = instance.call("work", Vec::new()).await;
+            // Without the permission the top-up is refused and the process runs out of fuel
+            assert_eq!(result.failure().is_none(), can_add_fuel);
+        }
+    }
+
+    #[tokio::test]
+    async fn watcher_receives_exit_reason() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::message::Message;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::{Process, Signal};
+        use std::sync::Arc;
+        use tokio::sync::mpsc;
+
+        struct Watcher(mpsc::UnboundedSender<Signal>);
+
+        impl Process for Watcher {
+            fn id(&self) -> u64 {
+                u64::MAX
+            }
+
+            fn send(&self, signal: Signal) {
+                self.0.send(signal).ok();
+            }
+        }
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Gives the watcher time to register and exits with a reason
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::process" "exit" (func $exit (param i32 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "shutting down")
+                (func (export "main")
+                    i64.const 50
+                    call $sleep_ms
+                    i32.const 7
+                    i32.const 0
+                    i32.const 13
+                    call $exit
+                    unreachable))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            Arc::new(dashmap::DashMap::new()),
+        )
+        .unwrap();
+
+        let (handle, process) = spawn_wasm(
+            env.clone(),
+            runtime,
+            &module,
+            state,
+            "main",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        lunatic_process::notify_on_exit(env.as_ref(), process.id(), 1, Arc::new(Watcher(sender)));
+        // Exiting with a reason is a clean finish
+        assert!(handle.await.unwrap().is_ok());
+
+        let mut expected = process.id().to_le_bytes().to_vec();
+        expected.push(3);
+        expected.extend(7u32.to_le_bytes());
+        expected.extend(b"shutting down");
+        match receiver.recv().await {
+            Some(Signal::Message(Message::Data(mut message))) => {
+                assert_eq!(message.tag, Some(1));
+                let mut data = Vec::new();
+                std::io::Read::read_to_end(&mut message, &mut data).unwrap();
+                assert_eq!(data, expected);
+            }
+            signal => panic!("unexpected signal {:?}", signal),
+        }
+    }
+
+    #[tokio::test]
+    async fn locate_returns_node_of_named_process() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Traps unless "service" is located on node 4 and "missing" is not found
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::registry" "locate" (func $locate (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "servicemissing")
+                (func (export "check")
+                    i32.const 0
+                    i32.const 7
+                    i32.const 64
+                    call $locate
+                    if unreachable end
+                    i32.const 64
+                    i64.load
+                    i64.const 4
+                    i64.ne
+                    if unreachable end
+                    i32.const 7
+                    i32.const 7
+                    i32.const 64
+                    call $locate
+                    i32.const 1
+                    i32.ne
+                    if unreachable end))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let registry = Arc::new(dashmap::DashMap::new());
+        registry.insert("service".to_string(), (4, 9));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            registry,
+        )
+        .unwrap();
+
+        let (handle, _) = spawn_wasm(env, runtime, &module, state, "check", Vec::new(), None)
+            .await
+            .unwrap();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn standby_takes_over_name_of_dead_primary() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::Environment;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::{Process, Signal};
+        use std::sync::Arc;
+
+        struct Alive;
+
+        impl Process for Alive {
+            fn id(&self) -> u64 {
+                77
+            }
+
+            fn send(&self, _: Signal) {}
+        }
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        // Takes over "primary" from a dead process, and "busy" from a living one only by force
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::registry" "takeover"
+                    (func $takeover (param i32 i32 i64 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "primarybusy")
+                (func (export "promote")
+                    i32.const 0
+                    i32.const 7
+                    i64.const 0
+                    i32.const 0
+                    call $takeover
+                    if unreachable end
+                    i32.const 7
+                    i32.const 4
+                    i64.const 0
+                    i32.const 0
+                    call $takeover
+                    i32.const 1
+                    i32.ne
+                    if unreachable end
+                    i32.const 7
+                    i32.const 4
+                    i64.const 0
+                    i32.const 1
+                    call $takeover
+                    if unreachable end))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        env.add_process(77, Arc::new(Alive));
+        let registry = Arc::new(dashmap::DashMap::new());
+        // The primary is not in the environment anymore
+        registry.insert("primary".to_string(), (0, 12345));
+        registry.insert("busy".to_string(), (0, 77));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            registry.clone(),
+        )
+        .unwrap();
+
+        let (handle, standby) =
+            spawn_wasm(env, runtime, &module, state, "promote", Vec::new(), None)
+                .await
+                .unwrap();
+        assert!(handle.await.unwrap().is_ok());
+        assert_eq!(*registry.get("primary").unwrap(), (0, standby.id()));
+        assert_eq!(*registry.get("busy").unwrap(), (0, standby.id()));
+    }
 }
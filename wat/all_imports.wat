@@ -11,6 +11,8 @@
     (import "lunatic::message" "seek_data" (func (param i64)))
     (import "lunatic::message" "get_tag" (func (result i64)))
     (import "lunatic::message" "data_size" (func (result i64)))
+    (import "lunatic::message" "set_priority" (func (param i32)))
+    (import "lunatic::message" "get_priority" (func (result i32)))
     (import "lunatic::message" "push_tcp_stream" (func (param i64) (result i64)))
     (import "lunatic::message" "take_tcp_stream" (func (param i64) (result i64)))
     (import "lunatic::message" "push_udp_socket" (func (param i64) (result i64)))
@@ -18,8 +20,19 @@
     (import "lunatic::message" "send" (func (param i64) (result i32)))
     (import "lunatic::message" "send_receive_skip_search" (func (param i64 i64) (result i32)))
     (import "lunatic::message" "receive" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::message" "receive_fifo" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::message" "subscribe" (func (param i32 i32)))
+    (import "lunatic::message" "unsubscribe" (func (param i32 i32) (result i32)))
+    (import "lunatic::message" "publish" (func (param i32 i32) (result i32)))
+    (import "lunatic::message" "mailbox_digest" (func (param i32 i32) (result i32)))
+    (import "lunatic::message" "register_ring" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::message" "unregister_ring" (func))
+    (import "lunatic::message" "drain_ring" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::message" "set_dedup_window" (func (param i64 i32)))
+    (import "lunatic::message" "duplicates_dropped" (func (result i64)))
 
     (import "lunatic::timer" "send_after" (func (param i64 i64) (result i64)))
+    (import "lunatic::timer" "send_interval" (func (param i64 i64) (result i64)))
     (import "lunatic::timer" "cancel_timer" (func (param i64) (result i32)))
 
     (import "lunatic::networking" "resolve" (func (param i32 i32 i64 i32) (result i32)))
@@ -63,20 +76,43 @@
     (import "lunatic::process" "config_get_max_memory" (func (param i64) (result i64)))
     (import "lunatic::process" "config_set_max_fuel" (func (param i64 i64)))
     (import "lunatic::process" "config_get_max_fuel" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_set_restart_policy" (func (param i64 i32 i64)))
+    (import "lunatic::process" "config_set_restart_type" (func (param i64 i32)))
+    (import "lunatic::process" "config_set_lifecycle" (func (param i64 i32)))
+    (import "lunatic::process" "config_get_lifecycle" (func (param i64) (result i32)))
     (import "lunatic::process" "config_can_compile_modules" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_compile_modules" (func (param i64 i32)))
     (import "lunatic::process" "config_can_create_configs" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_create_configs" (func (param i64 i32)))
     (import "lunatic::process" "config_can_spawn_processes" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_spawn_processes" (func (param i64 i32)))
+    (import "lunatic::process" "config_can_add_fuel" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_can_add_fuel" (func (param i64 i32)))
+    (import "lunatic::process" "config_can_drain_node" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_can_drain_node" (func (param i64 i32)))
     (import "lunatic::process" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::process" "sleep_ms" (func (param i64)))
     (import "lunatic::process" "die_when_link_dies" (func (param i32)))
     (import "lunatic::process" "process_id" (func (result i64)))
+    (import "lunatic::process" "node_memory_usage" (func (result i64)))
+    (import "lunatic::process" "scheduling_latency" (func (param i32)))
+    (import "lunatic::process" "current_config" (func (param i32 i32) (result i32)))
+    (import "lunatic::process" "process_uptime_ms" (func (result i64)))
+    (import "lunatic::process" "process_start_time" (func (result i64)))
+    (import "lunatic::process" "fuel_remaining" (func (result i64)))
+    (import "lunatic::process" "fuel_add" (func (param i64) (result i32)))
+    (import "lunatic::process" "kv_put" (func (param i32 i32 i32 i32)))
+    (import "lunatic::process" "kv_get" (func (param i32 i32 i32 i32) (result i64)))
+    (import "lunatic::process" "kv_delete" (func (param i32 i32) (result i32)))
+    (import "lunatic::process" "kv_checkpoint" (func (result i32)))
+    (import "lunatic::process" "boost_priority" (func (result i32)))
+    (import "lunatic::process" "restore_priority" (func (result i32)))
     (import "lunatic::process" "link" (func (param i64 i64)))
     (import "lunatic::process" "unlink" (func (param i64)))
     (import "lunatic::process" "kill" (func (param i64)))
+    (import "lunatic::process" "shutdown" (func (param i64 i64)))
     (import "lunatic::process" "exists" (func (param i64) (result i32)))
+    (import "lunatic::process" "exit" (func (param i32 i32 i32)))
 
     (import "lunatic::version" "major" (func (result i32)))
     (import "lunatic::version" "minor" (func (result i32)))
@@ -88,15 +124,68 @@
 
     (import "lunatic::registry" "put" (func (param i32 i32 i64 i64)))
     (import "lunatic::registry" "get" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::registry" "locate" (func (param i32 i32 i32) (result i32)))
     (import "lunatic::registry" "remove" (func (param i32 i32)))
+    (import "lunatic::registry" "takeover" (func (param i32 i32 i64 i32) (result i32)))
+    (import "lunatic::registry" "set_label" (func (param i32 i32)))
+    (import "lunatic::registry" "find_by_label" (func (param i32 i32 i32 i32) (result i32)))
 
+    (import "lunatic::distributed" "is_distributed" (func (result i32)))
     (import "lunatic::distributed" "nodes_count" (func (result i32)))
     (import "lunatic::distributed" "get_nodes" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "get_nodes_by_attr" (func (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::distributed" "node_id" (func (result i64)))
+    (import "lunatic::distributed" "capabilities" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "node_info" (func (param i64 i32) (result i32)))
     (import "lunatic::distributed" "module_id" (func (result i64)))
     (import "lunatic::distributed" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_with_fallback" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_async" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_poll" (func (param i64 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_replicated" (func (param i64 i64 i64 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_named" (func (param i64 i64 i64 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_version" (func (param i64 i64 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "migrate" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::distributed" "send" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "try_send" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "send_reliable" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "kill" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "notify_on_exit" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::distributed" "link" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::distributed" "monitor" (func (param i64 i64 i64 i32) (result i32)))
+    (import "lunatic::distributed" "demonitor" (func (param i64) (result i32)))
+    (import "lunatic::distributed" "create_cancel_token" (func (result i64)))
+    (import "lunatic::distributed" "cancel" (func (param i64) (result i64)))
+    (import "lunatic::distributed" "send_two_phase" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "send_with_reply_cap" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "take_reply_cap" (func (param i64) (result i64)))
+    (import "lunatic::distributed" "sender_info" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "message_id" (func (param i32) (result i32)))
+    (import "lunatic::distributed" "ack_message" (func (param i64) (result i32)))
+    (import "lunatic::distributed" "ack_range" (func (param i64) (result i32)))
+    (import "lunatic::distributed" "reply_cap" (func (param i64) (result i32)))
     (import "lunatic::distributed" "send_receive_skip_search" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::distributed" "call" (func (param i64 i64 i32 i32 i32 i32 i64 i32) (result i32)))
+    (import "lunatic::distributed" "compare_and_swap" (func (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "in_flight_requests" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "cancel_request" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "publish" (func (param i64 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "counter_add" (func (param i32 i32 i64)))
+    (import "lunatic::distributed" "counter_get" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "claim_singleton" (func (param i32 i32 i32 i64 i32 i32) (result i32)))
+    (import "lunatic::distributed" "release_singleton" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "group_join" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "group_leave" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "group_send" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "group_kill" (func (param i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "group_monitor" (func (param i32 i32 i64 i32) (result i32)))
+    (import "lunatic::distributed" "subscribe_node_events" (func (param i64)))
+    (import "lunatic::distributed" "control_status" (func (result i32)))
+    (import "lunatic::distributed" "drain_node" (func))
+    (import "lunatic::distributed" "is_draining" (func (result i32)))
+    (import "lunatic::distributed" "topology_snapshot" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "node_sequence_next" (func (result i64)))
+    (import "lunatic::distributed" "self_test" (func (param i32 i64 i32 i32 i32 i32) (result i32)))
 
     (import "lunatic::metrics" "counter" (func (param i32 i32 i64)))
     (import "lunatic::metrics" "increment_counter" (func (param i32 i32)))